@@ -0,0 +1,36 @@
+//! Lexing and parsing a single large generated deck, the shape of file that
+//! users generating their cards from other sources end up with.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use flash::{model_loader::builtin_models, parse::lex, types::deck::Deck};
+
+/// Lines in the generated file, four to a note
+const LINES: usize = 100_000;
+
+fn synthetic_deck() -> String {
+	let mut content = String::from("=Basic=\n\n");
+	for idx in 0..LINES / 4 {
+		content.push_str(&format!("[generated, batch-{}]\n", idx % 50));
+		content.push_str(&format!("Front: What is the answer to question number {}?\n", idx));
+		content.push_str(&format!(
+			"Back: It is answer {}, see also: the = sign, [brackets] and a\\nbreak\n\n",
+			idx
+		));
+	}
+	content
+}
+
+fn parse(c: &mut Criterion) {
+	let models = builtin_models().expect("built-in models load");
+	let content = synthetic_deck();
+
+	c.bench_function("lex 100k lines", |b| b.iter(|| lex(black_box(&content)).len()));
+	c.bench_function("parse 100k lines", |b| {
+		b.iter(|| Deck::parse_cards(&models, black_box(&content)).map(|notes| notes.len()))
+	});
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -7,58 +7,330 @@
 //! user doesn't attempt more than one change operation at a time (Following
 //! typical Git commit standards)
 
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashSet, mem};
 
-use uuid::Uuid;
-
-use crate::{change_router::Transforms::{self, Additions, Deletions, Modifications, Reorders}, types::note::{Identified, Note}, uuid_generator};
+use crate::{change_router::Transforms::{self, Additions, Deletions, Edits, Modifications, Reorders}, types::note::{Identified, Note, TextElement}, uuid_generator::{HostContext, UuidStrategy}};
 
 /// This function takes a set of transformations, in order from earliest to
 /// latest, and applies them to the original notes within a deck. It is tracking
 /// the state of the list over time, and returning its stable representation.
+/// Notes in need of a new identity are given one by `strategy`.
+///
+/// Each kind of change is indexed against a particular state of the list, so
+/// they're applied in a fixed order: modifications in place at their old
+/// positions, then deletions from the highest position down, so removing one
+/// leaves those still to go where they were, then additions from the lowest
+/// position up, each landing at its position in the new list. Debug builds
+/// assert the order and that every position is in bounds.
+///
+/// A modification leaving a note less similar to its former self than
+/// `similarity_threshold` is treated as a different card altogether, and given
+/// a fresh identity rather than inheriting the old one, as is a note moved to a
+/// different model.
 pub fn resolve_changes<'a, 'b>(
 	transformations: &Transforms<'a>,
 	substrate: &mut Vec<Identified<Note<'b>>>,
-	host_uuid: Uuid,
+	strategy: &dyn UuidStrategy,
+	host: &HostContext,
+	similarity_threshold: f64,
 ) {
 	match transformations {
 		Additions(additions) => {
+			debug_assert!(
+				additions.windows(2).all(|pair| pair[0].0 < pair[1].0),
+				"additions must be in ascending order"
+			);
 			for (idx, new_note) in additions {
-				let base_uuid =
-					uuid_generator::generate_note_uuid(&host_uuid, &new_note.to_content_string());
+				debug_assert!(*idx <= substrate.len(), "addition at {} is out of bounds", idx);
+				let base_uuid = strategy.note_id(new_note, host);
 				substrate.insert(*idx, Identified {
 					id:    base_uuid,
 					inner: Note {
-						fields: new_note.fields.clone(),
-						model:  Cow::Owned(new_note.model.clone().into_owned()),
-						tags:   new_note.tags.clone(),
+						fields:   new_note.fields.clone(),
+						model:    Cow::Owned(new_note.model.clone().into_owned()),
+						tags:     new_note.tags.clone(),
+						media:    new_note.media.clone(),
+						comments: new_note.comments.clone(),
+						data:     new_note.data.clone(),
+						classes:  new_note.classes.clone(),
+						reveal:   new_note.reveal,
 					},
 				});
 			}
 		}
 		Deletions(deletions) => {
 			// Deletions are reversed during change vector creation
+			debug_assert!(
+				deletions.windows(2).all(|pair| pair[0] > pair[1]),
+				"deletions must be in descending order"
+			);
 			for idx in deletions {
+				debug_assert!(*idx < substrate.len(), "deletion at {} is out of bounds", idx);
 				substrate.remove(*idx);
 			}
 		}
 		Modifications(modifications) => {
 			for (idx, modified_note) in modifications {
-				let existing_id = substrate[*idx].id;
+				debug_assert!(*idx < substrate.len(), "modification at {} is out of bounds", idx);
+				let existing = &substrate[*idx];
+				let id = if existing.inner.model.id != modified_note.model.id {
+					// Anki can't change a note's type in place, so under a new model it's a
+					// deletion and an addition
+					strategy.note_id(modified_note, host)
+				} else if similarity(&existing.inner, modified_note) < similarity_threshold {
+					// Changed beyond recognition, so it's a deletion and an addition
+					strategy.note_id(modified_note, host)
+				} else {
+					existing.id
+				};
 				substrate[*idx] = Identified {
-					id:    existing_id,
+					id,
 					inner: Note {
-						fields: modified_note.fields.clone(),
-						model:  Cow::Owned(modified_note.model.clone().into_owned()),
-						tags:   modified_note.tags.clone(),
+						fields:   modified_note.fields.clone(),
+						model:    Cow::Owned(modified_note.model.clone().into_owned()),
+						tags:     modified_note.tags.clone(),
+						media:    modified_note.media.clone(),
+						comments: modified_note.comments.clone(),
+						data:     modified_note.data.clone(),
+						classes:  modified_note.classes.clone(),
+						reveal:   modified_note.reveal,
 					},
 				};
 			}
 		}
-		Reorders(mappings) => {
-			for (from, to) in mappings {
-				substrate.swap(*from, *to);
+		Reorders(sources) => {
+			// Identities travel with their notes to the new positions
+			let mut previous: Vec<Option<_>> = mem::take(substrate).into_iter().map(Some).collect();
+			*substrate = sources.iter().filter_map(|&from| previous.get_mut(from)?.take()).collect();
+		}
+		Edits { modifications, deletions, additions } => {
+			// Modifications first, while the positions are still the old ones
+			for transforms in [
+				Modifications(modifications.clone()),
+				Deletions(deletions.clone()),
+				Additions(additions.clone()),
+			] {
+				resolve_changes(&transforms, substrate, strategy, host, similarity_threshold);
+			}
+		}
+	}
+}
+
+/// Jaccard similarity between the words making up two notes' fields, from 0
+/// (nothing in common) to 1 (the same words)
+pub fn similarity(a: &Note, b: &Note) -> f64 {
+	fn words<'n>(note: &'n Note) -> HashSet<&'n str> {
+		note
+			.fields
+			.iter()
+			.flat_map(|field| &field.content)
+			.flat_map(|part| match part {
+				TextElement::Text(text) | TextElement::Html(text) => text.split_whitespace(),
+				TextElement::Cloze(cloze) => cloze.answer.split_whitespace(),
+			})
+			.collect()
+	}
+
+	let (a, b) = (words(a), words(b));
+	let union = a.union(&b).count();
+
+	// Two empty notes are trivially the same
+	if union == 0 { 1.0 } else { a.intersection(&b).count() as f64 / union as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+	use uuid::Uuid;
+
+	use super::*;
+	use crate::{change_router::determine_changes, test_support, uuid_generator::HostScoped};
+
+	const ORIGINAL: Uuid = Uuid::from_u128(1);
+	const HOST: Uuid = Uuid::from_u128(2);
+
+	/// The id the note at the front of `before` holds once edited into `after`
+	fn id_after_edit(before: &str, after: &str, similarity_threshold: f64) -> Uuid {
+		let models = test_support::models();
+		let before = test_support::parse(&models, before).remove(0);
+		let after = test_support::parse(&models, after);
+
+		let mut substrate = vec![Identified { id: ORIGINAL, inner: before }];
+		let host = HostContext { host_uuid: HOST };
+		let changes = Modifications(vec![(0, &after[0])]);
+		resolve_changes(&changes, &mut substrate, &HostScoped, &host, similarity_threshold);
+		substrate[0].id
+	}
+
+	fn similarity_of(a: &str, b: &str) -> f64 {
+		let models = test_support::models();
+		let a = test_support::parse(&models, a).remove(0);
+		let b = test_support::parse(&models, b).remove(0);
+		similarity(&a, &b)
+	}
+
+	#[test]
+	fn similarity_runs_from_disjoint_to_the_same_words() {
+		assert_eq!(similarity_of("=Basic=\nFront: a b\n", "=Basic=\nFront: b a\n"), 1.0);
+		assert_eq!(similarity_of("=Basic=\nFront: a b\n", "=Basic=\nFront: c d\n"), 0.0);
+		assert_eq!(similarity_of("=Basic=\nFront: a b\n", "=Basic=\nFront: a c\n"), 1.0 / 3.0);
+	}
+
+	#[test]
+	fn similarity_counts_cloze_answers_as_words() {
+		assert_eq!(similarity_of("=Cloze=\nText: {c1::a} b\n", "=Basic=\nFront: a b\n"), 1.0);
+	}
+
+	#[test]
+	fn an_edit_at_the_threshold_keeps_its_identity() {
+		// Two of the four words are shared, a similarity of exactly one half
+		let (before, after) = ("=Basic=\nFront: a b c\n", "=Basic=\nFront: a b d\n");
+		assert_eq!(id_after_edit(before, after, 0.5), ORIGINAL);
+		assert_ne!(id_after_edit(before, after, 0.51), ORIGINAL);
+	}
+
+	#[test]
+	fn a_threshold_of_zero_keeps_every_identity() {
+		assert_eq!(id_after_edit("=Basic=\nFront: a\n", "=Basic=\nFront: b\n", 0.0), ORIGINAL);
+	}
+
+	#[test]
+	fn a_threshold_of_one_renews_any_change_of_words() {
+		let before = "=Basic=\nFront: a b\n";
+		assert_ne!(id_after_edit(before, "=Basic=\nFront: a b c\n", 1.0), ORIGINAL);
+		// Only the words count, so moving them between fields is no change
+		assert_eq!(id_after_edit(before, "=Basic=\nFront: a\nBack: b\n", 1.0), ORIGINAL);
+	}
+
+	#[test]
+	fn identities_travel_with_reordered_notes() {
+		let models = test_support::models();
+		let mut substrate: Vec<_> =
+			test_support::parse(&models, "=Basic=\nFront: a\n\nFront: b\n\nFront: c\n")
+				.into_iter()
+				.enumerate()
+				.map(|(idx, note)| Identified { id: Uuid::from_u128(idx as u128), inner: note })
+				.collect();
+
+		let host = HostContext { host_uuid: HOST };
+		resolve_changes(&Reorders(vec![2, 0, 1]), &mut substrate, &HostScoped, &host, 0.0);
+
+		let ids: Vec<u128> = substrate.iter().map(|card| card.id.as_u128()).collect();
+		assert_eq!(ids, vec![2, 0, 1]);
+		assert_eq!(
+			substrate[0].inner.field("Front").map(|field| field.content.clone()),
+			Some(vec![TextElement::Text("c".to_string())])
+		);
+	}
+
+	#[test]
+	fn a_note_moved_to_another_model_is_renewed() {
+		// The same words, so only the model tells them apart
+		assert_ne!(id_after_edit("=Basic=\nFront: a\n", "=Cloze=\nText: a\n", 0.0), ORIGINAL);
+	}
+
+	/// What an edit does to a note of the deck
+	#[derive(Debug, Clone, Copy)]
+	enum Edit {
+		Keep,
+		Delete,
+		Replace,
+	}
+
+	/// An edit per note of a deck of up to eight, each with whether a fresh note
+	/// goes in ahead of it, so every script is one a real edit could produce
+	fn edit_script() -> impl Strategy<Value = Vec<(bool, Edit)>> {
+		let edit =
+			prop_oneof![2 => Just(Edit::Keep), 1 => Just(Edit::Delete), 1 => Just(Edit::Replace)];
+		prop::collection::vec((prop::bool::weighted(0.25), edit), 1..=8)
+	}
+
+	/// The words of a deck of up to ten notes, shuffled
+	fn reorder() -> impl Strategy<Value = Vec<usize>> {
+		(1..=10usize).prop_flat_map(|len| Just((1..=len).collect::<Vec<_>>()).prop_shuffle())
+	}
+
+	/// Words from here up are new to a deck, and so never carry an identity
+	const FRESH: usize = 1_000;
+
+	/// A Basic note fronted by each word in turn
+	fn source(words: &[usize]) -> String {
+		let notes: Vec<String> = words.iter().map(|word| format!("Front: w{}\n", word)).collect();
+		format!("=Basic=\n{}", notes.join("\n"))
+	}
+
+	/// The word a note was fronted by
+	fn word(note: &Note) -> usize {
+		match note.field("Front").map(|field| field.content.as_slice()) {
+			Some([TextElement::Text(text)]) => text[1..].parse().expect("a numbered word"),
+			content => panic!("a single word, got {:?}", content),
+		}
+	}
+
+	/// Resolve the changes from `before` to `after` over notes identified by
+	/// their words, returning the resolved notes
+	fn resolve(before: &[usize], after: &[usize]) -> Vec<(Uuid, usize)> {
+		let models = test_support::models();
+		let (before, after) = (source(before), source(after));
+		let old = test_support::parse(&models, &before);
+		let new = test_support::parse(&models, &after);
+
+		let mut substrate: Vec<_> = old
+			.iter()
+			.map(|note| Identified { id: Uuid::from_u128(word(note) as u128), inner: note.clone() })
+			.collect();
+		let host = HostContext { host_uuid: test_support::host() };
+		if let Some(changes) = determine_changes(&old, &new).expect("the changes are unambiguous") {
+			resolve_changes(&changes, &mut substrate, &HostScoped, &host, 0.0);
+		}
+
+		substrate.iter().map(|card| (card.id, word(&card.inner))).collect()
+	}
+
+	// `resolve_changes` asserts every position it's handed is in bounds, so any
+	// script taking it out of them fails here
+	proptest! {
+		#[test]
+		fn resolving_any_edit_script_keeps_the_notes_it_leaves_alone(script in edit_script()) {
+			let before: Vec<usize> = (1..=script.len()).collect();
+			let mut fresh = FRESH..;
+
+			let mut after = Vec::new();
+			for (&word, &(insert, edit)) in before.iter().zip(&script) {
+				if insert {
+					after.push(fresh.next().expect("words to spare"));
+				}
+				match edit {
+					Edit::Keep => after.push(word),
+					Edit::Delete => {}
+					Edit::Replace => after.push(fresh.next().expect("words to spare")),
+				}
 			}
+			if after.is_empty() {
+				after.push(fresh.next().expect("words to spare"));
+			}
+
+			let resolved = resolve(&before, &after);
+			let words: Vec<usize> = resolved.iter().map(|&(_, word)| word).collect();
+			prop_assert_eq!(words, after);
+
+			for &(id, word) in &resolved {
+				if word < FRESH {
+					prop_assert_eq!(id, Uuid::from_u128(word as u128), "w{} lost its id", word);
+				}
+			}
+			let ids: HashSet<Uuid> = resolved.iter().map(|&(id, _)| id).collect();
+			prop_assert_eq!(ids.len(), resolved.len(), "an identity was handed out twice");
+		}
+
+		#[test]
+		fn resolving_any_reorder_moves_every_identity_with_its_note(after in reorder()) {
+			let before: Vec<usize> = (1..=after.len()).collect();
+
+			let resolved = resolve(&before, &after);
+			let expected: Vec<(Uuid, usize)> =
+				after.iter().map(|&word| (Uuid::from_u128(word as u128), word)).collect();
+			prop_assert_eq!(resolved, expected);
 		}
 	}
 }
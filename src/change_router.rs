@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use crate::{error::DeckError, types::note::Note};
 
@@ -7,12 +7,25 @@ pub enum Transforms<'a> {
 	Additions(Vec<(usize, &'a Note<'a>)>),
 	Deletions(Vec<usize>),
 	Modifications(Vec<(usize, &'a Note<'a>)>),
-	Reorders(HashSet<(usize, usize)>),
+	// For each position in the new deck, the position its note held in the old
+	Reorders(Vec<usize>),
+	// More than one kind of change at once. Modifications are made in place at
+	// their old positions, then deletions from the back, then additions at
+	// their new positions
+	Edits {
+		modifications: Vec<(usize, &'a Note<'a>)>,
+		deletions:     Vec<usize>,
+		additions:     Vec<(usize, &'a Note<'a>)>,
+	},
 }
 
-/// Determines the kinds of changes that have occured between two decks. The
-/// returned vector is compromised of just one ChangeType. Errors are returned
-/// when the algorithim detects more than one kind of change.
+/// Determines the kinds of changes that have occured between two decks.
+/// Additions, deletions and modifications are found through the shortest edit
+/// script between the decks, so a single insertion or edit is reported as just
+/// that rather than a change at every position after it. The returned
+/// Transforms hold just one kind of change where that's all there is. Errors
+/// are returned when a note was both moved and modified, which can't be told
+/// apart from editing a different note.
 pub fn determine_changes<'b>(
 	deck_1: &[Note], // The old deck is MORE disposable
 	deck_2: &'b [Note],
@@ -23,82 +36,321 @@ pub fn determine_changes<'b>(
 		return Ok(None);
 	}
 
-	// Case 1: Different lengths - either all additions or all deletions
-	// We can't mix these types because indices would become inconsistent
-	if deck_1.len() != deck_2.len() {
-		if deck_2.len() > deck_1.len() {
-			// Deck grew - find all additions by walking both decks
-			let mut additions = Vec::new();
-			let mut deck_1_idx = 0;
-			let mut deck_2_idx = 0;
-
-			while deck_2_idx < deck_2.len() {
-				if deck_1_idx < deck_1.len() && deck_1[deck_1_idx] == deck_2[deck_2_idx] {
-					// Cards match, advance both pointers
-					deck_1_idx += 1;
-					deck_2_idx += 1;
-				} else {
-					// Card at deck_2_idx is new - record the addition
-					additions.push((deck_2_idx, &deck_2[deck_2_idx]));
-					deck_2_idx += 1;
+	// Same cards in a different order - this is a reordering
+	if deck_1.len() == deck_2.len() {
+		let mut sorted_1 = deck_1.to_vec();
+		let mut sorted_2 = deck_2.to_vec();
+		sorted_1.sort();
+		sorted_2.sort();
+
+		if sorted_1 == sorted_2 {
+			// Notes with identical content are paired up in the order they appear, the
+			// first copy in the new deck taking the first copy from the old, so the
+			// permutation never depends on which duplicate a search happens upon
+			let mut unclaimed: BTreeMap<&Note, VecDeque<usize>> = BTreeMap::new();
+			for (idx, card) in deck_1.iter().enumerate() {
+				unclaimed.entry(card).or_default().push_back(idx);
+			}
+
+			let sources = deck_2
+				.iter()
+				.map(|card| unclaimed.get_mut(card).and_then(VecDeque::pop_front))
+				.collect::<Option<Vec<usize>>>()
+				.ok_or_else(|| DeckError::AmbiguousChange("reordered notes didn't pair up".to_string()))?;
+
+			return Ok(Some(Transforms::Reorders(sources)));
+		}
+	}
+
+	let mut modifications = Vec::new();
+	let mut deletions = Vec::new();
+	let mut additions = Vec::new();
+
+	let hunks = hunks(&shortest_edit_script(deck_1, deck_2));
+
+	// Everything the script takes out or puts in, anywhere in the deck
+	let all_removed: BTreeSet<usize> =
+		hunks.iter().flat_map(|(removed, _)| removed.clone()).collect();
+	let all_inserted: BTreeSet<usize> =
+		hunks.iter().flat_map(|(_, inserted)| inserted.clone()).collect();
+
+	for (removed, inserted) in hunks {
+		// As many notes replaced as replaced them, each was edited in place
+		if removed.len() == inserted.len() {
+			for (old, new) in removed.into_iter().zip(inserted) {
+				// A card the script takes out elsewhere and puts back here, or takes out here
+				// and puts back elsewhere, was moved, not edited. Moving and editing in the
+				// same commit can't be told apart from editing in place, so refuse to guess
+				// rather than hand the wrong card the wrong identity. Cards the script keeps
+				// are another note's content, an edit may well duplicate them.
+				let moved_from =
+					all_removed.iter().find(|&&other| other != old && deck_1[other] == deck_2[new]);
+				let moved_to =
+					all_inserted.iter().find(|&&other| other != new && deck_2[other] == deck_1[old]);
+				if let Some(other) = moved_from.or(moved_to) {
+					return Err(DeckError::AmbiguousChange(format!(
+						"notes {} and {} were both moved and modified",
+						new, other
+					)));
 				}
+
+				modifications.push((old, &deck_2[new]));
 			}
-			return Ok(Some(Transforms::Additions(additions)));
 		} else {
-			// Deck shrank - find all deletions by walking both decks
-			let mut deletions = Vec::new();
-			let mut deck_1_idx = 0;
-			let mut deck_2_idx = 0;
-
-			while deck_1_idx < deck_1.len() {
-				if deck_2_idx < deck_2.len() && deck_1[deck_1_idx] == deck_2[deck_2_idx] {
-					// Cards match, advance both pointers
-					deck_1_idx += 1;
-					deck_2_idx += 1;
-				} else {
-					// Card at deck_1_idx was deleted - record the deletion
-					deletions.push(deck_1_idx);
-					deck_1_idx += 1;
-				}
+			// Which of the notes became which is anyone's guess, so none carry over
+			deletions.extend(removed);
+			additions.extend(inserted.into_iter().map(|new| (new, &deck_2[new])));
+		}
+	}
+
+	// IMPORTANT: Deletions must be applied in reverse order to maintain
+	// index consistency. When you delete at index 0, everything shifts down,
+	// so we need to delete from the end first.
+	deletions.reverse();
+
+	let transforms = match (modifications.is_empty(), deletions.is_empty(), additions.is_empty()) {
+		(false, true, true) => Transforms::Modifications(modifications),
+		(true, false, true) => Transforms::Deletions(deletions),
+		(true, true, false) => Transforms::Additions(additions),
+		_ => Transforms::Edits { modifications, deletions, additions },
+	};
+	Ok(Some(transforms))
+}
+
+/// A step of the edit script turning one deck into another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+	Keep,
+	// Position in the old deck
+	Delete(usize),
+	// Position in the new deck
+	Insert(usize),
+}
+
+/// The shortest sequence of deletions and insertions turning `old` into `new`,
+/// found with Myers' algorithm. Ties between equally short scripts are always
+/// broken the same way, so the same decks give the same script.
+fn shortest_edit_script<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+	let (n, m) = (old.len() as isize, new.len() as isize);
+	let max = (n + m) as usize;
+	let offset = max as isize;
+
+	// The furthest x reached on each diagonal k = x - y, kept for every round d
+	// so the path can be traced back
+	let mut furthest = vec![0isize; 2 * max + 2];
+	let mut trace = Vec::new();
+
+	'search: for d in 0..=max as isize {
+		trace.push(furthest.clone());
+
+		for k in (-d..=d).step_by(2) {
+			let idx = (k + offset) as usize;
+			let mut x = if k == -d || (k != d && furthest[idx - 1] < furthest[idx + 1]) {
+				furthest[idx + 1]
+			} else {
+				furthest[idx - 1] + 1
+			};
+			let mut y = x - k;
+
+			while x < n && y < m && old[x as usize] == new[y as usize] {
+				x += 1;
+				y += 1;
+			}
+
+			furthest[idx] = x;
+			if x >= n && y >= m {
+				break 'search;
 			}
-			// IMPORTANT: Deletions must be applied in reverse order to maintain
-			// index consistency. When you delete at index 0, everything shifts down,
-			// so we need to delete from the end first.
-			deletions.reverse();
-			return Ok(Some(Transforms::Deletions(deletions)));
 		}
 	}
 
-	// Case 2: Same length - could be reordering or modifications
-	// Check if it's a reorder by comparing sorted versions
-	let mut sorted_1 = deck_1.to_vec();
-	let mut sorted_2 = deck_2.to_vec();
-	sorted_1.sort();
-	sorted_2.sort();
-
-	if sorted_1 == sorted_2 {
-		// Same cards, different order - this is a reordering
-		// Find all positions where cards differ
-		let mut reorderings = HashSet::new();
-		for ((idx1, card1), (_, card2)) in deck_1.iter().enumerate().zip(deck_2.iter().enumerate()) {
-			if *card1 != *card2
-				&& let Some(idx2) = deck_2.iter().position(|cur| cur == card1)
-			{
-				// Track where each card moved from -> to
-				let swap = if idx1 < idx2 { (idx1, idx2) } else { (idx2, idx1) };
-				reorderings.insert(swap);
+	let mut script = Vec::new();
+	let (mut x, mut y) = (n, m);
+
+	for (d, furthest) in trace.iter().enumerate().rev() {
+		let d = d as isize;
+		let k = x - y;
+		let idx = (k + offset) as usize;
+
+		let prev_k =
+			if k == -d || (k != d && furthest[idx - 1] < furthest[idx + 1]) { k + 1 } else { k - 1 };
+		let prev_x = furthest[(prev_k + offset) as usize];
+		let prev_y = prev_x - prev_k;
+
+		while x > prev_x && y > prev_y {
+			script.push(Edit::Keep);
+			x -= 1;
+			y -= 1;
+		}
+
+		if d > 0 {
+			if x == prev_x {
+				script.push(Edit::Insert((y - 1) as usize));
+			} else {
+				script.push(Edit::Delete((x - 1) as usize));
 			}
 		}
-		Ok(Some(Transforms::Reorders(reorderings)))
-	} else {
-		// Different cards at same positions - these are modifications
-		// Find all positions where content changed
-		let mut modifications = Vec::new();
-		for (index, (card1, card2)) in deck_1.iter().zip(deck_2.iter()).enumerate() {
-			if card1 != card2 {
-				modifications.push((index, card2));
+
+		(x, y) = (prev_x, prev_y);
+	}
+
+	script.reverse();
+	script
+}
+
+/// Group an edit script into runs of changes between kept notes, each as the
+/// old positions removed and the new positions inserted in their place
+fn hunks(script: &[Edit]) -> Vec<(Vec<usize>, Vec<usize>)> {
+	let mut hunks = Vec::new();
+	let mut current: (Vec<usize>, Vec<usize>) = (Vec::new(), Vec::new());
+
+	for edit in script {
+		match *edit {
+			Edit::Delete(old) => current.0.push(old),
+			Edit::Insert(new) => current.1.push(new),
+			Edit::Keep => {
+				if !current.0.is_empty() || !current.1.is_empty() {
+					hunks.push(std::mem::take(&mut current));
+				}
 			}
 		}
-		Ok(Some(Transforms::Modifications(modifications)))
+	}
+
+	if !current.0.is_empty() || !current.1.is_empty() {
+		hunks.push(current);
+	}
+	hunks
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	/// A Basic note for each letter, the letter as its front
+	fn source(letters: &str) -> String {
+		let fronts: Vec<String> =
+			letters.chars().map(|letter| format!("Front: {}\n", letter)).collect();
+		format!("=Basic=\n{}", fronts.join("\n"))
+	}
+
+	#[test]
+	fn identical_decks_have_no_changes() {
+		let models = test_support::models();
+		let source = source("ab");
+		let notes = test_support::parse(&models, &source);
+		assert!(determine_changes(&notes, &notes).unwrap().is_none());
+	}
+
+	#[test]
+	fn a_reorder_maps_each_position_to_its_source() {
+		let models = test_support::models();
+		let (old, new) = (source("abc"), source("cab"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(matches!(changes, Some(Transforms::Reorders(sources)) if sources == vec![2, 0, 1]));
+	}
+
+	#[test]
+	fn an_edit_is_made_in_place() {
+		let models = test_support::models();
+		let (old, new) = (source("abc"), source("axc"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(
+			matches!(changes, Some(Transforms::Modifications(edits)) if edits.len() == 1 && edits[0].0 == 1)
+		);
+	}
+
+	#[test]
+	fn editing_a_note_into_a_copy_of_another_is_an_edit() {
+		let models = test_support::models();
+		let (old, new) = (source("ab"), source("aa"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(
+			matches!(changes, Some(Transforms::Modifications(edits)) if edits.len() == 1 && edits[0].0 == 1)
+		);
+	}
+
+	#[test]
+	fn swapping_without_editing_is_a_reorder() {
+		let models = test_support::models();
+		let (old, new) = (source("abcd"), source("dbca"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(matches!(changes, Some(Transforms::Reorders(sources)) if sources == vec![3, 1, 2, 0]));
+	}
+
+	#[test]
+	fn swapping_and_editing_at_once_is_ambiguous() {
+		let models = test_support::models();
+		// a and d trade places, a being edited into x on the way
+		let (old, new) = (source("abcd"), source("dbcx"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new);
+		assert!(matches!(changes, Err(DeckError::AmbiguousChange(_))));
+	}
+
+	#[test]
+	fn insertions_and_deletions_keep_the_rest_in_place() {
+		let models = test_support::models();
+		let (old, new) = (source("abc"), source("bcd"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(matches!(
+			changes,
+			Some(Transforms::Edits { modifications, deletions, additions })
+				if modifications.is_empty() && deletions == vec![0] && additions[0].0 == 2
+		));
+	}
+
+	#[test]
+	fn duplicates_are_paired_in_the_order_they_appear() {
+		let models = test_support::models();
+		let (old, new) = (source("aba"), source("aab"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(matches!(changes, Some(Transforms::Reorders(sources)) if sources == vec![0, 2, 1]));
+
+		let (old, new) = (source("aab"), source("baa"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(matches!(changes, Some(Transforms::Reorders(sources)) if sources == vec![2, 0, 1]));
+	}
+
+	#[test]
+	fn a_single_insertion_is_just_that() {
+		let models = test_support::models();
+		let (old, new) = (source("acd"), source("abcd"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		let Some(Transforms::Additions(additions)) = changes else {
+			panic!("a single insertion is an addition");
+		};
+		assert_eq!(additions.iter().map(|(idx, _)| *idx).collect::<Vec<_>>(), vec![1]);
+	}
+
+	#[test]
+	fn deletions_are_listed_from_the_back() {
+		let models = test_support::models();
+		let (old, new) = (source("abcde"), source("bd"));
+		let (old, new) = (test_support::parse(&models, &old), test_support::parse(&models, &new));
+		let changes = determine_changes(&old, &new).unwrap();
+		assert!(
+			matches!(changes, Some(Transforms::Deletions(deletions)) if deletions == vec![4, 2, 0])
+		);
+	}
+
+	#[test]
+	fn the_edit_script_is_as_short_as_can_be() {
+		let (old, new): (Vec<char>, Vec<char>) =
+			("abcabba".chars().collect(), "cbabac".chars().collect());
+		let script = shortest_edit_script(&old, &new);
+
+		// The longest common subsequence of the two is four long
+		let kept = script.iter().filter(|edit| **edit == Edit::Keep).count();
+		assert_eq!((kept, script.len() - kept), (4, 5));
+		assert_eq!(script, shortest_edit_script(&old, &new));
 	}
 }
@@ -0,0 +1,263 @@
+//! Validations that don't prevent a deck from being exported, but flag content
+//! that likely won't behave the way the author expects once it's in Anki.
+
+use std::{fs, path::PathBuf};
+
+use thiserror::Error;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::{deck_locator::scan_deck_contents, error::DeckError, parse::expected_notes, types::{config::ModelKind, deck::Deck, note::TextElement}};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Warning {
+	#[error(
+		"Note {note} has an empty sort field '{field}', it will have a blank sort key in the browser."
+	)]
+	EmptySortField { note: Uuid, field: String },
+
+	#[error(
+		"Note {note} has clozes but its model '{model}' is a standard model, declare `type = \"cloze\"` in its config.toml for Anki to generate cloze cards."
+	)]
+	ClozeInStandardModel { note: Uuid, model: String },
+
+	#[error(
+		"{file:?}:{line} has a tab inside a field's content, it's part of the note's identity but easy to mistake for spaces."
+	)]
+	TabInField { file: PathBuf, line: usize },
+
+	#[error("{file:?}:{line} is indented with a mix of tabs and spaces.")]
+	MixedIndentation { file: PathBuf, line: usize },
+}
+
+/// Run every validation over the deck, returning the problems found
+#[instrument(skip(deck))]
+pub fn check_deck(deck: &Deck) -> Vec<Warning> {
+	let mut warnings = Vec::new();
+
+	warnings.extend(empty_sort_fields(deck));
+	warnings.extend(clozes_in_standard_models(deck));
+
+	info!("Check found {} warnings", warnings.len());
+	warnings
+}
+
+/// Anki sorts on the model's designated sort field, or the first field when
+/// there isn't one. A note with nothing in that field gets a blank sort key,
+/// which is a common cause of cards seemingly disappearing.
+fn empty_sort_fields(deck: &Deck) -> Vec<Warning> {
+	deck
+		.cards
+		.iter()
+		.filter_map(|card| {
+			let model = &card.inner.model;
+			let sort_field =
+				model.sort_field.as_ref().or_else(|| model.fields.first().map(|field| &field.name))?;
+
+			let is_empty = card.inner.field(sort_field).is_none_or(|field| field.is_empty());
+
+			is_empty.then(|| Warning::EmptySortField { note: card.id, field: sort_field.clone() })
+		})
+		.collect()
+}
+
+/// Anki only turns clozes into cards under a cloze model, anywhere else they
+/// show up as literal braces
+fn clozes_in_standard_models(deck: &Deck) -> Vec<Warning> {
+	deck
+		.cards
+		.iter()
+		.filter(|card| card.inner.model.kind == ModelKind::Standard)
+		.filter(|card| {
+			card
+				.inner
+				.fields
+				.iter()
+				.flat_map(|field| &field.content)
+				.any(|part| matches!(part, TextElement::Cloze(_)))
+		})
+		.map(|card| Warning::ClozeInStandardModel {
+			note:  card.id,
+			model: card.inner.model.name.clone(),
+		})
+		.collect()
+}
+
+/// Tabs and spaces look alike in most editors, yet a tab inside a field is
+/// content like any other, so one sneaking in changes the note's identity
+/// without any visible change. Opt-in as it reads every card file in the deck
+/// afresh, reporting where in each the whitespace is.
+#[instrument(skip(deck))]
+pub fn check_whitespace(deck: &Deck) -> Result<Vec<Warning>, DeckError> {
+	let (_, card_paths) = scan_deck_contents(&deck.path)?;
+	let mut warnings = Vec::new();
+
+	for file in card_paths {
+		let content = fs::read_to_string(&file)?;
+		for (idx, line) in content.lines().enumerate() {
+			if line.trim_start().starts_with("//") {
+				continue;
+			}
+
+			let indent = &line[..line.len() - line.trim_start().len()];
+			if indent.contains('\t') && indent.contains(' ') {
+				warnings.push(Warning::MixedIndentation { file: file.clone(), line: idx + 1 });
+			}
+
+			if has_inner_tab(line) {
+				warnings.push(Warning::TabInField { file: file.clone(), line: idx + 1 });
+			}
+		}
+	}
+
+	info!("Whitespace check found {} warnings", warnings.len());
+	Ok(warnings)
+}
+
+/// Hold the deck to the note count index.flash declares through
+/// `@expect-notes`, give or take the config's `note_count_tolerance`, so a bad
+/// edit dropping half the file fails the check rather than the next export.
+/// Notes of tracked files count toward it, as they're part of the deck.
+#[instrument(skip(deck))]
+pub fn check_note_count(deck: &Deck) -> Result<(), DeckError> {
+	let content = fs::read_to_string(deck.path.join("index.flash"))?;
+	let Some(expected) = expected_notes(&content).map_err(DeckError::Parse)? else {
+		return Ok(());
+	};
+
+	let found = deck.cards.len();
+	let tolerance = deck.configuration.note_count_tolerance;
+	if expected.abs_diff(found) > tolerance {
+		return Err(DeckError::NoteCountMismatch(expected, tolerance, found));
+	}
+
+	info!("Deck holds {} notes, {} were expected", found, expected);
+	Ok(())
+}
+
+/// Whether a tab survives into a field's value, once the whitespace the parser
+/// trims (around cells, and between a field's name and its value) is gone
+fn has_inner_tab(line: &str) -> bool {
+	line.split('|').any(|cell| {
+		let cell = cell.trim();
+		let value = cell
+			.split_once(':')
+			.filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+			.map_or(cell, |(_, value)| value.trim_start());
+		value.contains('\t')
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{self, Scratch};
+
+	const NOTES: &str = "=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n\nFront: e\nBack: f\n";
+
+	/// Check a deck of three notes against the index `header` opens
+	fn check(header: &str, tolerance: usize) -> Result<(), DeckError> {
+		let scratch = Scratch::new();
+		let index = format!("{}{}", header, NOTES);
+		scratch.write("index.flash", &index);
+
+		let mut deck = test_support::deck(&[&index]);
+		deck.path = scratch.path.clone();
+		deck.configuration.note_count_tolerance = tolerance;
+		check_note_count(&deck)
+	}
+
+	#[test]
+	fn a_count_within_the_tolerance_passes() {
+		assert!(check("@expect-notes 3\n", 0).is_ok());
+		assert!(check("@expect-notes 5\n", 2).is_ok());
+	}
+
+	#[test]
+	fn a_count_outside_the_tolerance_fails() {
+		assert!(matches!(check("@expect-notes 5\n", 1), Err(DeckError::NoteCountMismatch(5, 1, 3))));
+	}
+
+	#[test]
+	fn a_deck_without_an_expectation_passes() {
+		assert!(check("", 0).is_ok());
+	}
+
+	#[test]
+	fn an_expectation_that_isnt_a_number_fails() {
+		assert!(matches!(check("@expect-notes many\n", 0), Err(DeckError::Parse(_))));
+	}
+
+	#[test]
+	fn notes_of_tracked_files_count_toward_the_expectation() {
+		let scratch = Scratch::new();
+		let index = format!("@expect-notes 4\n{}", NOTES);
+		scratch.write("index.flash", &index);
+
+		let files = vec![
+			("index.flash".to_string(), vec![index]),
+			("extra.flash".to_string(), vec!["=Basic=\nFront: g\nBack: h\n".to_string()]),
+		];
+		let mut deck = Deck::from_parts(test_support::models(), files, test_support::host())
+			.expect("the deck builds");
+		deck.path = scratch.path.clone();
+		assert!(check_note_count(&deck).is_ok());
+	}
+
+	#[test]
+	fn notes_missing_their_sort_field_are_flagged() {
+		let mut models = test_support::models();
+		models[0].sort_field = Some("Back".to_string());
+		let source = "=Basic=\nFront: a\n\nFront: c\nBack: d\n".to_string();
+		let deck = Deck::from_parts(
+			models,
+			vec![("index.flash".to_string(), vec![source])],
+			test_support::host(),
+		)
+		.expect("the deck builds");
+
+		let warnings = empty_sort_fields(&deck);
+		assert_eq!(warnings, vec![Warning::EmptySortField {
+			note:  deck.cards[0].id,
+			field: "Back".to_string(),
+		}]);
+	}
+
+	#[test]
+	fn notes_filling_their_sort_field_pass() {
+		assert!(empty_sort_fields(&test_support::deck(&[NOTES])).is_empty());
+	}
+
+	#[test]
+	fn clozes_under_a_standard_model_are_flagged() {
+		let deck =
+			test_support::deck(&["=Basic=\nFront: {c1::a}\nBack: b\n\n=Cloze=\nText: {c1::a}\n"]);
+
+		assert_eq!(clozes_in_standard_models(&deck), vec![Warning::ClozeInStandardModel {
+			note:  deck.cards[0].id,
+			model: "Basic".to_string(),
+		}]);
+	}
+
+	#[test]
+	fn stray_tabs_and_mixed_indentation_are_flagged() {
+		let scratch = Scratch::new();
+		let source = "=Basic=\n// a\ttab in a comment\nFront:\ta\nBack: b\tc\n\t Front: d\n";
+		let file = scratch.write("index.flash", source);
+
+		let mut deck = test_support::deck(&[NOTES]);
+		deck.path = scratch.path.clone();
+
+		assert_eq!(check_whitespace(&deck).expect("the deck is readable"), vec![
+			Warning::TabInField { file: file.clone(), line: 4 },
+			Warning::MixedIndentation { file, line: 5 },
+		]);
+	}
+
+	#[test]
+	fn a_tab_between_a_field_and_its_value_passes() {
+		assert!(!has_inner_tab("Front:\ta"));
+		assert!(!has_inner_tab("\ta\t|\tb\t"));
+		assert!(has_inner_tab("a | b\tc"));
+	}
+}
@@ -4,20 +4,42 @@ use tracing::{debug, error, info, instrument};
 
 use crate::error::DeckError;
 
+fn is_deck_dir(path: &Path) -> bool {
+	path.is_dir() && path.extension().and_then(|e| e.to_str()) == Some("deck")
+}
+
 #[instrument]
 pub fn find_deck_directory() -> Result<PathBuf, DeckError> {
 	info!("Searching for deck directory");
 
-	pub fn is_deck_dir(path: &Path) -> bool {
-		path.is_dir() && path.extension().and_then(|e| e.to_str()) == Some("deck")
-	}
-
 	fs::read_dir(".")?.flatten().map(|e| e.path()).find(|p| is_deck_dir(p)).ok_or_else(|| {
 		error!("No deck directory found");
 		DeckError::NoDeckFound
 	})
 }
 
+/// Every deck directory in the current directory, sorted by path
+#[instrument]
+pub fn find_all_deck_directories() -> Result<Vec<PathBuf>, DeckError> {
+	deck_directories_in(Path::new("."))
+}
+
+fn deck_directories_in(root: &Path) -> Result<Vec<PathBuf>, DeckError> {
+	info!("Searching for deck directories in {:?}", root);
+
+	let mut decks: Vec<PathBuf> =
+		fs::read_dir(root)?.flatten().map(|e| e.path()).filter(|p| is_deck_dir(p)).collect();
+
+	if decks.is_empty() {
+		error!("No deck directory found");
+		return Err(DeckError::NoDeckFound);
+	}
+
+	decks.sort();
+	debug!("Found deck directories: {:?}", decks);
+	Ok(decks)
+}
+
 #[instrument]
 pub fn scan_deck_contents(deck_path: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), DeckError> {
 	info!("Scanning deck contents at {:?}", deck_path);
@@ -41,3 +63,29 @@ pub fn scan_deck_contents(deck_path: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf
 	info!("Found {} models and {} card files", models.len(), cards.len());
 	Ok((models, cards))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::Scratch;
+
+	#[test]
+	fn every_deck_directory_is_found_in_order() {
+		let scratch = Scratch::new();
+		scratch.write("spanish.deck/index.flash", "");
+		scratch.write("french.deck/index.flash", "");
+		scratch.write("notes/index.flash", "");
+		scratch.write("stray.deck", "");
+
+		let decks = deck_directories_in(&scratch.path).expect("the decks are found");
+		assert_eq!(decks, vec![scratch.path.join("french.deck"), scratch.path.join("spanish.deck")]);
+	}
+
+	#[test]
+	fn no_deck_directory_is_an_error() {
+		let scratch = Scratch::new();
+		scratch.write("notes/index.flash", "");
+
+		assert!(matches!(deck_directories_in(&scratch.path), Err(DeckError::NoDeckFound)));
+	}
+}
@@ -0,0 +1,102 @@
+//! Incremental exports. Rather than the deck as a whole, a delta lists what
+//! happened to its notes since an earlier commit, keyed by their identities, so
+//! a downstream tool can bring a live collection up to date in place.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::{error::DeckError, history::{GixHistory, resolve_commit}, types::{deck::Deck, note::DetachedNote}};
+
+/// Every change made to a deck's notes between two commits. Applying the
+/// operations in order to the notes as of `since` gives the notes as of now.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delta {
+	// The commit the delta starts from, as it was resolved
+	pub since:      String,
+	pub operations: Vec<Operation>,
+}
+
+/// A single change to a note. Serialized with an `op` of `add`, `modify`, or
+/// `delete` alongside the note's identity, and its content for all but
+/// deletions.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+	Add { id: Uuid, note: DetachedNote },
+	Modify { id: Uuid, note: DetachedNote },
+	Delete { id: Uuid },
+}
+
+/// The changes to the deck's notes since `since` (a branch, tag, or commit).
+/// Deletions come first, then modifications and additions in deck order. Notes
+/// that only moved aren't changes as far as Anki is concerned, so reorders
+/// don't appear.
+#[instrument(skip(deck))]
+pub fn deck_delta(deck: &Deck, since: &str) -> Result<Delta, DeckError> {
+	let history = GixHistory::discover(&deck.path)?;
+	let commit = resolve_commit(history.repository(), since)?;
+
+	let before = deck.notes_at(commit)?;
+	let current: HashSet<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+	let mut operations: Vec<Operation> = before
+		.iter()
+		.filter(|old| !current.contains(&old.id))
+		.map(|old| Operation::Delete { id: old.id })
+		.collect();
+
+	let before: HashMap<Uuid, _> = before.iter().map(|old| (old.id, &old.inner)).collect();
+	for card in &deck.cards {
+		let note = DetachedNote::from(&card.inner);
+		match before.get(&card.id) {
+			None => operations.push(Operation::Add { id: card.id, note }),
+			Some(old) if **old != card.inner => operations.push(Operation::Modify { id: card.id, note }),
+			Some(_) => {}
+		}
+	}
+
+	info!("{} operations since {}", operations.len(), commit);
+	Ok(Delta { since: commit.to_string(), operations })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{commit, repository};
+
+	#[test]
+	fn deletions_come_before_modifications_and_additions() {
+		let repo = repository();
+		repo.write("index.flash", "=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n");
+		let since = commit(&repo, "add a and c", 2_000_000_000);
+		repo.write("index.flash", "=Basic=\nFront: a\nBack: b and more\n\nFront: c\nBack: d\n");
+		commit(&repo, "edit a", 2_000_000_100);
+		repo.write("index.flash", "=Basic=\nFront: a\nBack: b and more\n");
+		commit(&repo, "drop c", 2_000_000_200);
+		repo.write("index.flash", "=Basic=\nFront: a\nBack: b and more\n\nFront: e\nBack: f\n");
+		commit(&repo, "add e", 2_000_000_300);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		let delta = deck_delta(&deck, &since).expect("the commit resolves");
+		let (a, e) = (deck.cards[0].id, deck.cards[1].id);
+
+		assert_eq!(delta.since, since);
+		assert!(matches!(delta.operations.as_slice(), [
+			Operation::Delete { id: c },
+			Operation::Modify { id: modified, .. },
+			Operation::Add { id: added, .. },
+		] if ![a, e].contains(c) && *modified == a && *added == e));
+	}
+
+	#[test]
+	fn an_unchanged_deck_has_no_operations() {
+		let repo = repository();
+		repo.write("index.flash", "=Basic=\nFront: a\nBack: b\n");
+		let since = commit(&repo, "add a", 2_000_000_000);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert!(deck_delta(&deck, &since).expect("the commit resolves").operations.is_empty());
+	}
+}
@@ -0,0 +1,195 @@
+//! Identities the next commit would take away. Notes keep their identities
+//! through most changes, but one edited beyond recognition, moved to another
+//! model, or caught up in an ambiguous reshuffle is given a new one, and Anki
+//! sees it as a new note with none of the old one's review history.
+
+use std::{collections::HashSet, path::Path};
+
+use serde::Serialize;
+use tracing::{info, instrument};
+use uuid::Uuid;
+
+use crate::{change_resolver::similarity, error::DeckError, types::{crowd_anki_config::ConfigOverrides, deck::Deck, note::{Note, TextElement}}};
+
+/// How long a preview of a note's content runs before it's cut short
+const PREVIEW_LENGTH: usize = 60;
+
+/// Why a note would lose its identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cause {
+	// Its content changed too much to still count as the same note
+	Edit,
+	// Its content is unchanged, but it moved among other changes that made it
+	// impossible to follow
+	Reorder,
+	// It now belongs to a different model
+	Reparent,
+	// It's gone from the deck
+	Deletion,
+}
+
+/// A committed note whose identity wouldn't survive the next commit
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+	pub before:  Uuid,
+	// The identity it would have instead, unless it's been deleted
+	pub after:   Option<Uuid>,
+	pub cause:   Cause,
+	pub preview: String,
+}
+
+/// Compare the identities of the deck's notes as committed against those the
+/// working tree would give them once committed, listing every note that would
+/// lose its identity. The note each is most likely to have become is judged
+/// by content, as that's what the working tree has to go on.
+#[instrument(skip(deck_path, overrides))]
+pub fn uuid_drift(deck_path: &Path, overrides: &ConfigOverrides) -> Result<Vec<Drift>, DeckError> {
+	let committed = Deck::from_with_overrides(deck_path, overrides.clone())?;
+	let pending = Deck::from_pending_with_overrides(deck_path, overrides.clone())?;
+
+	let committed_ids: HashSet<Uuid> = committed.cards.iter().map(|card| card.id).collect();
+	let pending_ids: HashSet<Uuid> = pending.cards.iter().map(|card| card.id).collect();
+
+	// Only notes with identities the committed deck lacks can be what a drifted
+	// note became
+	let fresh: Vec<_> =
+		pending.cards.iter().filter(|card| !committed_ids.contains(&card.id)).collect();
+	let drifted: Vec<_> =
+		committed.cards.iter().filter(|old| !pending_ids.contains(&old.id)).collect();
+
+	// Pair drifted notes with fresh ones, the closest pairs first, so no fresh
+	// note is taken for what two drifted notes became. Ties go to the earlier
+	// note as written
+	let mut pairs: Vec<(usize, usize, f64)> = drifted
+		.iter()
+		.enumerate()
+		.flat_map(|(old, note)| {
+			fresh
+				.iter()
+				.enumerate()
+				.map(move |(new, card)| (old, new, similarity(&note.inner, &card.inner)))
+		})
+		.filter(|(_, _, score)| *score > 0.0)
+		.collect();
+	pairs.sort_by(|(_, _, a), (_, _, b)| b.total_cmp(a));
+
+	let mut successors = vec![None; drifted.len()];
+	let mut taken = vec![false; fresh.len()];
+	for (old, new, _) in pairs {
+		if successors[old].is_none() && !taken[new] {
+			successors[old] = Some(fresh[new]);
+			taken[new] = true;
+		}
+	}
+
+	let drifts: Vec<Drift> = drifted
+		.into_iter()
+		.zip(successors)
+		.map(|(old, successor)| {
+			let cause = match successor {
+				None => Cause::Deletion,
+				Some(card) if card.inner.model.id != old.inner.model.id => Cause::Reparent,
+				Some(card) if card.inner == old.inner => Cause::Reorder,
+				Some(_) => Cause::Edit,
+			};
+
+			Drift {
+				before: old.id,
+				after: successor.map(|card| card.id),
+				cause,
+				preview: preview(successor.map_or(&old.inner, |card| &card.inner)),
+			}
+		})
+		.collect();
+
+	info!("{} notes would change identity", drifts.len());
+	Ok(drifts)
+}
+
+/// The start of a note's first field, on a single line
+fn preview(note: &Note) -> String {
+	let text: String = note
+		.fields
+		.first()
+		.into_iter()
+		.flat_map(|field| &field.content)
+		.map(|part| match part {
+			TextElement::Text(text) | TextElement::Html(text) => text.as_str(),
+			TextElement::Cloze(cloze) => cloze.answer.as_str(),
+		})
+		.collect::<Vec<_>>()
+		.join("")
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	match text.char_indices().nth(PREVIEW_LENGTH) {
+		Some((end, _)) => format!("{}...", &text[..end]),
+		None => text,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{self, commit, repository};
+
+	const COMMITTED: &str = "=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n";
+
+	/// The drift from `COMMITTED` to `working`, left uncommitted
+	fn drift(working: &str) -> (Deck<'static>, Vec<Drift>) {
+		let repo = repository();
+		repo.write("index.flash", COMMITTED);
+		commit(&repo, "add a and c", 2_000_000_000);
+		let committed = Deck::from(&repo.path).expect("the deck builds");
+
+		repo.write("index.flash", working);
+		let drifts = uuid_drift(&repo.path, &ConfigOverrides::default()).expect("the drift is found");
+		(committed, drifts)
+	}
+
+	#[test]
+	fn notes_keeping_their_identities_are_left_out() {
+		let (_, drifts) = drift("=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: changed\n");
+		assert!(drifts.is_empty(), "{:?}", drifts);
+	}
+
+	#[test]
+	fn a_deleted_note_drifts_to_nothing() {
+		let (committed, drifts) = drift("=Basic=\nFront: a\nBack: b\n");
+
+		assert_eq!(drifts.len(), 1);
+		assert_eq!((drifts[0].before, drifts[0].after), (committed.cards[1].id, None));
+		assert_eq!((drifts[0].cause, drifts[0].preview.as_str()), (Cause::Deletion, "c"));
+	}
+
+	#[test]
+	fn a_note_moved_to_another_model_is_reparented() {
+		let (committed, drifts) = drift("=Basic=\nFront: a\nBack: b\n\n=Cloze=\nText: {c}\n");
+
+		assert_eq!(drifts.len(), 1);
+		assert_eq!(drifts[0].before, committed.cards[1].id);
+		assert!(drifts[0].after.is_some_and(|after| after != committed.cards[1].id));
+		assert_eq!(drifts[0].cause, Cause::Reparent);
+	}
+
+	#[test]
+	fn a_fresh_note_succeeds_a_single_drifted_one() {
+		let (committed, drifts) = drift("=Cloze=\nText: {a} b {c} d\n");
+
+		assert_eq!(drifts.len(), 2);
+		assert_eq!((drifts[0].before, drifts[0].cause), (committed.cards[0].id, Cause::Reparent));
+		assert!(drifts[0].after.is_some());
+		assert_eq!((drifts[1].before, drifts[1].after), (committed.cards[1].id, None));
+		assert_eq!(drifts[1].cause, Cause::Deletion);
+	}
+
+	#[test]
+	fn previews_are_cut_short_on_a_single_line() {
+		let models = test_support::models();
+		let long = format!("=Basic=\nFront: {}\n", "word ".repeat(20));
+		let note = test_support::parse(&models, &long).remove(0);
+		assert_eq!(preview(&note), format!("{}...", &"word ".repeat(12)[..PREVIEW_LENGTH]));
+	}
+}
@@ -0,0 +1,366 @@
+//! Turns parsed notes back into .flash source. Anything the parser keeps,
+//! comments included, is written back out, so a file survives a round-trip
+//! through the note model.
+
+use std::{collections::HashSet, fmt};
+
+use tracing::instrument;
+
+use crate::types::{crowd_anki_config::NoteSeparator, note::{ClozeReveal, Note, NoteField, TextElement}};
+
+/// Render notes as a .flash file, declaring each model as the notes switch to
+/// it and separating notes with a blank line, or a `---` line with
+/// `NoteSeparator::Rule`
+#[instrument(skip(notes))]
+pub fn emit_flash(notes: &[Note], separator: NoteSeparator) -> String {
+	let mut out = String::new();
+	let mut current_model: Option<&str> = None;
+
+	for note in notes {
+		if current_model != Some(note.model.name.as_str()) {
+			if current_model.is_some() {
+				out.push('\n');
+			}
+			out.push_str(&format!("={}=\n\n", note.model.name));
+			current_model = Some(note.model.name.as_str());
+		} else {
+			match separator {
+				NoteSeparator::Blank => out.push('\n'),
+				NoteSeparator::Rule => out.push_str("---\n"),
+			}
+		}
+
+		out.push_str(&emit_note(note));
+	}
+
+	out
+}
+
+/// Render a single note, without any model declaration
+pub fn emit_note(note: &Note) -> String {
+	let mut out = String::new();
+
+	for comment in &note.comments {
+		out.push_str(&format!("// {}\n", comment));
+	}
+
+	for (key, value) in &note.data {
+		out.push_str(&format!("@data {}={}\n", key, value));
+	}
+
+	if !note.classes.is_empty() {
+		out.push_str(&format!("@class {}\n", note.classes.join(" ")));
+	}
+
+	if note.reveal == ClozeReveal::One {
+		out.push_str("@cloze reveal-one\n");
+	}
+
+	if !note.tags.is_empty() {
+		out.push_str(&format!("[{}]\n", note.tags.join(", ")));
+	}
+
+	if !note.media.is_empty() {
+		let media: Vec<String> = note.media.iter().map(|path| path.display().to_string()).collect();
+		out.push_str(&format!("media [{}]\n", media.join(", ")));
+	}
+
+	for field in &note.fields {
+		out.push_str(&format!("{}: {}\n", field.name, emit_field_content(field)));
+	}
+
+	out
+}
+
+/// A note's tags and fields as they'd be written in source, leaving out the
+/// comments and media `emit_note` carries. Compact enough for messages such
+/// as "note changed: <before> -> <after>", and simple notes read back as is.
+impl fmt::Display for Note<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut lines = Vec::with_capacity(self.fields.len() + 1);
+
+		if !self.tags.is_empty() {
+			lines.push(format!("[{}]", self.tags.join(", ")));
+		}
+
+		for field in &self.fields {
+			lines.push(format!("{}: {}", field.name, emit_field_content(field)));
+		}
+
+		write!(f, "{}", lines.join("\n"))
+	}
+}
+
+/// What notes are grouped on when splitting a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitBy {
+	// The first tag a note carries, untagged notes are grouped together
+	Tag,
+	Model,
+}
+
+/// Split notes into one .flash file per group, returning an index that imports
+/// every file in turn alongside each file's name and source. Groups are kept
+/// in the order they first appear, so expanding the index yields every note
+/// unchanged, and so with the same identity.
+#[instrument(skip(notes))]
+pub fn split_flash(
+	notes: &[Note],
+	by: SplitBy,
+	separator: NoteSeparator,
+) -> (String, Vec<(String, String)>) {
+	let mut groups: Vec<(String, Vec<Note>)> = Vec::new();
+
+	for note in notes {
+		let key = match by {
+			SplitBy::Tag => note.tags.first().map_or("untagged", String::as_str),
+			SplitBy::Model => note.model.name.as_str(),
+		};
+
+		match groups.iter_mut().find(|(group, _)| group == key) {
+			Some((_, group)) => group.push(note.clone()),
+			None => groups.push((key.to_string(), vec![note.clone()])),
+		}
+	}
+
+	// Keys sanitizing to the same stem, `a/b` and `a_b`, are numbered apart.
+	// Case is ignored, as some file systems do
+	let mut taken = HashSet::new();
+	let files: Vec<(String, String)> = groups
+		.iter()
+		.map(|(key, notes)| {
+			let stem = file_stem(key);
+			let name = (1..)
+				.map(|n| if n == 1 { stem.clone() } else { format!("{}-{}", stem, n) })
+				.find(|name| taken.insert(name.to_lowercase()))
+				.expect("some number is free");
+			(format!("{}.flash", name), emit_flash(notes, separator))
+		})
+		.collect();
+
+	let index = files.iter().map(|(name, _)| format!("import {}\n", name)).collect();
+
+	(index, files)
+}
+
+/// Separate an index into what it keeps once its own notes are split out, the
+/// directives and comments leading up to its first model declaration followed
+/// by any imports further down, and the source of those notes without imports
+pub fn index_parts(index: &str) -> (String, String) {
+	let (mut kept, mut imports, mut own) = (String::new(), String::new(), String::new());
+	let mut header = true;
+
+	for line in index.split_inclusive('\n') {
+		let trimmed = line.trim_start();
+		header &= !trimmed.starts_with('=');
+
+		if trimmed.starts_with("import ") {
+			let lines = if header { &mut kept } else { &mut imports };
+			lines.push_str(line);
+			lines.push_str(if line.ends_with('\n') { "" } else { "\n" });
+		} else {
+			if header {
+				kept.push_str(line);
+			}
+			own.push_str(line);
+		}
+	}
+
+	kept.push_str(&imports);
+	(kept, own)
+}
+
+/// A file name for a group, tags like `lang::spanish` aren't safe as one
+fn file_stem(key: &str) -> String {
+	key.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+fn emit_field_content(field: &NoteField) -> String {
+	field
+		.content
+		.iter()
+		.map(|part| match part {
+			TextElement::Text(text) => escape(text),
+			TextElement::Html(html) => format!("@html {}", html),
+			TextElement::Cloze(cloze) => {
+				let hints: String = cloze.hint.iter().map(|hint| format!("|{}", escape(hint))).collect();
+				// Only clozes numbered in the source carry a number back to it
+				let ordinal = if cloze.id > 0 { format!("c{}::", cloze.id) } else { String::new() };
+				format!("{{{}{}{}}}", ordinal, escape(&cloze.answer), hints)
+			}
+		})
+		.collect()
+}
+
+/// Escape literal pipes so they aren't read back as cloze separators, and
+/// turn line breaks back into their shorthand
+fn escape(content: &str) -> String { content.replace('|', "\\|").replace("<br>", "\\n") }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{parse::ImportExpander, test_support::{self, Scratch}, types::deck::Deck};
+
+	#[test]
+	fn comments_survive_a_round_trip() {
+		let models = test_support::models();
+		let source = concat!(
+			"=Basic=\n\n// from chapter two\n// check the accent\nFront: a\nBack: b\n\n",
+			"Front: c\nBack: d\n"
+		);
+		let notes = test_support::parse(&models, source);
+		assert_eq!(notes[0].comments, vec!["from chapter two", "check the accent"]);
+		assert!(notes[1].comments.is_empty());
+
+		assert_eq!(test_support::parse(&models, &emit_flash(&notes, NoteSeparator::default())), notes);
+	}
+
+	#[test]
+	fn literal_pipes_survive_a_round_trip() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: x \\| y {c1::a\\|b|either\\|or}\n");
+		let emitted = emit_flash(&notes, NoteSeparator::default());
+
+		assert!(emitted.contains("x \\| y"));
+		assert_eq!(test_support::parse(&models, &emitted), notes);
+	}
+
+	#[test]
+	fn html_survives_a_round_trip() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: @html <b>a, b: {c}</b>\n");
+		let emitted = emit_flash(&notes, NoteSeparator::default());
+
+		assert_eq!(test_support::parse(&models, &emitted), notes);
+	}
+
+	#[test]
+	fn line_breaks_survive_a_round_trip() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: one\\ntwo\nBack: b\n");
+		let emitted = emit_flash(&notes, NoteSeparator::default());
+
+		assert!(emitted.contains("Front: one\\ntwo\n"), "{}", emitted);
+		assert_eq!(test_support::parse(&models, &emitted), notes);
+	}
+
+	/// Split `source` by tag, then read the files back through the index
+	fn split_and_reimport(source: &str) -> (Vec<String>, String) {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, source);
+		let (index, files) = split_flash(&notes, SplitBy::Tag, NoteSeparator::default());
+
+		let scratch = Scratch::new();
+		for (name, content) in &files {
+			scratch.write(name, content);
+		}
+		let index_path = scratch.write("index.flash", &index);
+		let expanded =
+			ImportExpander::new(&scratch.path).expand(&index, &index_path).expect("the index expands");
+
+		(files.into_iter().map(|(name, _)| name).collect(), expanded)
+	}
+
+	#[test]
+	fn splitting_round_trips_through_the_index() {
+		let source = concat!(
+			"=Basic=\n[spanish]\nFront: hola\nBack: hello\n\n",
+			"[french]\nFront: bonjour\nBack: hello\n\n",
+			"[spanish]\nFront: {adiós|bye}\nBack: 1 + 1 = 2\n\n",
+			"=Image Occlusion=\n@image-occlusion map.png\n",
+			"@region 0.1 0.1 0.2 0.2\n@region 0.5 0.5 0.25 0.25\n"
+		);
+		let models = test_support::models();
+		let (_, expanded) = split_and_reimport(source);
+
+		let mut before = test_support::parse(&models, source);
+		let mut after = test_support::parse(&models, &expanded);
+		before.sort();
+		after.sort();
+		assert_eq!(before, after);
+	}
+
+	#[test]
+	fn tags_sanitizing_alike_get_files_of_their_own() {
+		let source = "=Basic=\n[a/b]\nFront: a\n\n[a_b]\nFront: b\n\n[A_B]\nFront: c\n";
+		let (names, expanded) = split_and_reimport(source);
+
+		assert_eq!(names, vec!["a_b.flash", "a_b-2.flash", "A_B-3.flash"]);
+		assert_eq!(test_support::parse(&test_support::models(), &expanded).len(), 3);
+	}
+
+	#[test]
+	fn splitting_by_model_groups_notes_in_the_order_models_appear() {
+		let models = test_support::models();
+		let notes =
+			test_support::parse(&models, "=Basic=\nFront: a\n\n=Cloze=\nText: {b}\n\n=Basic=\nFront: c\n");
+		let (index, files) = split_flash(&notes, SplitBy::Model, NoteSeparator::default());
+
+		assert_eq!(index, "import Basic.flash\nimport Cloze.flash\n");
+		let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+		assert_eq!(names, vec!["Basic.flash", "Cloze.flash"]);
+		assert_eq!(test_support::parse(&models, &files[0].1), vec![notes[0].clone(), notes[2].clone()]);
+	}
+
+	#[test]
+	fn an_index_keeps_its_header_and_imports_when_split() {
+		let index = concat!(
+			"// Spanish vocabulary\nimport verbs.flash\n\n",
+			"=Basic=\nFront: a\nBack: b\n\nimport nouns.flash\n"
+		);
+		let (kept, own) = index_parts(index);
+
+		assert_eq!(kept, "// Spanish vocabulary\nimport verbs.flash\n\nimport nouns.flash\n");
+		let models = test_support::models();
+		let notes = test_support::parse(&models, &own);
+		assert_eq!(notes.len(), 1);
+	}
+
+	#[test]
+	fn a_note_displays_as_its_tags_and_fields() {
+		let models = test_support::models();
+		let notes = test_support::parse(
+			&models,
+			"=Basic=\n// left out\n[x, y]\nFront: a\nBack: b\n\nFront: {c1::c|hint}\n",
+		);
+
+		assert_eq!(notes[0].to_string(), "[x, y]\nFront: a\nBack: b");
+		assert_eq!(notes[1].to_string(), "Front: {c1::c|hint}");
+	}
+
+	#[test]
+	fn cloze_numbers_survive_a_round_trip() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: {c2::a|hint} {b}\n");
+		let emitted = emit_flash(&notes, NoteSeparator::default());
+
+		assert!(emitted.contains("{c2::a|hint} {b}"), "{}", emitted);
+		assert_eq!(test_support::parse(&models, &emitted), notes);
+	}
+
+	#[test]
+	fn ruled_notes_survive_a_round_trip() {
+		let models = test_support::models();
+		let source = "=Basic=\n\nFront: a\nBack: b\n---\nFront: c\n";
+		let notes =
+			Deck::parse_cards_separated(&models, source, NoteSeparator::Rule).expect("the source parses");
+		let emitted = emit_flash(&notes, NoteSeparator::Rule);
+
+		assert_eq!(emitted, source);
+		assert_eq!(
+			Deck::parse_cards_separated(&models, &emitted, NoteSeparator::Rule).expect("it parses"),
+			notes
+		);
+	}
+
+	#[test]
+	fn the_reveal_survives_a_round_trip() {
+		let models = test_support::models();
+		let source = "=Cloze=\n@cloze reveal-one\nText: {c1::a} {c2::b}\n";
+		let note = test_support::parse(&models, source).remove(0);
+		let emitted = format!("=Cloze=\n{}", emit_note(&note));
+
+		assert!(emitted.contains("@cloze reveal-one\n"));
+		assert_eq!(test_support::parse(&models, &emitted)[0].reveal, ClozeReveal::One);
+	}
+}
@@ -8,29 +8,67 @@ pub enum DeckError {
 	#[error("No .deck directory found in the current directory or any parent directories.")]
 	NoDeckFound,
 
+	#[error("Deck '{0}' not found.")]
+	DeckNotFound(String),
+
+	#[error("Collection contains no decks.")]
+	EmptyCollection,
+
 	#[error("Model '{0}' not found.")]
 	ModelNotFound(String),
 
 	#[error("File '{0}' not found in history.")]
 	FileNotInHistory(String),
 
+	#[error("'{0}' is not committed; commit it or use working-tree mode.")]
+	Uncommitted(String),
+
+	#[error(
+		"{0} is tracked through '{1}', which has no history; give the path as it was committed, relative to the deck."
+	)]
+	TrackedPathNotInHistory(String, String),
+
+	#[error("{0:?} isn't within a git repository; run `git init` there, or use working-tree mode.")]
+	NotARepository(PathBuf),
+
+	#[error("The git repository holding {0:?} couldn't be opened, it may be corrupt: {1}")]
+	CorruptRepository(PathBuf, String),
+
 	#[error("History is Empty")]
 	EmptyHistory,
 
+	#[error("History is out of step, {0} revisions of the cards were read for {1} commits.")]
+	HistoryMismatch(usize, usize),
+
+	#[error("index.flash expects {0} notes, give or take {1}, but the deck holds {2}.")]
+	NoteCountMismatch(usize, usize, usize),
+
+	#[error("Ambiguous change, {0}. Split reorders and modifications across separate commits.")]
+	AmbiguousChange(String),
+
 	#[error("Invalid tree entry.")]
 	InvalidEntry,
 
 	#[error("I/O error.")]
 	Io(#[from] std::io::Error),
 
+	#[error("Failed to write the package database: {0}")]
+	Sqlite(#[from] rusqlite::Error),
+
+	#[error("Failed to write the package archive: {0}")]
+	Zip(#[from] zip::result::ZipError),
+
 	#[error("TOML deserialization error.")]
 	Toml(#[from] toml::de::Error),
 
 	#[error("Git error: {0}")]
 	Git(String),
 
-	#[error("Invalid UTF-8 in file: {0:?}.")]
-	InvalidUtf8(PathBuf),
+	#[error("Invalid UTF-8 in file {0:?}: {1}.")]
+	InvalidUtf8(PathBuf, String),
+
+	#[error("Failed to expand imports: {0}")]
+	Import(String),
 
 	#[error("Template file '{0}' has an invalid format.")]
 	InvalidTemplateFilename(String),
@@ -38,12 +76,54 @@ pub enum DeckError {
 	#[error("Model config file not found: {0:?}")]
 	ModelConfigNotFound(PathBuf),
 
+	#[error("Model config {0:?} is invalid: {1}")]
+	ModelConfig(PathBuf, String),
+
+	#[error("Model config {0:?} sets both `required` and `required_fields`, use one or the other.")]
+	ConflictingRequirements(PathBuf),
+
+	#[error("Model config {0:?} has schema_version {1}, which isn't supported: {2}.")]
+	UnsupportedSchema(PathBuf, String, String),
+
 	#[error("Deck config file not found: {0:?}")]
 	DeckConfigNotFound(PathBuf),
 
+	#[error("Deck has more than one config file, keep just one of {0:?}")]
+	MultipleDeckConfigs(Vec<PathBuf>),
+
+	#[error("Failed to read deck config {0:?}: {1}")]
+	DeckConfigFormat(PathBuf, String),
+
+	#[error(
+		"Model directory {0:?} has no templates, and too few fields to fall back on a default one."
+	)]
+	NoTemplates(PathBuf),
+
+	#[error(
+		"Model directory {0:?} defines templates both inline and in .hbs files, use one or the other."
+	)]
+	MixedTemplates(PathBuf),
+
+	#[error(
+		"Template '{1}' of model directory {0:?} needs both `qfmt` and `afmt` when defined inline."
+	)]
+	IncompleteTemplate(PathBuf, String),
+
 	#[error("Template file not found: {0:?}")]
 	TemplateNotFound(PathBuf),
 
+	#[error("Media file not found: {0:?}")]
+	MediaNotFound(PathBuf),
+
+	#[error("Media files {0:?} and {1:?} share a file name, which Anki would store just one of.")]
+	MediaNameClash(PathBuf, PathBuf),
+
+	#[error("A note with identical content already exists: {0}")]
+	DuplicateNote(uuid::Uuid),
+
+	#[error("Both decks hold different content under the same identity: {0:?}")]
+	MergeConflicts(Vec<uuid::Uuid>),
+
 	#[error("UUID generation error.")]
 	Uuid(#[from] uuid::Error),
 
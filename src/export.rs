@@ -0,0 +1,498 @@
+//! Anki's own package formats, for decks going straight into Anki rather than
+//! through CrowdAnki. Both are a zip archive around `collection.anki2`, a
+//! SQLite database in Anki's legacy schema (version 11), with a `media` file
+//! mapping the numbered entries after it back to the media's file names. They
+//! differ in what importing one does.
+//!
+//! - An `.apkg` is merged into the open collection, notes matched up by their
+//!   guid so importing a later export updates them in place. Every release
+//!   from 2.0 on imports it.
+//! - A `.colpkg` replaces the open collection outright, so it carries the
+//!   settings a whole collection has. Anki 2.1 onwards restores it on import,
+//!   2.0 only recognised a whole collection as an `.apkg` named
+//!   `collection.apkg`. From 2.1.50 Anki writes a newer layout itself, but
+//!   still restores this one.
+//!
+//! Review history doesn't travel in either: cards come out new, and every deck
+//! uses the default options group rather than its preset.
+
+use std::{collections::{BTreeSet, HashMap, HashSet}, fs, io::Write, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use rusqlite::{Connection, params};
+use serde_json::{Map, Value, json};
+use tracing::{info, instrument};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{error::DeckError, media, types::{collection::{Collection, crowd_anki_note}, config::ModelKind, crowd_anki_models::NoteModel as CrowdAnkiModel, note::{Note, TextElement}}};
+
+/// Anki's default deck and options group, which every collection has
+const DEFAULT_ID: i64 = 1;
+
+/// What separates a note's fields in the database
+const FIELD_SEPARATOR: &str = "\x1f";
+
+/// The legacy schema, which Anki upgrades from on import
+const SCHEMA: &str = "
+	create table col (id integer primary key, crt integer not null, mod integer not null,
+		scm integer not null, ver integer not null, dty integer not null, usn integer not null,
+		ls integer not null, conf text not null, models text not null, decks text not null,
+		dconf text not null, tags text not null);
+	create table notes (id integer primary key, guid text not null, mid integer not null,
+		mod integer not null, usn integer not null, tags text not null, flds text not null,
+		sfld integer not null, csum integer not null, flags integer not null, data text not null);
+	create table cards (id integer primary key, nid integer not null, did integer not null,
+		ord integer not null, mod integer not null, usn integer not null, type integer not null,
+		queue integer not null, due integer not null, ivl integer not null, factor integer not null,
+		reps integer not null, lapses integer not null, left integer not null, odue integer not null,
+		odid integer not null, flags integer not null, data text not null);
+	create table revlog (id integer primary key, cid integer not null, usn integer not null,
+		ease integer not null, ivl integer not null, lastIvl integer not null,
+		factor integer not null, time integer not null, type integer not null);
+	create table graves (usn integer not null, oid integer not null, type integer not null);
+	create index ix_notes_usn on notes (usn);
+	create index ix_cards_usn on cards (usn);
+	create index ix_revlog_usn on revlog (usn);
+	create index ix_cards_nid on cards (nid);
+	create index ix_cards_sched on cards (did, queue, due);
+	create index ix_revlog_cid on revlog (cid);
+	create index ix_notes_csum on notes (csum);
+";
+
+/// Which of Anki's packages is being written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Package {
+	// An `.apkg`, merged into the open collection
+	Deck,
+	// A `.colpkg`, restored in place of the open collection
+	Collection,
+}
+
+/// Write the collection out as an `.apkg` at `path`, for importing into an
+/// existing collection
+#[instrument(skip(collection))]
+pub fn to_apkg(collection: &Collection, path: &Path) -> Result<(), DeckError> {
+	write_package(collection, path, Package::Deck)
+}
+
+/// Write the collection out as a `.colpkg` at `path`, for restoring in place
+/// of a collection
+#[instrument(skip(collection))]
+pub fn to_colpkg(collection: &Collection, path: &Path) -> Result<(), DeckError> {
+	write_package(collection, path, Package::Collection)
+}
+
+/// Build the package's database beside `path` and archive it with the media
+fn write_package(collection: &Collection, path: &Path, package: Package) -> Result<(), DeckError> {
+	let database = path.with_extension("anki2.partial");
+	fs::remove_file(&database).ok();
+
+	let written = write_database(collection, &database, package)
+		.and_then(|()| write_archive(collection, &database, path));
+	fs::remove_file(&database).ok();
+	written?;
+
+	info!("Packaged {} notes into {:?}", collection.notes().len(), path);
+	Ok(())
+}
+
+#[instrument(skip(collection))]
+fn write_database(collection: &Collection, path: &Path, package: Package) -> Result<(), DeckError> {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+	let mut connection = Connection::open(path)?;
+	connection.execute_batch(SCHEMA)?;
+	let transaction = connection.transaction()?;
+
+	let deck_ids: Vec<i64> = collection
+		.decks()
+		.iter()
+		.map(|deck| numeric_id(deck.configuration.crowdanki_uuid.as_bytes()))
+		.collect();
+	let mut decks = Map::new();
+	decks.insert(DEFAULT_ID.to_string(), deck_json(DEFAULT_ID, "Default", now));
+	for (deck, &id) in collection.decks().iter().zip(&deck_ids) {
+		decks.insert(id.to_string(), deck_json(id, &deck.configuration.name, now));
+	}
+
+	// Notes are sorted in the browser by their model's sort field
+	let mut sort_fields = HashMap::new();
+	let mut models = Map::new();
+	let mut model_ids = Vec::new();
+	for model in collection.models() {
+		let id = numeric_id(model.id.as_bytes());
+		let crowd_anki = CrowdAnkiModel::from(model);
+		sort_fields.insert(model.name.as_str(), crowd_anki.sortf.unwrap_or(0) as usize);
+		let did = deck_ids.first().copied().unwrap_or(DEFAULT_ID);
+		models.insert(id.to_string(), model_json(crowd_anki, id, did, now));
+		model_ids.push(id);
+	}
+
+	// Anki dates a note by its id, the creation time in milliseconds, so notes
+	// authored in the same second are spread a millisecond apart
+	let (mut note_ids, mut card_ids) = (HashSet::new(), HashSet::new());
+	let mut position = 0;
+	for (deck, &did) in collection.decks().iter().zip(&deck_ids) {
+		for note in deck.notes.iter().filter_map(|id| collection.note(*id)) {
+			let mut id = collection.created(note.id).unwrap_or(now).saturating_mul(1000);
+			while !note_ids.insert(id) {
+				id += 1;
+			}
+
+			let model = &note.inner.model;
+			let exported = crowd_anki_note(&deck.configuration, note.clone(), None);
+			let sort_field = sort_fields.get(model.name.as_str()).copied().unwrap_or(0);
+			let field = |index: usize| exported.fields.get(index).map_or("", String::as_str);
+			let tags =
+				if exported.tags.is_empty() { String::new() } else { format!(" {} ", exported.tags.join(" ")) };
+
+			transaction.execute(
+				"insert into notes values (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, ?8, 0, '')",
+				params![
+					id,
+					exported.guid,
+					numeric_id(model.id.as_bytes()),
+					now,
+					tags,
+					exported.fields.join(FIELD_SEPARATOR),
+					strip_html(field(sort_field)),
+					checksum(field(0)),
+				],
+			)?;
+
+			// New cards are shown in the order their notes were added
+			position += 1;
+			for ord in card_ordinals(&note.inner) {
+				let mut card_id = id;
+				while !card_ids.insert(card_id) {
+					card_id += 1;
+				}
+				transaction.execute(
+					"insert into cards values (?1, ?2, ?3, ?4, ?5, -1, 0, 0, ?6, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+					params![card_id, id, did, ord, now, position],
+				)?;
+			}
+		}
+	}
+
+	// Settings are the importing collection's own, unless this replaces it
+	let current_deck = deck_ids.first().copied().unwrap_or(DEFAULT_ID);
+	let conf = match package {
+		Package::Deck => json!({}),
+		Package::Collection => json!({
+			"curDeck": current_deck,
+			"activeDecks": [current_deck],
+			"curModel": model_ids.first(),
+			"nextPos": position + 1,
+			"newSpread": 0,
+			"collapseTime": 1200,
+			"timeLim": 0,
+			"estTimes": true,
+			"dueCounts": true,
+			"sortType": "noteFld",
+			"sortBackwards": false,
+			"addToCur": true,
+			"schedVer": 2,
+		}),
+	};
+
+	transaction.execute(
+		"insert into col values (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+		params![
+			now,
+			now.saturating_mul(1000),
+			conf.to_string(),
+			Value::Object(models).to_string(),
+			Value::Object(decks).to_string(),
+			json!({ DEFAULT_ID.to_string(): default_options() }).to_string(),
+		],
+	)?;
+	transaction.commit()?;
+	Ok(())
+}
+
+/// Zip the database up with the media, which goes in numbered with `media`
+/// mapping the numbers back to file names
+fn write_archive(collection: &Collection, database: &Path, path: &Path) -> Result<(), DeckError> {
+	let names = media::file_names(collection.media())?;
+	let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+	let mut archive = ZipWriter::new(fs::File::create(path)?);
+
+	archive.start_file("collection.anki2", options)?;
+	archive.write_all(&fs::read(database)?)?;
+
+	let manifest: Map<String, Value> =
+		names.into_iter().enumerate().map(|(n, name)| (n.to_string(), Value::String(name))).collect();
+	archive.start_file("media", options)?;
+	archive.write_all(Value::Object(manifest).to_string().as_bytes())?;
+
+	for (n, file) in collection.media().iter().enumerate() {
+		let content = fs::read(file).map_err(|_| DeckError::MediaNotFound(file.clone()))?;
+		archive.start_file(n.to_string(), options)?;
+		archive.write_all(&content)?;
+	}
+
+	archive.finish()?;
+	Ok(())
+}
+
+/// An id Anki can hold for something flash identifies by uuid, the same on
+/// every export. Kept within what JavaScript represents exactly, and clear of
+/// the default deck and options group
+fn numeric_id(key: &[u8]) -> i64 {
+	let hashed = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, key).as_u128() as i64;
+	(hashed & ((1 << 53) - 1)).max(DEFAULT_ID + 1)
+}
+
+/// The templates a note has cards for, by ordinal. A card per template of a
+/// standard model, Anki's Empty Cards clearing any a template leaves blank, and
+/// per cloze number of a cloze one
+fn card_ordinals(note: &Note) -> Vec<u32> {
+	match note.model.kind {
+		ModelKind::Standard => (0..note.model.templates.len() as u32).collect(),
+		ModelKind::Cloze => {
+			let numbers: BTreeSet<u32> = note
+				.fields
+				.iter()
+				.flat_map(|field| &field.content)
+				.filter_map(|part| match part {
+					TextElement::Cloze(cloze) => Some(cloze.id.saturating_sub(1)),
+					_ => None,
+				})
+				.collect();
+			if numbers.is_empty() { vec![0] } else { numbers.into_iter().collect() }
+		}
+	}
+}
+
+/// The model as Anki stores it, CrowdAnki's form with the keys only a
+/// collection carries
+fn model_json(model: CrowdAnkiModel, id: i64, did: i64, now: i64) -> Value {
+	let mut value = json!(model);
+	let Value::Object(object) = &mut value else { return value };
+	object.remove("crowdanki_uuid");
+
+	// CrowdAnki leaves the browser's formats null, Anki reads them as strings
+	if let Some(Value::Array(templates)) = object.get_mut("tmpls") {
+		for template in templates {
+			for format in ["bqfmt", "bafmt"] {
+				if template[format].is_null() {
+					template[format] = json!("");
+				}
+			}
+		}
+	}
+
+	object.extend([
+		("id".to_string(), json!(id)),
+		("did".to_string(), json!(did)),
+		("mod".to_string(), json!(now)),
+		("usn".to_string(), json!(-1)),
+		("req".to_string(), json!([])),
+		("tags".to_string(), json!([])),
+		("vers".to_string(), json!([])),
+	]);
+	value
+}
+
+fn deck_json(id: i64, name: &str, now: i64) -> Value {
+	json!({
+		"id": id,
+		"name": name,
+		"mod": now,
+		"usn": -1,
+		"desc": "",
+		"dyn": 0,
+		"conf": DEFAULT_ID,
+		"collapsed": false,
+		"browserCollapsed": false,
+		"extendNew": 0,
+		"extendRev": 0,
+		"newToday": [0, 0],
+		"revToday": [0, 0],
+		"lrnToday": [0, 0],
+		"timeToday": [0, 0],
+	})
+}
+
+/// Anki's default options group, as a fresh collection has it
+fn default_options() -> Value {
+	json!({
+		"id": DEFAULT_ID,
+		"name": "Default",
+		"mod": 0,
+		"usn": 0,
+		"dyn": false,
+		"maxTaken": 60,
+		"timer": 0,
+		"autoplay": true,
+		"replayq": true,
+		"new": { "delays": [1.0, 10.0], "ints": [1, 4, 0], "initialFactor": 2500, "order": 1, "perDay": 20, "bury": false },
+		"lapse": { "delays": [10.0], "mult": 0.0, "minInt": 1, "leechFails": 8, "leechAction": 1 },
+		"rev": { "perDay": 200, "ease4": 1.3, "ivlFct": 1.0, "maxIvl": 36500, "bury": false, "hardFactor": 1.2 },
+	})
+}
+
+/// A field's text as the browser searches and sorts it, without markup
+fn strip_html(text: &str) -> String {
+	let mut stripped = String::with_capacity(text.len());
+	let mut in_tag = false;
+	for c in text.chars() {
+		match c {
+			'<' => in_tag = true,
+			'>' if in_tag => in_tag = false,
+			_ if !in_tag => stripped.push(c),
+			_ => {}
+		}
+	}
+	stripped
+}
+
+/// What Anki spots duplicates by, the first 32 bits of the SHA-1 of the first
+/// field without markup
+fn checksum(field: &str) -> i64 {
+	let digest = sha1_smol::Sha1::from(strip_html(field)).digest().bytes();
+	i64::from(u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+
+	use zip::ZipArchive;
+
+	use super::*;
+	use crate::test_support::{self, Scratch};
+
+	/// The entries of the package at `path`, by name
+	fn entries(path: &Path) -> HashMap<String, Vec<u8>> {
+		let mut archive = ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+		(0..archive.len())
+			.map(|n| {
+				let mut entry = archive.by_index(n).unwrap();
+				let mut content = Vec::new();
+				entry.read_to_end(&mut content).unwrap();
+				(entry.name().to_string(), content)
+			})
+			.collect()
+	}
+
+	/// The package's database, written out to read back
+	fn database(scratch: &Scratch, entries: &HashMap<String, Vec<u8>>) -> Connection {
+		let path = scratch.path.join("collection.anki2");
+		fs::write(&path, &entries["collection.anki2"]).unwrap();
+		Connection::open(path).unwrap()
+	}
+
+	#[test]
+	fn a_collection_package_is_a_legacy_collection_and_its_media() {
+		let scratch = Scratch::new();
+		let deck = test_support::deck(&["=Basic=\nFront: one\nBack: two\n\n=Cloze=\nText: {three}\n"]);
+		let path = scratch.path.join("collection.colpkg");
+		to_colpkg(&Collection::try_from(deck).unwrap(), &path).unwrap();
+
+		let entries = entries(&path);
+		let mut names: Vec<&str> = entries.keys().map(String::as_str).collect();
+		names.sort();
+		assert_eq!(names, vec!["collection.anki2", "media"]);
+		assert_eq!(entries["media"], b"{}");
+
+		let db = database(&scratch, &entries);
+		let tables: Vec<String> = db
+			.prepare("select name from sqlite_master where type = 'table' order by name")
+			.unwrap()
+			.query_map([], |row| row.get(0))
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(tables, vec!["cards", "col", "graves", "notes", "revlog"]);
+
+		let (version, notes, cards): (i64, i64, i64) = db
+			.query_row(
+				"select ver, (select count(*) from notes), (select count(*) from cards) from col",
+				[],
+				|row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+			)
+			.unwrap();
+		assert_eq!((version, notes, cards), (11, 2, 2));
+	}
+
+	#[test]
+	fn a_package_holds_a_note_and_its_cards_per_template_and_cloze() {
+		let scratch = Scratch::new();
+		let deck = test_support::deck(&[
+			"=Basic=\n#a #b\nFront: one\nBack: two\n\n=Cloze=\nText: {c1::three} and {c2::four}\n",
+		]);
+		let path = scratch.path.join("deck.apkg");
+		to_apkg(&Collection::try_from(deck).unwrap(), &path).unwrap();
+
+		let entries = entries(&path);
+		assert_eq!(entries["media"], b"{}");
+		let db = database(&scratch, &entries);
+
+		let notes: Vec<(String, String, String)> = db
+			.prepare("select flds, sfld, tags from notes order by id")
+			.unwrap()
+			.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(notes[0], ("one\x1ftwo".to_string(), "one".to_string(), " a b ".to_string()));
+
+		let ordinals: Vec<u32> = db
+			.prepare("select ord from cards order by nid, ord")
+			.unwrap()
+			.query_map([], |row| row.get(0))
+			.unwrap()
+			.collect::<Result<_, _>>()
+			.unwrap();
+		assert_eq!(ordinals, vec![0, 0, 1]);
+
+		let version: i64 = db.query_row("select ver from col", [], |row| row.get(0)).unwrap();
+		assert_eq!(version, 11);
+	}
+
+	#[test]
+	fn media_is_numbered_and_mapped_back_to_its_name() {
+		let scratch = Scratch::new();
+		let image = scratch.write("images/diagram.png", "png");
+		let deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n"]);
+		let collection = Collection::try_from(deck).unwrap().with_media(image);
+		let path = scratch.path.join("deck.apkg");
+		to_apkg(&collection, &path).unwrap();
+
+		let entries = entries(&path);
+		assert_eq!(entries["media"], br#"{"0":"diagram.png"}"#);
+		assert_eq!(entries["0"], b"png");
+	}
+
+	/// The settings in the `col` row of the package at `path`
+	fn settings(scratch: &Scratch, path: &Path) -> Map<String, Value> {
+		let db = database(scratch, &entries(path));
+		let conf: String = db.query_row("select conf from col", [], |row| row.get(0)).unwrap();
+		serde_json::from_str(&conf).unwrap()
+	}
+
+	#[test]
+	fn only_a_collection_package_carries_settings() {
+		let scratch = Scratch::new();
+		let collection =
+			Collection::try_from(test_support::deck(&["=Basic=\nFront: a\nBack: b\n"])).unwrap();
+		let (apkg, colpkg) = (scratch.path.join("deck.apkg"), scratch.path.join("deck.colpkg"));
+		to_apkg(&collection, &apkg).unwrap();
+		to_colpkg(&collection, &colpkg).unwrap();
+
+		assert!(settings(&scratch, &apkg).is_empty());
+
+		let settings = settings(&scratch, &colpkg);
+		let deck = numeric_id(collection.decks()[0].configuration.crowdanki_uuid.as_bytes());
+		assert_eq!(settings["curDeck"], deck);
+		assert_eq!(settings["nextPos"], 2);
+		assert_eq!(settings["schedVer"], 2);
+	}
+
+	#[test]
+	fn identities_come_out_the_same_on_every_export() {
+		assert_eq!(numeric_id(b"deck"), numeric_id(b"deck"));
+		assert_ne!(numeric_id(b"deck"), numeric_id(b"other deck"));
+		assert!(numeric_id(b"deck") < 1 << 53);
+	}
+}
@@ -0,0 +1,395 @@
+//! Abstraction over the version control backend a deck is tracked in. The
+//! change resolution pipeline only ever needs to know how a single file looked
+//! at each point it was changed, and who changed it when, so that's all a
+//! provider has to supply.
+
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use gix::{ObjectId, Repository};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::error::DeckError;
+
+/// The raw contents of a file as of a particular commit
+pub type Blob = Vec<u8>;
+
+/// Stands in for the author of a commit that has none, or none that can be
+/// read. Git author names can't contain a NUL, so it never passes for a real
+/// author, and author-less commits are still told apart by their time.
+pub const UNKNOWN_AUTHOR: &str = "\0unknown";
+
+/// The details of a commit that identity generation relies on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitMeta {
+	pub id:     String,
+	pub author: String,
+	pub time:   i64,
+}
+
+pub trait HistoryProvider {
+	/// Every revision of the file at `path` that introduced a change to it, in
+	/// chronological order (oldest first)
+	fn file_history(&self, path: &str) -> Result<Vec<(Blob, CommitMeta)>, DeckError>;
+
+	/// Every commit reachable from the one with the given id, itself included,
+	/// for placing commits within a file's history whatever their clocks say.
+	/// `None` if the commit isn't known.
+	fn ancestors(&self, _id: &str) -> Option<HashSet<String>> { None }
+
+	/// The commit the history is read as of, `None` without one
+	fn head_commit(&self) -> Option<String> { None }
+}
+
+/// History backed by a git repository through gix
+pub struct GixHistory {
+	repository: Repository,
+	// The commit history is walked back from, HEAD when unset
+	start:      Option<ObjectId>,
+	// Where the deck sits within the repository, `/` separated and empty at its
+	// root. Files are looked up beneath it
+	prefix:     String,
+}
+
+impl GixHistory {
+	pub fn new(repository: Repository) -> Self {
+		Self { repository, start: None, prefix: String::new() }
+	}
+
+	/// History from whichever repository the deck directory is in, whether at
+	/// its root, further up, or through a `.git` file pointing elsewhere as
+	/// worktrees and submodules have
+	#[instrument]
+	pub fn discover(deck_path: &Path) -> Result<Self, DeckError> {
+		let repository = gix::discover(deck_path).map_err(|e| match e {
+			gix::discover::Error::Discover(
+				gix::discover::upwards::Error::NoGitRepository { .. }
+				| gix::discover::upwards::Error::NoGitRepositoryWithinCeiling { .. }
+				| gix::discover::upwards::Error::NoGitRepositoryWithinFs { .. },
+			) => DeckError::NotARepository(deck_path.to_path_buf()),
+			e => DeckError::CorruptRepository(deck_path.to_path_buf(), e.to_string()),
+		})?;
+
+		// A bare repository has no working tree for the deck to sit within
+		let prefix = match repository.workdir() {
+			Some(workdir) => {
+				let deck = deck_path.canonicalize()?;
+				let root = workdir.canonicalize()?;
+				let relative = deck.strip_prefix(&root).unwrap_or(Path::new(""));
+				relative
+					.components()
+					.map(|component| component.as_os_str().to_string_lossy())
+					.collect::<Vec<_>>()
+					.join("/")
+			}
+			None => String::new(),
+		};
+
+		debug!("Deck is at '{}' within {:?}", prefix, repository.git_dir());
+		Ok(Self { repository, start: None, prefix })
+	}
+
+	/// History as of the commit `reference` points at (a branch, tag, or any
+	/// other revision git understands) rather than HEAD
+	#[instrument(skip(repository))]
+	pub fn at_ref(repository: Repository, reference: &str) -> Result<Self, DeckError> {
+		Self::new(repository).at(reference)
+	}
+
+	/// The same history, walked back from `reference` rather than HEAD
+	#[instrument(skip(self))]
+	pub fn at(mut self, reference: &str) -> Result<Self, DeckError> {
+		let start = resolve_commit(&self.repository, reference)?;
+
+		info!("Walking history from {} ({})", reference, start);
+		self.start = Some(start);
+		Ok(self)
+	}
+
+	/// Access the underlying repository
+	pub fn repository(&self) -> &Repository { &self.repository }
+}
+
+impl HistoryProvider for GixHistory {
+	#[instrument(skip(self))]
+	fn file_history(&self, target: &str) -> Result<Vec<(Blob, CommitMeta)>, DeckError> {
+		info!("Finding history of file: {}", target);
+
+		let vcs = &self.repository;
+		let path = if self.prefix.is_empty() {
+			target.to_string()
+		} else {
+			format!("{}/{}", self.prefix, target)
+		};
+		let mut history = Vec::new();
+		let start = match self.start {
+			Some(start) => start,
+			None => vcs.head()?.peel_to_object()?.id,
+		};
+		let revwalk = vcs.rev_walk([start]);
+
+		for commit_id in revwalk.all()? {
+			let commit_id = commit_id?;
+			let commit = vcs.find_commit(commit_id.id())?;
+			let tree = commit.tree()?;
+
+			// Check if file exists in this commit
+			let current_entry = tree.lookup_entry_by_path(&path)?.filter(|e| e.mode().is_blob());
+
+			let Some(current_entry) = current_entry else {
+				continue; // File doesn't exist in this commit
+			};
+
+			let parent_ids: Vec<_> = commit.parent_ids().collect();
+
+			// Check if file was added or modified compared to ANY parent
+			let mut file_changed = parent_ids.is_empty();
+
+			if file_changed {
+				// Initial commit with the file
+				info!("File created in initial commit {}", commit.id());
+			}
+
+			for parent_id in parent_ids {
+				let parent_commit = vcs.find_commit(parent_id)?;
+				let parent_tree = parent_commit.tree()?;
+				let parent_entry = parent_tree.lookup_entry_by_path(&path)?.filter(|e| e.mode().is_blob());
+
+				match parent_entry {
+					None => {
+						// File didn't exist in this parent - it was added
+						file_changed = true;
+						info!("File added in commit {} (from parent {})", commit.id(), parent_id);
+						break;
+					}
+					Some(entry) => {
+						// File exists in parent - check if it changed
+						if entry.oid() != current_entry.oid() {
+							file_changed = true;
+							break;
+						}
+					}
+				}
+			}
+
+			if file_changed {
+				let blob = vcs.find_blob(current_entry.id())?.data.clone();
+				let author = commit.author().map(|author| author.name.to_string()).unwrap_or_else(|e| {
+					warn!("Commit {} has no readable author ({}), using a placeholder", commit.id(), e);
+					UNKNOWN_AUTHOR.to_string()
+				});
+				let meta = CommitMeta { id: commit.id().to_string(), author, time: commit.time()?.seconds };
+				history.push((blob, meta));
+			}
+		}
+
+		// Reverse to get chronological order (oldest first)
+		history.reverse();
+
+		if history.is_empty() {
+			error!("File not found in repository history");
+			Err(DeckError::FileNotInHistory(target.to_string()))
+		} else {
+			info!("Found {} commits in file history", history.len());
+			Ok(history)
+		}
+	}
+
+	fn head_commit(&self) -> Option<String> {
+		match self.start {
+			Some(start) => Some(start.to_string()),
+			None => self.repository.head_id().ok().map(|id| id.to_string()),
+		}
+	}
+
+	fn ancestors(&self, id: &str) -> Option<HashSet<String>> {
+		let id = gix::ObjectId::from_hex(id.as_bytes()).ok()?;
+		let walk = self.repository.rev_walk([id]).all().ok()?;
+		walk.map(|info| info.ok().map(|info| info.id().to_string())).collect()
+	}
+}
+
+/// The commit `reference` points at, which may be a branch, tag, or any other
+/// revision git understands
+pub fn resolve_commit(repository: &Repository, reference: &str) -> Result<ObjectId, DeckError> {
+	Ok(
+		repository
+			.rev_parse_single(reference)
+			.map_err(|e| {
+				DeckError::Git(format!("Reference '{}' could not be resolved: {}", reference, e))
+			})?
+			.object()?
+			.peel_to_commit()
+			.map_err(|e| {
+				DeckError::Git(format!("Reference '{}' doesn't point at a commit: {}", reference, e))
+			})?
+			.id,
+	)
+}
+
+/// History consisting solely of the files as they currently sit on disk, for
+/// decks that haven't been committed yet. Every read is treated as a brand new
+/// commit, so the identities derived from it are fresh on each run and only
+/// become stable once the files are committed.
+#[derive(Debug, Clone)]
+pub struct WorkingTreeHistory {
+	root: PathBuf,
+}
+
+impl WorkingTreeHistory {
+	pub fn new(root: impl Into<PathBuf>) -> Self { Self { root: root.into() } }
+}
+
+impl HistoryProvider for WorkingTreeHistory {
+	#[instrument(skip(self))]
+	fn file_history(&self, path: &str) -> Result<Vec<(Blob, CommitMeta)>, DeckError> {
+		let file = self.root.join(path);
+		let blob = fs::read(&file).map_err(|_| DeckError::FileNotInHistory(path.to_string()))?;
+
+		let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+		info!("Reading {:?} from the working tree", file);
+
+		Ok(vec![(blob, CommitMeta {
+			id: "WORKTREE".to_string(),
+			author: UNKNOWN_AUTHOR.to_string(),
+			time,
+		})])
+	}
+}
+
+/// Committed history with the working tree on top as one more commit, for
+/// seeing what identities the next commit would give the deck. Files the
+/// working tree leaves as they were committed add nothing.
+pub struct PendingHistory {
+	committed: GixHistory,
+	working:   WorkingTreeHistory,
+}
+
+impl PendingHistory {
+	pub fn new(committed: GixHistory, root: impl Into<PathBuf>) -> Self {
+		Self { committed, working: WorkingTreeHistory::new(root) }
+	}
+}
+
+impl HistoryProvider for PendingHistory {
+	#[instrument(skip(self))]
+	fn file_history(&self, path: &str) -> Result<Vec<(Blob, CommitMeta)>, DeckError> {
+		// A file that's yet to be committed has only its working tree revision
+		let mut history = match self.committed.file_history(path) {
+			Ok(history) => history,
+			Err(DeckError::FileNotInHistory(_)) => Vec::new(),
+			Err(e) => return Err(e),
+		};
+
+		for (blob, meta) in self.working.file_history(path)? {
+			if history.last().is_none_or(|(last, _)| *last != blob) {
+				history.push((blob, meta));
+			}
+		}
+		Ok(history)
+	}
+
+	fn ancestors(&self, id: &str) -> Option<HashSet<String>> { self.committed.ancestors(id) }
+
+	fn head_commit(&self) -> Option<String> { self.committed.head_commit() }
+}
+
+/// History held entirely in memory, for exercising the change resolution
+/// pipeline without a real repository
+#[derive(Debug, Default, Clone)]
+pub struct MemoryHistory {
+	files: HashMap<String, Vec<(Blob, CommitMeta)>>,
+}
+
+impl MemoryHistory {
+	pub fn new() -> Self { Self::default() }
+
+	/// Record a new revision of a file, later calls are treated as newer
+	pub fn record(&mut self, path: &str, content: impl Into<Blob>, meta: CommitMeta) {
+		self.files.entry(path.to_string()).or_default().push((content.into(), meta));
+	}
+}
+
+impl HistoryProvider for MemoryHistory {
+	fn file_history(&self, path: &str) -> Result<Vec<(Blob, CommitMeta)>, DeckError> {
+		self
+			.files
+			.get(path)
+			.filter(|revisions| !revisions.is_empty())
+			.cloned()
+			.ok_or_else(|| DeckError::FileNotInHistory(path.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::{Scratch, commit, repository};
+
+	fn meta(id: &str, time: i64) -> CommitMeta {
+		CommitMeta { id: id.to_string(), author: "Tester".to_string(), time }
+	}
+
+	#[test]
+	fn memory_history_replays_revisions_in_the_order_recorded() {
+		let mut history = MemoryHistory::new();
+		history.record("index.flash", "first", meta("a", 1));
+		history.record("index.flash", "second", meta("b", 2));
+
+		let revisions = history.file_history("index.flash").expect("the file has history");
+		let ids: Vec<&str> = revisions.iter().map(|(_, meta)| meta.id.as_str()).collect();
+		assert_eq!(ids, vec!["a", "b"]);
+		assert_eq!(revisions[1].0, b"second".to_vec());
+	}
+
+	#[test]
+	fn files_without_history_are_reported() {
+		let history = MemoryHistory::new();
+		assert!(matches!(history.file_history("index.flash"), Err(DeckError::FileNotInHistory(_))));
+	}
+
+	#[test]
+	fn git_history_holds_only_the_commits_changing_the_file() {
+		let repo = repository();
+		repo.write("index.flash", "one");
+		let first = commit(&repo, "add", 1_000);
+		repo.write("notes.txt", "unrelated");
+		commit(&repo, "unrelated", 2_000);
+		repo.write("index.flash", "two");
+		let second = commit(&repo, "change", 3_000);
+
+		let history = GixHistory::new(gix::open(&repo.path).expect("the repository opens"));
+		let revisions = history.file_history("index.flash").expect("the file has history");
+		let ids: Vec<&str> = revisions.iter().map(|(_, meta)| meta.id.as_str()).collect();
+		assert_eq!(ids, vec![first.as_str(), second.as_str()]);
+		assert_eq!(revisions[0].1.time, 1_000);
+	}
+
+	#[test]
+	fn the_working_tree_has_no_author() {
+		let repo = repository();
+		repo.write("index.flash", "one");
+
+		let revisions =
+			WorkingTreeHistory::new(&repo.path).file_history("index.flash").expect("the file is there");
+		assert_eq!(revisions[0].1.author, UNKNOWN_AUTHOR);
+		assert_ne!(revisions[0].1.author, "");
+	}
+
+	#[test]
+	fn a_deck_is_found_beneath_the_repository_root() {
+		let repo = repository();
+		repo.write("decks/spanish/index.flash", "one");
+		commit(&repo, "add spanish", 1);
+
+		let history =
+			GixHistory::discover(&repo.path.join("decks/spanish")).expect("the repository is found");
+		assert_eq!(history.file_history("index.flash").expect("the file has history").len(), 1);
+	}
+
+	#[test]
+	fn a_directory_outside_any_repository_is_told_apart() {
+		let scratch = Scratch::new();
+		let result = GixHistory::discover(&scratch.path);
+		assert!(matches!(result, Err(DeckError::NotARepository(path)) if path == scratch.path));
+	}
+}
@@ -4,13 +4,26 @@ use crate::types::note::Note;
 
 pub mod change_resolver;
 pub mod change_router;
+pub mod check;
 pub mod deck_locator;
+pub mod delta;
+pub mod drift;
+pub mod emit;
 pub mod error;
+pub mod export;
+pub mod history;
+pub mod media;
 pub mod model_loader;
 pub mod parse;
+pub mod render;
+pub mod stats;
+#[cfg(test)]
+mod test_support;
 pub mod types;
 pub mod uuid_generator;
 
+pub use parse::render_diagnostics;
+
 #[instrument(skip(note))]
 pub fn print_note_debug(note: &Note) {
 	for field in &note.fields {
@@ -1,14 +1,13 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{env, fs, ops::Range, path::{Path, PathBuf}};
 
 use eyre::{Context, Result, eyre};
-use flash::{change_resolver::resolve_changes, change_router::determine_changes, deck_locator::find_deck_directory, parse::ImportExpander, types::{crowd_anki_models::CrowdAnkiEntity, deck::Deck, note::{Identified, Note}, note_methods::Identifiable}};
-use gix::{Commit, object::tree::Entry};
+use flash::{check::{check_deck, check_note_count, check_whitespace}, deck_locator::find_all_deck_directories, delta::deck_delta, drift::uuid_drift, emit::{SplitBy, index_parts, split_flash}, error::DeckError, export, media, parse::{decode_source, lex}, render::render_note, stats::deck_stats, types::{collection::Collection, crowd_anki_config::ConfigOverrides, crowd_anki_models::CrowdAnkiEntity, deck::Deck}};
 use opentelemetry::trace::TracerProvider;
 use opentelemetry_sdk::trace::SdkTracerProvider;
 use opentelemetry_stdout::SpanExporter;
-use tracing::{info, instrument, warn};
+use serde::Serialize;
+use tracing::{error, info, instrument, warn};
 use tracing_subscriber::{Registry, fmt::{self, time::ChronoUtc}, prelude::__tracing_subscriber_SubscriberExt};
-use uuid::Uuid;
 
 pub fn init_opentelemetry_tracing() {
 	// Create a new OpenTelemetry trace pipeline that prints to stdout
@@ -21,7 +20,8 @@ pub fn init_opentelemetry_tracing() {
 	let fmt_layer =
 		fmt::layer().with_target(false).with_timer(ChronoUtc::new("Sec.%S.Nanos.%f".to_string()));
 
-	let subscriber = Registry::default()
+	// Built but left uninstalled, the fmt layer would write over exports to stdout
+	let _subscriber = Registry::default()
         .with(telemetry_layer) // OpenTelemetry layer
         .with(fmt_layer); // Formatted console output layer
 }
@@ -33,18 +33,835 @@ fn main() -> Result<()> {
 
 	info!("Starting Anki deck parser");
 
-	// Find and scan deck
-	let deck_path = find_deck_directory().wrap_err("Failed to find deck directory")?;
-	info!("Found deck at: {:?}", deck_path);
+	let args: Vec<String> = env::args().skip(1).collect();
+	let command = args.first().filter(|arg| !arg.starts_with('-'));
 
-	let deck = Deck::from(deck_path)?;
+	// Undocumented, for seeing exactly what the lexer makes of a file
+	if command.is_some_and(|c| c == "debug-lex") {
+		let file = args.get(1).ok_or_else(|| eyre!("debug-lex expects a file"))?;
+		return debug_lex(Path::new(file));
+	}
 
-	let out: CrowdAnkiEntity = deck.into();
+	// Validate the command up front, rather than once per deck
+	let recent = match flag_value(&args, "--recent") {
+		Some(value) => value.parse().wrap_err("--recent expects a number of commits")?,
+		None => 10,
+	};
+	if let Some(other) = command
+		.filter(|c| !["export", "check", "stats", "split", "render", "repair"].contains(&c.as_str()))
+	{
+		return Err(eyre!(
+			"Unknown command '{}'. Available: [export, check, stats, split, render, repair]",
+			other
+		));
+	}
+	let note_index = match command.filter(|c| *c == "render") {
+		Some(_) => Some(
+			args
+				.get(1)
+				.and_then(|index| index.parse::<usize>().ok())
+				.filter(|index| *index > 0)
+				.ok_or_else(|| eyre!("render expects the number of a note, counting from 1"))?,
+		),
+		None => None,
+	};
+	let split_by =
+		if args.iter().any(|arg| arg == "--by-model") { SplitBy::Model } else { SplitBy::Tag };
 
-	let out = sonic_rs::serde::to_string(&out)?;
+	// Flags take precedence over the environment, and both over the deck's
+	// config, see `ConfigOverrides`
+	let output_env = env::var("FLASH_OUTPUT").ok();
+	let output = match flag_value(&args, "-o")
+		.or_else(|| flag_value(&args, "--output"))
+		.or(output_env.as_deref())
+	{
+		Some("-") => Output::Stdout,
+		Some(path) => Output::Path(PathBuf::from(path)),
+		None => Output::Default,
+	};
+	let overrides = ConfigOverrides {
+		name: flag_value(&args, "--deck-name").map(String::from),
+		host: flag_value(&args, "--host").map(String::from),
+	};
+	let no_clobber = args.iter().any(|arg| arg == "--no-clobber");
+	let sorted = args.iter().any(|arg| arg == "--sort");
+	let reset_scheduling = args.iter().any(|arg| arg == "--reset-scheduling");
+	let since = flag_value(&args, "--since");
 
-	fs::write("flash.json", out)?;
+	// `check` has a --format of its own
+	let format = flag_value(&args, "--format")
+		.filter(|_| command.is_none_or(|c| c == "export" || c == "repair"))
+		.map_or(Ok(ExportFormat::CrowdAnki), ExportFormat::from_name)?;
+
+	// A delta is a document of its own rather than the deck in some format
+	if since.is_some()
+		&& command.is_none_or(|c| c == "export")
+		&& flag_value(&args, "--format").is_some()
+	{
+		return Err(eyre!("--since writes a delta document, it can't be combined with --format"));
+	}
+
+	let whitespace = args.iter().any(|arg| arg == "--whitespace");
+	let drift = args.iter().any(|arg| arg == "--uuid-drift");
+	if command.is_some_and(|c| c == "check") && args.iter().any(|arg| arg == "--check-only") {
+		let json = flag_value(&args, "--format") == Some("json");
+		std::process::exit(check_only(json, whitespace, &overrides));
+	}
+
+	// Find and scan decks
+	let deck_paths = find_all_deck_directories().wrap_err("Failed to find deck directory")?;
+	info!("Found {} decks", deck_paths.len());
+
+	// Every deck would be written over the last otherwise
+	let several = deck_paths.len() > 1;
+	if let Output::Path(path) = &output
+		&& !path.is_dir()
+		&& several
+	{
+		return Err(eyre!(
+			"{:?} names a single file but {} decks were found, point --output at a directory",
+			path,
+			deck_paths.len()
+		));
+	}
+
+	// One deck failing shouldn't stop the rest, failures are reported at the end
+	let mut failures = Vec::new();
+	for deck_path in &deck_paths {
+		info!("Processing deck at: {:?}", deck_path);
+
+		let outcome =
+			load_deck(deck_path, &overrides).and_then(|deck| match command.map(String::as_str) {
+				Some("check") => check(&deck, whitespace, drift.then_some(&overrides)),
+				Some("stats") => stats(&deck, recent, args.iter().any(|arg| arg == "--json")),
+				Some("split") => split(&deck, split_by),
+				Some("render") => render(&deck, note_index.unwrap_or(1)),
+				Some("repair") => repair(
+					deck,
+					output.resolve(deck_path, several, format.extension()),
+					output.resolve(deck_path, several, "repair.json"),
+					no_clobber,
+					format,
+					reset_scheduling,
+				),
+				_ => match since {
+					Some(since) => {
+						export_delta(&deck, since, output.resolve(deck_path, several, "delta.json"), no_clobber)
+					}
+					None => export(
+						deck,
+						output.resolve(deck_path, several, format.extension()),
+						no_clobber,
+						format,
+						sorted,
+						reset_scheduling,
+					),
+				},
+			});
+
+		if let Err(e) = outcome {
+			error!("Deck {:?} failed: {:?}", deck_path, e);
+			failures.push(deck_path);
+		}
+	}
+
+	if failures.is_empty() {
+		Ok(())
+	} else {
+		Err(eyre!("{} of {} decks failed: {:?}", failures.len(), deck_paths.len(), failures))
+	}
+}
+
+/// Where exports are written, set through `-o/--output`
+#[derive(Debug)]
+enum Output {
+	// `flash.json` in the current directory, or `<deck>.json` for each of several
+	Default,
+	// `-o -`
+	Stdout,
+	// A file, or a directory to write `<deck>.json` into
+	Path(PathBuf),
+}
+
+impl Output {
+	/// The file a deck's export goes to, `None` for stdout. A lone deck is
+	/// written to `flash.<extension>`, and each of `several` under its own name
+	fn resolve(&self, deck_path: &Path, several: bool, extension: &str) -> Option<PathBuf> {
+		let name = deck_path.file_stem().map_or("flash".into(), |stem| stem.to_string_lossy());
+		let own_name = format!("{}.{}", name, extension);
+		match self {
+			Output::Stdout => None,
+			Output::Path(path) if path.is_dir() => Some(path.join(own_name)),
+			Output::Path(path) => Some(path.clone()),
+			Output::Default if several => Some(PathBuf::from(own_name)),
+			Output::Default => Some(PathBuf::from(format!("flash.{}", extension))),
+		}
+	}
+}
+
+/// What `export --format` writes a deck out as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+	// The deck, its models and presets as CrowdAnki JSON, with media copied
+	// alongside. The default, and the only format that round-trips into Anki
+	CrowdAnki,
+	// A line of JSON per note, its identity and content with the model named.
+	// Notes only, no models, presets or media
+	JsonLines,
+	// Anki's own package, for importing without CrowdAnki. Cards come out new,
+	// and every deck uses the default options group
+	Apkg,
+	// Like apkg, but a whole collection that Anki restores in place of its own
+	Colpkg,
+}
+
+impl ExportFormat {
+	fn from_name(name: &str) -> Result<Self> {
+		match name {
+			"crowdanki" => Ok(Self::CrowdAnki),
+			"json-lines" => Ok(Self::JsonLines),
+			"apkg" => Ok(Self::Apkg),
+			"colpkg" => Ok(Self::Colpkg),
+			other => Err(eyre!(
+				"Unknown export format '{}'. Available: [crowdanki, json-lines, apkg, colpkg]",
+				other
+			)),
+		}
+	}
+
+	fn extension(self) -> &'static str {
+		match self {
+			Self::CrowdAnki => "json",
+			Self::JsonLines => "jsonl",
+			Self::Apkg => "apkg",
+			Self::Colpkg => "colpkg",
+		}
+	}
+}
+
+/// Load a deck, falling back to the working tree if it hasn't been committed
+fn load_deck(deck_path: &Path, overrides: &ConfigOverrides) -> Result<Deck<'static>> {
+	match Deck::from_with_overrides(deck_path, overrides.clone()) {
+		Err(DeckError::Uncommitted(target)) => {
+			warn!("{} is not committed, falling back to the working tree with fresh UUIDs", target);
+			Ok(Deck::from_working_tree_with_overrides(deck_path, overrides.clone())?)
+		}
+		deck => Ok(deck?),
+	}
+}
+
+/// Print every token in the file alongside the span it covers
+fn debug_lex(file: &Path) -> Result<()> {
+	let bytes = fs::read(file).wrap_err_with(|| format!("Failed to read {:?}", file))?;
+	let content =
+		decode_source(bytes).map_err(|reason| eyre!("Failed to decode {:?}: {}", file, reason))?;
+
+	for (token, span) in lex(&content) {
+		println!("{:>6}..{:<6} {:?}", span.start, span.end, token);
+	}
+	Ok(())
+}
+
+/// The value following a flag, as in `--flag value`
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+	args.iter().position(|arg| arg == flag).and_then(|idx| args.get(idx + 1)).map(String::as_str)
+}
+
+/// Print the composition of the deck and how it changed recently
+#[instrument(skip(deck))]
+fn stats(deck: &Deck, recent: usize, json: bool) -> Result<()> {
+	let stats = deck_stats(deck, recent)?;
+
+	if json {
+		println!("{}", serde_json::to_string_pretty(&stats)?);
+		return Ok(());
+	}
+
+	println!("{:<24}{}", "Notes", stats.total_notes);
+	println!("{:<24}{}", "Cloze notes", stats.cloze_notes);
+	println!("{:<24}{}", "Standard notes", stats.standard_notes);
+	println!("{:<24}{:.1}", "Average field length", stats.average_field_length);
+
+	println!("\nNotes per model");
+	for (model, count) in &stats.notes_per_model {
+		println!("  {:<22}{}", model, count);
+	}
+
+	println!("\nNotes per tag");
+	for (tag, count) in &stats.notes_per_tag {
+		println!("  {:<22}{}", tag, count);
+	}
+
+	println!("\nOver the last {} commits", stats.recent_commits);
+	println!("  {:<22}{}", "Added", stats.recent_additions);
+	println!("  {:<22}{}", "Modified", stats.recent_modifications);
+	println!("  {:<22}{}", "Deleted", stats.recent_deletions);
+
+	Ok(())
+}
+
+/// Break the notes written in the deck's index.flash up into a file per tag or
+/// model, leaving the index importing them after its own directives and
+/// imports. Existing files are never overwritten.
+#[instrument(skip(deck))]
+fn split(deck: &Deck, by: SplitBy) -> Result<()> {
+	let index_path = deck.path.join("index.flash");
+	let (kept, own) = index_parts(&fs::read_to_string(&index_path)?);
+	let separator = deck.configuration.note_separator;
+	let notes = Deck::parse_cards_separated(&deck.models, &own, separator)?;
+	let (imports, files) = split_flash(&notes, by, separator);
+
+	if let Some((name, _)) = files.iter().find(|(name, _)| deck.path.join(name).exists()) {
+		return Err(eyre!("{:?} already exists, move it aside before splitting", deck.path.join(name)));
+	}
+
+	for (name, content) in &files {
+		fs::write(deck.path.join(name), content)?;
+	}
+	fs::write(index_path, kept + &imports)?;
+
+	info!("Split {} notes into {} files", notes.len(), files.len());
+	Ok(())
+}
+
+/// Print the front and back of every card the deck's `index`th note (counting
+/// from 1) turns into, as HTML
+#[instrument(skip(deck))]
+fn render(deck: &Deck, index: usize) -> Result<()> {
+	let card = deck
+		.cards
+		.get(index - 1)
+		.ok_or_else(|| eyre!("There's no note {}, the deck has {} notes", index, deck.cards.len()))?;
+
+	for rendered in render_note(&card.inner) {
+		println!("== {} ==\n\nFront:\n{}\n\nBack:\n{}\n", rendered.name, rendered.front, rendered.back);
+	}
+	Ok(())
+}
+
+/// Report anything in the deck that's likely to surprise once it's in Anki.
+/// Given `drift`, also the notes that committing the working tree would give
+/// new identities, losing their review history
+#[instrument(skip(deck, drift))]
+fn check(deck: &Deck, whitespace: bool, drift: Option<&ConfigOverrides>) -> Result<()> {
+	for warning in check_deck(deck) {
+		warn!("{}", warning);
+	}
+
+	// Stray tabs are opt-in, as the card files are read again to find them
+	if whitespace {
+		for warning in check_whitespace(deck)? {
+			warn!("{}", warning);
+		}
+	}
+
+	if let Some(overrides) = drift {
+		for drifted in uuid_drift(&deck.path, overrides)? {
+			let after = drifted.after.map_or_else(|| "nothing".to_string(), |id| id.to_string());
+			warn!(
+				"Note {} would become {} ({:?}): {}",
+				drifted.before, after, drifted.cause, drifted.preview
+			);
+		}
+	}
+
+	check_note_count(deck)?;
+
+	info!("Deck check completed");
+	Ok(())
+}
+
+/// Everything is fine, warnings aside
+const EXIT_OK: i32 = 0;
+/// A deck failed to parse or validate, the author has something to fix
+const EXIT_INVALID: i32 = 2;
+/// A deck couldn't be read at all, through I/O or git failing
+const EXIT_UNREADABLE: i32 = 3;
+
+/// A single problem found by `check --check-only`, in the shape emitted by
+/// `--format json`
+#[derive(Debug, Serialize)]
+struct CheckDiagnostic {
+	deck:     PathBuf,
+	severity: &'static str,
+	message:  String,
+	// The card file a problem in the source was found in
+	file:     Option<String>,
+	// Byte range within that file
+	span:     Option<Range<usize>>,
+}
+
+impl CheckDiagnostic {
+	fn error(deck: &Path, error: impl ToString) -> Self {
+		Self {
+			deck:     deck.to_path_buf(),
+			severity: "error",
+			message:  error.to_string(),
+			file:     None,
+			span:     None,
+		}
+	}
+}
+
+/// Check every deck for CI, exiting with `EXIT_OK`, `EXIT_INVALID`, or
+/// `EXIT_UNREADABLE` (the worst across decks) so a pipeline can gate on the
+/// result without scraping the logs. `whitespace` adds the stray tab checks.
+fn check_only(json: bool, whitespace: bool, overrides: &ConfigOverrides) -> i32 {
+	let mut diagnostics = Vec::new();
+	let mut code = EXIT_OK;
+
+	let deck_paths = match find_all_deck_directories() {
+		Ok(deck_paths) => deck_paths,
+		Err(e) => {
+			code = exit_code(&e);
+			diagnostics.push(CheckDiagnostic::error(Path::new("."), e));
+			Vec::new()
+		}
+	};
+
+	for deck_path in &deck_paths {
+		let problems = match Deck::diagnose(deck_path) {
+			Ok(problems) => problems,
+			Err(e) => {
+				code = code.max(exit_code(&e));
+				diagnostics.push(CheckDiagnostic::error(deck_path, e));
+				continue;
+			}
+		};
+
+		// Source problems say far more than the error loading would
+		if !problems.is_empty() {
+			code = code.max(EXIT_INVALID);
+			diagnostics.extend(problems.into_iter().map(|(file, problem)| CheckDiagnostic {
+				deck:     deck_path.clone(),
+				severity: "error",
+				message:  problem.message,
+				file:     Some(file),
+				span:     Some(problem.span),
+			}));
+			continue;
+		}
+
+		match load_deck(deck_path, overrides) {
+			Ok(deck) => {
+				if let Err(e) = check_note_count(&deck) {
+					code = code.max(exit_code(&e));
+					diagnostics.push(CheckDiagnostic::error(deck_path, e));
+				}
+
+				let mut warnings = check_deck(&deck);
+				if whitespace {
+					match check_whitespace(&deck) {
+						Ok(found) => warnings.extend(found),
+						Err(e) => {
+							code = code.max(exit_code(&e));
+							diagnostics.push(CheckDiagnostic::error(deck_path, e));
+						}
+					}
+				}
+
+				diagnostics.extend(warnings.into_iter().map(|warning| CheckDiagnostic {
+					deck:     deck_path.clone(),
+					severity: "warning",
+					message:  warning.to_string(),
+					file:     None,
+					span:     None,
+				}))
+			}
+			Err(e) => {
+				code = code.max(e.downcast_ref::<DeckError>().map_or(EXIT_UNREADABLE, exit_code));
+				diagnostics.push(CheckDiagnostic::error(deck_path, e));
+			}
+		}
+	}
+
+	if json {
+		println!("{}", serde_json::to_string_pretty(&diagnostics).unwrap_or_else(|_| "[]".into()));
+	} else {
+		for diagnostic in &diagnostics {
+			let location = diagnostic
+				.file
+				.as_ref()
+				.zip(diagnostic.span.as_ref())
+				.map_or(String::new(), |(file, s)| format!(" in {} at {}..{}", file, s.start, s.end));
+			println!(
+				"{:?}{}: {}: {}",
+				diagnostic.deck, location, diagnostic.severity, diagnostic.message
+			);
+		}
+	}
+
+	code
+}
+
+/// Whether an error is the author's to fix, or the environment's
+fn exit_code(error: &DeckError) -> i32 {
+	match error {
+		DeckError::Parse(_)
+		| DeckError::ModelNotFound(_)
+		| DeckError::AmbiguousChange(_)
+		| DeckError::InvalidUtf8(..)
+		| DeckError::Import(_)
+		| DeckError::InvalidTemplateFilename(_)
+		| DeckError::ModelConfigNotFound(_)
+		| DeckError::ModelConfig(..)
+		| DeckError::ConflictingRequirements(_)
+		| DeckError::UnsupportedSchema(..)
+		| DeckError::DeckConfigNotFound(_)
+		| DeckError::MultipleDeckConfigs(_)
+		| DeckError::DeckConfigFormat(..)
+		| DeckError::TemplateNotFound(_)
+		| DeckError::NoTemplates(_)
+		| DeckError::MixedTemplates(_)
+		| DeckError::IncompleteTemplate(..)
+		| DeckError::MediaNotFound(_)
+		| DeckError::MediaNameClash(..)
+		| DeckError::DuplicateNote(_)
+		| DeckError::MergeConflicts(_)
+		| DeckError::TrackedPathNotInHistory(..)
+		| DeckError::NoteCountMismatch(..)
+		| DeckError::Toml(_) => EXIT_INVALID,
+		_ => EXIT_UNREADABLE,
+	}
+}
+
+/// Write out only what changed in the deck's notes since the commit `since`,
+/// as a delta document, rather than the deck as a whole
+#[instrument(skip(deck))]
+fn export_delta(
+	deck: &Deck,
+	since: &str,
+	out_path: Option<PathBuf>,
+	no_clobber: bool,
+) -> Result<()> {
+	if no_clobber && let Some(out_path) = out_path.as_ref().filter(|path| path.exists()) {
+		return Err(eyre!("{:?} already exists, refusing to overwrite it with --no-clobber", out_path));
+	}
+
+	let out = serde_json::to_string(&deck_delta(deck, since)?)?;
+	match out_path {
+		None => println!("{}", out),
+		Some(out_path) => {
+			fs::write(&out_path, out)?;
+			info!("Wrote {:?}", out_path);
+		}
+	}
+
+	Ok(())
+}
+
+/// Give notes sharing an identity identities of their own and export the
+/// repaired deck to `out_path`, writing which identities became which to
+/// `mapping_path`. The repaired notes lose their review history, as Anki sees
+/// them as new ones.
+#[instrument(skip(deck))]
+fn repair(
+	mut deck: Deck,
+	out_path: Option<PathBuf>,
+	mapping_path: Option<PathBuf>,
+	no_clobber: bool,
+	format: ExportFormat,
+	reset_scheduling: bool,
+) -> Result<()> {
+	let repairs = deck.repair_duplicates();
+	if repairs.is_empty() {
+		info!("No notes share an identity, nothing to repair");
+		return Ok(());
+	}
+
+	// Logged as they're made, there's nowhere else for them with the export on
+	// stdout
+	if let Some(mapping_path) = mapping_path {
+		if no_clobber && mapping_path.exists() {
+			return Err(eyre!(
+				"{:?} already exists, refusing to overwrite it with --no-clobber",
+				mapping_path
+			));
+		}
+		fs::write(&mapping_path, serde_json::to_string(&repairs)?)?;
+		info!("Wrote {:?}", mapping_path);
+	}
+
+	export(deck, out_path, no_clobber, format, false, reset_scheduling)
+}
+
+/// Copy `source` to `destination` unless the copy there is already up to date,
+/// going by its size and modification time. Returns whether it was copied.
+fn copy_if_changed(source: &Path, destination: &Path) -> std::io::Result<bool> {
+	let source_meta = fs::metadata(source)?;
+
+	if let Ok(destination_meta) = fs::metadata(destination)
+		&& destination_meta.len() == source_meta.len()
+		&& destination_meta.modified()? >= source_meta.modified()?
+	{
+		return Ok(false);
+	}
+
+	fs::copy(source, destination)?;
+	Ok(true)
+}
+
+/// Write the deck out in the given format to `out_path`, stdout without one.
+/// CrowdAnki's is written alongside its media. `sorted` orders the notes by
+/// their sort field rather than as written, and `reset_scheduling` drops the
+/// scheduling notes would otherwise carry over in their data, including
+/// what Anki left in an earlier export at the same path
+#[instrument(skip(deck))]
+fn export(
+	mut deck: Deck,
+	out_path: Option<PathBuf>,
+	no_clobber: bool,
+	format: ExportFormat,
+	sorted: bool,
+	reset_scheduling: bool,
+) -> Result<()> {
+	deck.expand_macros();
+	if sorted {
+		deck.sort_by_sort_field();
+	}
+	if reset_scheduling {
+		deck.reset_scheduling();
+	}
+
+	if no_clobber && let Some(out_path) = out_path.as_ref().filter(|path| path.exists()) {
+		return Err(eyre!("{:?} already exists, refusing to overwrite it with --no-clobber", out_path));
+	}
+
+	// Copy declared media next to the export, where CrowdAnki expects to find it
+	if format == ExportFormat::CrowdAnki && !deck.media.is_empty() {
+		let media_dir = out_path
+			.as_ref()
+			.and_then(|path| path.parent())
+			.unwrap_or_else(|| Path::new(""))
+			.join("media");
+		fs::create_dir_all(&media_dir)?;
+
+		let mut copied = 0;
+		for (path, file_name) in deck.media.iter().zip(media::file_names(&deck.media)?) {
+			if copy_if_changed(path, &media_dir.join(file_name))
+				.wrap_err_with(|| format!("Failed to copy media file {:?}", path))?
+			{
+				copied += 1;
+			}
+		}
+		info!("Copied {} media files, skipped {} unchanged", copied, deck.media.len() - copied);
+	}
+
+	let out = match format {
+		ExportFormat::CrowdAnki => {
+			let mut out = CrowdAnkiEntity::try_from(deck)?;
+
+			// Anki writes its scheduling into the data of the notes it syncs back
+			// through the same file, losing it would reset every card
+			if let Some(previous) = out_path.as_ref().and_then(|path| fs::read_to_string(path).ok()) {
+				match serde_json::from_str::<CrowdAnkiEntity>(&previous) {
+					Ok(previous) => out.preserve_data(&previous, reset_scheduling),
+					Err(e) => warn!("Not carrying over data from the earlier export: {}", e),
+				}
+			}
+			sonic_rs::serde::to_string(&out)?
+		}
+		ExportFormat::JsonLines => {
+			deck.cards.iter().map(serde_json::to_string).collect::<Result<Vec<_>, _>>()?.join("\n")
+		}
+		// A package is a zip archive, which has no place on a terminal
+		ExportFormat::Apkg | ExportFormat::Colpkg => {
+			let out_path = out_path
+				.ok_or_else(|| eyre!("A package can't be written to stdout, point --output at a file"))?;
+			let collection = Collection::try_from(deck)?;
+			match format {
+				ExportFormat::Colpkg => export::to_colpkg(&collection, &out_path)?,
+				_ => export::to_apkg(&collection, &out_path)?,
+			}
+			info!("Wrote {:?}", out_path);
+			return Ok(());
+		}
+	};
+
+	match out_path {
+		// Each deck ends on a line of its own, so several can be piped through together
+		None => println!("{}", out),
+		Some(out_path) => {
+			fs::write(&out_path, out)?;
+			info!("Wrote {:?}", out_path);
+		}
+	}
 
 	info!("Deck parsing completed");
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+
+	use flash::model_loader;
+	use uuid::Uuid;
+
+	use super::*;
+
+	/// A directory of its own for a test's exports
+	fn scratch(name: &str) -> PathBuf {
+		let dir = env::temp_dir().join(format!("flash-{}-{}", name, std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	fn deck(source: &str) -> Deck<'static> {
+		let files = vec![("index.flash".to_string(), vec![source.to_string()])];
+		Deck::from_parts(model_loader::builtin_models().unwrap(), files, Uuid::from_u128(1)).unwrap()
+	}
+
+	const SOURCE: &str = "=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n";
+
+	/// Export the deck to `path` in `format`, returning what was written
+	fn exported(path: &Path, format: ExportFormat) -> Vec<u8> {
+		export(deck(SOURCE), Some(path.to_path_buf()), false, format, false, false).unwrap();
+		fs::read(path).unwrap()
+	}
+
+	#[test]
+	fn a_lone_export_is_flash_json_and_several_are_named_after_their_deck() {
+		let deck = Path::new("decks/Spanish");
+		assert_eq!(Output::Default.resolve(deck, false, "json"), Some(PathBuf::from("flash.json")));
+		assert_eq!(Output::Default.resolve(deck, true, "json"), Some(PathBuf::from("Spanish.json")));
+		assert_eq!(Output::Default.resolve(deck, false, "apkg"), Some(PathBuf::from("flash.apkg")));
+		assert_eq!(Output::Stdout.resolve(deck, true, "json"), None);
+	}
+
+	#[test]
+	fn an_output_directory_holds_the_export_and_a_file_is_taken_as_is() {
+		let deck = Path::new("decks/Spanish");
+		let dir = env::temp_dir();
+		assert_eq!(
+			Output::Path(dir.clone()).resolve(deck, false, "json"),
+			Some(dir.join("Spanish.json"))
+		);
+
+		let file = dir.join("flash-output-that-does-not-exist.json");
+		assert_eq!(Output::Path(file.clone()).resolve(deck, false, "json"), Some(file));
+	}
+
+	#[test]
+	fn export_formats_are_chosen_by_name() {
+		assert_eq!(ExportFormat::from_name("crowdanki").unwrap(), ExportFormat::CrowdAnki);
+		assert_eq!(ExportFormat::from_name("json-lines").unwrap().extension(), "jsonl");
+		assert_eq!(ExportFormat::from_name("apkg").unwrap().extension(), "apkg");
+		assert_eq!(ExportFormat::from_name("colpkg").unwrap().extension(), "colpkg");
+		assert!(ExportFormat::from_name("csv").is_err());
+	}
+
+	#[test]
+	fn crowd_anki_exports_hold_the_deck_as_json() {
+		let dir = scratch("crowdanki");
+		let out = exported(&dir.join("deck.json"), ExportFormat::CrowdAnki);
+
+		let Ok(CrowdAnkiEntity::Deck(deck)) = serde_json::from_slice(&out) else {
+			panic!("the export is a CrowdAnki deck")
+		};
+		let fields: Vec<Vec<String>> = deck.notes.into_iter().map(|note| note.fields).collect();
+		assert_eq!(fields, vec![vec!["a", "b"], vec!["c", "d"]]);
+		assert_eq!(deck.note_models.len(), model_loader::builtin_models().unwrap().len());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn json_lines_exports_hold_a_line_per_note() {
+		let dir = scratch("json-lines");
+		let out = String::from_utf8(exported(&dir.join("deck.jsonl"), ExportFormat::JsonLines)).unwrap();
+
+		let lines: Vec<serde_json::Value> =
+			out.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+		assert_eq!(lines.len(), 2);
+		assert!(lines.iter().all(serde_json::Value::is_object));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn apkg_exports_hold_the_notes_in_a_collection() {
+		let dir = scratch("apkg");
+		let out = exported(&dir.join("deck.apkg"), ExportFormat::Apkg);
+
+		let mut archive = zip::ZipArchive::new(std::io::Cursor::new(out)).unwrap();
+		let mut database = Vec::new();
+		archive.by_name("collection.anki2").unwrap().read_to_end(&mut database).unwrap();
+		assert!(archive.by_name("media").is_ok());
+
+		fs::write(dir.join("collection.anki2"), database).unwrap();
+		let connection = rusqlite::Connection::open(dir.join("collection.anki2")).unwrap();
+		let notes: i64 = connection.query_row("select count(*) from notes", [], |row| row.get(0)).unwrap();
+		assert_eq!(notes, 2);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn colpkg_exports_hold_the_notes_in_a_collection() {
+		let dir = scratch("colpkg");
+		let out = exported(&dir.join("deck.colpkg"), ExportFormat::Colpkg);
+
+		let archive = zip::ZipArchive::new(std::io::Cursor::new(out)).unwrap();
+		let names: Vec<&str> = archive.file_names().collect();
+		assert!(names.contains(&"collection.anki2") && names.contains(&"media"));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn apkg_exports_need_a_file() {
+		assert!(export(deck(SOURCE), None, false, ExportFormat::Apkg, false, false).is_err());
+	}
+
+	#[test]
+	fn unchanged_media_isnt_copied_again() {
+		let dir = env::temp_dir().join(format!("flash-media-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let (source, destination) = (dir.join("a.png"), dir.join("copy.png"));
+
+		fs::write(&source, "one").unwrap();
+		assert!(copy_if_changed(&source, &destination).unwrap());
+		assert!(!copy_if_changed(&source, &destination).unwrap());
+
+		fs::write(&source, "longer").unwrap();
+		assert!(copy_if_changed(&source, &destination).unwrap());
+		assert_eq!(fs::read_to_string(&destination).unwrap(), "longer");
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// Export over the earlier export at `path` after Anki left `data` on its
+	/// first note, returning that note's data afterwards
+	fn reexported_data(path: &Path, data: &str, reset_scheduling: bool) -> serde_json::Value {
+		let mut previous: CrowdAnkiEntity = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+		if let CrowdAnkiEntity::Deck(deck) = &mut previous {
+			deck.notes[0].data = Some(data.to_string());
+		}
+		fs::write(path, serde_json::to_string(&previous).unwrap()).unwrap();
+
+		let out_path = Some(path.to_path_buf());
+		export(deck(SOURCE), out_path, false, ExportFormat::CrowdAnki, false, reset_scheduling).unwrap();
+		let Ok(CrowdAnkiEntity::Deck(deck)) = serde_json::from_slice(&fs::read(path).unwrap()) else {
+			panic!("the export is a CrowdAnki deck")
+		};
+		serde_json::from_str(deck.notes[0].data.as_deref().unwrap_or("{}")).unwrap()
+	}
+
+	#[test]
+	fn reexporting_keeps_the_data_anki_left_unless_scheduling_is_reset() {
+		let dir = scratch("reexport");
+		let path = dir.join("deck.json");
+		exported(&path, ExportFormat::CrowdAnki);
+
+		let data = reexported_data(&path, r#"{"ease":"2500","flag":"red"}"#, false);
+		assert_eq!(data["ease"], "2500");
+		assert_eq!(data["flag"], "red");
+
+		let data = reexported_data(&path, r#"{"ease":"2500","flag":"red"}"#, true);
+		assert!(data.get("ease").is_none());
+		assert_eq!(data["flag"], "red");
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,198 @@
+//! Finding references to media files within field content. Anki only copies
+//! media it can see referenced, so every syntax an author might reach for has
+//! to be recognised before the files can be collected or checked.
+
+use std::{ops::Range, path::{Path, PathBuf}};
+
+use crate::error::DeckError;
+
+/// The syntax a media file was referenced through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaSyntax {
+	// [sound:file.mp3]
+	Sound,
+	// <img src="file.png">
+	Image,
+	// ![alt](file.jpg)
+	Markdown,
+}
+
+/// A media file referenced from field content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaRef {
+	pub file:   String,
+	// Byte range of the file name within the content it was found in
+	pub span:   Range<usize>,
+	pub syntax: MediaSyntax,
+}
+
+/// Every media reference in the content, in the order they appear
+pub fn extract_references(content: &str) -> Vec<MediaRef> {
+	let mut references = Vec::new();
+
+	references.extend(sound_references(content));
+	references.extend(image_references(content));
+	references.extend(markdown_references(content));
+
+	references.sort_by_key(|reference| reference.span.start);
+	references
+}
+
+/// The name each media file goes by once exported, where Anki keeps all media
+/// side by side. Two files of the same name would overwrite one another, so
+/// they're refused rather than one of them being lost
+pub fn file_names(paths: &[PathBuf]) -> Result<Vec<String>, DeckError> {
+	let mut names: Vec<(String, &Path)> = Vec::with_capacity(paths.len());
+
+	for path in paths {
+		let name = path
+			.file_name()
+			.ok_or_else(|| DeckError::MediaNotFound(path.clone()))?
+			.to_string_lossy()
+			.to_string();
+
+		if let Some((_, other)) = names.iter().find(|(existing, _)| *existing == name) {
+			return Err(DeckError::MediaNameClash(other.to_path_buf(), path.clone()));
+		}
+		names.push((name, path));
+	}
+
+	Ok(names.into_iter().map(|(name, _)| name).collect())
+}
+
+fn reference(content: &str, span: Range<usize>, syntax: MediaSyntax) -> Option<MediaRef> {
+	let file = content[span.clone()].trim();
+	if file.is_empty() {
+		return None;
+	}
+
+	// Keep the span on the trimmed name
+	let start = span.start + content[span.clone()].find(file).unwrap_or(0);
+	Some(MediaRef { file: file.to_string(), span: start..start + file.len(), syntax })
+}
+
+fn sound_references(content: &str) -> Vec<MediaRef> {
+	const OPEN: &str = "[sound:";
+
+	content
+		.match_indices(OPEN)
+		.filter_map(|(idx, _)| {
+			let start = idx + OPEN.len();
+			let end = start + content[start..].find(']')?;
+			reference(content, start..end, MediaSyntax::Sound)
+		})
+		.collect()
+}
+
+fn image_references(content: &str) -> Vec<MediaRef> {
+	// Tags are case insensitive, lowercasing ASCII keeps byte offsets intact
+	let lowered = content.to_ascii_lowercase();
+
+	lowered
+		.match_indices("<img")
+		.filter_map(|(idx, _)| {
+			let tag_end = idx + lowered[idx..].find('>')?;
+			let tag = &lowered[idx..tag_end];
+
+			// The attribute name has to stand alone, `data-src` isn't it
+			let attribute = tag
+				.match_indices("src")
+				.map(|(offset, _)| offset)
+				.find(|&offset| tag[..offset].ends_with(|c: char| c.is_whitespace()))?;
+
+			let rest = &tag[attribute + "src".len()..];
+			let value = rest.trim_start().strip_prefix('=')?.trim_start();
+			let value_start = tag_end - value.len();
+
+			let span = match value.chars().next()? {
+				quote @ ('"' | '\'') => {
+					let start = value_start + 1;
+					start..start + value[1..].find(quote)?
+				}
+				_ => {
+					let mut len = value.find(char::is_whitespace).unwrap_or(value.len());
+					// Relative paths contain slashes, only a trailing one closes the tag
+					if len == value.len() && value.ends_with('/') {
+						len -= 1;
+					}
+					value_start..value_start + len
+				}
+			};
+
+			reference(content, span, MediaSyntax::Image)
+		})
+		.collect()
+}
+
+fn markdown_references(content: &str) -> Vec<MediaRef> {
+	content
+		.match_indices("![")
+		.filter_map(|(idx, _)| {
+			let alt_end = idx + content[idx..].find("](")?;
+			let start = alt_end + "](".len();
+			let end = start + content[start..].find(')')?;
+
+			// An optional title follows the path, separated by whitespace
+			let target = &content[start..end];
+			let len = target.find(char::is_whitespace).unwrap_or(target.len());
+			reference(content, start..start + len, MediaSyntax::Markdown)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn files(content: &str) -> Vec<(String, MediaSyntax)> {
+		extract_references(content)
+			.into_iter()
+			.map(|reference| (reference.file, reference.syntax))
+			.collect()
+	}
+
+	#[test]
+	fn media_sharing_a_file_name_is_refused() {
+		let paths = [PathBuf::from("a/x.png"), PathBuf::from("b/y.png")];
+		assert_eq!(file_names(&paths).unwrap(), vec!["x.png", "y.png"]);
+
+		let paths = [PathBuf::from("a/x.png"), PathBuf::from("b/x.png")];
+		assert!(matches!(
+			file_names(&paths),
+			Err(DeckError::MediaNameClash(first, second)) if first == paths[0] && second == paths[1]
+		));
+	}
+
+	#[test]
+	fn every_syntax_is_found_in_order() {
+		assert_eq!(files("![x](c.jpg) [sound:a.mp3] <img src=\"b.png\">"), vec![
+			("c.jpg".to_string(), MediaSyntax::Markdown),
+			("a.mp3".to_string(), MediaSyntax::Sound),
+			("b.png".to_string(), MediaSyntax::Image),
+		]);
+	}
+
+	#[test]
+	fn spans_cover_the_file_name() {
+		let content = "[sound: a.mp3 ]";
+		let reference = &extract_references(content)[0];
+		assert_eq!(&content[reference.span.clone()], "a.mp3");
+	}
+
+	#[test]
+	fn image_sources_are_read_however_theyre_quoted() {
+		assert_eq!(files("<IMG SRC='a.png'>")[0].0, "a.png");
+		assert_eq!(files("<img src = img/b.png/>")[0].0, "img/b.png");
+		assert_eq!(files("<img data-src=\"x.png\" src=c.png>")[0].0, "c.png");
+	}
+
+	#[test]
+	fn markdown_titles_are_left_out() {
+		assert_eq!(files("![alt](a.png \"A title\")")[0].0, "a.png");
+	}
+
+	#[test]
+	fn empty_and_unclosed_references_are_skipped() {
+		assert!(files("[sound:] <img src=\"\"> ![x]( [sound:a.mp3").is_empty());
+	}
+}
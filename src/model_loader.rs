@@ -1,9 +1,129 @@
-use std::{fs, path::{Path, PathBuf}};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::{LazyLock, Mutex}, time::SystemTime};
 
+use semver::VersionReq;
 use tracing::{debug, info, instrument};
 
 use crate::{error::DeckError, types::note::NoteModel};
 
+/// The model schema versions this release understands. Anything newer may
+/// carry settings that would be silently ignored, so it's refused instead.
+pub static SUPPORTED_SCHEMA: LazyLock<VersionReq> =
+	LazyLock::new(|| VersionReq::parse("^1.0").expect("the supported schema range is valid"));
+
+/// The built-in Basic model, for plain question and answer cards without
+/// having to set up a model directory. Its identity is fixed, so notes using it
+/// stay stable across decks and releases.
+const BASIC_CONFIG: &str = r#"
+schema_version = "1.0.0"
+name           = "Basic"
+id             = "2b99bfeb-6961-52b8-af30-ec19b93b3611"
+required       = "Front"
+sort_field     = "Front"
+fields         = [{ name = "Front" }, { name = "Back" }]
+templates      = [{ name = "Card 1" }]
+"#;
+
+const BASIC_FRONT: &str = "{{Front}}";
+const BASIC_BACK: &str = "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}";
+const BASIC_CSS: &str =
+	".card {\n\tfont-family: arial;\n\tfont-size: 20px;\n\ttext-align: center;\n}\n";
+
+/// The built-in image occlusion model, filled in by `@image-occlusion` notes.
+/// Each region is a cloze in the Occlusion field, which Anki's image occlusion
+/// script draws over the image rather than showing as text.
+const IMAGE_OCCLUSION_CONFIG: &str = r#"
+schema_version = "1.0.0"
+name           = "Image Occlusion"
+id             = "d8ecd971-38a7-571d-bdb2-4cdb4f002f63"
+type           = "cloze"
+required       = "Occlusion && Image"
+sort_field     = "Header"
+fields         = [
+	{ name = "Occlusion" },
+	{ name = "Image" },
+	{ name = "Header" },
+	{ name = "Extra" },
+	{ name = "Comments" },
+]
+templates      = [{ name = "Image Occlusion" }]
+"#;
+
+const IMAGE_OCCLUSION_FRONT: &str = r#"{{#Header}}<div>{{Header}}</div>{{/Header}}
+<div style="display: none">{{cloze:Occlusion}}</div>
+<div id="err"></div>
+<div id="image-occlusion-container">
+	{{Image}}
+	<canvas id="image-occlusion-canvas"></canvas>
+</div>
+<script>
+try {
+	anki.imageOcclusion.setup();
+} catch (exc) {
+	document.getElementById("err").innerHTML = `Error loading image occlusion, is Anki up to date?<br><br>${exc}`;
+}
+</script>"#;
+const IMAGE_OCCLUSION_BACK: &str = "{{FrontSide}}\n<div><button id=\"toggle\">Toggle Masks</button></div>\n{{#Extra}}<div>{{Extra}}</div>{{/Extra}}";
+const IMAGE_OCCLUSION_CSS: &str = "#image-occlusion-canvas {\n\t--inactive-shape-color: #ffeba2;\n\t--active-shape-color: #ff8e8e;\n\t--inactive-shape-border: 1px #212121;\n\t--active-shape-border: 1px #212121;\n}\n";
+
+/// The name, size, and modification time of every file in a model directory,
+/// sorted by name. Any file being added, removed, or touched changes it.
+type Fingerprint = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+/// Models already loaded this run, keyed by their directory, each with the
+/// fingerprint of the directory it was loaded from. Reloading a deck (or
+/// loading another that shares a model) reuses them while the directory is
+/// unchanged, rather than reading and parsing every file again.
+static MODEL_CACHE: LazyLock<Mutex<HashMap<PathBuf, (Fingerprint, NoteModel)>>> =
+	LazyLock::new(Mutex::default);
+
+/// Fingerprint a model directory from its entries' metadata alone, without
+/// reading any of them
+fn fingerprint(model_path: &Path) -> Result<Fingerprint, DeckError> {
+	let mut entries = fs::read_dir(model_path)?
+		.map(|entry| {
+			let entry = entry?;
+			let metadata = entry.metadata()?;
+			Ok((entry.path(), metadata.len(), metadata.modified().ok()))
+		})
+		.collect::<Result<Fingerprint, std::io::Error>>()?;
+	entries.sort();
+	Ok(entries)
+}
+
+/// Load the model in `model_path`, from the cache if its directory hasn't
+/// changed since it was last loaded
+fn load_model(model_path: &Path) -> Result<NoteModel, DeckError> {
+	// A directory that can't be listed is left for the load to report
+	let fingerprint = fingerprint(model_path).ok();
+	let cache = MODEL_CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	if let Some(fingerprint) = &fingerprint
+		&& let Some((cached, model)) = cache.get(model_path)
+		&& cached == fingerprint
+	{
+		debug!("Reusing the cached model from {:?}", model_path);
+		return Ok(model.clone());
+	}
+	drop(cache);
+
+	let config_path = model_path.join("config.toml");
+	debug!("Loading model config from {:?}", config_path);
+
+	let config_content = fs::read_to_string(&config_path)
+		.map_err(|_| DeckError::ModelConfigNotFound(config_path.clone()))?;
+	let mut model = parse_model_config(&config_content, &config_path)?;
+	check_schema(&model, &config_path)?;
+
+	// TODO: This path should be more dynamic
+	model.complete(model_path)?;
+
+	if let Some(fingerprint) = fingerprint {
+		let mut cache = MODEL_CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		cache.insert(model_path.to_path_buf(), (fingerprint, model.clone()));
+	}
+
+	Ok(model)
+}
+
 #[instrument]
 pub fn load_models(model_paths: &[PathBuf], deck_path: &Path) -> Result<Vec<NoteModel>, DeckError> {
 	info!("Loading {} models", model_paths.len());
@@ -11,19 +131,233 @@ pub fn load_models(model_paths: &[PathBuf], deck_path: &Path) -> Result<Vec<Note
 	let mut all_models = Vec::new();
 
 	for model_path in model_paths {
-		let config_path = model_path.join("config.toml");
-		debug!("Loading model config from {:?}", config_path);
-
-		let config_content = fs::read_to_string(&config_path)
-			.map_err(|_| DeckError::ModelConfigNotFound(config_path.clone()))?;
-		let mut model: NoteModel = toml::from_str(&config_content)?;
-
-		// TODO: This path should be more dynamic
-		model.complete(model_path)?;
+		let model = load_model(model_path)?;
 
 		info!("Loaded model: {}", model.name);
 		all_models.push(model);
 	}
 
+	// Built-ins fill in for whatever the deck doesn't define itself
+	for builtin in builtin_models()? {
+		if all_models.iter().any(|model| model.name == builtin.name) {
+			debug!("Deck overrides the built-in {} model", builtin.name);
+		} else {
+			all_models.push(builtin);
+		}
+	}
+
 	Ok(all_models)
 }
+
+/// Parse a model's config.toml. Requirements can be written as a `required`
+/// expression, or as a `required_fields` list of fields that must all be
+/// present, which is compiled into the equivalent expression. Keys the model
+/// doesn't know are refused, naming the key.
+fn parse_model_config(content: &str, path: &Path) -> Result<NoteModel, DeckError> {
+	let invalid =
+		|e: toml::de::Error| DeckError::ModelConfig(path.to_path_buf(), e.message().to_string());
+	let mut table: toml::Table = toml::from_str(content).map_err(invalid)?;
+
+	if let Some(fields) = table.remove("required_fields") {
+		if table.contains_key("required") {
+			return Err(DeckError::ConflictingRequirements(path.to_path_buf()));
+		}
+
+		let fields: Vec<String> = fields.try_into().map_err(invalid)?;
+		let expression = if fields.is_empty() { "true".to_string() } else { fields.join(" && ") };
+		debug!("Compiled required_fields into '{}'", expression);
+		table.insert("required".to_string(), toml::Value::String(expression));
+	}
+
+	toml::Value::Table(table).try_into().map_err(invalid)
+}
+
+/// Refuse a model written for a schema this release doesn't support, pointing
+/// at which side has to move
+fn check_schema(model: &NoteModel, path: &Path) -> Result<(), DeckError> {
+	if SUPPORTED_SCHEMA.matches(&model.schema_version) {
+		return Ok(());
+	}
+
+	let newer = SUPPORTED_SCHEMA
+		.comparators
+		.first()
+		.is_some_and(|supported| model.schema_version.major > supported.major);
+	let advice = if newer {
+		"it was written for a newer release of flash, upgrade to load it".to_string()
+	} else {
+		format!("update its schema_version and settings to match {}", *SUPPORTED_SCHEMA)
+	};
+
+	Err(DeckError::UnsupportedSchema(path.to_path_buf(), model.schema_version.to_string(), advice))
+}
+
+/// Models every deck can use without declaring them, a deck defining a model
+/// of the same name replaces the built-in
+pub fn builtin_models() -> Result<Vec<NoteModel>, DeckError> {
+	let mut basic: NoteModel = toml::from_str(BASIC_CONFIG)?;
+	basic.css = BASIC_CSS.to_string();
+	for template in &mut basic.templates {
+		template.question_format = BASIC_FRONT.to_string();
+		template.answer_format = BASIC_BACK.to_string();
+	}
+
+	let mut image_occlusion: NoteModel = toml::from_str(IMAGE_OCCLUSION_CONFIG)?;
+	image_occlusion.css = format!("{}{}", BASIC_CSS, IMAGE_OCCLUSION_CSS);
+	for template in &mut image_occlusion.templates {
+		template.question_format = IMAGE_OCCLUSION_FRONT.to_string();
+		template.answer_format = IMAGE_OCCLUSION_BACK.to_string();
+	}
+
+	Ok(vec![basic, image_occlusion])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::Scratch;
+
+	/// A Basic model of the deck's own, with a third field
+	const OWN_BASIC: &str = r#"
+schema_version = "1.0.0"
+name           = "Basic"
+id             = "6a8c0e2f-4b6d-5f8a-9c1e-3d5f7a9c1e35"
+required       = "Front"
+fields         = [{ name = "Front" }, { name = "Back" }, { name = "Source" }]
+templates      = [{ name = "Card 1", qfmt = "{{Front}}", afmt = "{{Back}}" }]
+"#;
+
+	#[test]
+	fn decks_without_models_get_the_built_ins() {
+		let scratch = Scratch::new();
+		let models = load_models(&[], &scratch.path).expect("the built-ins load");
+
+		let basic = models.iter().find(|model| model.name == "Basic").expect("Basic is built in");
+		assert_eq!(basic.id.to_string(), "2b99bfeb-6961-52b8-af30-ec19b93b3611");
+		assert_eq!(basic.templates[0].question_format, BASIC_FRONT);
+	}
+
+	#[test]
+	fn a_deck_model_replaces_the_built_in_of_its_name() {
+		let scratch = Scratch::new();
+		scratch.write("Basic/config.toml", OWN_BASIC);
+		let models =
+			load_models(&[scratch.path.join("Basic")], &scratch.path).expect("the models load");
+
+		let basics: Vec<&NoteModel> = models.iter().filter(|model| model.name == "Basic").collect();
+		assert_eq!(basics.len(), 1);
+		assert_eq!(basics[0].fields.len(), 3);
+	}
+
+	/// OWN_BASIC with its requirement written as `requirement` instead
+	fn required_by(requirement: &str) -> Result<NoteModel, DeckError> {
+		let content = OWN_BASIC.replace("required       = \"Front\"", requirement);
+		parse_model_config(&content, Path::new("Basic/config.toml"))
+	}
+
+	#[test]
+	fn required_fields_compile_into_an_expression() {
+		let listed = required_by("required_fields = [\"Front\", \"Back\"]").expect("the model parses");
+		let written = required_by("required = \"Front && Back\"").expect("the model parses");
+		assert_eq!(listed.required, written.required);
+
+		let none = required_by("required_fields = []").expect("the model parses");
+		assert_eq!(
+			none.required,
+			required_by("required = \"true\"").expect("the model parses").required
+		);
+	}
+
+	#[test]
+	fn required_fields_and_required_conflict() {
+		let result = required_by("required = \"Front\"\nrequired_fields = [\"Front\"]");
+		assert!(matches!(result, Err(DeckError::ConflictingRequirements(_))));
+	}
+
+	/// The advice given for OWN_BASIC written at `version`, if it's refused
+	fn schema_advice(version: &str) -> Option<String> {
+		let content = OWN_BASIC.replace("\"1.0.0\"", &format!("\"{}\"", version));
+		let path = Path::new("Basic/config.toml");
+		let model = parse_model_config(&content, path).expect("the model parses");
+
+		match check_schema(&model, path) {
+			Ok(()) => None,
+			Err(DeckError::UnsupportedSchema(_, _, advice)) => Some(advice),
+			Err(e) => panic!("unexpected error {}", e),
+		}
+	}
+
+	#[test]
+	fn supported_schemas_load() {
+		assert_eq!(schema_advice("1.0.0"), None);
+		assert_eq!(schema_advice("1.4.2"), None);
+	}
+
+	#[test]
+	fn unsupported_schemas_say_which_side_has_to_move() {
+		assert!(schema_advice("2.0.0").is_some_and(|advice| advice.contains("upgrade")));
+		assert!(
+			schema_advice("0.9.0").is_some_and(|advice| advice.contains("update its schema_version"))
+		);
+	}
+
+	#[test]
+	fn unknown_keys_are_refused_by_name() {
+		let result = required_by("required = \"Front\"\nsort_feild = \"Front\"");
+		let Err(DeckError::ModelConfig(path, message)) = result else {
+			panic!("the misspelt key is refused, got {:?}", result);
+		};
+		assert_eq!(path, Path::new("Basic/config.toml"));
+		assert!(message.contains("sort_feild"), "{}", message);
+
+		let content = OWN_BASIC.replace("afmt = ", "bfmt = \"\", afmt = ");
+		let nested = parse_model_config(&content, Path::new("Basic/config.toml"));
+		assert!(matches!(nested, Err(DeckError::ModelConfig(_, message)) if message.contains("bfmt")));
+	}
+
+	#[test]
+	fn unchanged_model_directories_are_loaded_from_the_cache() {
+		let scratch = Scratch::new();
+		scratch.write("Basic/config.toml", OWN_BASIC);
+		let model_path = scratch.path.join("Basic");
+		load_model(&model_path).expect("the model loads");
+
+		// Marking the cached copy shows whether the directory was read again
+		let mut cache = MODEL_CACHE.lock().expect("the cache is intact");
+		cache.get_mut(&model_path).expect("the model is cached").1.name = "Cached".to_string();
+		drop(cache);
+		assert_eq!(load_model(&model_path).expect("the model loads").name, "Cached");
+
+		scratch.write("Basic/config.toml", OWN_BASIC.replace("\"Basic\"", "\"Renamed\""));
+		assert_eq!(load_model(&model_path).expect("the model loads").name, "Renamed");
+	}
+
+	/// Load OWN_BASIC written as `config`, alongside any other `files`
+	fn load_own_basic(config: &str, files: &[&str]) -> Result<NoteModel, DeckError> {
+		let scratch = Scratch::new();
+		scratch.write("Basic/config.toml", config);
+		for file in files {
+			scratch.write(&format!("Basic/{}", file), "{{Front}}");
+		}
+		load_model(&scratch.path.join("Basic"))
+	}
+
+	#[test]
+	fn templates_can_be_given_inline() {
+		let model = load_own_basic(OWN_BASIC, &[]).expect("the model loads");
+		assert_eq!(model.templates[0].question_format, "{{Front}}");
+		assert_eq!(model.templates[0].answer_format, "{{Back}}");
+	}
+
+	#[test]
+	fn inline_templates_stand_alone_and_complete() {
+		let mixed = load_own_basic(OWN_BASIC, &["Card 1.front.hbs"]);
+		assert!(matches!(mixed, Err(DeckError::MixedTemplates(_))));
+
+		let incomplete = load_own_basic(&OWN_BASIC.replace(", afmt = \"{{Back}}\"", ""), &[]);
+		let Err(DeckError::IncompleteTemplate(_, name)) = incomplete else {
+			panic!("the template is refused, got {:?}", incomplete);
+		};
+		assert_eq!(name, "Card 1");
+	}
+}
@@ -1,22 +1,99 @@
-use std::{borrow::Cow, collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}};
+use std::{borrow::Cow, collections::{BTreeMap, HashMap, HashSet}, fs, ops::Range, path::{Path, PathBuf}};
 
+use ariadne::{Label, Report, ReportKind, Source};
 use chumsky::{input::ValueInput, prelude::*};
-use evalexpr::{DefaultNumericTypes, HashMapContext, Value, eval_empty_with_context_mut};
+use evalexpr::{ContextWithMutableVariables, DefaultNumericTypes, HashMapContext, Value};
 use logos::Logos;
 
-use crate::types::note::{Cloze, Note, NoteField, NoteModel, TextElement};
+use crate::types::{crowd_anki_config::NoteSeparator, note::{Cloze, ClozeReveal, Note, NoteField, NoteModel, TextElement}};
 
-/// Preprocessor that expands import statements recursively
+const BOM: char = '\u{feff}';
+
+/// Decode a source file, which must be UTF-8. The byte order mark some editors
+/// prepend is dropped, otherwise it ends up glued to the first declaration.
+pub fn decode_source(bytes: Vec<u8>) -> Result<String, String> {
+	// UTF-16 announces itself through its byte order mark
+	if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) {
+		return Err("the file is UTF-16 encoded, re-save it as UTF-8".to_string());
+	}
+
+	let content = String::from_utf8(bytes)
+		.map_err(|e| format!("{}, re-save the file as UTF-8", e.utf8_error()))?;
+
+	Ok(content.strip_prefix(BOM).map(str::to_string).unwrap_or(content))
+}
+
+/// Preprocessor that expands import statements recursively.
+///
+/// With the `remote-imports` feature, `import https://...` pulls in content
+/// published elsewhere. Notes take their identity from their content, so
+/// anything changed upstream is seen as an edit (or a new note) on the next
+/// export, and remote decks that churn will churn the UUIDs with them. Pin to
+/// a versioned URL where that matters.
+#[cfg_attr(not(feature = "remote-imports"), allow(dead_code))]
 pub struct ImportExpander {
-	/// Track visited files to prevent circular imports
-	visited:  HashSet<PathBuf>,
+	/// Track visited files (by canonical path) and remote imports (by URL) to
+	/// prevent circular imports
+	visited:   HashSet<String>,
 	/// Base directory for resolving relative imports
-	base_dir: PathBuf,
+	base_dir:  PathBuf,
+	/// Remote content already fetched this run, keyed by URL
+	fetched:   HashMap<String, String>,
+	/// Where fetched remote content is kept between runs
+	cache_dir: Option<PathBuf>,
+	/// Only serve remote imports from the cache, never touching the network
+	offline:   bool,
+	/// Wrap each import in a file tag named after it, see `tag_imports`
+	tag:       bool,
+}
+
+/// Where a piece of source came from, which decides how its own relative
+/// imports resolve
+#[derive(Clone, Copy)]
+enum Origin<'a> {
+	File(&'a Path),
+	Remote(&'a str),
 }
 
+/// The tag an import is known by, its file name without the extension.
+/// Whitespace would split it into several tags in Anki, so it's replaced.
+fn import_tag(import_path: &str) -> Option<String> {
+	let stem = Path::new(import_path.trim_end_matches('/')).file_stem()?.to_string_lossy();
+	Some(stem.split_whitespace().collect::<Vec<_>>().join("_")).filter(|tag| !tag.is_empty())
+}
+
+fn is_remote(path: &str) -> bool { path.starts_with("https://") || path.starts_with("http://") }
+
 impl ImportExpander {
 	pub fn new(base_dir: impl AsRef<Path>) -> Self {
-		Self { visited: HashSet::new(), base_dir: base_dir.as_ref().to_path_buf() }
+		Self {
+			visited:   HashSet::new(),
+			base_dir:  base_dir.as_ref().to_path_buf(),
+			fetched:   HashMap::new(),
+			cache_dir: None,
+			offline:   false,
+			tag:       false,
+		}
+	}
+
+	/// Keep fetched remote imports in `dir`, so they're available offline
+	pub fn with_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.cache_dir = Some(dir.into());
+		self
+	}
+
+	/// Serve remote imports from the cache alone, failing on anything missing
+	pub fn offline(mut self, offline: bool) -> Self {
+		self.offline = offline;
+		self
+	}
+
+	/// Tag every note an import brings in with the name of the file it came
+	/// from, `import grammar.flash` tagging its notes `grammar`. Nested imports
+	/// carry the tags of each file they pass through.
+	pub fn tag_imports(mut self, tag: bool) -> Self {
+		self.tag = tag;
+		self
 	}
 
 	/// Expands all imports in the given content recursively
@@ -26,8 +103,12 @@ impl ImportExpander {
 			.canonicalize()
 			.map_err(|e| format!("Cannot resolve path {}: {}", current_file.display(), e))?;
 
-		if !self.visited.insert(canonical.clone()) {
-			return Err(format!("Circular import detected: {}", current_file.display()));
+		self.expand_from(content, canonical.to_string_lossy().to_string(), Origin::File(current_file))
+	}
+
+	fn expand_from(&mut self, content: &str, key: String, origin: Origin) -> Result<String, String> {
+		if !self.visited.insert(key.clone()) {
+			return Err(format!("Circular import detected: {}", key));
 		}
 
 		let mut result = String::new();
@@ -39,15 +120,37 @@ impl ImportExpander {
 			if let Some(import_path) = trimmed.strip_prefix("import ") {
 				let import_path = import_path.trim();
 
-				// Resolve relative to current file's directory
-				let import_file = current_file.parent().unwrap_or(&self.base_dir).join(import_path);
-
-				// Read and recursively expand the imported file
-				let imported_content = fs::read_to_string(&import_file)
-					.map_err(|e| format!("Cannot read import {}: {}", import_file.display(), e))?;
-
-				let expanded = self.expand(&imported_content, &import_file)?;
-				result.push_str(&expanded);
+				let expanded = match origin {
+					_ if is_remote(import_path) => self.expand_remote(import_path.to_string())?,
+					// Relative imports within remote content are relative to its URL
+					Origin::Remote(url) => {
+						let base = &url[..url.rfind('/').map_or(url.len(), |idx| idx + 1)];
+						self.expand_remote(format!("{}{}", base, import_path))?
+					}
+					Origin::File(current_file) => {
+						// Resolve relative to current file's directory
+						let import_file = current_file.parent().unwrap_or(&self.base_dir).join(import_path);
+
+						// Read and recursively expand the imported file
+						let imported_content = fs::read(&import_file)
+							.map_err(|e| format!("Cannot read import {}: {}", import_file.display(), e))
+							.and_then(|bytes| {
+								decode_source(bytes)
+									.map_err(|e| format!("Cannot decode import {}: {}", import_file.display(), e))
+							})?;
+
+						self.expand(&imported_content, &import_file)?
+					}
+				};
+				// The file boundary is lost once expanded, so it's marked for the parser
+				match import_tag(import_path).filter(|_| self.tag) {
+					Some(tag) => {
+						result.push_str(&format!("@file-tag {}\n", tag));
+						result.push_str(&expanded);
+						result.push_str("@end-file-tag\n");
+					}
+					None => result.push_str(&expanded),
+				}
 
 				// Add a blank line to separate imported content
 				if !expanded.ends_with("\n\n") {
@@ -61,33 +164,198 @@ impl ImportExpander {
 		}
 
 		// Remove from visited when done
-		self.visited.remove(&canonical);
+		self.visited.remove(&key);
 
 		Ok(result)
 	}
+
+	fn expand_remote(&mut self, url: String) -> Result<String, String> {
+		let content = self.fetch(&url)?;
+		self.expand_from(&content, url.clone(), Origin::Remote(&url))
+	}
+
+	#[cfg(not(feature = "remote-imports"))]
+	fn fetch(&mut self, url: &str) -> Result<String, String> {
+		Err(format!("Cannot import {}: remote imports need the `remote-imports` feature", url))
+	}
+
+	/// Fetch remote content, from memory, the cache, or the network in that order
+	#[cfg(feature = "remote-imports")]
+	fn fetch(&mut self, url: &str) -> Result<String, String> {
+		if let Some(content) = self.fetched.get(url) {
+			return Ok(content.clone());
+		}
+
+		// URLs don't make for valid file names, so the cache is keyed by a hash of them
+		let cached = self.cache_dir.as_ref().map(|dir| {
+			dir.join(format!("{}.flash", uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, url.as_bytes())))
+		});
+
+		let bytes = if self.offline {
+			let cached = cached
+				.ok_or_else(|| format!("Cannot import {} while offline: no cache is configured", url))?;
+			fs::read(&cached)
+				.map_err(|_| format!("Cannot import {} while offline: it isn't cached", url))?
+		} else {
+			let bytes = ureq::get(url)
+				.call()
+				.and_then(|mut response| response.body_mut().read_to_vec())
+				.map_err(|e| format!("Cannot fetch import {}: {}", url, e))?;
+
+			// A failed cache write only costs us the offline copy
+			if let Some(cached) = &cached
+				&& let Err(e) = fs::create_dir_all(cached.parent().unwrap_or(Path::new(".")))
+					.and_then(|_| fs::write(cached, &bytes))
+			{
+				tracing::warn!("Failed to cache import {}: {}", url, e);
+			}
+			bytes
+		};
+
+		let content =
+			decode_source(bytes).map_err(|e| format!("Cannot decode import {}: {}", url, e))?;
+		self.fetched.insert(url.to_string(), content.clone());
+		Ok(content)
+	}
 }
 
 type Span = SimpleSpan;
 
+/// Run the lexer alone, pairing each token with the byte range it covers.
+/// Anything the lexer can't make sense of comes through as `Token::Error`.
+pub fn lex(content: &str) -> Vec<(Token<'_>, Range<usize>)> {
+	let mut tokens = Vec::new();
+	lex_into(content, 0, &mut tokens);
+	tokens
+}
+
+/// Directives only mean anything at the start of a line. Elsewhere their name
+/// is text, and the rest of the line is lexed as it would be without it.
+fn lex_into<'a>(content: &'a str, offset: usize, tokens: &mut Vec<(Token<'a>, Range<usize>)>) {
+	for (token, span) in Token::lexer(content).spanned() {
+		let token = token.unwrap_or(Token::Error);
+		let line_start = tokens
+			.iter()
+			.rev()
+			.find(|(token, _)| !matches!(token, Token::WS(_)))
+			.is_none_or(|(token, _)| matches!(token, Token::Newline));
+
+		if token.is_directive() && !line_start {
+			let slice = &content[span.clone()];
+			let name = slice.find([' ', '\t']).unwrap_or(slice.len());
+			let start = offset + span.start;
+			tokens.push((Token::Text(&slice[..name]), start..start + name));
+			lex_into(&slice[name..], start + name, tokens);
+		} else {
+			tokens.push((token, offset + span.start..offset + span.end));
+		}
+	}
+}
+
+/// A tag applied to every note starting within a span of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileTag {
+	pub tag:  String,
+	pub span: Range<usize>,
+}
+
+/// Take the file tag directives out of the tokens, resolving them into the
+/// spans each covers. A tag left open runs to the end of the source. Note
+/// count expectations are dropped too, they're only read by `expected_notes`.
+pub fn extract_file_tags<'a>(
+	tokens: Vec<(Token<'a>, Range<usize>)>,
+	end: usize,
+) -> (Vec<(Token<'a>, Range<usize>)>, Vec<FileTag>) {
+	let mut remaining = Vec::with_capacity(tokens.len());
+	let mut open: Vec<(String, usize)> = Vec::new();
+	let mut file_tags = Vec::new();
+
+	for (token, span) in tokens {
+		match token {
+			Token::FileTag(tag) => open.push((tag.to_string(), span.end)),
+			Token::ExpectNotes(_) => {}
+			Token::EndFileTag => {
+				if let Some((tag, start)) = open.pop() {
+					file_tags.push(FileTag { tag, span: start..span.start });
+				}
+			}
+			token => remaining.push((token, span)),
+		}
+	}
+
+	file_tags.extend(open.into_iter().map(|(tag, start)| FileTag { tag, span: start..end }));
+	(remaining, file_tags)
+}
+
+/// The number of notes the source declares it holds through `@expect-notes`,
+/// a guard against an edit silently dropping a swathe of them. Only the first
+/// such directive counts.
+pub fn expected_notes(content: &str) -> Result<Option<usize>, String> {
+	let Some(count) = lex(content).into_iter().find_map(|(token, _)| match token {
+		Token::ExpectNotes(count) => Some(count),
+		_ => None,
+	}) else {
+		return Ok(None);
+	};
+
+	count
+		.parse()
+		.map(Some)
+		.map_err(|_| format!("@expect-notes expects a number of notes, found '{}'", count))
+}
+
 use std::fmt;
 
+impl Token<'_> {
+	/// Whether the token is a directive, which only counts at the start of a line
+	pub fn is_directive(&self) -> bool {
+		matches!(
+			self,
+			Self::ModelOverride(_)
+				| Self::ImageOcclusion(_)
+				| Self::Region(_)
+				| Self::FileTag(_)
+				| Self::EndFileTag
+				| Self::Data(_)
+				| Self::Class(_)
+				| Self::ClozeMode(_)
+				| Self::ExpectNotes(_)
+		)
+	}
+}
+
 impl<'a> fmt::Display for Token<'a> {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Self::Eq => write!(f, "="),
+			Self::Arrow => write!(f, "=>"),
 			Self::Colon => write!(f, ":"),
 			Self::LBracket => write!(f, "["),
 			Self::RBracket => write!(f, "]"),
 			Self::LBrace => write!(f, "{{"),
 			Self::RBrace => write!(f, "}}"),
 			Self::Pipe => write!(f, "|"),
+			Self::EscapedPipe => write!(f, "\\|"),
+			Self::LineBreak => write!(f, "\\n"),
 			Self::Comma => write!(f, ","),
 			Self::Alias => write!(f, "alias"),
 			Self::To => write!(f, "to"),
+			Self::Media => write!(f, "media"),
 			Self::Newline => write!(f, "\\n"),
 			Self::WS(s) => write!(f, "{}", s),
 			Self::Text(s) => write!(f, "{}", s),
+			Self::HashTag(s) => write!(f, "{}", s),
 			Self::Comment(s) => write!(f, "{}", s),
+			Self::Html(s) => write!(f, "@html {}", s),
+			Self::ModelOverride(s) => write!(f, "@model {}", s),
+			Self::ImageOcclusion(s) => write!(f, "@image-occlusion {}", s),
+			Self::Region(s) => write!(f, "@region {}", s),
+			Self::Data(s) => write!(f, "@data {}", s),
+			Self::Class(s) => write!(f, "@class {}", s),
+			Self::ClozeMode(s) => write!(f, "@cloze {}", s),
+			Self::FileTag(s) => write!(f, "@file-tag {}", s),
+			Self::ExpectNotes(s) => write!(f, "@expect-notes {}", s),
+			Self::EndFileTag => write!(f, "@end-file-tag"),
 			Self::Error => write!(f, "<parse error>"),
 		}
 	}
@@ -98,6 +366,10 @@ pub enum Token<'a> {
 	#[token("=")]
 	Eq,
 
+	// Between the sides of a `Front => Back` note
+	#[token("=>")]
+	Arrow,
+
 	#[token(":")]
 	Colon,
 
@@ -116,6 +388,10 @@ pub enum Token<'a> {
 	#[token("|")]
 	Pipe,
 
+	// A literal pipe, which would otherwise separate a cloze's answer and hints
+	#[token(r"\|")]
+	EscapedPipe,
+
 	#[token(",")]
 	Comma,
 
@@ -125,18 +401,72 @@ pub enum Token<'a> {
 	#[token("to", priority = 5)]
 	To,
 
+	#[token("media", priority = 5)]
+	Media,
+
 	#[token("\n")]
 	Newline,
 
 	#[regex(r"[ \t]+")]
 	WS(&'a str),
 
-	#[regex(r"[^ \t\n:=\[\]{},|]+", priority = 4)]
+	// An explicit line break within a single line of content
+	#[token(r"\n")]
+	LineBreak,
+
+	// Backslashes are only kept out of text when they escape a pipe or break a
+	// line
+	#[regex(r"([^ \t\n:=\[\]{},|\\]|\\[^|n\n])+", priority = 4)]
+	#[regex(r"\\", priority = 4)]
 	Text(&'a str),
 
 	#[regex(r"//[^\n]*", allow_greedy = true, priority = 3)]
 	Comment(&'a str),
 
+	// A tag written `#tag`, anywhere other than a line of tags it's plain text
+	#[regex(r"#([^ \t\n:=\[\]{},|\\#])+", priority = 5)]
+	HashTag(&'a str),
+
+	// The rest of the line is raw markup, structural characters and all
+	#[regex(r"@html([ \t][^\n]*)?", |lex| lex.slice()["@html".len()..].trim(), allow_greedy = true, priority = 5)]
+	Html(&'a str),
+
+	// Puts a single note under a different model to the rest of its section
+	#[regex(r"@model[ \t][^\n]*", |lex| lex.slice()["@model".len()..].trim(), allow_greedy = true, priority = 5)]
+	ModelOverride(&'a str),
+
+	// The image an image occlusion note is drawn over, its regions follow
+	#[regex(r"@image-occlusion[ \t][^\n]*", |lex| lex.slice()["@image-occlusion".len()..].trim(), allow_greedy = true, priority = 5)]
+	ImageOcclusion(&'a str),
+
+	// A single region of an image occlusion note, see `image_occlusion`
+	#[regex(r"@region[ \t][^\n]*", |lex| lex.slice()["@region".len()..].trim(), allow_greedy = true, priority = 5)]
+	Region(&'a str),
+
+	// A key=value pair of free-form data for the note, see `data_directive`
+	#[regex(r"@data[ \t][^\n]*", |lex| lex.slice()["@data".len()..].trim(), allow_greedy = true, priority = 5)]
+	Data(&'a str),
+
+	// Classes for a single note, see `class_directive`
+	#[regex(r"@class[ \t][^\n]*", |lex| lex.slice()["@class".len()..].trim(), allow_greedy = true, priority = 5)]
+	Class(&'a str),
+
+	// How a note's clozes are split into cards, see `cloze_directive`
+	#[regex(r"@cloze[ \t][^\n]*", |lex| lex.slice()["@cloze".len()..].trim(), allow_greedy = true, priority = 5)]
+	ClozeMode(&'a str),
+
+	// How many notes the deck is expected to hold, checked by `flash check`
+	#[regex(r"@expect-notes[ \t][^\n]*", |lex| lex.slice()["@expect-notes".len()..].trim(), allow_greedy = true, priority = 5)]
+	ExpectNotes(&'a str),
+
+	// Tags every note up to the matching end with the given tag, placed around
+	// imported content by the import expander
+	#[regex(r"@file-tag[ \t][^\n]*", |lex| lex.slice()["@file-tag".len()..].trim(), allow_greedy = true, priority = 5)]
+	FileTag(&'a str),
+
+	#[token("@end-file-tag", priority = 5)]
+	EndFileTag,
+
 	Error,
 }
 
@@ -166,6 +496,74 @@ where
 	select! { Token::WS(_) => () }.labelled("whitespace")
 }
 
+/// Extract whitespace that isn't a comment, which may carry meaning
+fn blank<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, (), extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! {
+		Token::Newline => (),
+		Token::WS(_) => (),
+	}
+	.labelled("newline or whitespace")
+}
+
+/// A line of just `---`, which ends a note when notes are separated by rules
+fn rule<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, (), extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! { Token::Text("---") => () }
+		.then_ignore(ws().repeated())
+		.then_ignore(just(Token::Newline).ignored().or(end()).rewind())
+		.labelled("---")
+}
+
+/// What comes between two notes. With blank line separators a comment can
+/// stand in for the blank line, with rules there may be blank lines either
+/// side of it.
+fn note_separator<'tokens, 'src: 'tokens, I>(
+	separator: NoteSeparator,
+) -> impl Parser<'tokens, I, (), extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let blank_line = blank().repeated().at_least(1).ignored().or(comment().rewind().ignored());
+	let ruled = blank().repeated().then(rule()).then(blank().repeated()).ignored();
+
+	// Both are built so they share a type, only the configured one ever matches
+	empty()
+		.filter(move |_| separator == NoteSeparator::Rule)
+		.ignore_then(ruled)
+		.or(empty().filter(move |_| separator == NoteSeparator::Blank).ignore_then(blank_line))
+}
+
+/// Where a broken note is skipped up to, so the next can be parsed: the blank
+/// line or rule ending it
+fn note_end<'tokens, 'src: 'tokens, I>(
+	separator: NoteSeparator,
+) -> impl Parser<'tokens, I, (), extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	blank()
+		.repeated()
+		.at_least(1)
+		.filter(move |_| separator == NoteSeparator::Blank)
+		.or(blank().repeated().then(rule()).filter(move |_| separator == NoteSeparator::Rule).ignored())
+}
+
+/// Extract the text of a comment, without its leading slashes
+fn comment<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, String, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! { Token::Comment(s) => s.trim_start_matches('/').trim().to_string() }.labelled("comment")
+}
+
 fn text<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, &'src str, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
@@ -176,6 +574,22 @@ where
 	}
 }
 
+/// A field's name. Keywords are only keywords where they begin a declaration, a
+/// field may well be called `media`.
+fn field_name<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, &'src str, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! {
+		Token::Text(s) => s,
+		Token::Media => "media",
+		Token::Alias => "alias",
+		Token::To => "to",
+	}
+	.labelled("field name")
+}
+
 /// Parse model declaration: = Model Name =
 fn model_declaration<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, String, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
@@ -184,6 +598,7 @@ where
 {
 	let model_name_parts = select! {
 		Token::Text(s) => s,
+		Token::HashTag(s) => s,
 		Token::WS(s) => s,
 	};
 
@@ -210,6 +625,38 @@ where
 		.labelled("alias declaration")
 }
 
+/// Parse a line of hash tags: #tag1 #tag2 #tag3
+fn hash_tags<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Vec<String>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! { Token::HashTag(s) => s[1..].to_string() }
+		.separated_by(ws().repeated().at_least(1))
+		.at_least(1)
+		.collect()
+		.then_ignore(ws().repeated())
+		.then_ignore(noise())
+		.labelled("hash tags")
+}
+
+/// Hash tags follow on from any bracketed tags, each tag kept once
+fn merge_tags(
+	(tags, hash_tags): (Option<Vec<String>>, Option<Vec<String>>),
+) -> Option<Vec<String>> {
+	match (tags, hash_tags) {
+		(Some(mut tags), Some(hash_tags)) => {
+			for tag in hash_tags {
+				if !tags.contains(&tag) {
+					tags.push(tag);
+				}
+			}
+			Some(tags)
+		}
+		(tags, hash_tags) => tags.or(hash_tags),
+	}
+}
+
 /// Parse tags: [tag1, tag2, tag3]
 fn tags_declaration<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, Vec<String>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
@@ -218,9 +665,14 @@ where
 {
 	let tag_chars = select! {
 		Token::Text(s) => s,
+		Token::HashTag(s) => s,
 		Token::WS(s) => s,
 		Token::Alias => "alias",
 		Token::To => "to",
+		Token::Media => "media",
+		// Braces reference a field, substituted once the note's fields are known
+		Token::LBrace => "{",
+		Token::RBrace => "}",
 	};
 
 	let single_tag = tag_chars
@@ -238,7 +690,48 @@ where
 		.labelled("tags")
 }
 
-/// Parse cloze: {Answer|Hint}
+/// Parse media: media [audio.mp3, diagram.png]
+fn media_declaration<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Vec<PathBuf>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let path_chars = select! {
+		Token::Text(s) => s,
+		Token::HashTag(s) => s,
+		Token::WS(s) => s,
+		Token::Alias => "alias",
+		Token::To => "to",
+		Token::Media => "media",
+	};
+
+	let single_path = path_chars
+		.repeated()
+		.at_least(1)
+		.collect::<Vec<&str>>()
+		.map(|parts| PathBuf::from(parts.concat().trim()));
+
+	just(Token::Media)
+		.ignore_then(ws().repeated())
+		.ignore_then(
+			single_path
+				.separated_by(just(Token::Comma))
+				.allow_trailing()
+				.collect()
+				.delimited_by(just(Token::LBracket), just(Token::RBracket)),
+		)
+		.then_ignore(noise())
+		.labelled("media")
+}
+
+/// Parse cloze: {Answer|Hint|Another hint}, where \| is a literal pipe.
+///
+/// Anki's own syntax is accepted too, so existing cloze text can be pasted in:
+/// `{c1::Answer}` or `{1::Answer}` numbers the cloze, optionally inside
+/// doubled braces and with a `::Hint` after the answer. Shorthand clozes are
+/// left unnumbered (0), and the two can be mixed within a field. A number may
+/// be given to any number of clozes, each is exported as a span of its own and
+/// Anki hides every span sharing a number together on one card.
 fn cloze<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, TextElement, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
@@ -246,42 +739,136 @@ where
 {
 	let cloze_chars = select! {
 		Token::Text(s) => s,
+		Token::HashTag(s) => s,
 		Token::WS(s) => s,
 		Token::Alias => "alias",
 		Token::To => "to",
+		Token::Media => "media",
+		Token::EscapedPipe => "|",
+		Token::LineBreak => "<br>",
 		Token::Comma => ",",
 		Token::Colon => ":",
+		// Image occlusion regions are written back as `left=0.1000:top=...`
+		Token::Eq => "=",
+		Token::Arrow => "=>",
 	};
 
 	let cloze_part = cloze_chars.repeated().at_least(1).collect::<Vec<&str>>().map(|v| v.concat());
 
-	let hint = just(Token::Pipe).ignore_then(cloze_part).map(|s| s.trim().to_string()).or_not();
+	// Any number of hints may follow the answer, each introduced by a pipe
+	let hints = just(Token::Pipe)
+		.ignore_then(cloze_part)
+		.map(|s| s.trim().to_string())
+		.repeated()
+		.collect::<Vec<String>>();
+
+	// `c1::` or `1::`, Anki numbers clozes from one
+	let ordinal = ws()
+		.repeated()
+		.ignore_then(select! { Token::Text(s) => s })
+		.try_map(|s: &str, span| {
+			s.strip_prefix('c')
+				.unwrap_or(s)
+				.parse::<u32>()
+				.ok()
+				.filter(|id| *id > 0)
+				.ok_or_else(|| Rich::custom(span, format!("'{}' isn't a cloze number", s)))
+		})
+		.then_ignore(just(Token::Colon))
+		.then_ignore(just(Token::Colon));
+
+	let body = ordinal.or_not().then(cloze_part.map(|s| s.trim().to_string())).then(hints);
+
+	let doubled = body.clone().delimited_by(
+		just(Token::LBrace).then(just(Token::LBrace)),
+		just(Token::RBrace).then(just(Token::RBrace)),
+	);
+	let single = body.delimited_by(just(Token::LBrace), just(Token::RBrace));
+
+	doubled
+		.or(single)
+		.map(|((id, answer), mut hint)| {
+			// A numbered cloze follows Anki in giving its hint after a double colon
+			let answer = match id.and(answer.split_once("::")) {
+				Some((answer, anki_hint)) => {
+					hint.insert(0, anki_hint.trim().to_string());
+					answer.trim().to_string()
+				}
+				None => answer,
+			};
 
-	just(Token::LBrace)
-		.ignore_then(cloze_part.map(|s| s.trim().to_string()))
-		.then(hint)
-		.then_ignore(just(Token::RBrace))
-		.map(|(answer, hint)| TextElement::Cloze(Cloze { id: 0, answer, hint }))
+			TextElement::Cloze(Cloze { id: id.unwrap_or(0), answer, hint })
+		})
 		.labelled("cloze")
 }
 
-/// Parse field content (text and clozes)
+/// Macros a field may contain, written `{{@commit}}` or `{{@date}}`
+pub const FIELD_MACROS: [&str; 2] = ["@commit", "@date"];
+
+/// Parse a field macro, kept as written so the note is identified by the macro
+/// rather than whatever it expands to. See `Deck::expand_macros`
+fn field_macro<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, TextElement, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! { Token::Text(s) if FIELD_MACROS.contains(&s) => s }
+		.map(|name| TextElement::Text(format!("{{{{{}}}}}", name)))
+		.delimited_by(
+			just(Token::LBrace).then(just(Token::LBrace)),
+			just(Token::RBrace).then(just(Token::RBrace)),
+		)
+		.labelled("macro")
+}
+
+/// Parse field content (text and clozes).
+///
+/// Whitespace inside a cloze's braces is part of the syntax and trimmed from
+/// the answer and each hint, so `{ bar }` and `{bar}` are the same cloze.
+/// Whitespace outside the braces is content, kept exactly as written
+/// (`foo {bar} baz` keeps both spaces) except at the edges of the field, where
+/// it's dropped wherever the field starts or ends with a cloze or text.
 fn field_content<'tokens, 'src: 'tokens, I>()
 -> impl Parser<'tokens, I, Vec<TextElement>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
 	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
 {
+	content_parts(false)
+}
+
+/// Field content up to the first `=>`, the front of a `Front => Back` note
+fn front_content<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Vec<TextElement>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	content_parts(true)
+}
+
+/// Field content, where `=>` is text like any other unless `stop_at_arrow`
+fn content_parts<'tokens, 'src: 'tokens, I>(
+	stop_at_arrow: bool,
+) -> impl Parser<'tokens, I, Vec<TextElement>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let arrow = select! { Token::Arrow => "=>" }.filter(move |_| !stop_at_arrow);
 	let text_chars = select! {
 		Token::Text(s) => s,
+		Token::HashTag(s) => s,
 		Token::WS(s) => s,
 		Token::Alias => "alias",
 		Token::To => "to",
+		Token::Media => "media",
+		Token::EscapedPipe => "|",
+		Token::LineBreak => "<br>",
 		Token::Comma => ",",
 		Token::Eq => "=",
 		Token::LBracket => "[",
 		Token::RBracket => "]",
 		Token::Colon => ":",
-	};
+	}
+	.or(arrow);
 
 	// Collect consecutive text tokens into a Vec, then join into a single string
 	let merged_text = text_chars
@@ -290,35 +877,223 @@ where
 		.collect::<Vec<_>>()
 		.map(|parts| TextElement::Text(parts.join("")));
 
-	let content_element = cloze().or(merged_text);
+	let raw_html = select! { Token::Html(s) => TextElement::Html(s.to_string()) };
+
+	let content_element = raw_html.or(field_macro()).or(cloze()).or(merged_text);
 
 	content_element.repeated().collect()
 }
 
 /// Parse field: Name: Content
-fn field_declaration<'tokens, 'src: 'tokens, I>()
--> impl Parser<'tokens, I, NoteField, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+///
+/// When notes are separated by rules, a field runs on over the lines after it
+/// up to the next field or rule, each line break kept as one, so a blank line
+/// between paragraphs is two. A line reading like `Name: ...` starts a field
+/// of its own rather than continuing the last.
+fn field_declaration<'tokens, 'src: 'tokens, I>(
+	separator: NoteSeparator,
+) -> impl Parser<'tokens, I, NoteField, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
 	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
 {
-	text()
+	let field_start = field_name().then(just(Token::Colon)).ignored();
+	let continuation = just(Token::Newline)
+		.then_ignore(ws().repeated())
+		.repeated()
+		.at_least(1)
+		.count()
+		.then(field_content().and_is(field_start.or(rule()).not()))
+		.filter(move |(_, content)| separator == NoteSeparator::Rule && !content.is_empty());
+
+	let content =
+		field_content().then(continuation.repeated().collect::<Vec<_>>()).map(|(first, rest)| {
+			let mut content = trim_content(first);
+			for (breaks, more) in rest {
+				content.push(TextElement::Text("<br>".repeat(breaks)));
+				content.extend(trim_content(more));
+			}
+			content
+		});
+
+	// Fields of a ruled note may have blank lines between them
+	let between = blank().repeated().filter(move |_| separator == NoteSeparator::Rule).or(noise());
+
+	field_name()
 		.map(|s| s.to_string())
 		.then_ignore(just(Token::Colon))
 		.then_ignore(ws().repeated())
-		.then(field_content())
-		.map(|(name, content)| NoteField { name, content })
-		.then_ignore(noise())
+		.then(content)
+		.map(|(name, content)| NoteField { name, content: trim_content(content) })
+		.then_ignore(between)
 		.labelled("field")
 }
 
+/// Parse a row of cells, `| word | translation |`, each filling the model's
+/// fields in order. The names are left empty until the model is known.
+fn field_row<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Vec<NoteField>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let cell = field_content()
+		.then_ignore(just(Token::Pipe))
+		.map(|content| NoteField { name: String::new(), content: trim_content(content) });
+
+	// The newline is left to separate this row from the next
+	just(Token::Pipe).ignore_then(cell.repeated().at_least(1).collect()).labelled("row")
+}
+
+/// Parse a note written `Front => Back` on a single line, the two sides
+/// filling a two field model in order. Either side can hold clozes like any
+/// field, and `=>` can still be written within the back. The names are left
+/// empty until the model is known.
+fn pair<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Vec<NoteField>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let side = |content: Vec<TextElement>| NoteField {
+		name:    String::new(),
+		content: trim_content(content),
+	};
+
+	// The newline is left to separate this note from the next
+	front_content()
+		.map(side)
+		.then_ignore(just(Token::Arrow))
+		.then(field_content().map(side))
+		.map(|(front, back)| vec![front, back])
+		.labelled("pair")
+}
+
+/// The fields of Anki's image occlusion model an occlusion fills in
+const OCCLUSION_FIELD: &str = "Occlusion";
+const OCCLUSION_IMAGE_FIELD: &str = "Image";
+
+/// Parse an image occlusion note, the image followed by one region per line,
+/// and optionally any other fields of the model:
+///
+/// ```text
+/// @image-occlusion heart.png
+/// @region 0.10 0.20 0.30 0.15
+/// @region 0.55 0.40 0.25 0.10
+/// Header: The heart
+/// ```
+///
+/// A region is `left top width height`, each a fraction from 0 to 1 of the
+/// image's width (left, width) or height (top, height), measured from its top
+/// left corner. This is the form Anki stores them in, so it holds whatever
+/// size the image is shown at. Every region becomes a cloze of its own in the
+/// Occlusion field, numbered in order, and the image goes in the Image field.
+fn image_occlusion<'tokens, 'src: 'tokens, I>(
+	separator: NoteSeparator,
+) -> impl Parser<'tokens, I, (PathBuf, Vec<NoteField>), extra::Err<Rich<'tokens, Token<'src>, Span>>>
++ Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let image = select! { Token::ImageOcclusion(path) => PathBuf::from(path) };
+
+	// A malformed region is reported but doesn't end the note, so any others
+	// still get checked
+	let region = select! { Token::Region(region) => region }.validate(|region, extra, emitter| {
+		occlusion_region(region).unwrap_or_else(|reason| {
+			emitter.emit(Rich::custom(extra.span(), reason));
+			String::new()
+		})
+	});
+
+	// Any other fields come after the regions, the newline is left otherwise
+	let other_fields = just(Token::Newline)
+		.ignore_then(field_declaration(separator).repeated().at_least(1).collect::<Vec<_>>())
+		.or_not();
+
+	image
+		.then(just(Token::Newline).ignore_then(region).repeated().at_least(1).collect::<Vec<_>>())
+		.then(other_fields)
+		.map(|((image, regions), other_fields)| {
+			let occlusions = regions
+				.into_iter()
+				.zip(1..)
+				.map(|(answer, id)| TextElement::Cloze(Cloze { id, answer, hint: Vec::new() }))
+				.collect();
+
+			let mut fields =
+				vec![NoteField { name: OCCLUSION_FIELD.to_string(), content: occlusions }, NoteField {
+					name:    OCCLUSION_IMAGE_FIELD.to_string(),
+					content: vec![TextElement::Html(format!("<img src=\"{}\">", image.display()))],
+				}];
+			fields.extend(other_fields.unwrap_or_default());
+
+			(image, fields)
+		})
+		.labelled("image occlusion")
+}
+
+/// Encode a `left top width height` region the way Anki's image occlusion
+/// expects to find it within a cloze
+fn occlusion_region(region: &str) -> Result<String, String> {
+	let values = region
+		.split_whitespace()
+		.map(|value| {
+			value.parse::<f64>().map_err(|_| format!("Region value '{}' isn't a number", value))
+		})
+		.collect::<Result<Vec<f64>, String>>()?;
+
+	let [left, top, width, height] = values[..] else {
+		return Err(format!(
+			"A region is 'left top width height', but '{}' has {} values",
+			region,
+			values.len()
+		));
+	};
+
+	if values.iter().any(|value| !(0.0..=1.0).contains(value)) {
+		return Err(format!("Region '{}' has values outside of 0 to 1", region));
+	}
+
+	if left + width > 1.0 || top + height > 1.0 {
+		return Err(format!("Region '{}' extends past the edge of the image", region));
+	}
+
+	Ok(format!(
+		"image-occlusion:rect:left={:.4}:top={:.4}:width={:.4}:height={:.4}",
+		left, top, width, height
+	))
+}
+
+/// Drop the whitespace at either edge of a field's content, see `field_content`
+fn trim_content(mut content: Vec<TextElement>) -> Vec<TextElement> {
+	if let Some(TextElement::Text(text)) = content.last_mut() {
+		text.truncate(text.trim_end().len());
+		if text.is_empty() {
+			content.pop();
+		}
+	}
+
+	if let Some(TextElement::Text(text)) = content.first_mut() {
+		*text = text.trim_start().to_string();
+		if text.is_empty() {
+			content.remove(0);
+		}
+	}
+
+	content
+}
+
 // Note Builder
 
 /// Build a note from parsed components
 struct NoteComponents<'m> {
-	model:   &'m NoteModel,
-	aliases: HashMap<String, String>,
-	tags:    Vec<String>,
-	fields:  Vec<NoteField>,
+	model:    &'m NoteModel,
+	aliases:  HashMap<String, String>,
+	comments: Vec<String>,
+	tags:     Vec<String>,
+	media:    Vec<PathBuf>,
+	data:     BTreeMap<String, String>,
+	classes:  Vec<String>,
+	reveal:   ClozeReveal,
+	fields:   Vec<NoteField>,
 }
 
 impl<'m> NoteComponents<'m> {
@@ -331,23 +1106,265 @@ impl<'m> NoteComponents<'m> {
 			}
 		}
 
-		Note { fields: self.fields, model: Cow::Borrowed(self.model), tags: self.tags }
+		let tags = self.tags.iter().map(|tag| self.render_tag(tag)).collect();
+
+		// Clozes without a hint of their own fall back to the model's hint field
+		let default_hint = self
+			.model
+			.hint_field
+			.as_ref()
+			.and_then(|name| self.fields.iter().find(|field| field.name == *name))
+			.map(|field| field_text(field).trim().to_string())
+			.filter(|hint| !hint.is_empty());
+
+		if let Some(default_hint) = default_hint {
+			for field in &mut self.fields {
+				for part in &mut field.content {
+					if let TextElement::Cloze(cloze) = part
+						&& cloze.hint.is_empty()
+					{
+						cloze.hint.push(default_hint.clone());
+					}
+				}
+			}
+		}
+
+		Note {
+			fields: self.fields,
+			model: Cow::Borrowed(self.model),
+			tags,
+			media: self.media,
+			comments: self.comments,
+			data: self.data,
+			classes: self.classes,
+			reveal: self.reveal,
+		}
+	}
+
+	/// Substitute each `{Field}` in the tag with that field's content. Anki
+	/// splits tags on whitespace, so any within the content becomes an
+	/// underscore.
+	fn render_tag(&self, tag: &str) -> String {
+		let mut rendered = tag.to_string();
+		for reference in tag_references(tag) {
+			let name = self.aliases.get(reference).map_or(reference, String::as_str);
+			let value =
+				self.fields.iter().find(|field| field.name == name).map(field_text).unwrap_or_default();
+
+			let value = value.split_whitespace().collect::<Vec<_>>().join("_");
+			rendered = rendered.replace(&format!("{{{}}}", reference), &value);
+		}
+		rendered
 	}
 }
 
-/// Parse a single note's content (tags and fields only)
-fn note<'tokens, 'src: 'tokens, I>() -> impl Parser<
-	'tokens,
-	I,
-	(Option<Vec<String>>, Vec<NoteField>),
-	extra::Err<Rich<'tokens, Token<'src>, Span>>,
-> + Clone
+/// A field's content as plain text, clozes reduced to their answers
+fn field_text(field: &NoteField) -> String {
+	field
+		.content
+		.iter()
+		.map(|part| match part {
+			TextElement::Text(text) | TextElement::Html(text) => text.as_str(),
+			TextElement::Cloze(cloze) => cloze.answer.as_str(),
+		})
+		.collect()
+}
+
+/// The field names a tag references through `{Field}`
+fn tag_references(tag: &str) -> impl Iterator<Item = &str> {
+	tag.split('{').skip(1).filter_map(|part| part.split_once('}')).map(|(name, _)| name)
+}
+
+/// A problem found while parsing, detached from the tokens so it can outlive
+/// the source it was found in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+	/// Byte range within the source
+	pub span:    Range<usize>,
+	pub message: String,
+}
+
+/// Render diagnostics the way a compiler would, each with the line it was
+/// found on and the offending span underlined. Plain text, without colour, so
+/// it reads the same wherever it ends up.
+pub fn render_diagnostics(source: &str, diagnostics: &[Diagnostic]) -> String {
+	let config = ariadne::Config::new().with_index_type(ariadne::IndexType::Byte).with_color(false);
+
+	let mut out = Vec::new();
+	for diagnostic in diagnostics {
+		Report::build(ReportKind::Error, ((), diagnostic.span.clone()))
+			.with_config(config)
+			.with_code(3)
+			.with_message(&diagnostic.message)
+			.with_label(Label::new(((), diagnostic.span.clone())).with_message(&diagnostic.message))
+			.finish()
+			.write(Source::from(source), &mut out)
+			.expect("writing to a Vec can't fail");
+	}
+
+	String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A note as it appears in the source, before it's bound to a model. Notes
+/// that failed to parse are recovered as one with no fields.
+#[derive(Debug, Clone, Default)]
+struct RawNote {
+	// Where the note begins in the source
+	start:    usize,
+	comments: Vec<String>,
+	model:    Option<String>,
+	data:     BTreeMap<String, String>,
+	classes:  Vec<String>,
+	reveal:   ClozeReveal,
+	layout:   Layout,
+	tags:     Option<Vec<String>>,
+	media:    Option<Vec<PathBuf>>,
+	fields:   Vec<NoteField>,
+}
+
+/// How a note's fields are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Layout {
+	// `Name: content`, a line per field
+	#[default]
+	Named,
+	// A row of cells, `| word | translation |`, named by their position in the
+	// model
+	Row,
+	// `Front => Back`, filling a two field model
+	Pair,
+}
+
+/// Parse `@data key=value`, one pair of a note's free-form data. The value is
+/// everything after the first `=`, with the whitespace around both trimmed.
+fn data_directive<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, (String, String), extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
 	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
 {
-	tags_declaration()
-		.or_not() // It's optional whether we have tags or not
-		.then(field_declaration().repeated().at_least(1).collect::<Vec<_>>())
+	select! { Token::Data(pair) => pair }
+		.validate(|pair: &str, extra, emitter| match pair.split_once('=') {
+			Some((key, value)) if !key.trim().is_empty() => {
+				(key.trim().to_string(), value.trim().to_string())
+			}
+			_ => {
+				emitter
+					.emit(Rich::custom(extra.span(), format!("'@data {}' isn't a key=value pair", pair)));
+				(String::new(), String::new())
+			}
+		})
+		.then_ignore(just(Token::Newline))
+		.labelled("data")
+}
+
+/// Parse `@class highlight`, classes the note's fields are wrapped in on export
+/// so the model's CSS can style this note apart. Any number may be given,
+/// separated by whitespace.
+fn class_directive<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, Vec<String>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! { Token::Class(classes) => classes.split_whitespace().map(str::to_string).collect() }
+		.then_ignore(just(Token::Newline))
+		.labelled("class")
+}
+
+/// Parse `@cloze reveal-all` or `@cloze reveal-one`, what each of the note's
+/// cards reveals. Either way there's a card per cloze number. `reveal-all` is
+/// Anki's usual, a card hiding its own clozes and revealing the rest.
+/// `reveal-one` turns that around, a card revealing only its own clozes and
+/// hiding every other, see `ClozeReveal`. It plays no part in a note's
+/// identity, so switching re-UUIDs nothing.
+fn cloze_directive<'tokens, 'src: 'tokens, I>()
+-> impl Parser<'tokens, I, ClozeReveal, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	select! { Token::ClozeMode(mode) => mode }
+		.validate(|mode: &str, extra, emitter| match mode {
+			"reveal-all" => ClozeReveal::All,
+			"reveal-one" => ClozeReveal::One,
+			_ => {
+				emitter.emit(Rich::custom(
+					extra.span(),
+					format!("Unknown cloze mode '{}'. Available: [reveal-all, reveal-one]", mode),
+				));
+				ClozeReveal::default()
+			}
+		})
+		.then_ignore(just(Token::Newline))
+		.labelled("cloze mode")
+}
+
+/// Parse a single note's content (leading comments, model override, data,
+/// classes, tags, media, and fields, or an image occlusion).
+/// Comments directly preceding a note belong to it, so they survive a
+/// round-trip back to source.
+fn note<'tokens, 'src: 'tokens, I>(
+	separator: NoteSeparator,
+) -> impl Parser<'tokens, I, RawNote, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
+where
+	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
+{
+	let leading_comments =
+		comment().then_ignore(blank().repeated()).repeated().collect::<Vec<String>>();
+
+	let model_override =
+		select! { Token::ModelOverride(name) => name.to_string() }.then_ignore(just(Token::Newline));
+
+	leading_comments
+		.then(model_override.or_not())
+		// A key declared twice keeps its last value
+		.then(
+			data_directive()
+				.repeated()
+				.collect::<BTreeMap<String, String>>()
+				.then(class_directive().repeated().collect::<Vec<Vec<String>>>().map(|c| c.concat()))
+				.then(cloze_directive().or_not().map(Option::unwrap_or_default)),
+		)
+		// It's optional whether we have tags or not, given bracketed, as hash tags, or both
+		.then(tags_declaration().or_not().then(hash_tags().or_not()).map(merge_tags))
+		.then(media_declaration().or_not()) // As is declared media
+		.then(
+			field_declaration(separator)
+				.repeated()
+				.at_least(1)
+				.collect::<Vec<_>>()
+				.map(|fields| (Layout::Named, fields, None))
+				.or(field_row().map(|fields| (Layout::Row, fields, None)))
+				.or(image_occlusion(separator).map(|(image, fields)| (Layout::Named, fields, Some(image))))
+				.or(pair().map(|fields| (Layout::Pair, fields, None))),
+		)
+		.map_with(|(((head, tags), media), (layout, fields, image)), extra| {
+			let ((comments, model), ((data, classes), reveal)) = head;
+
+			// The occluded image is media the note needs like any other
+			let media = match image {
+				Some(image) => Some(media.unwrap_or_default().into_iter().chain([image]).collect()),
+				None => media,
+			};
+
+			let span: Span = extra.span();
+			RawNote {
+				start: span.start,
+				comments,
+				model,
+				data,
+				classes,
+				reveal,
+				layout,
+				tags,
+				media,
+				fields,
+			}
+		})
+		// Skip a broken note up to the separator ending it, so its neighbours survive
+		.recover_with(skip_until(
+			any().ignored(),
+			just(Token::Newline).then(note_end(separator)).ignored().rewind().or(end()),
+			RawNote::default,
+		))
 }
 
 type AliasPairs = Vec<(String, String)>;
@@ -381,17 +1398,64 @@ where
 			)
 		})
 		// Parse aliases ONCE after model declaration
-		.then(alias_declaration()
-        .padded_by(noise().repeated()) // Handle noise around each alias
-        .repeated()
-        .collect::<Vec<_>>())
-		.then_ignore(noise().repeated())
+		.then(
+			noise()
+				.repeated()
+				.ignore_then(alias_declaration().map_with(|(from, to), extra| (from, to, extra.span())))
+				.then_ignore(blank().repeated()) // Leave comments for the note they precede
+				.repeated()
+				.collect::<Vec<_>>(),
+		)
+		.validate(|(model, aliases), _, emitter| {
+			validate_aliases(model, &aliases, emitter);
+			(model, aliases.into_iter().map(|(from, to, _)| (from, to)).collect::<AliasPairs>())
+		})
+		.then_ignore(blank().repeated())
 }
 
-type RawNote = (Option<Vec<String>>, Vec<NoteField>);
+/// Reject alias declarations that would make a field name mean more than one
+/// thing, pointing at the declaration that introduced the conflict
+fn validate_aliases<'tokens, 'src: 'tokens>(
+	model: Option<&NoteModel>,
+	aliases: &[(String, String, Span)],
+	emitter: &mut chumsky::input::Emitter<Rich<'tokens, Token<'src>, Span>>,
+) {
+	let mut aliased_fields: HashMap<&str, Span> = HashMap::new();
+	let mut shorthands: HashMap<&str, Span> = HashMap::new();
+
+	for (from, to, span) in aliases {
+		if let Some(model) = model
+			&& model.fields.iter().any(|field| field.name == *to)
+		{
+			emitter.emit(Rich::custom(
+				*span,
+				format!("Alias '{}' shadows the field of the same name in model '{}'", to, model.name),
+			));
+		}
+
+		if let Some(previous) = aliased_fields.insert(from, *span) {
+			emitter.emit(Rich::custom(
+				*span,
+				format!("Field '{}' is already aliased at {}..{}", from, previous.start, previous.end),
+			));
+		}
+
+		if let Some(previous) = shorthands.insert(to, *span) {
+			emitter.emit(Rich::custom(
+				*span,
+				format!("Alias '{}' is already declared at {}..{}", to, previous.start, previous.end),
+			));
+		}
+	}
+}
 
+/// Parse a whole .flash file. Notes starting within any of the `file_tags`
+/// spans carry those tags, after any of their own, and are told apart by
+/// `separator`.
 pub fn flash<'tokens, 'src: 'tokens, I>(
 	available_models: &'tokens [NoteModel],
+	file_tags: Vec<FileTag>,
+	separator: NoteSeparator,
 ) -> impl Parser<'tokens, I, Vec<Note<'tokens>>, extra::Err<Rich<'tokens, Token<'src>, Span>>> + Clone
 where
 	I: ValueInput<'tokens, Token = Token<'src>, Span = Span>,
@@ -400,8 +1464,14 @@ where
 	let model_section = intro(available_models)
 		// Then parse multiple notes
 		.then(
-            note()
-                .separated_by(noise().repeated().at_least(1))
+            // The end of the file or a model declaration after a separator isn't a
+            // broken note, the section has just ended
+            model_declaration()
+                .ignored()
+                .or(end())
+                .not()
+                .ignore_then(note(separator))
+                .separated_by(note_separator(separator))
                 .at_least(1)
                 .collect::<Vec<RawNote>>()
         )
@@ -412,31 +1482,125 @@ where
 			// Build alias map once for all notes
 			let alias_map: HashMap<_, _> =
 				aliases.into_iter().map(|(from, to)| (to, from)).collect();
+			let no_aliases = HashMap::new();
 
 			let notes: Vec<Note> = notes_data
 				.into_iter()
-				.filter_map(|(tags, fields)| {
-					let mut context = HashMapContext::<DefaultNumericTypes>::new();
+				// Recovered notes have already been reported
+				.filter(|raw| !raw.fields.is_empty())
+				.filter_map(|RawNote { start, comments, model: model_override, data, classes, reveal, layout, tags, media, mut fields }| {
+					// `@model` takes precedence over the section's model for this note alone. The
+					// section's aliases name fields of the section's model, so they don't carry over
+					let (model, alias_map) = match model_override {
+						None => (model, &alias_map),
+						Some(name) => match available_models.iter().find(|m| m.name == name) {
+							Some(overridden) => (overridden, &no_aliases),
+							None => {
+								emitter.emit(Rich::custom(span, format!("Unknown model '{}' in @model", name)));
+								return None;
+							}
+						},
+					};
+
+					// Rows and pairs fill the model's fields in order, empty cells leaving theirs out
+					if layout != Layout::Named {
+						if layout == Layout::Pair && model.fields.len() != 2 {
+							emitter.emit(Rich::custom(
+								span,
+								format!(
+									"'Front => Back' fills two fields, but model '{}' has {}",
+									model.name,
+									model.fields.len()
+								),
+							));
+							return None;
+						}
 
+						if fields.len() != model.fields.len() {
+							emitter.emit(Rich::custom(
+								span,
+								format!(
+									"Row has {} columns but model '{}' has {} fields",
+									fields.len(),
+									model.name,
+									model.fields.len()
+								),
+							));
+							return None;
+						}
 
-					// Validate fields against model (with alias resolution)
-					for field in &fields {
-						let resolved_name = alias_map.get(&field.name).unwrap_or(&field.name);
-												// Setting the fields provided to true within the evaluation context
-					eval_empty_with_context_mut(&format!("{} = true", resolved_name), &mut context).unwrap();
+						for (field, model_field) in fields.iter_mut().zip(&model.fields) {
+							field.name = model_field.name.clone();
+						}
+						fields.retain(|field| !field.content.is_empty());
+					}
 
+					let mut context = HashMapContext::<DefaultNumericTypes>::new();
 
+					// Every model field starts out absent, so fields the requirements don't
+					// insist on can be omitted from the note entirely. They're set directly
+					// rather than by evaluating an assignment, which parses it afresh for every
+					// field of every note
+					for model_field in &model.fields {
+						let _ = context.set_value(model_field.name.clone(), Value::from(false));
+					}
 
+					// Validate fields against model (with alias resolution), collecting every
+					// field the model doesn't have so they're reported together
+					let mut unknown = Vec::new();
+					for field in &fields {
+						let resolved_name = alias_map.get(&field.name).unwrap_or(&field.name);
+						// Setting the fields provided to true within the evaluation context
+						let _ = context.set_value(resolved_name.clone(), Value::from(true));
 
 						if !model.fields.iter().any(|f| &f.name == resolved_name) {
-							emitter.emit(Rich::custom(
-								span,
-								format!("Field '{}' not found in model '{}'", field.name, model.name),
-							));
-							return None;
+							unknown.push(format!("'{}'", field.name));
 						}
 					}
 
+					if !unknown.is_empty() {
+						let available =
+							model.fields.iter().map(|f| f.name.as_str()).collect::<Vec<_>>().join(", ");
+						let message = if fields.len() > model.fields.len() {
+							format!(
+								"Note has {} fields but model '{}' defines only {}, {} aren't among them. Available: [{}]",
+								fields.len(),
+								model.name,
+								model.fields.len(),
+								unknown.join(", "),
+								available
+							)
+						} else {
+							format!(
+								"Unknown field{} {} in model '{}'. Available: [{}]",
+								if unknown.len() == 1 { "" } else { "s" },
+								unknown.join(", "),
+								model.name,
+								available
+							)
+						};
+						emitter.emit(Rich::custom(span, message));
+						return None;
+					}
+
+					// Templated tags can only draw on fields the note actually has
+					for tag in tags.iter().flatten() {
+						for reference in tag_references(tag) {
+							let resolved = alias_map.get(reference).map_or(reference, String::as_str);
+							if !fields.iter().any(|field| {
+								alias_map.get(&field.name).unwrap_or(&field.name) == resolved
+							}) {
+								emitter.emit(Rich::custom(
+									span,
+									format!(
+										"Tag '{}' references field '{}', which the note doesn't have",
+										tag, reference
+									),
+								));
+								return None;
+							}
+						}
+					}
 
 					// Check against the field constraints
 					let has_met_field_constraints = model.required.eval_with_context(&context);
@@ -446,13 +1610,28 @@ where
 								span,
 								format!("The provided fields don't meet model {}'s requirements", model.name),
 						));
+						return None;
+					}
+
+					// Tags only ever describe a note, so those coming from its file leave its
+					// identity alone like any other
+					let mut tags = tags.unwrap_or_default();
+					for file_tag in file_tags.iter().filter(|file_tag| file_tag.span.contains(&start)) {
+						if !tags.contains(&file_tag.tag) {
+							tags.push(file_tag.tag.clone());
+						}
 					}
 
 					Some(
 						NoteComponents {
 							model,
 							aliases: alias_map.clone(), // Clone the shared alias map
-							tags: tags.unwrap_or_default(),
+							comments,
+							tags,
+							media: media.unwrap_or_default(),
+							data,
+							classes,
+							reveal,
 							fields,
 						}
 						.into_note(),
@@ -472,3 +1651,613 @@ where
 		.map(|v| v.into_iter().flatten().collect())
 		.then_ignore(end())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	/// A field's content with clozes and markup left out
+	fn text(note: &Note, name: &str) -> String {
+		let field = note.fields.iter().find(|field| field.name == name).expect("the note has the field");
+		field
+			.content
+			.iter()
+			.filter_map(|element| match element {
+				TextElement::Text(text) => Some(text.as_str()),
+				_ => None,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn keywords_can_name_fields() {
+		let models = test_support::models();
+		let mut model = models[0].clone();
+		model.name = "Media".to_string();
+		model.fields[1].name = "media".to_string();
+		let models = [model];
+
+		let notes = test_support::parse(&models, "=Media=\nmedia [a.png]\nFront: a\nmedia: b\n");
+		assert_eq!(notes[0].media, vec![PathBuf::from("a.png")]);
+		assert_eq!(text(&notes[0], "media"), "b");
+	}
+
+	/// Every cloze of a field, in order
+	fn clozes(note: &Note, name: &str) -> Vec<Cloze> {
+		let field = note.fields.iter().find(|field| field.name == name).expect("the note has the field");
+		field
+			.content
+			.iter()
+			.filter_map(|element| match element {
+				TextElement::Cloze(cloze) => Some(cloze.clone()),
+				_ => None,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn a_cloze_takes_any_number_of_hints() {
+		let models = test_support::models();
+		let notes =
+			test_support::parse(&models, "=Cloze=\nText: {Paris|a city|in France} and {Rome}\n");
+
+		assert_eq!(clozes(&notes[0], "Text"), vec![
+			Cloze {
+				id:     0,
+				answer: "Paris".to_string(),
+				hint:   vec!["a city".to_string(), "in France".to_string()],
+			},
+			Cloze { id: 0, answer: "Rome".to_string(), hint: Vec::new() },
+		]);
+	}
+
+	#[test]
+	fn escaped_pipes_are_literal() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: x \\| y {c1::a\\|b|either\\|or}\n");
+
+		assert_eq!(text(&notes[0], "Text"), "x | y ");
+		let clozes = clozes(&notes[0], "Text");
+		assert_eq!(clozes[0].answer, "a|b");
+		assert_eq!(clozes[0].hint, vec!["either|or"]);
+	}
+
+	#[test]
+	fn a_byte_order_mark_is_dropped() {
+		let source = decode_source("\u{feff}=Basic=\nFront: a\n".as_bytes().to_vec()).unwrap();
+		assert_eq!(source, "=Basic=\nFront: a\n");
+
+		let models = test_support::models();
+		assert_eq!(test_support::parse(&models, &source)[0].model.name, "Basic");
+	}
+
+	#[test]
+	fn sources_without_a_byte_order_mark_are_untouched() {
+		assert_eq!(
+			decode_source("=Basic=\nFront: a\n".as_bytes().to_vec()).unwrap(),
+			"=Basic=\nFront: a\n"
+		);
+	}
+
+	#[test]
+	fn utf_16_is_refused_either_way_round() {
+		for mark in [[0xff, 0xfe], [0xfe, 0xff]] {
+			let mut bytes = mark.to_vec();
+			bytes.extend("=\0B\0".as_bytes());
+			assert!(decode_source(bytes).unwrap_err().contains("UTF-16"));
+		}
+	}
+
+	#[test]
+	fn invalid_utf_8_says_where_it_went_wrong() {
+		let error = decode_source(vec![b'a', b'b', 0xc3, b'\n']).unwrap_err();
+		assert!(error.contains("index 2"));
+		assert!(error.contains("re-save the file as UTF-8"));
+	}
+
+	#[test]
+	fn html_runs_to_the_end_of_the_line_verbatim() {
+		let models = test_support::models();
+		let notes =
+			test_support::parse(&models, "=Basic=\nFront: see @html <b class=x>a, b: {c}|d</b>\n");
+		let content = &notes[0].field("Front").expect("the note has the field").content;
+
+		assert_eq!(content, &vec![
+			TextElement::Text("see ".to_string()),
+			TextElement::Html("<b class=x>a, b: {c}|d</b>".to_string())
+		]);
+	}
+
+	/// The messages of every diagnostic parsing `source` raises
+	fn diagnostics(source: &str) -> Vec<String> {
+		let models = test_support::models();
+		let (_, diagnostics) =
+			crate::types::deck::Deck::parse_cards_tolerant(&models, source, NoteSeparator::Blank);
+		diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect()
+	}
+
+	#[test]
+	fn an_alias_stands_in_for_its_field() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nalias Front to Q\n\nQ: a\nBack: b\n");
+		assert_eq!(text(&notes[0], "Front"), "a");
+	}
+
+	#[test]
+	fn an_alias_may_not_shadow_a_field() {
+		let messages = diagnostics("=Basic=\nalias Front to Back\n\nFront: a\n");
+		assert!(messages.iter().any(|message| message.contains("shadows the field")), "{:?}", messages);
+	}
+
+	#[test]
+	fn a_field_may_only_be_aliased_once() {
+		let messages = diagnostics("=Basic=\nalias Front to Q\nalias Front to F\n\nQ: a\n");
+		assert!(messages.iter().any(|message| message.contains("already aliased")), "{:?}", messages);
+	}
+
+	#[test]
+	fn an_alias_may_only_stand_for_one_field() {
+		let messages = diagnostics("=Basic=\nalias Front to Q\nalias Back to Q\n\nQ: a\n");
+		assert!(
+			messages.iter().any(|message| message.contains("is already declared")),
+			"{:?}",
+			messages
+		);
+	}
+
+	#[cfg(not(feature = "remote-imports"))]
+	#[test]
+	fn remote_imports_need_the_feature() {
+		let scratch = test_support::Scratch::new();
+		let index = scratch.write("index.flash", "import https://example.com/shared.flash\n");
+
+		let error = ImportExpander::new(&scratch.path)
+			.expand("import https://example.com/shared.flash\n", &index)
+			.unwrap_err();
+		assert!(error.contains("remote-imports"), "{}", error);
+	}
+
+	/// Place `content` in the cache as it would be after fetching `url`
+	#[cfg(feature = "remote-imports")]
+	fn cache(scratch: &test_support::Scratch, url: &str, content: &str) {
+		let key = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, url.as_bytes());
+		scratch.write(&format!("cache/{}.flash", key), content);
+	}
+
+	#[cfg(feature = "remote-imports")]
+	#[test]
+	fn remote_imports_resolve_relative_to_their_url_offline() {
+		let scratch = test_support::Scratch::new();
+		cache(&scratch, "https://example.com/decks/shared.flash", "Front: a\nimport part.flash\n");
+		cache(&scratch, "https://example.com/decks/part.flash", "Front: b\n");
+		let source = "import https://example.com/decks/shared.flash\n";
+		let index = scratch.write("index.flash", source);
+
+		let expanded = ImportExpander::new(&scratch.path)
+			.with_cache(scratch.path.join("cache"))
+			.offline(true)
+			.expand(source, &index)
+			.expect("the imports are cached");
+		assert!(expanded.contains("Front: a\n") && expanded.contains("Front: b\n"), "{}", expanded);
+	}
+
+	#[cfg(feature = "remote-imports")]
+	#[test]
+	fn remote_imports_missing_from_the_cache_fail_offline() {
+		let scratch = test_support::Scratch::new();
+		let source = "import https://example.com/decks/shared.flash\n";
+		let index = scratch.write("index.flash", source);
+
+		let error = ImportExpander::new(&scratch.path)
+			.with_cache(scratch.path.join("cache"))
+			.offline(true)
+			.expand(source, &index)
+			.unwrap_err();
+		assert!(error.contains("isn't cached"), "{}", error);
+	}
+
+	#[test]
+	fn tags_are_templated_on_the_note_fields() {
+		let models = test_support::models();
+		let source = "=Basic=\nalias Front to Q\n\n[topic_{Q}, plain]\nQ: solar system\nBack: b\n";
+		let notes = test_support::parse(&models, source);
+		assert_eq!(notes[0].tags, vec!["topic_solar_system", "plain"]);
+	}
+
+	#[test]
+	fn a_tag_may_not_reference_a_missing_field() {
+		let messages = diagnostics("=Basic=\n[topic_{Back}]\nFront: a\n");
+		assert!(
+			messages.iter().any(|message| message.contains("which the note doesn't have")),
+			"{:?}",
+			messages
+		);
+	}
+
+	#[test]
+	fn a_backslash_n_breaks_the_line() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: one\\ntwo {c1::three\\nfour}\n");
+
+		assert_eq!(text(&notes[0], "Text"), "one<br>two ");
+		assert_eq!(clozes(&notes[0], "Text")[0].answer, "three<br>four");
+	}
+
+	#[test]
+	fn other_backslashes_are_text() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: C:\\tmp \\x\nBack: b\n");
+		assert_eq!(text(&notes[0], "Front"), "C:\\tmp \\x");
+	}
+
+	#[test]
+	fn lexing_pairs_each_token_with_its_span() {
+		assert_eq!(lex("Front: a\n"), vec![
+			(Token::Text("Front"), 0..5),
+			(Token::Colon, 5..6),
+			(Token::WS(" "), 6..7),
+			(Token::Text("a"), 7..8),
+			(Token::Newline, 8..9),
+		]);
+	}
+
+	#[test]
+	fn directives_only_count_at_the_start_of_a_line() {
+		let tokens = lex("@model Basic\nFront: use @model Cloze\n");
+		assert_eq!(tokens[0].0, Token::ModelOverride("Basic"));
+		assert!(!tokens[1..].iter().any(|(token, _)| token.is_directive()));
+		assert!(tokens.contains(&(Token::Text("@model"), 24..30)));
+	}
+
+	#[test]
+	fn a_model_override_within_a_field_is_text() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: switch with @model Cloze\nBack: b\n");
+		assert_eq!(notes[0].model.name, "Basic");
+		assert_eq!(text(&notes[0], "Front"), "switch with @model Cloze");
+	}
+
+	#[test]
+	fn image_occlusion_directives_within_a_field_are_text() {
+		let tokens = lex("Front: see @image-occlusion map.png and @region rect 0 0 1 1\n");
+		assert!(!tokens.iter().any(|(token, _)| token.is_directive()));
+
+		let models = test_support::models();
+		let notes =
+			test_support::parse(&models, "=Basic=\nFront: mark it with @region rect 1 2 3 4\nBack: b\n");
+		assert_eq!(text(&notes[0], "Front"), "mark it with @region rect 1 2 3 4");
+	}
+
+	#[test]
+	fn file_tags_within_a_field_are_text() {
+		let tokens = lex("Front: wrap with @file-tag intro, then @end-file-tag\n");
+		assert!(!tokens.iter().any(|(token, _)| token.is_directive()));
+
+		let (_, file_tags) = extract_file_tags(tokens, 0);
+		assert!(file_tags.is_empty());
+	}
+
+	#[test]
+	fn file_tags_at_the_start_of_a_line_span_what_they_wrap() {
+		let source = "@file-tag intro\nFront: a\n@end-file-tag\n";
+		let (_, file_tags) = extract_file_tags(lex(source), source.len());
+		assert_eq!(file_tags, vec![FileTag { tag: "intro".to_string(), span: 15..25 }]);
+	}
+
+	#[test]
+	fn a_model_override_applies_to_its_note_alone() {
+		let models = test_support::models();
+		let notes = test_support::parse(
+			&models,
+			"=Basic=\nFront: a\nBack: b\n\n@model Cloze\nText: {c}\n\nFront: d\nBack: e\n",
+		);
+
+		let names: Vec<&str> = notes.iter().map(|note| note.model.name.as_str()).collect();
+		assert_eq!(names, vec!["Basic", "Cloze", "Basic"]);
+	}
+
+	#[test]
+	fn an_overridden_note_is_checked_against_its_own_model() {
+		let models = test_support::models();
+		let source = "=Basic=\n@model Cloze\nFront: a\nBack: b\n";
+		assert!(crate::types::deck::Deck::parse_cards(&models, source).is_err());
+	}
+
+	#[test]
+	fn whitespace_is_trimmed_inside_braces_and_kept_between_them() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: foo {c1:: bar | hint } baz  \n");
+		let content = &notes[0].field("Text").expect("the note has the field").content;
+
+		assert_eq!(content, &vec![
+			TextElement::Text("foo ".to_string()),
+			TextElement::Cloze(Cloze {
+				id:     1,
+				answer: "bar".to_string(),
+				hint:   vec!["hint".to_string()],
+			}),
+			TextElement::Text(" baz".to_string()),
+		]);
+	}
+
+	#[test]
+	fn trailing_whitespace_after_a_cloze_is_dropped() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: {c1::a}   \n");
+		let content = &notes[0].field("Text").expect("the note has the field").content;
+
+		assert!(matches!(content.as_slice(), [TextElement::Cloze(_)]), "{:?}", content);
+	}
+
+	#[test]
+	fn clozes_without_a_hint_take_the_hint_field() {
+		let mut models = test_support::models();
+		models[2].hint_field = Some("Extra".to_string());
+
+		let notes =
+			test_support::parse(&models, "=Cloze=\nText: {c1::a} {c2::b|own}\nExtra: fallback\n");
+		let hints: Vec<Vec<String>> =
+			clozes(&notes[0], "Text").into_iter().map(|cloze| cloze.hint).collect();
+		assert_eq!(hints, vec![vec!["fallback"], vec!["own"]]);
+
+		let notes = test_support::parse(&models, "=Cloze=\nText: {c1::a}\n");
+		assert!(clozes(&notes[0], "Text")[0].hint.is_empty());
+	}
+
+	#[test]
+	fn rows_fill_the_fields_in_order() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\n| a | b |\n\n| c | |\n");
+
+		assert_eq!((text(&notes[0], "Front"), text(&notes[0], "Back")), ("a".into(), "b".into()));
+		assert_eq!(text(&notes[1], "Front"), "c");
+		assert!(notes[1].field("Back").is_none());
+	}
+
+	#[test]
+	fn rows_need_a_cell_per_field() {
+		let messages = diagnostics("=Basic=\n| a | b | c |\n");
+		assert!(messages.iter().any(|message| message.contains("Row has 3 columns")), "{:?}", messages);
+	}
+
+	#[test]
+	fn each_region_becomes_a_cloze_over_the_declared_image() {
+		let models = test_support::models();
+		let source = concat!(
+			"=Image Occlusion=\n@image-occlusion map.png\n",
+			"@region 0.1 0.2 0.3 0.15\n@region 0.5 0.5 0.25 0.25\n",
+		);
+		let notes = test_support::parse(&models, source);
+
+		let occlusions: Vec<(u32, String)> =
+			clozes(&notes[0], "Occlusion").into_iter().map(|cloze| (cloze.id, cloze.answer)).collect();
+		assert_eq!(occlusions, vec![
+			(1, "image-occlusion:rect:left=0.1000:top=0.2000:width=0.3000:height=0.1500".to_string()),
+			(2, "image-occlusion:rect:left=0.5000:top=0.5000:width=0.2500:height=0.2500".to_string()),
+		]);
+		assert_eq!(notes[0].field("Image").expect("the note has the field").content, vec![
+			TextElement::Html("<img src=\"map.png\">".to_string())
+		]);
+		assert_eq!(notes[0].media, vec![PathBuf::from("map.png")]);
+	}
+
+	#[test]
+	fn regions_past_the_edge_of_the_image_are_refused() {
+		let models = test_support::models();
+		let source = "=Image Occlusion=\n@image-occlusion map.png\n@region 0.8 0 0.3 0.1\n";
+		assert!(crate::types::deck::Deck::parse_cards(&models, source).is_err());
+	}
+
+	#[test]
+	fn tagging_imports_tags_their_notes_without_changing_identities() {
+		let scratch = test_support::Scratch::new();
+		scratch.write("grammar.flash", "=Basic=\nFront: a\nBack: b\n");
+		let index = scratch.write("index.flash", "import grammar.flash\n");
+
+		let expand = |tag| {
+			ImportExpander::new(&scratch.path)
+				.tag_imports(tag)
+				.expand("import grammar.flash\n", &index)
+				.expect("the index expands")
+		};
+		let (tagged, untagged) = (expand(true), expand(false));
+
+		let models = test_support::models();
+		let tagged = test_support::parse(&models, &tagged);
+		let untagged = test_support::parse(&models, &untagged);
+		assert_eq!(tagged[0].tags, vec!["grammar".to_string()]);
+		assert!(untagged[0].tags.is_empty());
+		assert_eq!(tagged[0].to_content_string(), untagged[0].to_content_string());
+	}
+
+	#[test]
+	fn anki_numbered_clozes_mix_with_the_shorthand() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: {c1::a} {{2::b::hint}} {c}\n");
+		let clozes: Vec<(u32, String, Vec<String>)> = clozes(&notes[0], "Text")
+			.into_iter()
+			.map(|cloze| (cloze.id, cloze.answer, cloze.hint))
+			.collect();
+
+		assert_eq!(clozes, vec![
+			(1, "a".to_string(), vec![]),
+			(2, "b".to_string(), vec!["hint".to_string()]),
+			(0, "c".to_string(), vec![]),
+		]);
+	}
+
+	#[test]
+	fn data_within_a_field_is_text() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: set @data key=value\nBack: b\n");
+		assert!(notes[0].data.is_empty());
+		assert_eq!(text(&notes[0], "Front"), "set @data key=value");
+	}
+
+	#[test]
+	fn data_pairs_are_split_at_the_first_equals_sign() {
+		let models = test_support::models();
+		let source = "=Basic=\n@data source = p. 12\n@data query=a=b\nFront: a\nBack: b\n";
+		let notes = test_support::parse(&models, source);
+
+		let expected = BTreeMap::from([
+			("query".to_string(), "a=b".to_string()),
+			("source".to_string(), "p. 12".to_string()),
+		]);
+		assert_eq!(notes[0].data, expected);
+
+		let plain = test_support::parse(&models, "=Basic=\nFront: a\nBack: b\n");
+		assert_eq!(notes[0].to_content_string(), plain[0].to_content_string());
+	}
+
+	#[test]
+	fn data_without_a_key_is_refused() {
+		let models = test_support::models();
+		let source = "=Basic=\n@data =value\nFront: a\nBack: b\n";
+		assert!(crate::types::deck::Deck::parse_cards(&models, source).is_err());
+	}
+
+	#[test]
+	fn runs_of_text_join_into_a_single_element() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\n[two words, x]\nFront: a, b = c [d]: e\n");
+		let content = &notes[0].field("Front").expect("the note has the field").content;
+
+		assert_eq!(content, &vec![TextElement::Text("a, b = c [d]: e".to_string())]);
+		assert_eq!(notes[0].tags, vec!["two words", "x"]);
+	}
+
+	#[test]
+	fn hash_tags_follow_on_from_bracketed_tags() {
+		let models = test_support::models();
+		let notes = test_support::parse(
+			&models,
+			"=Basic=\n#a #b\nFront: 1\n\n[a, c]\n#c #d\nFront: 2\n\nFront: see #3\n",
+		);
+
+		assert_eq!(notes[0].tags, vec!["a", "b"]);
+		assert_eq!(notes[1].tags, vec!["a", "c", "d"]);
+		assert!(notes[2].tags.is_empty());
+		assert_eq!(text(&notes[2], "Front"), "see #3");
+	}
+
+	#[test]
+	fn every_unknown_field_is_reported_at_once() {
+		let messages = diagnostics("=Basic=\nFront: a\nQ: b\nR: c\n");
+		assert_eq!(messages, vec![concat!(
+			"Note has 3 fields but model 'Basic' defines only 2, 'Q', 'R' aren't among them. ",
+			"Available: [Front, Back]"
+		)]);
+
+		let messages = diagnostics("=Basic=\nFront: a\nQ: b\n");
+		assert_eq!(messages, vec!["Unknown field 'Q' in model 'Basic'. Available: [Front, Back]"]);
+	}
+
+	#[test]
+	fn ruled_notes_hold_fields_running_over_several_lines() {
+		let models = test_support::models();
+		let source = "=Basic=\nFront: one\ntwo\n\nthree\n\nBack: b\n---\n\nFront: c\n";
+		let notes =
+			crate::types::deck::Deck::parse_cards_separated(&models, source, NoteSeparator::Rule)
+				.expect("the source parses");
+
+		assert_eq!(notes.len(), 2);
+		assert_eq!(text(&notes[0], "Front"), "one<br>two<br><br>three");
+		assert_eq!(text(&notes[0], "Back"), "b");
+		assert_eq!(text(&notes[1], "Front"), "c");
+	}
+
+	#[test]
+	fn diagnostics_render_with_their_source_line() {
+		let source = "=Basic=\nFront: a\nQ: b\n";
+		let models = test_support::models();
+		let (_, diagnostics) =
+			crate::types::deck::Deck::parse_cards_tolerant(&models, source, NoteSeparator::Blank);
+		let rendered = render_diagnostics(source, &diagnostics);
+
+		assert!(rendered.contains(&diagnostics[0].message), "{}", rendered);
+		assert!(rendered.contains("Q: b"), "{}", rendered);
+		assert!(!rendered.contains('\x1b'), "{}", rendered);
+		assert!(render_diagnostics(source, &[]).is_empty());
+	}
+
+	#[test]
+	fn pairs_fill_both_fields_of_a_two_field_model() {
+		let models = test_support::models();
+		let notes = test_support::parse(
+			&models,
+			"=Basic=\nhola => hello\n\n{c1::x} => a => b\n\nFront: c => d\n",
+		);
+
+		assert_eq!(text(&notes[0], "Front"), "hola");
+		assert_eq!(text(&notes[0], "Back"), "hello");
+		assert_eq!(clozes(&notes[1], "Front").len(), 1);
+		assert_eq!(text(&notes[1], "Back"), "a => b");
+		assert_eq!(text(&notes[2], "Front"), "c => d");
+	}
+
+	#[test]
+	fn arrows_within_a_cloze_are_its_text() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Cloze=\nText: {c1::a => b}\n");
+		assert_eq!(clozes(&notes[0], "Text")[0].answer, "a => b");
+	}
+
+	#[test]
+	fn pairs_are_refused_for_models_without_two_fields() {
+		let messages = diagnostics("=Image Occlusion=\na => b\n");
+		let fields = test_support::models()[1].fields.len();
+		assert_eq!(messages, vec![format!(
+			"'Front => Back' fills two fields, but model 'Image Occlusion' has {}",
+			fields
+		)]);
+	}
+
+	#[test]
+	fn classes_within_a_field_are_text() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: style with @class loud\nBack: b\n");
+		assert!(notes[0].classes.is_empty());
+		assert_eq!(text(&notes[0], "Front"), "style with @class loud");
+	}
+
+	#[test]
+	fn classes_at_the_start_of_a_line_apply_to_the_note() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\n@class loud wide\nFront: a\nBack: b\n");
+		assert_eq!(notes[0].classes, vec!["loud".to_string(), "wide".to_string()]);
+	}
+
+	#[test]
+	fn cloze_modes_within_a_field_are_text() {
+		let tokens = lex("Text: {a} then @cloze all\n");
+		assert!(!tokens.iter().any(|(token, _)| token.is_directive()));
+	}
+
+	#[test]
+	fn cloze_modes_choose_what_each_card_reveals() {
+		let models = test_support::models();
+		let source = "=Cloze=\n@cloze reveal-one\nText: {a}\n\n@cloze reveal-all\nText: {b}\n\nText: {c}\n";
+		let reveals: Vec<ClozeReveal> =
+			test_support::parse(&models, source).iter().map(|note| note.reveal).collect();
+		assert_eq!(reveals, vec![ClozeReveal::One, ClozeReveal::All, ClozeReveal::All]);
+
+		let unknown = "=Cloze=\n@cloze reveal-some\nText: {a}\n";
+		assert!(crate::types::deck::Deck::parse_cards(&models, unknown).is_err());
+	}
+
+	#[test]
+	fn note_count_expectations_only_count_at_the_start_of_a_line() {
+		assert_eq!(expected_notes("@expect-notes 3\n=Basic=\n"), Ok(Some(3)));
+		assert_eq!(expected_notes("=Basic=\nFront: @expect-notes 3\n"), Ok(None));
+		assert!(expected_notes("@expect-notes many\n").is_err());
+	}
+
+	#[test]
+	fn a_note_count_expectation_within_a_field_stays_in_it() {
+		let models = test_support::models();
+		let notes = test_support::parse(&models, "=Basic=\nFront: keep @expect-notes 3\nBack: b\n");
+		assert_eq!(text(&notes[0], "Front"), "keep @expect-notes 3");
+	}
+}
@@ -0,0 +1,244 @@
+//! Previews of the cards a note turns into, rendered through its model's
+//! templates without a trip through Anki. Only the parts of Anki's template
+//! language that cards lean on most are understood: field substitution,
+//! `{{#Field}}`/`{{^Field}}` sections, `{{FrontSide}}`, and `{{cloze:Field}}`.
+//! Any other filter is ignored and the bare field substituted.
+
+use std::collections::{BTreeSet, HashMap};
+
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::types::{config::{ModelKind, Template}, crowd_anki_models, note::{Identified, Note, TextElement}, note_methods::CLOZE_HINT_SEPARATOR};
+
+/// A single card as Anki would show it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedCard {
+	// The template the card comes from, with the cloze number for cloze models
+	pub name:  String,
+	pub front: String,
+	pub back:  String,
+}
+
+/// Which side of a card is being rendered, clozes are only revealed on the back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+	Front,
+	Back,
+}
+
+/// Render every card the note generates. Standard models give a card per
+/// template, cloze models a card per cloze number through their first
+/// template.
+#[instrument(skip(note))]
+pub fn render_note(note: &Note) -> Vec<RenderedCard> {
+	// Fields as they're exported, so the preview matches what Anki is given
+	let exported: crowd_anki_models::Note =
+		Identified { id: Uuid::nil(), inner: note.clone() }.into();
+	let fields: HashMap<&str, String> =
+		note.model.fields.iter().map(|field| field.name.as_str()).zip(exported.fields).collect();
+
+	match note.model.kind {
+		ModelKind::Standard => note
+			.model
+			.templates
+			.iter()
+			.map(|template| render_card(note, &fields, template, template.name.clone(), None))
+			.collect(),
+		ModelKind::Cloze => {
+			let Some(template) = note.model.templates.first() else {
+				return Vec::new();
+			};
+
+			let ordinals: BTreeSet<u32> = note
+				.fields
+				.iter()
+				.flat_map(|field| &field.content)
+				.filter_map(|part| match part {
+					TextElement::Cloze(cloze) => Some(cloze.id),
+					_ => None,
+				})
+				.collect();
+
+			ordinals
+				.into_iter()
+				.map(|ordinal| {
+					let name = format!("{} {}", template.name, ordinal);
+					render_card(note, &fields, template, name, Some(ordinal))
+				})
+				.collect()
+		}
+	}
+}
+
+fn render_card(
+	note: &Note,
+	fields: &HashMap<&str, String>,
+	template: &Template,
+	name: String,
+	ordinal: Option<u32>,
+) -> RenderedCard {
+	let front = render_template(&template.question_format, note, fields, ordinal, Side::Front, "");
+	let back = render_template(&template.answer_format, note, fields, ordinal, Side::Back, &front);
+	RenderedCard { name, front, back }
+}
+
+/// Substitute everything between `{{` and `}}` in the template, leaving
+/// anything unterminated as written
+fn render_template(
+	template: &str,
+	note: &Note,
+	fields: &HashMap<&str, String>,
+	ordinal: Option<u32>,
+	side: Side,
+	front: &str,
+) -> String {
+	let mut out = String::new();
+	let mut rest = template;
+
+	while let Some(start) = rest.find("{{") {
+		out.push_str(&rest[..start]);
+		let Some(end) = rest[start..].find("}}").map(|end| start + end) else {
+			rest = &rest[start..];
+			break;
+		};
+		let tag = rest[start + 2..end].trim();
+		rest = &rest[end + 2..];
+
+		// Sections render their body when the field is filled (`#`) or empty (`^`)
+		if let Some((inverted, name)) = tag
+			.strip_prefix('#')
+			.map(|name| (false, name))
+			.or(tag.strip_prefix('^').map(|name| (true, name)))
+		{
+			let close = format!("{{{{/{}}}}}", name.trim());
+			let (body, after) = rest.split_once(&close).unwrap_or((rest, ""));
+			let filled = fields.get(name.trim()).is_some_and(|value| !value.trim().is_empty());
+			if filled != inverted {
+				out.push_str(&render_template(body, note, fields, ordinal, side, front));
+			}
+			rest = after;
+			continue;
+		}
+
+		if tag == "FrontSide" {
+			out.push_str(front);
+			continue;
+		}
+
+		// Filters are written ahead of the field, as in `text:Field`
+		let (filter, name) = tag.rsplit_once(':').unwrap_or(("", tag));
+		match (filter, ordinal) {
+			("cloze", Some(ordinal)) => out.push_str(&render_cloze_field(note, name, ordinal, side)),
+			_ => out.push_str(fields.get(name).map_or("", String::as_str)),
+		}
+	}
+
+	out.push_str(rest);
+	out
+}
+
+/// A field with the clozes asked for on card `ordinal` hidden (on the front) or
+/// highlighted (on the back), and every other cloze showing its answer
+fn render_cloze_field(note: &Note, name: &str, ordinal: u32, side: Side) -> String {
+	let Some(field) = note.field(name) else {
+		return String::new();
+	};
+
+	let content = field
+		.content
+		.iter()
+		.map(|part| match part {
+			TextElement::Text(text) | TextElement::Html(text) => text.clone(),
+			TextElement::Cloze(cloze) if !note.cloze_numbers(cloze.id).contains(&ordinal) => {
+				cloze.answer.clone()
+			}
+			TextElement::Cloze(cloze) => match side {
+				Side::Back => format!("<span class=\"cloze\">{}</span>", cloze.answer),
+				Side::Front if cloze.hint.is_empty() => "<span class=\"cloze\">[...]</span>".to_string(),
+				Side::Front => {
+					format!("<span class=\"cloze\">[{}]</span>", cloze.hint.join(CLOZE_HINT_SEPARATOR))
+				}
+			},
+		})
+		.collect();
+
+	match note.model.fields.iter().find(|model_field| model_field.name == name) {
+		Some(model_field) => model_field.wrap_content(content),
+		None => content,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	fn fronts(source: &str) -> Vec<String> {
+		let models = test_support::models();
+		let note = test_support::parse(&models, source).remove(0);
+		render_note(&note).into_iter().map(|card| card.front).collect()
+	}
+
+	#[test]
+	fn cloze_cards_hint_on_the_front_and_reveal_on_the_back() {
+		let models = test_support::models();
+		let note =
+			test_support::parse(&models, "=Cloze=\nText: {c1::a|hint} {c2::b}\nExtra: x\n").remove(0);
+		let cards = render_note(&note);
+
+		let names: Vec<&str> = cards.iter().map(|card| card.name.as_str()).collect();
+		assert_eq!(names, vec!["Cloze 1", "Cloze 2"]);
+		assert_eq!(cards[0].front, "<span class=\"cloze\">[hint]</span> b");
+		assert_eq!(cards[0].back, "<span class=\"cloze\">a</span> b<br>x");
+	}
+
+	#[test]
+	fn sections_render_by_whether_their_field_is_filled() {
+		let mut models = test_support::models();
+		models[0].templates = vec![Template {
+			name:                    "Card 1".to_string(),
+			order:                   0,
+			question_format:         "{{Front}}{{#Back}}, backed{{/Back}}{{^Back}}, bare{{/Back}}"
+				.to_string(),
+			answer_format:           "{{FrontSide}}<hr>{{Back}}".to_string(),
+			browser_question_format: String::new(),
+			browser_answer_format:   String::new(),
+		}];
+		let notes = test_support::parse(&models, "=Basic=\nFront: a\nBack: b\n\nFront: c\n");
+
+		let cards: Vec<RenderedCard> = notes.iter().flat_map(render_note).collect();
+		assert_eq!((cards[0].front.as_str(), cards[0].back.as_str()), ("a, backed", "a, backed<hr>b"));
+		assert_eq!(cards[1].front, "c, bare");
+	}
+
+	#[test]
+	fn clozes_sharing_a_number_are_hidden_together() {
+		let fronts = fronts("=Cloze=\nText: {c1::walrus} {c2::ferret} {c1::yak}\n");
+		assert_eq!(fronts.len(), 2);
+		assert!(
+			!fronts[0].contains("walrus") && fronts[0].contains("ferret") && !fronts[0].contains("yak")
+		);
+		assert!(fronts[1].contains("walrus") && fronts[1].contains("yak"));
+	}
+
+	#[test]
+	fn a_card_hides_its_own_cloze_by_default() {
+		let fronts = fronts("=Cloze=\nText: {c1::walrus} {c2::ferret}\n");
+		assert_eq!(fronts.len(), 2);
+		assert!(!fronts[0].contains("walrus") && fronts[0].contains("ferret"));
+		assert!(fronts[1].contains("walrus") && !fronts[1].contains("ferret"));
+	}
+
+	#[test]
+	fn revealing_one_hides_every_other_cloze() {
+		let fronts = fronts("=Cloze=\n@cloze reveal-one\nText: {c1::walrus} {c2::ferret} {c3::yak}\n");
+		assert_eq!(fronts.len(), 3);
+		assert!(
+			fronts[0].contains("walrus") && !fronts[0].contains("ferret") && !fronts[0].contains("yak")
+		);
+		assert!(
+			!fronts[2].contains("walrus") && !fronts[2].contains("ferret") && fronts[2].contains("yak")
+		);
+	}
+}
@@ -0,0 +1,152 @@
+//! Read-only analytics over a parsed deck and its history, for keeping track of
+//! how a deck grows over time.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::{change_router::{Transforms, determine_changes}, error::DeckError, types::{deck::Deck, note::TextElement}};
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DeckStats {
+	pub total_notes:          usize,
+	pub notes_per_model:      BTreeMap<String, usize>,
+	pub notes_per_tag:        BTreeMap<String, usize>,
+	pub cloze_notes:          usize,
+	pub standard_notes:       usize,
+	// Average number of characters in a field that's present on a note
+	pub average_field_length: f64,
+	// How many commits the recent counts below cover
+	pub recent_commits:       usize,
+	pub recent_additions:     usize,
+	pub recent_modifications: usize,
+	pub recent_deletions:     usize,
+}
+
+/// Gather the composition of the deck, along with how many notes changed over
+/// the last `recent` commits that touched it
+#[instrument(skip(deck))]
+pub fn deck_stats(deck: &Deck, recent: usize) -> Result<DeckStats, DeckError> {
+	let mut stats = DeckStats { total_notes: deck.cards.len(), ..Default::default() };
+
+	let mut field_count = 0;
+	let mut field_length = 0;
+
+	for card in &deck.cards {
+		let note = &card.inner;
+
+		*stats.notes_per_model.entry(note.model.name.clone()).or_default() += 1;
+		for tag in &note.tags {
+			*stats.notes_per_tag.entry(tag.clone()).or_default() += 1;
+		}
+
+		let has_cloze = note
+			.fields
+			.iter()
+			.flat_map(|field| &field.content)
+			.any(|part| matches!(part, TextElement::Cloze(_)));
+		if has_cloze {
+			stats.cloze_notes += 1;
+		} else {
+			stats.standard_notes += 1;
+		}
+
+		for field in &note.fields {
+			field_count += 1;
+			field_length += field
+				.content
+				.iter()
+				.map(|part| match part {
+					TextElement::Text(text) | TextElement::Html(text) => text.chars().count(),
+					TextElement::Cloze(cloze) => cloze.answer.chars().count(),
+				})
+				.sum::<usize>();
+		}
+	}
+
+	if field_count > 0 {
+		stats.average_field_length = field_length as f64 / field_count as f64;
+	}
+
+	// Replay the tail of the history, one more revision than requested so the
+	// oldest recent commit has something to be compared against
+	let revisions = deck.revisions()?;
+	let window = &revisions[revisions.len().saturating_sub(recent + 1)..];
+	stats.recent_commits = window.len().saturating_sub(1);
+
+	let parsed = window
+		.iter()
+		.map(|(content, _)| {
+			Deck::parse_cards_separated(&deck.models, content, deck.configuration.note_separator)
+		})
+		.collect::<Result<Vec<_>, DeckError>>()?;
+
+	for pair in parsed.windows(2) {
+		match determine_changes(&pair[0], &pair[1]) {
+			Ok(Some(Transforms::Additions(additions))) => stats.recent_additions += additions.len(),
+			Ok(Some(Transforms::Modifications(modifications))) => {
+				stats.recent_modifications += modifications.len()
+			}
+			Ok(Some(Transforms::Deletions(deletions))) => stats.recent_deletions += deletions.len(),
+			Ok(Some(Transforms::Edits { modifications, deletions, additions })) => {
+				stats.recent_modifications += modifications.len();
+				stats.recent_deletions += deletions.len();
+				stats.recent_additions += additions.len();
+			}
+			Ok(Some(Transforms::Reorders(_))) | Ok(None) => {}
+			// A note both moved and modified can't be attributed, it still counts above
+			Err(e) => debug!("Skipping uncountable commit: {}", e),
+		}
+	}
+
+	Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	const ONE: &str = "=Basic=\n[greek]\nFront: a\nBack: b\n";
+	const TWO: &str = "=Basic=\n[greek]\nFront: a\nBack: b\n\nFront: c\nBack: d\n";
+	const THREE: &str =
+		"=Basic=\n[greek]\nFront: a\nBack: bb\n\nFront: c\nBack: d\n\n=Cloze=\nText: {c1::x}\n";
+
+	#[test]
+	fn the_composition_covers_every_note() {
+		let stats = deck_stats(&test_support::deck(&[ONE, TWO, THREE]), 10).expect("the stats gather");
+
+		assert_eq!(stats.total_notes, 3);
+		assert_eq!(
+			stats.notes_per_model,
+			BTreeMap::from([("Basic".to_string(), 2), ("Cloze".to_string(), 1)])
+		);
+		assert_eq!(stats.notes_per_tag, BTreeMap::from([("greek".to_string(), 1)]));
+		assert_eq!((stats.cloze_notes, stats.standard_notes), (1, 2));
+		// a, bb, c, d and the cloze's answer
+		assert_eq!(stats.average_field_length, 6.0 / 5.0);
+	}
+
+	#[test]
+	fn recent_changes_count_every_commit_in_the_window() {
+		let stats = deck_stats(&test_support::deck(&[ONE, TWO, THREE]), 10).expect("the stats gather");
+
+		assert_eq!(stats.recent_commits, 2);
+		assert_eq!(
+			(stats.recent_additions, stats.recent_modifications, stats.recent_deletions),
+			(2, 1, 0)
+		);
+	}
+
+	#[test]
+	fn recent_changes_stop_at_the_window() {
+		let stats = deck_stats(&test_support::deck(&[ONE, TWO, THREE]), 1).expect("the stats gather");
+
+		assert_eq!(stats.recent_commits, 1);
+		assert_eq!(
+			(stats.recent_additions, stats.recent_modifications, stats.recent_deletions),
+			(1, 1, 0)
+		);
+	}
+}
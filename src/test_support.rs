@@ -0,0 +1,127 @@
+//! Fixtures shared by the unit tests: models to parse against, decks built in
+//! memory, and scratch directories and repositories on disk.
+
+use std::{fs, path::{Path, PathBuf}, process::Command, sync::atomic::{AtomicUsize, Ordering}};
+
+use uuid::Uuid;
+
+use crate::{model_loader, types::{deck::Deck, note::{Note, NoteModel}}};
+
+/// A cloze model alongside the built-ins, its clozes in Text
+const CLOZE_CONFIG: &str = r#"
+schema_version = "1.0.0"
+name           = "Cloze"
+id             = "5f3a1b9e-6c1d-5b7e-9d8a-0e2f4c6a8b10"
+type           = "cloze"
+required       = "Text"
+fields         = [{ name = "Text" }, { name = "Extra" }]
+templates      = [{ name = "Cloze", qfmt = "{{cloze:Text}}", afmt = "{{cloze:Text}}<br>{{Extra}}" }]
+"#;
+
+/// Basic (Front, Back) and Cloze (Text, Extra)
+pub fn models() -> Vec<NoteModel> {
+	let mut models = model_loader::builtin_models().expect("the built-in models load");
+	models.push(toml::from_str(CLOZE_CONFIG).expect("the cloze model is valid"));
+	models
+}
+
+/// Parse a card file, panicking on any error
+pub fn parse<'a>(models: &'a [NoteModel], source: &'a str) -> Vec<Note<'a>> {
+	Deck::parse_cards(models, source).expect("the source parses")
+}
+
+/// The host every in-memory deck is identified within
+pub fn host() -> Uuid { Uuid::from_u128(0x0f1a_5b00_0000_4000_8000_0000_0000_0001) }
+
+/// A deck whose index.flash went through the given revisions, oldest first
+pub fn deck(revisions: &[&str]) -> Deck<'static> {
+	let revisions = revisions.iter().map(|revision| revision.to_string()).collect();
+	Deck::from_parts(models(), vec![("index.flash".to_string(), revisions)], host())
+		.expect("the deck builds")
+}
+
+/// A directory of its own under the system's temporary directory, removed
+/// when dropped
+pub struct Scratch {
+	pub path: PathBuf,
+}
+
+impl Scratch {
+	pub fn new() -> Self {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!(
+			"flash-test-{}-{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		fs::create_dir_all(&path).expect("the scratch directory can be created");
+		Self { path }
+	}
+
+	/// Write a file beneath the directory, creating any parents
+	pub fn write(&self, name: &str, content: impl AsRef<[u8]>) -> PathBuf {
+		let path = self.path.join(name);
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).expect("the parent directory can be created");
+		}
+		fs::write(&path, content).expect("the file can be written");
+		path
+	}
+}
+
+impl Drop for Scratch {
+	fn drop(&mut self) { let _ = fs::remove_dir_all(&self.path); }
+}
+
+/// A git command run within `dir`, under a fixed identity and with no
+/// configuration of the machine's leaking in
+fn git_command(dir: &Path) -> Command {
+	let mut command = Command::new("git");
+	command
+		.current_dir(dir)
+		.env("GIT_AUTHOR_NAME", "Tester")
+		.env("GIT_AUTHOR_EMAIL", "tester@example.com")
+		.env("GIT_COMMITTER_NAME", "Tester")
+		.env("GIT_COMMITTER_EMAIL", "tester@example.com")
+		.env("GIT_CONFIG_GLOBAL", "/dev/null")
+		.env("GIT_CONFIG_NOSYSTEM", "1");
+	command
+}
+
+fn run(command: &mut Command) -> String {
+	let output = command.output().expect("git runs");
+	assert!(
+		output.status.success(),
+		"{:?} failed: {}",
+		command,
+		String::from_utf8_lossy(&output.stderr)
+	);
+	String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// Run git within `dir`, returning what it printed
+pub fn git(dir: &Path, args: &[&str]) -> String { run(git_command(dir).args(args)) }
+
+/// A deck directory at the root of a fresh repository, with a config naming it
+/// and the same models as `models`
+pub fn repository() -> Scratch {
+	let scratch = Scratch::new();
+	git(&scratch.path, &["init", "--quiet", "--initial-branch=main"]);
+	scratch.write("config.toml", "crowdanki_uuid = \"deck\"\nname = \"Test\"\n");
+	scratch.write("Cloze.model/config.toml", CLOZE_CONFIG);
+	scratch
+}
+
+/// Commit everything in the repository with the given message, dated `time`
+/// in seconds since the epoch, returning the new commit's id
+pub fn commit(scratch: &Scratch, message: &str, time: i64) -> String {
+	let date = format!("@{} +0000", time);
+	git(&scratch.path, &["add", "-A"]);
+	run(
+		git_command(&scratch.path)
+			.args(["commit", "--quiet", "--allow-empty", "-m", message])
+			.env("GIT_AUTHOR_DATE", &date)
+			.env("GIT_COMMITTER_DATE", &date),
+	);
+	git(&scratch.path, &["rev-parse", "HEAD"])
+}
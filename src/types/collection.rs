@@ -0,0 +1,402 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+use crate::{error::DeckError, media, types::{crowd_anki_config::DeckConfig, crowd_anki_models::{CrowdAnkiEntity, Deck as CrowdAnkiDeck, Note as CrowdAnkiNote}, deck::Deck, note::{Identified, Note, NoteModel}}};
+
+/// A deck within a collection, holding the identities of the notes it contains
+#[derive(Debug, Clone)]
+pub struct CollectionDeck {
+	pub configuration: DeckConfig,
+	pub notes:         Vec<Uuid>,
+}
+
+/// An owned, in-memory picture of everything that makes up an Anki collection.
+/// Downstream tools can query and mutate it before serializing to whichever
+/// format they need.
+#[derive(Debug, Clone, Default)]
+pub struct Collection {
+	decks:  Vec<CollectionDeck>,
+	models: Vec<NoteModel>,
+	notes:  Vec<Identified<Note<'static>>>,
+	media:  Vec<PathBuf>,
+
+	// Positions within the vectors above, for constant time lookups
+	note_index:  HashMap<Uuid, usize>,
+	model_index: HashMap<String, usize>,
+
+	// When notes were first authored, in seconds since the epoch
+	created: HashMap<Uuid, i64>,
+}
+
+impl Collection {
+	pub fn new() -> Self { Self::default() }
+
+	/// Add a deck, notes added through `with_note` afterwards land in it
+	pub fn with_deck(mut self, configuration: DeckConfig) -> Self {
+		self.insert_deck(configuration);
+		self
+	}
+
+	pub fn with_model(mut self, model: NoteModel) -> Self {
+		self.insert_model(model);
+		self
+	}
+
+	/// Add a note to the most recently added deck
+	pub fn with_note(mut self, note: Identified<Note<'_>>) -> Result<Self, DeckError> {
+		let deck_uuid = self
+			.decks
+			.last()
+			.map(|deck| deck.configuration.crowdanki_uuid.clone())
+			.ok_or(DeckError::EmptyCollection)?;
+		self.insert_note(&deck_uuid, note)?;
+		Ok(self)
+	}
+
+	pub fn with_media(mut self, path: impl Into<PathBuf>) -> Self {
+		self.insert_media(path);
+		self
+	}
+
+	pub fn insert_deck(&mut self, configuration: DeckConfig) {
+		self.decks.push(CollectionDeck { configuration, notes: Vec::new() });
+	}
+
+	/// Add a model, replacing any existing model of the same name
+	pub fn insert_model(&mut self, model: NoteModel) {
+		match self.model_index.get(&model.name) {
+			Some(&idx) => self.models[idx] = model,
+			None => {
+				self.model_index.insert(model.name.clone(), self.models.len());
+				self.models.push(model);
+			}
+		}
+	}
+
+	/// Add a note to the deck with the given uuid, registering its model if the
+	/// collection doesn't know of it yet
+	#[instrument(skip(self, note))]
+	pub fn insert_note(
+		&mut self,
+		deck_uuid: &str,
+		note: Identified<Note<'_>>,
+	) -> Result<(), DeckError> {
+		if self.note_index.contains_key(&note.id) {
+			return Err(DeckError::DuplicateNote(note.id));
+		}
+
+		let deck = self
+			.decks
+			.iter_mut()
+			.find(|deck| deck.configuration.crowdanki_uuid == deck_uuid)
+			.ok_or_else(|| DeckError::DeckNotFound(deck_uuid.to_string()))?;
+		deck.notes.push(note.id);
+
+		if !self.model_index.contains_key(&note.inner.model.name) {
+			self.insert_model(note.inner.model.clone().into_owned());
+		}
+
+		debug!("Inserted note {} into deck {}", note.id, deck_uuid);
+		self.note_index.insert(note.id, self.notes.len());
+		self.notes.push(Identified { id: note.id, inner: note.inner.into_owned() });
+		Ok(())
+	}
+
+	pub fn insert_media(&mut self, path: impl Into<PathBuf>) {
+		let path = path.into();
+		if !self.media.contains(&path) {
+			self.media.push(path);
+		}
+	}
+
+	/// Remove a note from the collection and whichever deck held it
+	pub fn remove_note(&mut self, id: Uuid) -> Option<Identified<Note<'static>>> {
+		let idx = self.note_index.remove(&id)?;
+		let note = self.notes.remove(idx);
+		self.created.remove(&id);
+
+		// Everything after the removed note shifted down by one
+		for position in self.note_index.values_mut() {
+			if *position > idx {
+				*position -= 1;
+			}
+		}
+
+		for deck in &mut self.decks {
+			deck.notes.retain(|note_id| *note_id != id);
+		}
+
+		Some(note)
+	}
+
+	/// Record when a note was first authored, in seconds since the epoch
+	pub fn set_created(&mut self, id: Uuid, time: i64) { self.created.insert(id, time); }
+
+	pub fn created(&self, id: Uuid) -> Option<i64> { self.created.get(&id).copied() }
+
+	pub fn note(&self, id: Uuid) -> Option<&Identified<Note<'static>>> {
+		self.note_index.get(&id).map(|&idx| &self.notes[idx])
+	}
+
+	pub fn note_mut(&mut self, id: Uuid) -> Option<&mut Identified<Note<'static>>> {
+		self.note_index.get(&id).map(|&idx| &mut self.notes[idx])
+	}
+
+	pub fn model(&self, name: &str) -> Option<&NoteModel> {
+		self.model_index.get(name).map(|&idx| &self.models[idx])
+	}
+
+	pub fn deck(&self, uuid: &str) -> Option<&CollectionDeck> {
+		self.decks.iter().find(|deck| deck.configuration.crowdanki_uuid == uuid)
+	}
+
+	pub fn decks(&self) -> &[CollectionDeck] { &self.decks }
+
+	pub fn models(&self) -> &[NoteModel] { &self.models }
+
+	pub fn notes(&self) -> &[Identified<Note<'static>>] { &self.notes }
+
+	pub fn media(&self) -> &[PathBuf] { &self.media }
+
+	/// Serialize to the CrowdAnki format. The first deck becomes the root,
+	/// carrying every model and media file, with the rest as its children.
+	#[instrument(skip(self))]
+	pub fn to_crowd_anki(&self) -> Result<CrowdAnkiEntity, DeckError> {
+		let mut decks = self.decks.iter().map(|deck| self.crowd_anki_deck(deck));
+		let mut root = decks.next().ok_or(DeckError::EmptyCollection)?;
+
+		root.children = decks.collect();
+		root.note_models = self.models.iter().map(|model| model.into()).collect();
+
+		// Media is registered by file name, the files themselves are copied alongside
+		root.media_files = media::file_names(&self.media)?;
+
+		Ok(CrowdAnkiEntity::Deck(root))
+	}
+
+	fn crowd_anki_deck(&self, deck: &CollectionDeck) -> CrowdAnkiDeck {
+		let deck_config = deck.configuration.clone();
+
+		// A schedule for a note that's gone is most likely stale, not worth failing
+		// over
+		for id in deck_config.schedule.keys().filter(|id| !deck.notes.contains(id)) {
+			warn!("Deck {} schedules note {}, which it doesn't contain", deck_config.name, id);
+		}
+
+		CrowdAnkiDeck {
+			name:                deck_config.name.clone(),
+			crowdanki_uuid:      deck_config.crowdanki_uuid.clone(),
+			deck_config_uuid:    deck_config.preset_uuid(),
+			desc:                String::new(), // Could be extended to read from deck metadata
+			is_dynamic:          0,
+			extend_new:          0,
+			extend_rev:          0,
+			note_models:         Vec::new(),
+			deck_configurations: vec![deck_config.to_preset()],
+			notes:               deck
+				.notes
+				.iter()
+				.filter_map(|id| self.note(*id))
+				.map(|note| crowd_anki_note(&deck.configuration, note.clone(), self.created(note.id)))
+				.collect(),
+			children:            Vec::new(),
+			media_files:         Vec::new(),
+		}
+	}
+}
+
+/// A note as CrowdAnki sees it within a deck of the given configuration,
+/// carrying the deck's default tags and its schedule for the note
+pub(crate) fn crowd_anki_note(
+	configuration: &DeckConfig,
+	mut note: Identified<Note<'_>>,
+	created: Option<i64>,
+) -> CrowdAnkiNote {
+	for tag in &configuration.default_tags {
+		if !note.inner.tags.contains(tag) {
+			note.inner.tags.push(tag.clone());
+		}
+	}
+	let mut data = note.inner.data.clone();
+	if let Some(schedule) = configuration.schedule.get(&note.id) {
+		schedule.fill(&mut data);
+	}
+
+	// Anki dates a note by its id, the creation time in milliseconds. CrowdAnki
+	// identifies notes by guid instead, so the time travels in the note's data, a
+	// JSON object of strings, under "created" unless the note sets that itself
+	if let Some(created) = created {
+		data.entry("created".to_string()).or_insert(time_millis(created));
+	}
+	let mut note: CrowdAnkiNote = note.into();
+	note.data = if data.is_empty() { None } else { serde_json::to_string(&data).ok() };
+	note
+}
+
+fn time_millis(seconds: i64) -> String { seconds.saturating_mul(1000).to_string() }
+
+/// Notes sharing an identity are refused rather than one of them being dropped,
+/// `Deck::repair_duplicates` gives them identities of their own
+impl<'a> TryFrom<Deck<'a>> for Collection {
+	type Error = DeckError;
+
+	fn try_from(deck: Deck<'a>) -> Result<Self, DeckError> {
+		let deck_uuid = deck.configuration.crowdanki_uuid.clone();
+
+		let mut collection = Collection::new().with_deck(deck.configuration);
+
+		for model in deck.models.iter().cloned() {
+			collection.insert_model(model);
+		}
+
+		for path in deck.media {
+			collection.insert_media(path);
+		}
+
+		for (id, time) in deck.created {
+			collection.set_created(id, time);
+		}
+
+		for card in deck.cards {
+			collection.insert_note(&deck_uuid, card)?;
+		}
+
+		Ok(collection)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{test_support, types::crowd_anki_config::Schedule};
+
+	#[test]
+	fn notes_sharing_an_identity_are_refused() {
+		let deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n\nFront: a\nBack: b\n"]);
+		assert!(matches!(Collection::try_from(deck), Err(DeckError::DuplicateNote(_))));
+	}
+
+	#[test]
+	fn a_deck_carries_its_notes_and_models_across() {
+		let deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n"]);
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		let collection = Collection::try_from(deck).expect("the deck converts");
+
+		assert_eq!(collection.notes().len(), 2);
+		assert!(ids.iter().all(|id| collection.note(*id).is_some()));
+		assert!(collection.model("Basic").is_some());
+	}
+
+	#[test]
+	fn notes_need_a_deck_to_land_in() {
+		let models = test_support::models();
+		let note = test_support::parse(&models, "=Basic=\nFront: a\nBack: b\n").remove(0);
+		let note = Identified { id: Uuid::nil(), inner: note };
+		assert!(matches!(Collection::new().with_note(note), Err(DeckError::EmptyCollection)));
+	}
+
+	#[test]
+	fn removing_a_note_keeps_the_rest_reachable() {
+		let deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n"]);
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		let mut collection = Collection::try_from(deck).expect("the deck converts");
+
+		assert!(collection.remove_note(ids[0]).is_some());
+		assert!(collection.note(ids[0]).is_none());
+		assert_eq!(collection.note(ids[1]).map(|note| note.id), Some(ids[1]));
+		assert_eq!(collection.decks()[0].notes, vec![ids[1]]);
+	}
+
+	#[test]
+	fn default_tags_join_each_note_once() {
+		let mut deck =
+			test_support::deck(&["=Basic=\n[greek]\nFront: a\nBack: b\n\nFront: c\nBack: d\n"]);
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		deck.configuration.default_tags = vec!["shared".to_string(), "greek".to_string()];
+
+		let CrowdAnkiEntity::Deck(exported) =
+			CrowdAnkiEntity::try_from(deck).expect("the deck exports")
+		else {
+			panic!("a deck exports as a deck")
+		};
+		let tags: Vec<Vec<String>> = exported.notes.iter().map(|note| note.tags.clone()).collect();
+		assert_eq!(tags, vec![vec!["greek", "shared"], vec!["shared", "greek"]]);
+
+		// Applied on export alone, so the notes keep their identities
+		let guids: Vec<String> = exported.notes.iter().map(|note| note.guid.clone()).collect();
+		assert_eq!(guids, ids.iter().map(Uuid::to_string).collect::<Vec<_>>());
+	}
+
+	fn exported_data(collection: &Collection) -> Vec<Option<String>> {
+		let Ok(CrowdAnkiEntity::Deck(deck)) = collection.to_crowd_anki() else {
+			panic!("the collection exports as a deck")
+		};
+		deck.notes.into_iter().map(|note| note.data).collect()
+	}
+
+	#[test]
+	fn data_is_always_an_object_carrying_the_creation_time() {
+		let deck =
+			test_support::deck(&["=Basic=\nFront: a\nBack: b\n\n@data source=book\nFront: c\nBack: d\n"]);
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		let mut collection = Collection::try_from(deck).expect("the deck converts");
+		for id in &ids {
+			collection.set_created(*id, 1_700_000_000);
+		}
+
+		assert_eq!(exported_data(&collection), vec![
+			Some(r#"{"created":"1700000000000"}"#.to_string()),
+			Some(r#"{"created":"1700000000000","source":"book"}"#.to_string()),
+		]);
+	}
+
+	#[test]
+	fn a_single_note_exports_as_it_would_within_its_deck() {
+		let mut deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n"]);
+		deck.configuration.default_tags = vec!["shared".to_string()];
+		let note = deck.cards[0].clone();
+		deck.created.insert(note.id, 1_700_000_000);
+
+		let CrowdAnkiEntity::Note(exported) = note.inner.to_crowd_anki_entity(note.id, &deck) else {
+			panic!("a note exports as a note")
+		};
+		assert_eq!(exported.tags, vec!["shared".to_string()]);
+		assert_eq!(exported.data.as_deref(), Some(r#"{"created":"1700000000000"}"#));
+	}
+
+	#[test]
+	fn creation_times_past_the_millisecond_range_saturate() {
+		let deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n"]);
+		let id = deck.cards[0].id;
+		let mut collection = Collection::try_from(deck).expect("the deck converts");
+		collection.set_created(id, i64::MAX / 10);
+
+		assert_eq!(exported_data(&collection), vec![Some(format!("{{\"created\":\"{}\"}}", i64::MAX))]);
+	}
+
+	#[test]
+	fn a_schedule_travels_in_the_data_of_its_note() {
+		let mut deck = test_support::deck(&["=Basic=\nFront: a\n\n@data ease=1300\nFront: b\n"]);
+		let schedule = Schedule {
+			ease:     Some(2500),
+			interval: Some(3),
+			due:      Some("2026-01-02".to_string()),
+		};
+		for card in &deck.cards {
+			deck.configuration.schedule.insert(card.id, schedule.clone());
+		}
+
+		let data: Vec<Option<String>> = deck
+			.cards
+			.iter()
+			.map(|card| crowd_anki_note(&deck.configuration, card.clone(), None).data)
+			.collect();
+		assert_eq!(data, vec![
+			Some(r#"{"due":"2026-01-02","ease":"2500","interval":"3"}"#.to_string()),
+			// The note's own data takes precedence
+			Some(r#"{"due":"2026-01-02","ease":"1300","interval":"3"}"#.to_string()),
+		]);
+	}
+}
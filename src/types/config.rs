@@ -1,15 +1,17 @@
 use serde::Deserialize;
 
 #[derive(Deserialize, Ord, PartialOrd, Eq, Hash, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Template {
 	pub name: String,
 
 	#[serde(skip)]
 	pub order: i32,
 
-	#[serde(skip)]
+	// Given inline as `qfmt` and `afmt`, or read from the model's .hbs files
+	#[serde(default, rename = "qfmt")]
 	pub question_format: String,
-	#[serde(skip)]
+	#[serde(default, rename = "afmt")]
 	pub answer_format:   String,
 
 	#[serde(skip)]
@@ -18,7 +20,17 @@ pub struct Template {
 	pub browser_answer_format:   String,
 }
 
+/// Whether a model's cards are generated from its templates or its clozes
+#[derive(Deserialize, Default, PartialOrd, Ord, Eq, Hash, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelKind {
+	#[default]
+	Standard,
+	Cloze,
+}
+
 #[derive(Deserialize, PartialOrd, Ord, Eq, Hash, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Defaults {
 	pub font: String,
 	pub size: u32,
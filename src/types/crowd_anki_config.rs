@@ -1,4 +1,13 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+use crate::{error::DeckError, uuid_generator};
+
+/// The names a deck's config may go by, each read in the format it names
+pub(crate) const CONFIG_FILES: [&str; 3] = ["config.toml", "config.yaml", "config.json"];
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Default, Clone)]
@@ -8,7 +17,7 @@ pub enum ConfigType {
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct DeckConfig {
 	// Note: Python uses UUID_FIELD_NAME. If that constant is "crowdanki_uuid", this works.
 	pub crowdanki_uuid: String,
@@ -36,6 +45,261 @@ pub struct DeckConfig {
 	pub replayq:         Option<bool>,
 	pub timer:           Option<i32>,
 	pub another_retreat: Option<bool>,
+
+	// Root namespace the deck's note identities are derived within, only
+	// meaningful to flash so it's kept out of the export
+	#[serde(default, skip_serializing)]
+	pub namespace: Option<Uuid>,
+
+	// A stable identity for the deck's host, in place of the author and time of
+	// its first commit. Setting (or changing) it re-identifies every note
+	#[serde(default, skip_serializing)]
+	pub host: Option<String>,
+
+	// How similar, from 0 to 1, a modified note must stay to keep its identity
+	#[serde(default, skip_serializing)]
+	pub similarity_threshold: Option<f64>,
+
+	// Tags every note in the deck carries on export. Only applied at export, so
+	// they never feed into a note's identity and changing them re-UUIDs nothing
+	#[serde(default, skip_serializing)]
+	pub default_tags: Vec<String>,
+
+	// Name of the scheduling preset the deck uses. Decks naming the same preset
+	// share one in Anki, without it the deck gets a preset of its own
+	#[serde(default, skip_serializing)]
+	pub preset: Option<String>,
+
+	// Trim whitespace from the edges of every field before it's hashed or
+	// exported, so stray trailing spaces don't churn identities. Off by default
+	// as turning it on re-identifies any note that had such whitespace
+	#[serde(default, skip_serializing)]
+	pub trim_fields: bool,
+
+	// Tag the notes each import brings in with the imported file's name. Tags
+	// play no part in a note's identity, so turning this on re-UUIDs nothing
+	#[serde(default, skip_serializing)]
+	pub tag_imports: bool,
+
+	// The Unicode form the deck's source is put in before it's parsed, so text
+	// that looks the same is hashed the same however it was typed
+	#[serde(default, skip_serializing)]
+	pub normalization: Normalization,
+
+	// What separates one note from the next in the deck's card files, a blank
+	// line unless set to "rule"
+	#[serde(default, skip_serializing)]
+	pub note_separator: NoteSeparator,
+
+	// How far the number of notes may stray from the `@expect-notes` of
+	// index.flash before `flash check` fails
+	#[serde(default, skip_serializing)]
+	pub note_count_tolerance: usize,
+
+	// Card files resolved against a history of their own, as `[track]` entries
+	// from the file to the path its history is read from, such as where it was
+	// committed before a rename. Files besides index.flash are entry points of
+	// their own, their notes following the index's, so they shouldn't also be
+	// imported by it
+	#[serde(default, skip_serializing)]
+	pub track: BTreeMap<String, String>,
+
+	// Initial scheduling for notes brought in from elsewhere, keyed by the
+	// note's identity, as `[schedule."<uuid>"]` tables
+	#[serde(default, skip_serializing)]
+	pub schedule: BTreeMap<Uuid, Schedule>,
+}
+
+/// Where a note stands in its reviews, for decks carrying scheduling over from
+/// another source. It travels in the note's data on export.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Schedule {
+	// In permille, as Anki keeps it, so 2500 is an ease of 250%
+	pub ease:     Option<u32>,
+	// In days
+	pub interval: Option<u32>,
+	// As YYYY-MM-DD
+	pub due:      Option<String>,
+}
+
+impl Schedule {
+	/// The keys scheduling travels under in a note's data
+	pub const KEYS: [&str; 3] = ["ease", "interval", "due"];
+
+	/// Add the scheduling to a note's data, under `ease`, `interval`, and `due`.
+	/// Keys the note already sets itself are left as they are.
+	pub fn fill(&self, data: &mut BTreeMap<String, String>) {
+		let values = [
+			self.ease.map(|ease| ease.to_string()),
+			self.interval.map(|interval| interval.to_string()),
+			self.due.clone(),
+		];
+		for (key, value) in Self::KEYS.into_iter().zip(values) {
+			if let Some(value) = value {
+				data.entry(key.to_string()).or_insert(value);
+			}
+		}
+	}
+}
+
+/// A Unicode normalization form, as named in the deck config
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+	// Composed, the form most editors and input methods produce
+	#[default]
+	Nfc,
+	// Decomposed, accents kept as combining characters of their own
+	Nfd,
+	// Left as written
+	None,
+}
+
+impl Normalization {
+	pub fn apply(self, text: &str) -> String {
+		match self {
+			Normalization::Nfc => text.nfc().collect(),
+			Normalization::Nfd => text.nfd().collect(),
+			Normalization::None => text.to_string(),
+		}
+	}
+}
+
+/// What ends one note and starts the next in a card file, as named in the
+/// deck config
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteSeparator {
+	// A blank line, so every field fits on a line of its own
+	#[default]
+	Blank,
+	// A line of `---`. Blank lines are then free for use within a note, and a
+	// field runs on over the lines up to the next field or rule
+	Rule,
+}
+
+/// Values set from outside the deck, for scripting it across environments
+/// without committing anything. Each source takes precedence over the last:
+/// the deck's config file, then the `FLASH_DECK_NAME` and `FLASH_HOST`
+/// environment variables, then the command line.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigOverrides {
+	pub name: Option<String>,
+	// Re-identifies every note, as setting `host` in the config file does
+	pub host: Option<String>,
+}
+
+impl ConfigOverrides {
+	/// The overrides set through the environment
+	pub fn from_env() -> Self {
+		Self { name: std::env::var("FLASH_DECK_NAME").ok(), host: std::env::var("FLASH_HOST").ok() }
+	}
+
+	/// Layer `over` on top, its values winning wherever it sets them
+	pub fn then(self, over: ConfigOverrides) -> Self {
+		Self { name: over.name.or(self.name), host: over.host.or(self.host) }
+	}
+
+	pub fn apply(&self, configuration: &mut DeckConfig) {
+		if let Some(name) = &self.name {
+			configuration.name = name.clone();
+		}
+		if let Some(host) = &self.host {
+			configuration.host = Some(host.clone());
+		}
+	}
+}
+
+impl DeckConfig {
+	/// Every card file whose history drives identities, with the path that
+	/// history is read from. index.flash always comes first, tracked under its
+	/// own name unless `track` says otherwise.
+	pub fn tracked_files(&self) -> Vec<(String, String)> {
+		let index = self.track.get("index.flash").cloned().unwrap_or_else(|| "index.flash".to_string());
+
+		[("index.flash".to_string(), index)]
+			.into_iter()
+			.chain(
+				self
+					.track
+					.iter()
+					.filter(|(file, _)| *file != "index.flash")
+					.map(|(file, path)| (file.clone(), path.clone())),
+			)
+			.collect()
+	}
+
+	/// Read the deck's config from whichever of config.toml, config.yaml, or
+	/// config.json it has. YAML and JSON need the `yaml-config` and
+	/// `json-config` features, and a deck with more than one is refused
+	/// rather than guessing which is meant.
+	pub fn load(deck_path: &Path) -> Result<DeckConfig, DeckError> {
+		let present: Vec<PathBuf> =
+			CONFIG_FILES.iter().map(|name| deck_path.join(name)).filter(|path| path.is_file()).collect();
+
+		let path = match present.as_slice() {
+			[] => return Err(DeckError::DeckConfigNotFound(deck_path.join(CONFIG_FILES[0]))),
+			[path] => path,
+			_ => return Err(DeckError::MultipleDeckConfigs(present)),
+		};
+
+		let content = fs::read_to_string(path)?;
+		match path.extension().and_then(|ext| ext.to_str()) {
+			Some("yaml") => Self::from_yaml(path, &content),
+			Some("json") => Self::from_json(path, &content),
+			_ => Ok(toml::from_str(&content)?),
+		}
+	}
+
+	#[cfg(feature = "yaml-config")]
+	fn from_yaml(path: &Path, content: &str) -> Result<DeckConfig, DeckError> {
+		serde_norway::from_str(content)
+			.map_err(|e| DeckError::DeckConfigFormat(path.to_path_buf(), e.to_string()))
+	}
+
+	#[cfg(not(feature = "yaml-config"))]
+	fn from_yaml(path: &Path, _content: &str) -> Result<DeckConfig, DeckError> {
+		Err(DeckError::DeckConfigFormat(
+			path.to_path_buf(),
+			"YAML configs need the `yaml-config` feature".to_string(),
+		))
+	}
+
+	#[cfg(feature = "json-config")]
+	fn from_json(path: &Path, content: &str) -> Result<DeckConfig, DeckError> {
+		serde_json::from_str(content)
+			.map_err(|e| DeckError::DeckConfigFormat(path.to_path_buf(), e.to_string()))
+	}
+
+	#[cfg(not(feature = "json-config"))]
+	fn from_json(path: &Path, _content: &str) -> Result<DeckConfig, DeckError> {
+		Err(DeckError::DeckConfigFormat(
+			path.to_path_buf(),
+			"JSON configs need the `json-config` feature".to_string(),
+		))
+	}
+
+	/// The identity of the scheduling preset, stable across exports
+	pub fn preset_uuid(&self) -> String {
+		self.preset.as_deref().map_or_else(
+			|| self.crowdanki_uuid.clone(),
+			|name| uuid_generator::generate_preset_uuid(name).to_string(),
+		)
+	}
+
+	/// The configuration as Anki sees it, a scheduling preset under its own
+	/// identity and name
+	pub fn to_preset(&self) -> DeckConfig {
+		let mut preset = self.clone();
+		preset.crowdanki_uuid = self.preset_uuid();
+		if let Some(name) = &self.preset {
+			preset.name = name.clone();
+		}
+		preset
+	}
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -75,3 +339,131 @@ pub struct LapseConfig {
 	pub leech_action: Option<i32>,
 	pub leech_fails:  Option<i32>,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support::Scratch;
+
+	fn config(crowdanki_uuid: &str, preset: Option<&str>) -> DeckConfig {
+		DeckConfig {
+			crowdanki_uuid: crowdanki_uuid.to_string(),
+			name: "Deck".to_string(),
+			preset: preset.map(str::to_string),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn decks_naming_a_preset_share_it() {
+		let (spanish, french) =
+			(config("spanish", Some("Languages")), config("french", Some("Languages")));
+
+		assert_eq!(spanish.preset_uuid(), french.preset_uuid());
+		assert_eq!(
+			spanish.preset_uuid(),
+			uuid_generator::generate_preset_uuid("Languages").to_string()
+		);
+		assert_ne!(spanish.preset_uuid(), config("spanish", Some("Sciences")).preset_uuid());
+	}
+
+	#[test]
+	fn a_deck_without_a_preset_has_its_own() {
+		let deck = config("spanish", None);
+		let preset = deck.to_preset();
+
+		assert_eq!(deck.preset_uuid(), "spanish");
+		assert_eq!((preset.crowdanki_uuid.as_str(), preset.name.as_str()), ("spanish", "Deck"));
+	}
+
+	#[test]
+	fn a_named_preset_exports_under_its_name() {
+		let preset = config("spanish", Some("Languages")).to_preset();
+
+		assert_eq!(preset.name, "Languages");
+		assert_eq!(
+			preset.crowdanki_uuid,
+			uuid_generator::generate_preset_uuid("Languages").to_string()
+		);
+	}
+
+	#[test]
+	fn a_deck_config_is_read_from_toml() {
+		let scratch = Scratch::new();
+		scratch.write("config.toml", "crowdanki_uuid = \"deck\"\nname = \"Test\"\n");
+
+		let config = DeckConfig::load(&scratch.path).expect("the config loads");
+		assert_eq!((config.crowdanki_uuid.as_str(), config.name.as_str()), ("deck", "Test"));
+	}
+
+	#[test]
+	fn a_deck_needs_exactly_one_config() {
+		let scratch = Scratch::new();
+		assert!(matches!(DeckConfig::load(&scratch.path), Err(DeckError::DeckConfigNotFound(_))));
+
+		scratch.write("config.toml", "crowdanki_uuid = \"deck\"\nname = \"Test\"\n");
+		scratch.write("config.json", "{\"crowdanki_uuid\": \"deck\", \"name\": \"Test\"}");
+		let result = DeckConfig::load(&scratch.path);
+		assert!(matches!(result, Err(DeckError::MultipleDeckConfigs(paths)) if paths.len() == 2));
+	}
+
+	#[cfg(feature = "json-config")]
+	#[test]
+	fn a_deck_config_is_read_from_json() {
+		let scratch = Scratch::new();
+		scratch.write("config.json", "{\"crowdanki_uuid\": \"deck\", \"name\": \"Test\"}");
+		assert_eq!(DeckConfig::load(&scratch.path).expect("the config loads").name, "Test");
+	}
+
+	#[cfg(not(feature = "yaml-config"))]
+	#[test]
+	fn yaml_configs_need_the_feature() {
+		let scratch = Scratch::new();
+		scratch.write("config.yaml", "crowdanki_uuid: deck\nname: Test\n");
+		assert!(matches!(DeckConfig::load(&scratch.path), Err(DeckError::DeckConfigFormat(..))));
+	}
+
+	#[test]
+	fn normalization_composes_by_default() {
+		let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+
+		assert_eq!(Normalization::default().apply(decomposed), composed);
+		assert_eq!(Normalization::Nfd.apply(composed), decomposed);
+		assert_eq!(Normalization::None.apply(decomposed), decomposed);
+	}
+
+	#[test]
+	fn normalization_is_named_in_lowercase() {
+		let config: DeckConfig =
+			toml::from_str("crowdanki_uuid = \"deck\"\nname = \"Test\"\nnormalization = \"nfd\"\n")
+				.expect("the config is valid");
+		assert_eq!(config.normalization, Normalization::Nfd);
+	}
+
+	#[test]
+	fn later_overrides_win_where_they_are_set() {
+		let env = ConfigOverrides { name: Some("Env".to_string()), host: Some("ci".to_string()) };
+		let flags = ConfigOverrides { name: Some("Flag".to_string()), host: None };
+
+		let mut deck = config("spanish", None);
+		env.then(flags).apply(&mut deck);
+		assert_eq!((deck.name.as_str(), deck.host.as_deref()), ("Flag", Some("ci")));
+
+		let mut untouched = config("spanish", None);
+		ConfigOverrides::default().apply(&mut untouched);
+		assert_eq!((untouched.name.as_str(), untouched.host), ("Deck", None));
+	}
+
+	#[test]
+	fn the_index_is_tracked_first_under_its_own_name_by_default() {
+		let mut deck = config("spanish", None);
+		assert_eq!(deck.tracked_files(), vec![("index.flash".to_string(), "index.flash".to_string())]);
+
+		deck.track.insert("extra.flash".to_string(), "extra.flash".to_string());
+		deck.track.insert("index.flash".to_string(), "old/index.flash".to_string());
+		assert_eq!(deck.tracked_files(), vec![
+			("index.flash".to_string(), "old/index.flash".to_string()),
+			("extra.flash".to_string(), "extra.flash".to_string()),
+		]);
+	}
+}
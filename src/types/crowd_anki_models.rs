@@ -1,6 +1,9 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 
-use crate::types::crowd_anki_config::DeckConfig;
+use crate::types::crowd_anki_config::{DeckConfig, Schedule};
 
 fn serialize_option_string<S>(val: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -101,7 +104,6 @@ pub struct NoteModel {
 	pub name:           String,
 
 	#[serde(rename = "type")]
-	#[serde(skip)]
 	#[serde(default)]
 	pub kind: NoteModelType,
 
@@ -169,3 +171,164 @@ pub struct Note {
 	#[serde(default)]
 	pub data: Option<String>,
 }
+
+impl CrowdAnkiEntity {
+	/// Carry over the data an earlier export of the same notes held, which Anki
+	/// fills with scheduling state when the deck syncs back through the same
+	/// file. Keys this export sets itself win, and `reset_scheduling` leaves the
+	/// earlier ease, interval and due behind.
+	pub fn preserve_data(&mut self, previous: &CrowdAnkiEntity, reset_scheduling: bool) {
+		let earlier: HashMap<&str, &str> = previous
+			.notes()
+			.into_iter()
+			.filter_map(|note| Some((note.guid.as_str(), note.data.as_deref()?)))
+			.collect();
+
+		for note in self.notes_mut() {
+			if let Some(previous) = earlier.get(note.guid.as_str()) {
+				note.data = merge_data(note.data.take(), previous, reset_scheduling);
+			}
+		}
+	}
+
+	/// Every note within the entity, through each of its child decks
+	pub fn notes(&self) -> Vec<&Note> {
+		let mut notes = Vec::new();
+		match self {
+			CrowdAnkiEntity::Deck(deck) => deck_notes(deck, &mut notes),
+			CrowdAnkiEntity::Note(note) => notes.push(note),
+			CrowdAnkiEntity::NoteModel(_) | CrowdAnkiEntity::DeckConfig(_) => {}
+		}
+		notes
+	}
+
+	fn notes_mut(&mut self) -> Vec<&mut Note> {
+		let mut notes = Vec::new();
+		match self {
+			CrowdAnkiEntity::Deck(deck) => deck_notes_mut(deck, &mut notes),
+			CrowdAnkiEntity::Note(note) => notes.push(note),
+			CrowdAnkiEntity::NoteModel(_) | CrowdAnkiEntity::DeckConfig(_) => {}
+		}
+		notes
+	}
+}
+
+fn deck_notes<'a>(deck: &'a Deck, notes: &mut Vec<&'a Note>) {
+	notes.extend(&deck.notes);
+	for child in &deck.children {
+		deck_notes(child, notes);
+	}
+}
+
+fn deck_notes_mut<'a>(deck: &'a mut Deck, notes: &mut Vec<&'a mut Note>) {
+	notes.extend(&mut deck.notes);
+	for child in &mut deck.children {
+		deck_notes_mut(child, notes);
+	}
+}
+
+/// Earlier data that isn't a JSON object is opaque, it's kept only when there's
+/// nothing newer and no scheduling to reset
+fn merge_data(current: Option<String>, previous: &str, reset_scheduling: bool) -> Option<String> {
+	let Ok(previous) = serde_json::from_str::<Map<String, Value>>(previous) else {
+		return current.or_else(|| (!reset_scheduling).then(|| previous.to_string()));
+	};
+	let mut data = match current.as_deref().map(serde_json::from_str::<Map<String, Value>>) {
+		Some(Ok(data)) => data,
+		Some(Err(_)) => return current,
+		None => Map::new(),
+	};
+
+	for (key, value) in previous {
+		if !(reset_scheduling && Schedule::KEYS.contains(&key.as_str())) {
+			data.entry(key).or_insert(value);
+		}
+	}
+
+	if data.is_empty() { None } else { serde_json::to_string(&data).ok() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	fn export(source: &str, reset_scheduling: bool) -> CrowdAnkiEntity {
+		let mut deck = test_support::deck(&[source]);
+		if reset_scheduling {
+			deck.reset_scheduling();
+		}
+		CrowdAnkiEntity::try_from(deck).expect("the deck exports")
+	}
+
+	/// The data Anki left on the first note of an export, as it would write it
+	/// back
+	fn synced(mut entity: CrowdAnkiEntity, data: &str) -> CrowdAnkiEntity {
+		entity.notes_mut()[0].data = Some(data.to_string());
+		let json = serde_json::to_string(&entity).expect("the export serializes");
+		serde_json::from_str(&json).expect("the export reads back")
+	}
+
+	fn data(entity: &CrowdAnkiEntity) -> Map<String, Value> {
+		let data = entity.notes()[0].data.as_deref().unwrap_or("{}");
+		serde_json::from_str(data).expect("the data is an object")
+	}
+
+	const SOURCE: &str = "=Basic=\nFront: a\nBack: b\n";
+
+	#[test]
+	fn data_survives_a_round_trip_through_anki() {
+		let previous = synced(export(SOURCE, false), r#"{"ease":"2500","due":"12","flag":"red"}"#);
+		let mut out = export(SOURCE, false);
+		out.preserve_data(&previous, false);
+
+		let data = data(&out);
+		assert!(self::data(&previous).iter().all(|(key, value)| data.get(key) == Some(value)));
+	}
+
+	#[test]
+	fn data_the_export_sets_wins_over_the_earlier_export() {
+		let source = "=Basic=\n@data flag=blue\nFront: a\nBack: b\n";
+		let previous = synced(export(source, false), r#"{"flag":"red","ease":"2500"}"#);
+		let mut out = export(source, false);
+		out.preserve_data(&previous, false);
+
+		let data = data(&out);
+		assert_eq!(data["flag"], "blue");
+		assert_eq!(data["ease"], "2500");
+	}
+
+	#[test]
+	fn resetting_scheduling_keeps_everything_else() {
+		let previous =
+			synced(export(SOURCE, false), r#"{"ease":"2500","interval":"4","due":"12","flag":"red"}"#);
+		let mut out = export(SOURCE, true);
+		out.preserve_data(&previous, true);
+
+		let data = data(&out);
+		assert_eq!(data.get("flag").and_then(Value::as_str), Some("red"));
+		assert!(Schedule::KEYS.iter().all(|key| !data.contains_key(*key)));
+	}
+
+	#[test]
+	fn resetting_scheduling_drops_what_the_notes_declare() {
+		let source = concat!(
+			"=Basic=\n",
+			"@data ease=2500\n@data interval=4\n@data due=12\n@data source=book\n",
+			"Front: a\nBack: b\n"
+		);
+		let data = data(&export(source, true));
+
+		assert_eq!(data.get("source").and_then(Value::as_str), Some("book"));
+		assert!(Schedule::KEYS.iter().all(|key| !data.contains_key(*key)));
+	}
+
+	#[test]
+	fn notes_anki_never_saw_are_left_alone() {
+		let previous = synced(export("=Basic=\nFront: x\nBack: y\n", false), r#"{"ease":"2500"}"#);
+		let mut out = export(SOURCE, false);
+		out.preserve_data(&previous, false);
+
+		assert!(!data(&out).contains_key("ease"));
+	}
+}
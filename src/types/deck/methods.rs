@@ -1,153 +1,761 @@
-use std::{fs, mem, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, fs, mem, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
 
 use ariadne::{Color, Label, Report, ReportKind, Source};
-use chumsky::{Parser, input::Input, span::SimpleSpan};
-use gix::{Commit, Repository, Tree, object::tree::Entry};
-use logos::Logos;
+use chumsky::{Parser, error::Rich, input::Input, span::SimpleSpan};
+use gix::{ObjectId, Tree};
 use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
 
-use crate::{change_resolver::resolve_changes, change_router::determine_changes, deck_locator::scan_deck_contents, error::DeckError, model_loader, parse::{ImportExpander, Token, flash}, types::{BEntry, crowd_anki_config::DeckConfig, deck::Deck, note::{Identified, Note, NoteModel}, note_methods::Identifiable}, uuid_generator};
+use crate::{change_resolver::resolve_changes, change_router::determine_changes, deck_locator::scan_deck_contents, error::DeckError, history::{CommitMeta, GixHistory, HistoryProvider, MemoryHistory, PendingHistory, UNKNOWN_AUTHOR, WorkingTreeHistory}, media, model_loader, parse::{Diagnostic, ImportExpander, Token, decode_source, extract_file_tags, flash, lex}, types::{crowd_anki_config::{CONFIG_FILES, ConfigOverrides, DeckConfig, Normalization, NoteSeparator, Schedule}, deck::{Deck, MergePolicy, MergeReport, Repair}, note::{Identified, Note, NoteModel, TextElement}, note_methods::Identifiable}, uuid_generator::{self, HostContext, HostScoped, UuidStrategy}};
 
-pub fn get_file_history<'a>(
-	vcs: &'a Repository,
-	target: &str,
-) -> Result<Vec<(gix::object::tree::Entry<'a>, gix::Commit<'a>)>, DeckError> {
-	info!("Finding history of file: {}", target);
+impl<'b> super::Deck<'b> {
+	#[instrument(skip(deck_path))]
+	pub fn from<P: AsRef<Path>>(deck_path: P) -> Result<Self, DeckError> {
+		Self::from_with_progress(deck_path, None)
+	}
+
+	/// Same as `from`, with `overrides` taking precedence over both the deck's
+	/// config and the environment
+	#[instrument(skip(deck_path))]
+	pub fn from_with_overrides<P: AsRef<Path>>(
+		deck_path: P,
+		overrides: ConfigOverrides,
+	) -> Result<Self, DeckError> {
+		let deck_path = deck_path.as_ref();
+		Self::build(deck_path, GixHistory::discover(deck_path)?, HostScoped, overrides, None)
+	}
+
+	/// Same as `from`, reporting `(current, total)` to the callback as each
+	/// commit in the history is processed
+	#[instrument(skip(deck_path, progress))]
+	pub fn from_with_progress<P: AsRef<Path>>(
+		deck_path: P,
+		progress: Option<&dyn Fn(usize, usize)>,
+	) -> Result<Self, DeckError> {
+		let deck_path = deck_path.as_ref();
+		Self::from_history(deck_path, GixHistory::discover(deck_path)?, progress)
+	}
+
+	/// Build the deck as it stood at `reference` (a branch, tag, or commit)
+	/// rather than HEAD, without needing it checked out. Models and
+	/// configuration are still read from the working tree.
+	#[instrument(skip(deck_path))]
+	pub fn from_ref<P: AsRef<Path>>(deck_path: P, reference: &str) -> Result<Self, DeckError> {
+		let deck_path = deck_path.as_ref();
+		Self::from_history(deck_path, GixHistory::discover(deck_path)?.at(reference)?, None)
+	}
+
+	/// Build a deck as the next commit would make it, the working tree taken as
+	/// a commit on top of the history, so notes keep whatever identities they'd
+	/// carry through it. `overrides` take precedence as in `from_with_overrides`
+	#[instrument(skip(deck_path))]
+	pub fn from_pending_with_overrides<P: AsRef<Path>>(
+		deck_path: P,
+		overrides: ConfigOverrides,
+	) -> Result<Self, DeckError> {
+		let deck_path = deck_path.as_ref();
+		let history = PendingHistory::new(GixHistory::discover(deck_path)?, deck_path);
+		Self::build(deck_path, history, HostScoped, overrides, None)
+	}
+
+	/// Build a deck from the files as they are on disk, ignoring git entirely.
+	/// Identities are derived fresh on every call, so this is only suitable for
+	/// previewing a deck that hasn't been committed yet.
+	#[instrument(skip(deck_path))]
+	pub fn from_working_tree<P: AsRef<Path>>(deck_path: P) -> Result<Self, DeckError> {
+		Self::from_working_tree_with_overrides(deck_path, ConfigOverrides::default())
+	}
+
+	/// Same as `from_working_tree`, with `overrides` taking precedence over both
+	/// the deck's config and the environment
+	#[instrument(skip(deck_path))]
+	pub fn from_working_tree_with_overrides<P: AsRef<Path>>(
+		deck_path: P,
+		overrides: ConfigOverrides,
+	) -> Result<Self, DeckError> {
+		let deck_path = deck_path.as_ref();
+		Self::build(deck_path, WorkingTreeHistory::new(deck_path), HostScoped, overrides, None)
+	}
+
+	/// Build a deck whose card history is drawn from an arbitrary provider
+	/// rather than the git repository inside the deck directory
+	#[instrument(skip(deck_path, history_provider, progress))]
+	pub fn from_history<P: AsRef<Path>>(
+		deck_path: P,
+		history_provider: impl HistoryProvider + 'static,
+		progress: Option<&dyn Fn(usize, usize)>,
+	) -> Result<Self, DeckError> {
+		Self::from_history_with_strategy(deck_path, history_provider, HostScoped, progress)
+	}
+
+	/// Same as `from_history`, identifying notes through `strategy` rather
+	/// than by their content within the deck's host
+	#[instrument(skip(deck_path, history_provider, strategy, progress))]
+	pub fn from_history_with_strategy<P: AsRef<Path>>(
+		deck_path: P,
+		history_provider: impl HistoryProvider + 'static,
+		strategy: impl UuidStrategy + 'static,
+		progress: Option<&dyn Fn(usize, usize)>,
+	) -> Result<Self, DeckError> {
+		let overrides = ConfigOverrides::default();
+		Self::build(deck_path.as_ref(), history_provider, strategy, overrides, progress)
+	}
+
+	/// Where every constructor reading a deck directory ends up. `overrides` are
+	/// layered over the environment's, which are layered over the config file.
+	fn build(
+		deck_path: &Path,
+		history_provider: impl HistoryProvider + 'static,
+		strategy: impl UuidStrategy + 'static,
+		overrides: ConfigOverrides,
+		progress: Option<&dyn Fn(usize, usize)>,
+	) -> Result<Self, DeckError> {
+		info!("Initializing deck from: {:?}", deck_path);
+
+		// Scan deck contents
+		let (model_paths, card_paths) = scan_deck_contents(deck_path)
+			.map_err(|e| DeckError::DeckInit(format!("Failed to scan deck contents: {}", e)))?;
+
+		if card_paths.is_empty() {
+			warn!("No card files found in deck directory");
+		}
+
+		// Load models
+		let models = model_loader::load_models(&model_paths, deck_path)
+			.map_err(|e| DeckError::DeckInit(format!("Failed to load models: {}", e)))?;
+
+		info!("Loaded {} models", models.len());
+
+		// Load the configuration, in whichever format the deck keeps it, before
+		// anything derived from it so overrides reach the identities too
+		let mut configuration = DeckConfig::load(deck_path)?;
+		ConfigOverrides::from_env().then(overrides).apply(&mut configuration);
+
+		let mut cards = Vec::new();
+		let mut created = HashMap::new();
+		let mut host_uuid = None;
+
+		// Each tracked file is resolved against its own history, the index first
+		for (file, path) in configuration.tracked_files() {
+			// Generating against the initial point of creation for the file, taking into
+			// account renames. This should keep things stable as long as the git repo is
+			// the token of trade
+			let history = match history_provider.file_history(&path) {
+				// The file is there, the user just hasn't committed it yet
+				Err(DeckError::FileNotInHistory(target)) if deck_path.join(&target).is_file() => {
+					error!("{} exists in the working tree but has never been committed", target);
+					return Err(DeckError::Uncommitted(target));
+				}
+				Err(DeckError::FileNotInHistory(_)) if file != path => {
+					error!("{} is tracked through {}, which isn't in the history", file, path);
+					return Err(DeckError::TrackedPathNotInHistory(file, path));
+				}
+				history => history?,
+			};
+
+			// Store all content strings so they live long enough
+			let content: Vec<String> = history
+				.iter()
+				.map(|(blob, _)| {
+					get_content(
+						deck_path,
+						&file,
+						blob,
+						configuration.tag_imports,
+						configuration.normalization,
+					)
+				})
+				.collect::<Result<Vec<_>, DeckError>>()?;
+
+			let commits: Vec<&CommitMeta> = history.iter().map(|(_, meta)| meta).collect();
 
-	let mut history = Vec::new();
-	let mut head = vcs.head()?;
-	let revwalk = vcs.rev_walk([head.peel_to_object()?.id()]);
+			// Decks can opt into their own scope, keeping them distinct from identical
+			// decks. Every file shares the host the index's history gives
+			let root = configuration.namespace.unwrap_or(uuid_generator::DEFAULT_NAMESPACE);
+			let host = match (host_uuid, &configuration.host) {
+				(Some(host), _) => host,
+				// A configured host stands in for whoever happened to make the first commit
+				(None, Some(host)) => uuid_generator::create_configured_host_uuid(&root, host),
+				(None, None) => commits
+					.first()
+					.map(|commit| uuid_generator::create_host_uuid(&root, commit.author.clone(), commit.time))
+					.ok_or(DeckError::EmptyHistory)?,
+			};
+			host_uuid = Some(host);
 
-	for commit_id in revwalk.all()? {
-		let commit_id = commit_id?;
-		let commit = vcs.find_commit(commit_id.id())?;
-		let tree = commit.tree()?;
+			// SAFETY: We use unsafe here to work around Rust's self-referential struct
+			// limitations. The cards will contain references to models and content. We
+			// construct the cards first with a temporary lifetime, then move everything
+			// into the Deck together. The safety invariant is: as long as the Deck
+			// exists, models and content exist, so the references in cards remain valid
+			// for the lifetime 'b of the Deck.
+			let (file_cards, file_created) = unsafe {
+				// Process with temporary lifetime
+				let (temp_cards, created) = process_card_history(
+					models.as_ref(),
+					content.as_ref(),
+					&commits,
+					&strategy,
+					&HostContext { host_uuid: host },
+					&configuration,
+					progress,
+				)?;
 
-		// Check if file exists in this commit
-		let current_entry = tree.lookup_entry_by_path(target)?.filter(|e| e.mode().is_blob());
+				// Transmute to the target lifetime 'b
+				// This is safe because we're about to move models and content into the Deck,
+				// and the cards will be moved along with them
+				(
+					mem::transmute::<Vec<Identified<Note<'_>>>, Vec<Identified<Note<'b>>>>(temp_cards),
+					created,
+				)
+			};
 
-		if current_entry.is_none() {
-			continue; // File doesn't exist in this commit
+			info!("Resolved {} notes from {} through {}", file_cards.len(), file, path);
+			cards.extend(file_cards);
+			created.extend(file_created);
 		}
 
-		let current_entry = current_entry.unwrap();
-		let parent_ids: Vec<_> = commit.parent_ids().collect();
+		let host_uuid = host_uuid.ok_or(DeckError::EmptyHistory)?;
 
-		if parent_ids.is_empty() {
-			// Initial commit with the file
-			info!("File created in initial commit {}", commit.id());
-			history.push((current_entry, commit));
-			continue;
+		let media = collect_media(deck_path, &cards)?;
+
+		info!("Deck initialized successfully");
+		Ok(Self {
+			path: deck_path.to_path_buf(),
+			models,
+			history: Box::new(history_provider),
+			cards,
+			configuration,
+			media,
+			host_uuid,
+			strategy: Box::new(strategy),
+			created,
+		})
+	}
+
+	/// Build a deck entirely in memory, with no git repository or anything on
+	/// disk. Each file's revisions are given oldest first and replayed as
+	/// consecutive commits, with notes identified within `host`. `index.flash`
+	/// is required, any other .flash file is tracked alongside it as `track`
+	/// would, its notes following the index's. Imports aren't expanded and
+	/// media isn't resolved, as there's nothing to resolve them against.
+	#[instrument(skip(models, files))]
+	pub fn from_parts(
+		models: Vec<NoteModel>,
+		files: Vec<(String, Vec<String>)>,
+		host: Uuid,
+	) -> Result<Self, DeckError> {
+		let mut configuration = DeckConfig {
+			crowdanki_uuid: host.to_string(),
+			name: "flash".to_string(),
+			..Default::default()
+		};
+
+		let mut history = MemoryHistory::new();
+		for (name, revisions) in files {
+			if !name.ends_with(".flash") {
+				return Err(DeckError::DeckInit(format!("{} isn't a card file", name)));
+			}
+			if name != "index.flash" {
+				configuration.track.insert(name.clone(), name.clone());
+			}
+
+			for (idx, revision) in revisions.into_iter().enumerate() {
+				let meta = CommitMeta {
+					id:     format!("MEMORY-{}", idx),
+					author: UNKNOWN_AUTHOR.to_string(),
+					time:   idx as i64,
+				};
+				history.record(&name, revision, meta);
+			}
+		}
+
+		let mut cards = Vec::new();
+		let mut created = HashMap::new();
+		for (file, path) in configuration.tracked_files() {
+			let revisions = history.file_history(&path)?;
+			let content: Vec<String> = revisions
+				.iter()
+				.map(|(blob, _)| decode_revision(&file, blob, configuration.normalization))
+				.collect::<Result<Vec<_>, DeckError>>()?;
+			let commits: Vec<&CommitMeta> = revisions.iter().map(|(_, meta)| meta).collect();
+
+			// SAFETY: As in `from_history`, the cards borrow the models, which move into
+			// the deck alongside them
+			let (file_cards, file_created) = unsafe {
+				let (temp_cards, created) = process_card_history(
+					models.as_ref(),
+					content.as_ref(),
+					&commits,
+					&HostScoped,
+					&HostContext { host_uuid: host },
+					&configuration,
+					None,
+				)?;
+
+				(
+					mem::transmute::<Vec<Identified<Note<'_>>>, Vec<Identified<Note<'b>>>>(temp_cards),
+					created,
+				)
+			};
+
+			cards.extend(file_cards);
+			created.extend(file_created);
+		}
+
+		info!("Built {} notes in memory", cards.len());
+		Ok(Self {
+			path: PathBuf::new(),
+			models,
+			history: Box::new(history),
+			cards,
+			configuration,
+			media: Vec::new(),
+			host_uuid: host,
+			strategy: Box::new(HostScoped),
+			created,
+		})
+	}
+
+	/// Parse the deck as it sits in the working tree, reporting every problem
+	/// in the source rather than stopping at the first broken note, each with
+	/// the card file it's in. Every file `track` names is read besides the
+	/// index. History is never consulted, so this works the same whether or not
+	/// it's committed.
+	#[instrument(skip(deck_path))]
+	pub fn diagnose<P: AsRef<Path>>(deck_path: P) -> Result<Vec<(String, Diagnostic)>, DeckError> {
+		let deck_path = deck_path.as_ref();
+
+		let (model_paths, _) = scan_deck_contents(deck_path)?;
+		let models = model_loader::load_models(&model_paths, deck_path)?;
+		let configuration = DeckConfig::load(deck_path)?;
+
+		let mut diagnostics = Vec::new();
+		for (file, _) in configuration.tracked_files() {
+			let blob = fs::read(deck_path.join(&file))?;
+			// Tags never decide whether a note parses, so imports go untagged here, and
+			// the source is left as written so spans point into the file as it is
+			let content = get_content(deck_path, &file, &blob, false, Normalization::None)?;
+
+			let (_, found) = Self::parse_cards_tolerant(&models, &content, configuration.note_separator);
+			info!("Found {} problems in {}", found.len(), file);
+			diagnostics.extend(found.into_iter().map(|diagnostic| (file.clone(), diagnostic)));
 		}
+		Ok(diagnostics)
+	}
 
-		// Check if file was added or modified compared to ANY parent
-		let mut file_changed = false;
+	/// Append a note to the deck, identifying it against the deck's host the
+	/// same way the history walk would once the note is committed. This only
+	/// touches the in-memory cards, writing the note to a .flash file and
+	/// committing it is left to the caller.
+	#[instrument(skip(self, note))]
+	pub fn add_note(&mut self, mut note: Note<'b>) -> Result<Uuid, DeckError> {
+		note.normalize(self.configuration.normalization);
+		if self.configuration.trim_fields {
+			note.trim_fields();
+		}
 
-		for parent_id in parent_ids {
-			let parent_commit = vcs.find_commit(parent_id)?;
-			let parent_tree = parent_commit.tree()?;
-			let parent_entry = parent_tree.lookup_entry_by_path(target)?.filter(|e| e.mode().is_blob());
+		let id = self.strategy.note_id(&note, &self.host_context());
 
-			match parent_entry {
+		if self.cards.iter().any(|card| card.id == id) {
+			warn!("A note with identical content already exists as {}", id);
+			return Err(DeckError::DuplicateNote(id));
+		}
+
+		self.cards.push(note.identified(id));
+
+		debug!("Added note {}", id);
+		Ok(id)
+	}
+
+	/// Bring another deck's notes into this one, matching them up by identity.
+	/// Notes only the other deck holds are appended, and notes held by both
+	/// are kept once, with `policy` deciding whose copy wins when their content
+	/// differs. Under `MergePolicy::Error` any such conflict fails the merge
+	/// before anything is changed.
+	///
+	/// The other deck's notes bring a copy of their model along rather than
+	/// adding it to this deck's models, which the existing notes borrow from.
+	#[instrument(skip(self, other))]
+	pub fn merge(&mut self, other: Deck<'_>, policy: MergePolicy) -> Result<MergeReport, DeckError> {
+		let positions: HashMap<Uuid, usize> =
+			self.cards.iter().enumerate().map(|(idx, card)| (card.id, idx)).collect();
+
+		let conflicts: Vec<Uuid> = other
+			.cards
+			.iter()
+			.filter(|theirs| {
+				positions.get(&theirs.id).is_some_and(|&idx| self.cards[idx].inner != theirs.inner)
+			})
+			.map(|theirs| theirs.id)
+			.collect();
+
+		if policy == MergePolicy::Error && !conflicts.is_empty() {
+			warn!("Refusing to merge {} conflicting notes", conflicts.len());
+			return Err(DeckError::MergeConflicts(conflicts));
+		}
+
+		let mut added = Vec::new();
+		for theirs in other.cards {
+			let theirs = Identified { id: theirs.id, inner: theirs.inner.into_owned() };
+			match positions.get(&theirs.id) {
 				None => {
-					// File didn't exist in this parent - it was added
-					file_changed = true;
-					info!("File added in commit {} (from parent {})", commit.id(), parent_id);
-					break;
-				}
-				Some(entry) => {
-					// File exists in parent - check if it changed
-					if entry.oid() != current_entry.oid() {
-						file_changed = true;
-						break;
-					}
+					added.push(theirs.id);
+					self.cards.push(theirs);
 				}
+				Some(&idx) if policy == MergePolicy::Theirs => self.cards[idx] = theirs,
+				Some(_) => {}
 			}
 		}
 
-		if file_changed {
-			history.push((current_entry, commit));
+		// A shared note was first written whenever the earlier deck had it
+		for (id, time) in other.created {
+			self.created.entry(id).and_modify(|ours| *ours = (*ours).min(time)).or_insert(time);
 		}
+		for path in other.media {
+			if !self.media.contains(&path) {
+				self.media.push(path);
+			}
+		}
+
+		info!("Merged in {} notes with {} conflicts", added.len(), conflicts.len());
+		Ok(MergeReport { added, conflicts })
 	}
 
-	// Reverse to get chronological order (oldest first)
-	history.reverse();
+	/// Reassign identities after an intentional content migration the history
+	/// walk can't follow, such as a bulk find-replace. `mapping` returns the
+	/// identity a note should carry, or `None` to leave it as is. Nothing
+	/// changes if the result would have two notes sharing an identity.
+	#[instrument(skip(self, mapping))]
+	pub fn remap_uuids(
+		&mut self,
+		mapping: impl Fn(&Note) -> Option<Uuid>,
+	) -> Result<usize, DeckError> {
+		let remapped: Vec<Option<Uuid>> = self.cards.iter().map(|card| mapping(&card.inner)).collect();
 
-	if history.is_empty() {
-		error!("File not found in repository history");
-		Err(DeckError::FileNotInHistory(target.to_string()))
-	} else {
-		info!("Found {} commits in file history", history.len());
-		Ok(history)
+		let mut seen = HashSet::new();
+		for (card, new_id) in self.cards.iter().zip(&remapped) {
+			let id = new_id.unwrap_or(card.id);
+			if !seen.insert(id) {
+				error!("Remapping would leave more than one note as {}", id);
+				return Err(DeckError::DuplicateNote(id));
+			}
+		}
+
+		let mut count = 0;
+		for (card, new_id) in self.cards.iter_mut().zip(remapped) {
+			if let Some(id) = new_id.filter(|id| *id != card.id) {
+				debug!("Remapping note {} to {}", card.id, id);
+				card.id = id;
+				count += 1;
+			}
+		}
+
+		info!("Remapped {} notes", count);
+		Ok(count)
 	}
-}
 
-impl<'b> super::Deck<'b> {
-	#[instrument(skip(deck_path))]
-	pub fn from<P: AsRef<Path>>(deck_path: P) -> Result<Self, DeckError> {
-		let deck_path = deck_path.as_ref();
-		info!("Initializing deck from: {:?}", deck_path);
+	/// Give every note sharing its identity with an earlier one an identity of
+	/// its own, derived from its content with a count of the earlier duplicates
+	/// appended, so repairing the same deck again gives the same identities.
+	/// The first note of each keeps its identity. Anki sees the rest as new
+	/// notes, so their review history is lost.
+	#[instrument(skip(self))]
+	pub fn repair_duplicates(&mut self) -> Vec<Repair> {
+		let host = self.host_uuid;
+		let mut taken: HashSet<Uuid> = self.cards.iter().map(|card| card.id).collect();
+		let mut seen: HashMap<Uuid, usize> = HashMap::new();
+		let mut repairs = Vec::new();
 
-		// Scan deck contents
-		let (model_paths, card_paths) = scan_deck_contents(deck_path)
-			.map_err(|e| DeckError::DeckInit(format!("Failed to scan deck contents: {}", e)))?;
+		for card in &mut self.cards {
+			let count = seen.entry(card.id).or_default();
+			*count += 1;
+			if *count == 1 {
+				continue;
+			}
 
-		if card_paths.is_empty() {
-			warn!("No card files found in deck directory");
+			// Content that happens to hash to an identity in use moves on to the next
+			let content = card.inner.to_content_string();
+			let new = (*count - 1..)
+				.map(|n| uuid_generator::generate_note_uuid(&host, &format!("{}#{}", content, n)))
+				.find(|id| !taken.contains(id))
+				.expect("some disambiguator gives an unused identity");
+			taken.insert(new);
+
+			warn!("Note {} shares its identity, repaired as {}", card.id, new);
+			if let Some(time) = self.created.get(&card.id).copied() {
+				self.created.insert(new, time);
+			}
+			repairs.push(Repair { old: card.id, new });
+			card.id = new;
 		}
 
-		// Load models
-		let models = model_loader::load_models(&model_paths, deck_path)
-			.map_err(|e| DeckError::DeckInit(format!("Failed to load models: {}", e)))?;
+		info!("Repaired {} duplicate identities", repairs.len());
+		repairs
+	}
 
-		info!("Loaded {} models", models.len());
+	/// Substitute the field macros: `{{@commit}}` with the short hash of the
+	/// commit the deck is read as of, and `{{@date}}` with today's date (UTC).
+	/// Notes are identified by their fields as written, macros and all, before
+	/// this, so what a macro expands to never changes an identity.
+	#[instrument(skip(self))]
+	pub fn expand_macros(&mut self) {
+		let commit = self
+			.history
+			.head_commit()
+			.map_or_else(|| "uncommitted".to_string(), |id| id.chars().take(7).collect());
+		let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+		let date = civil_date(seconds);
 
-		// Open Git repository
-		let repo_path = deck_path.join(".git");
-		debug!("Opening repository at: {:?}", repo_path);
-		let backing_vcs = gix::open(repo_path)
-			.map_err(|e| DeckError::DeckInit(format!("Failed to open git repository: {}", e)))?;
+		let parts = self
+			.cards
+			.iter_mut()
+			.flat_map(|card| card.inner.fields.iter_mut())
+			.flat_map(|field| field.content.iter_mut());
+		for part in parts {
+			if let TextElement::Text(text) | TextElement::Html(text) = part
+				&& text.contains("{{@")
+			{
+				*text = text.replace("{{@commit}}", &commit).replace("{{@date}}", &date);
+			}
+		}
+	}
 
-		// Load or create default configuration
-		let config_path = deck_path.join("config.toml");
+	/// Order the notes by their model's sort field, or first field where the
+	/// model doesn't name one, as Anki orders its browser. Comparison ignores
+	/// case, notes sorting the same keep their order, and identities are left
+	/// as they are.
+	#[instrument(skip(self))]
+	pub fn sort_by_sort_field(&mut self) {
+		self.cards.sort_by_cached_key(|card| sort_key(&card.inner));
+	}
 
-		let config_content = fs::read_to_string(&config_path)
-			.map_err(|_| DeckError::DeckConfigNotFound(config_path.clone()))?;
+	/// Drop the scheduling notes would carry into Anki, both the `[schedule]`
+	/// tables of the config and the ease, interval and due a note's own data
+	/// declares, so they arrive as new cards. Other data is kept as it is.
+	#[instrument(skip(self))]
+	pub fn reset_scheduling(&mut self) {
+		self.configuration.schedule.clear();
+		for card in &mut self.cards {
+			card.inner.data.retain(|key, _| !Schedule::KEYS.contains(&key.as_str()));
+		}
+	}
 
-		let configuration: DeckConfig = toml::from_str(&config_content)?;
+	/// What the deck's strategy identifies notes against
+	pub fn host_context(&self) -> HostContext { HostContext { host_uuid: self.host_uuid } }
 
-		// Generating against the initial point of creation for the file, taking into
-		// account renames. This should keep things stable as long as the git repo is
-		// the token of trade
-		let vcs = backing_vcs.clone();
-		let history = get_file_history(&vcs, "index.flash")?;
+	/// Every revision of the deck's index, oldest first, with imports expanded.
+	/// Files tracked apart from the index aren't included.
+	#[instrument(skip(self))]
+	pub fn revisions(&self) -> Result<Vec<(String, CommitMeta)>, DeckError> {
+		let (_, index) = self.configuration.tracked_files().swap_remove(0);
 
-		// Store all content strings so they live long enough
-		let content: Vec<String> = history
+		self
+			.history
+			.file_history(&index)?
+			.into_iter()
+			.map(|(blob, meta)| {
+				// A deck built in memory has nothing to expand imports against
+				let content = if self.in_memory() {
+					decode_revision("index.flash", &blob, self.configuration.normalization)?
+				} else {
+					get_content(
+						&self.path,
+						"index.flash",
+						&blob,
+						self.configuration.tag_imports,
+						self.configuration.normalization,
+					)?
+				};
+				Ok((content, meta))
+			})
+			.collect()
+	}
+
+	/// Whether the deck was built through `from_parts`, with nothing on disk
+	/// behind it
+	pub fn in_memory(&self) -> bool { self.path.as_os_str().is_empty() }
+
+	/// The deck's notes as they stood at `commit`, with identities resolved the
+	/// way they were at the time. A commit that didn't touch the cards sees
+	/// them as of the revisions it descends from, going by ancestry rather than
+	/// commit times, which clocks and rebases make unreliable.
+	#[instrument(skip(self))]
+	pub fn notes_at(&self, commit: ObjectId) -> Result<Vec<Identified<Note<'static>>>, DeckError> {
+		let mut revisions = self.revisions()?;
+		let commit_id = commit.to_string();
+
+		// Histories without ancestry are linear, the revisions up to the commit are
+		// all it descends from
+		match self.history.ancestors(&commit_id) {
+			Some(ancestors) => revisions.retain(|(_, meta)| ancestors.contains(&meta.id)),
+			None => {
+				let end = revisions.iter().position(|(_, meta)| meta.id == commit_id).ok_or_else(|| {
+					DeckError::Git(format!("Commit {} is not in the deck's history", commit_id))
+				})?;
+				revisions.truncate(end + 1);
+			}
+		}
+
+		if revisions.is_empty() {
+			debug!("The cards didn't exist yet as of {}", commit_id);
+			return Ok(Vec::new());
+		}
+
+		let (content, commits): (Vec<String>, Vec<&CommitMeta>) =
+			revisions.iter().map(|(content, meta)| (content.clone(), meta)).unzip();
+
+		let (notes, _) = process_card_history(
+			&self.models,
+			&content,
+			&commits,
+			self.strategy.as_ref(),
+			&self.host_context(),
+			&self.configuration,
+			None,
+		)?;
+
+		Ok(
+			notes
+				.into_iter()
+				.map(|note| Identified { id: note.id, inner: note.inner.into_owned() })
+				.collect(),
+		)
+	}
+
+	/// Find every media file referenced by the cards or the models' fields that
+	/// doesn't exist on disk, logging what referenced each so the source can be
+	/// fixed
+	#[instrument(skip(self))]
+	pub fn verify_media(&self) -> Result<Vec<PathBuf>, DeckError> {
+		let mut missing = Vec::new();
+
+		for card in &self.cards {
+			for declared in &card.inner.media {
+				let path = self.path.join(declared);
+				if !path.is_file() {
+					warn!("Note {} references missing media {:?}", card.id, declared);
+					if !missing.contains(&path) {
+						missing.push(path);
+					}
+				}
+			}
+
+			// Media referenced from within the fields themselves
+			for field in &card.inner.fields {
+				for part in &field.content {
+					let (TextElement::Text(text) | TextElement::Html(text)) = part else {
+						continue;
+					};
+
+					for reference in media::extract_references(text) {
+						let path = self.path.join(&reference.file);
+						if !path.is_file() {
+							warn!(
+								"Field '{}' of note {} references missing media {:?}",
+								field.name, card.id, reference.file
+							);
+							if !missing.contains(&path) {
+								missing.push(path);
+							}
+						}
+					}
+				}
+			}
+		}
+
+		for model in &self.models {
+			for field in &model.fields {
+				for declared in field.associated_media.iter().flatten() {
+					let path = self.path.join(declared);
+					if !path.is_file() {
+						warn!(
+							"Field '{}' of model '{}' references missing media {:?}",
+							field.name, model.name, declared
+						);
+						if !missing.contains(&path) {
+							missing.push(path);
+						}
+					}
+				}
+			}
+		}
+
+		info!("Found {} missing media files", missing.len());
+		Ok(missing)
+	}
+
+	/// Every note referencing the media file, whether declared or referenced
+	/// from within its fields. The file is named relative to the deck directory,
+	/// or by its bare file name as Anki's flat media folder knows it
+	#[instrument(skip(self))]
+	pub fn notes_using_media(&self, filename: &str) -> Vec<Uuid> {
+		let wanted = Path::new(filename);
+
+		self
+			.cards
 			.iter()
-			.map(|(entry, _)| get_content(&backing_vcs, entry))
-			.collect::<Result<Vec<_>, DeckError>>()?;
-
-		// SAFETY: We use unsafe here to work around Rust's self-referential struct
-		// limitations. The cards will contain references to models and content. We
-		// construct the cards first with a temporary lifetime, then move everything
-		// into the Deck together. The safety invariant is: as long as the Deck
-		// exists, models and content exist, so the references in cards remain valid
-		// for the lifetime 'b of the Deck.
-		let cards = unsafe {
-			// Process with temporary lifetime
-			let temp_cards =
-				process_card_history(models.as_ref(), content.as_ref(), &backing_vcs, &history)?;
-
-			// Transmute to the target lifetime 'b
-			// This is safe because we're about to move models and content into the Deck,
-			// and the cards will be moved along with them
-			mem::transmute::<Vec<Identified<Note<'_>>>, Vec<Identified<Note<'b>>>>(temp_cards)
-		};
+			.filter(|card| {
+				referenced_media(&card.inner).iter().any(|reference| {
+					reference == wanted
+						|| (wanted.parent() == Some(Path::new(""))
+							&& reference.file_name() == Some(wanted.as_os_str()))
+				})
+			})
+			.map(|card| card.id)
+			.collect()
+	}
 
-		info!("Deck initialized successfully");
-		Ok(Self { models, backing_vcs, cards, configuration })
+	/// Every file in the deck directory that's neither part of the deck's
+	/// source nor referenced by a note or model field, sorted by path. Card
+	/// files, configs, model directories and hidden entries aren't media. A
+	/// deck built in memory has no directory to hold any.
+	#[instrument(skip(self))]
+	pub fn orphan_media(&self) -> Result<Vec<PathBuf>, DeckError> {
+		if self.in_memory() {
+			return Ok(Vec::new());
+		}
+
+		let mut referenced: HashSet<PathBuf> = self
+			.cards
+			.iter()
+			.flat_map(|card| referenced_media(&card.inner))
+			.map(|reference| self.path.join(reference))
+			.collect();
+		referenced.extend(
+			self
+				.models
+				.iter()
+				.flat_map(|model| &model.fields)
+				.flat_map(|field| field.associated_media.iter().flatten())
+				.map(|declared| self.path.join(declared)),
+		);
+
+		let mut orphans = Vec::new();
+		let mut pending = vec![self.path.clone()];
+		while let Some(dir) = pending.pop() {
+			for entry in fs::read_dir(&dir)? {
+				let path = entry?.path();
+				let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+				let extension = path.extension().and_then(|ext| ext.to_str());
+
+				if name.starts_with('.') || extension == Some("model") {
+					continue;
+				}
+				if path.is_dir() {
+					pending.push(path);
+				} else if extension != Some("flash")
+					&& !(dir == self.path && CONFIG_FILES.contains(&name))
+					&& !referenced.contains(&path)
+				{
+					orphans.push(path);
+				}
+			}
+		}
+
+		orphans.sort();
+		info!("Found {} orphaned media files", orphans.len());
+		Ok(orphans)
 	}
 
 	#[instrument(skip(self))]
@@ -162,38 +770,60 @@ impl<'b> super::Deck<'b> {
 	pub fn parse_cards<'a>(
 		models: &'a [NoteModel],
 		content: &'a str,
+	) -> Result<Vec<Note<'a>>, DeckError> {
+		Self::parse_cards_separated(models, content, NoteSeparator::default())
+	}
+
+	/// Same as `parse_cards`, for card files whose notes are told apart by
+	/// `separator` rather than a blank line
+	pub fn parse_cards_separated<'a>(
+		models: &'a [NoteModel],
+		content: &'a str,
+		separator: NoteSeparator,
 	) -> Result<Vec<Note<'a>>, DeckError> {
 		debug!("Parsing card content");
 
-		// Create the lexer
-		let token_iter = Token::lexer(content).spanned().map(|(tok, span)| match tok {
-			Ok(t) => (t, span.into()),
-			Err(_) => (Token::Error, span.into()),
-		});
-
-		// Turn the iterator into a Chumsky-compatible stream
-		// We provide a zero-width span at the end of the content for EOI (End Of Input)
-		let eoi = SimpleSpan::from(content.len()..content.len());
-		let token_stream = chumsky::input::Stream::from_iter(token_iter).map(eoi, |(t, s)| (t, s));
-
-		// Parse the stream using the refactored flash parser
-		flash(models).parse(token_stream).into_result().map_err(|errors| {
-			for err in errors {
-				Report::build(ReportKind::Error, ((), err.span().into_range()))
-					.with_config(ariadne::Config::new().with_index_type(ariadne::IndexType::Byte))
-					.with_code(3)
-					.with_message(err.to_string())
-					.with_label(
-						Label::new(((), err.span().into_range()))
-							.with_message(err.reason().to_string())
-							.with_color(Color::Red),
-					)
-					.finish()
-					.eprint(Source::from(content))
-					.unwrap();
-			}
-			DeckError::Parse("".to_string())
-		})
+		let (notes, errors) = parse_with_errors(models, content, separator);
+		if errors.is_empty() {
+			return Ok(notes.unwrap_or_default());
+		}
+
+		for err in errors {
+			Report::build(ReportKind::Error, ((), err.span().into_range()))
+				.with_config(ariadne::Config::new().with_index_type(ariadne::IndexType::Byte))
+				.with_code(3)
+				.with_message(err.to_string())
+				.with_label(
+					Label::new(((), err.span().into_range()))
+						.with_message(err.reason().to_string())
+						.with_color(Color::Red),
+				)
+				.finish()
+				.eprint(Source::from(content))
+				.unwrap();
+		}
+		Err(DeckError::Parse("".to_string()))
+	}
+
+	/// Best effort counterpart to `parse_cards`, for editors that want to show
+	/// what parsed alongside what didn't. Broken notes are skipped rather than
+	/// failing the whole file, and reported in the diagnostics. Notes are told
+	/// apart by `separator`, as with `parse_cards_separated`.
+	#[instrument(skip(models, content))]
+	pub fn parse_cards_tolerant<'a>(
+		models: &'a [NoteModel],
+		content: &'a str,
+		separator: NoteSeparator,
+	) -> (Vec<Note<'a>>, Vec<Diagnostic>) {
+		let (notes, errors) = parse_with_errors(models, content, separator);
+		let diagnostics: Vec<Diagnostic> = errors
+			.into_iter()
+			.map(|err| Diagnostic { span: err.span().into_range(), message: err.to_string() })
+			.collect();
+
+		let notes = notes.unwrap_or_default();
+		debug!("Parsed {} notes with {} diagnostics", notes.len(), diagnostics.len());
+		(notes, diagnostics)
 	}
 
 	#[instrument(skip(self, parent_tree, current_tree))]
@@ -213,65 +843,102 @@ impl<'b> super::Deck<'b> {
 		Ok(())
 	}
 
-	#[instrument(skip(backing_vcs))]
-	pub fn read_file_content(backing_vcs: &Repository, entry: &BEntry) -> Result<String, DeckError> {
-		// Retrieve the entries binary representation from the VCS and serialize as UTF8
-		let binary_blob = backing_vcs.find_blob(entry.0.id())?;
-		let content = String::from_utf8(binary_blob.data.clone()).map_err(|_| {
-			DeckError::InvalidUtf8(backing_vcs.workdir().expect("Worktree should be checked out").into())
-		})?;
-		Ok(content)
-	}
-
-	#[instrument(skip(models, backing_vcs))]
+	#[instrument(skip(models, content))]
 	pub fn generate_note_uuids(
 		models: &[NoteModel],
-		backing_vcs: &Repository,
-		target: (Entry, Commit),
+		content: &str,
+		host_uuid: &Uuid,
 	) -> Result<Vec<Uuid>, DeckError> {
-		let (entry, commit) = target;
-
-		let entry = BEntry::new(&entry)?;
-		let author = commit.author().unwrap_or_default(); // Just ignore if non-existent, although reasonably impossible I think haha
-		let host_uuid =
-			uuid_generator::create_host_uuid(author.name.to_string(), commit.time()?.seconds);
+		let notes = Self::parse_cards(models, content)?;
 
-		let file_content = Self::read_file_content(backing_vcs, &entry)?;
-		let notes = Self::parse_cards(models, &file_content)?;
-
-		let uuids = notes
-			.iter()
-			.map(|note| {
-				let content = note.to_content_string();
-				uuid_generator::generate_note_uuid(&host_uuid, &content)
-			})
-			.collect();
+		let uuids = notes.iter().map(|note| note.stable_uuid(host_uuid)).collect();
 
 		debug!("Generated {} UUIDs", notes.len());
 		Ok(uuids)
 	}
 }
 
-// Parse cards from a string reference
+/// Run the parser over the content, keeping whatever it recovered alongside the
+/// errors it hit on the way
+fn parse_with_errors<'a>(
+	models: &'a [NoteModel],
+	content: &'a str,
+	separator: NoteSeparator,
+) -> (Option<Vec<Note<'a>>>, Vec<Rich<'a, Token<'a>, SimpleSpan>>) {
+	// Create the lexer, file tags are resolved before the parser sees the tokens
+	let (tokens, file_tags) = extract_file_tags(lex(content), content.len());
+	let token_iter = tokens.into_iter().map(|(tok, span)| (tok, SimpleSpan::from(span)));
+
+	// Turn the iterator into a Chumsky-compatible stream
+	// We provide a zero-width span at the end of the content for EOI (End Of Input)
+	let eoi = SimpleSpan::from(content.len()..content.len());
+	let token_stream = chumsky::input::Stream::from_iter(token_iter).map(eoi, |(t, s)| (t, s));
+
+	// Parse the stream using the refactored flash parser
+	flash(models, file_tags, separator).parse(token_stream).into_output_errors()
+}
+
 fn parse_cards_from_content<'a>(
 	models: &'a [NoteModel],
 	content: &'a str,
+	separator: NoteSeparator,
 ) -> Result<Vec<Note<'a>>, DeckError> {
-	Deck::parse_cards(models, content).map_err(|_| DeckError::Parse(String::default()))
+	Deck::parse_cards_separated(models, content, separator)
+		.map_err(|_| DeckError::Parse(String::default()))
+}
+
+/// A note's sort field as text, for ordering notes the way Anki's browser does
+fn sort_key(note: &Note) -> String {
+	let model = &note.model;
+	let Some(name) =
+		model.sort_field.as_ref().or_else(|| model.fields.first().map(|field| &field.name))
+	else {
+		return String::new();
+	};
+
+	note
+		.field(name)
+		.into_iter()
+		.flat_map(|field| &field.content)
+		.map(|part| match part {
+			TextElement::Text(text) | TextElement::Html(text) => text.as_str(),
+			TextElement::Cloze(cloze) => cloze.answer.as_str(),
+		})
+		.collect::<String>()
+		.to_lowercase()
+}
+
+/// The date as YYYY-MM-DD of a time in seconds since the epoch, in UTC
+fn civil_date(seconds: i64) -> String {
+	// Days since 0000-03-01, so leap days fall at the end of each year
+	let days = seconds.div_euclid(86_400) + 719_468;
+	let era = days.div_euclid(146_097);
+	let day_of_era = days.rem_euclid(146_097);
+	let year_of_era =
+		(day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let shifted_month = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+	let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+	let year = year_of_era + era * 400 + i64::from(month <= 2);
+	format!("{:04}-{:02}-{:02}", year, month, day)
 }
 
 // Initialize the first state with UUIDs
 fn initialize_cards<'a>(
-	models: &'a [NoteModel],
-	backing_vcs: &Repository,
-	entry: &Entry,
-	commit: &Commit,
+	strategy: &dyn UuidStrategy,
+	host: &HostContext,
 	cards: Vec<Note<'a>>,
-) -> Result<Vec<Identified<Note<'a>>>, DeckError> {
-	// Generate initial set of UUIDs
-	let uuids = Deck::generate_note_uuids(models, backing_vcs, (entry.clone(), commit.clone()))?;
-
-	Ok(cards.into_iter().zip(uuids).map(|(card, id)| card.identified(id)).collect())
+) -> Vec<Identified<Note<'a>>> {
+	// Identified from the cards as given, so any normalisation already applied
+	// to them carries into the initial set of UUIDs
+	cards
+		.into_iter()
+		.map(|card| {
+			let id = strategy.note_id(&card, host);
+			card.identified(id)
+		})
+		.collect()
 }
 
 /// Interpret the passing of a cycle
@@ -279,59 +946,876 @@ fn process_cycle(
 	last_cards: &[Note],
 	current_cards: &[Note],
 	static_cards: &mut Vec<Identified<Note>>,
+	strategy: &dyn UuidStrategy,
+	host: &HostContext,
+	similarity_threshold: f64,
 ) -> Result<(), DeckError> {
 	// It might be that a change was made but nothing of note happened, like a misc.
 	// newline, check for this.
 	if let Some(changes) = determine_changes(last_cards, current_cards)? {
-		// Assuming resolve_uuids mutates static_cards in place or returns new value
-		// If it returns a new value:
-		resolve_changes(&changes, static_cards, Uuid::default());
+		// Identities are carried forward in place
+		resolve_changes(&changes, static_cards, strategy, host, similarity_threshold);
 	}
 	Ok(())
 }
 
-fn get_content(backing_vcs: &Repository, entry: &Entry) -> Result<String, DeckError> {
-	let file: PathBuf =
-		backing_vcs.git_dir().parent().unwrap().join(PathBuf::from(entry.filename().to_string()));
+/// Every media file the note uses, declared or referenced from its fields, as
+/// written relative to the deck directory
+fn referenced_media(note: &Note) -> Vec<PathBuf> {
+	let mut media = note.media.clone();
+
+	for field in &note.fields {
+		for part in &field.content {
+			if let TextElement::Text(text) | TextElement::Html(text) = part {
+				media.extend(
+					media::extract_references(text)
+						.into_iter()
+						.map(|reference| PathBuf::from(reference.file)),
+				);
+			}
+		}
+	}
+
+	media
+}
+
+/// Resolve every declared media file against the deck directory, failing on
+/// the first one that doesn't exist
+fn collect_media(deck_path: &Path, cards: &[Identified<Note>]) -> Result<Vec<PathBuf>, DeckError> {
+	let mut media = Vec::new();
+
+	for declared in cards.iter().flat_map(|card| &card.inner.media) {
+		let path = deck_path.join(declared);
 
-	let content = Deck::read_file_content(backing_vcs, &entry.try_into()?)?;
+		if !path.is_file() {
+			error!("Declared media file is missing: {:?}", path);
+			return Err(DeckError::MediaNotFound(path));
+		}
 
-	// Expand all imports first
-	let mut expander = ImportExpander::new(file.parent().unwrap_or_else(|| Path::new(".")));
+		if !media.contains(&path) {
+			media.push(path);
+		}
+	}
+
+	debug!("Collected {} media files", media.len());
+	Ok(media)
+}
 
-	Ok(expander.expand(&content, file.as_path()).unwrap())
+/// A revision of a card file as written, decoded and normalized but with its
+/// imports left as they are
+fn decode_revision(
+	target: &str,
+	blob: &[u8],
+	normalization: Normalization,
+) -> Result<String, DeckError> {
+	let content = decode_source(blob.to_vec())
+		.map_err(|reason| DeckError::InvalidUtf8(PathBuf::from(target), reason))?;
+	Ok(normalization.apply(&content))
 }
 
+fn get_content(
+	deck_path: &Path,
+	target: &str,
+	blob: &[u8],
+	tag_imports: bool,
+	normalization: Normalization,
+) -> Result<String, DeckError> {
+	let file = deck_path.join(target);
+
+	let content = decode_source(blob.to_vec()).map_err(|reason| {
+		error!("Failed to decode {:?}: {}", file, reason);
+		DeckError::InvalidUtf8(file.clone(), reason)
+	})?;
+
+	// Expand all imports first. Remote imports are cached within the deck, and
+	// setting FLASH_OFFLINE serves them from that cache alone.
+	let mut expander = ImportExpander::new(file.parent().unwrap_or_else(|| Path::new(".")))
+		.with_cache(deck_path.join(".imports"))
+		.offline(std::env::var_os("FLASH_OFFLINE").is_some())
+		.tag_imports(tag_imports);
+
+	let expanded = expander.expand(&content, file.as_path()).map_err(|reason| {
+		error!("Failed to expand imports of {:?}: {}", file, reason);
+		DeckError::Import(reason)
+	})?;
+
+	// Imports included, before anything is parsed or hashed
+	Ok(normalization.apply(&expanded))
+}
+
+/// The resolved cards, along with when each first appeared under its current
+/// identity
+type ResolvedHistory<'a> = (Vec<Identified<Note<'a>>>, HashMap<Uuid, i64>);
+
 // Main processing logic
 fn process_card_history<'a>(
 	models: &'a [NoteModel],
 	content: &'a [String],
-	backing_vcs: &Repository,
-	history: &[(Entry, Commit)],
-) -> Result<Vec<Identified<Note<'a>>>, DeckError> {
-	let mut history_iter = history.iter();
+	history: &[&CommitMeta],
+	strategy: &dyn UuidStrategy,
+	host: &HostContext,
+	configuration: &DeckConfig,
+	progress: Option<&dyn Fn(usize, usize)>,
+) -> Result<ResolvedHistory<'a>, DeckError> {
+	let report = |current: usize| {
+		if let Some(progress) = progress {
+			progress(current, history.len());
+		}
+	};
+
+	// Trimming happens before anything is hashed or compared, so whitespace at
+	// the edges of a field never registers as a change
+	let parse = |content: &'a str| -> Result<Vec<Note<'a>>, DeckError> {
+		let mut cards = parse_cards_from_content(models, content, configuration.note_separator)?;
+		if configuration.trim_fields {
+			cards.iter_mut().for_each(Note::trim_fields);
+		}
+		Ok(cards)
+	};
+
+	// Every revision is paired with the commit it came from, a mismatch means
+	// they were gathered out of step
+	if content.len() != history.len() {
+		error!("{} revisions of the cards for {} commits", content.len(), history.len());
+		return Err(DeckError::HistoryMismatch(content.len(), history.len()));
+	}
+
+	let mut revisions = content.iter().zip(history);
 
 	// Handle first entry separately
-	let (first_entry, first_commit) = history_iter.next().ok_or_else(|| DeckError::EmptyHistory)?;
+	let (first_content, first_commit) = revisions.next().ok_or(DeckError::EmptyHistory)?;
+
+	let first_cards = parse(first_content)?;
 
-	let first_cards = parse_cards_from_content(models, &content[0])?;
+	// The first revision is what the second is diffed against
+	let mut bygone_cards = first_cards.clone();
 
-	// Blankly initialize, as we immediately overwrite
-	let mut bygone_cards = Vec::with_capacity(first_cards.len());
+	// Later additions are identified against the same host as the originals
+	let mut elder_cards = initialize_cards(strategy, host, first_cards);
 
-	let mut elder_cards =
-		initialize_cards(models, backing_vcs, first_entry, first_commit, first_cards)?;
+	let mut created: HashMap<Uuid, i64> =
+		elder_cards.iter().map(|card| (card.id, first_commit.time)).collect();
+
+	report(1);
 
 	// Process remaining entries
-	for (idx, _entry_info) in history_iter.enumerate() {
-		let cards_of_the_day = parse_cards_from_content(models, &content[idx + 1])?;
+	for (idx, (revision, commit)) in revisions.enumerate() {
+		let cards_of_the_day = parse(revision)?;
 
 		// Make a diff of the changes and update the final cards appropriately
-		process_cycle(&bygone_cards, &cards_of_the_day, &mut elder_cards)?;
+		process_cycle(
+			&bygone_cards,
+			&cards_of_the_day,
+			&mut elder_cards,
+			strategy,
+			host,
+			configuration.similarity_threshold.unwrap_or_default(),
+		)?;
+
+		// Anything without a creation time was born in this commit
+		for card in &elder_cards {
+			created.entry(card.id).or_insert(commit.time);
+		}
 
 		// Cycle complete, the once-new cards lose their youth.
 		bygone_cards = cards_of_the_day;
+
+		report(idx + 2);
+	}
+
+	// Identities that didn't survive to the end are of no interest
+	created.retain(|id, _| elder_cards.iter().any(|card| card.id == *id));
+
+	Ok((elder_cards, created))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{test_support::{self, commit, git, repository}, types::{crowd_anki_models::CrowdAnkiEntity, note::TextElement}};
+
+	const ONE: &str = "=Basic=\nFront: a\nBack: b\n";
+	const TWO: &str = "=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n";
+
+	#[test]
+	fn declared_media_is_exported_in_media_files() {
+		let repo = repository();
+		repo.write("audio.mp3", "");
+		repo.write("images/diagram.png", "");
+		repo.write("index.flash", "=Basic=\nmedia [audio.mp3, images/diagram.png]\nFront: a\nBack: b\n");
+		commit(&repo, "add a", 2_000_000_000);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert_eq!(deck.media, vec![repo.path.join("audio.mp3"), repo.path.join("images/diagram.png")]);
+		let CrowdAnkiEntity::Deck(exported) = CrowdAnkiEntity::try_from(deck).expect("the deck exports")
+		else {
+			panic!("a deck exports as a deck");
+		};
+		assert_eq!(exported.media_files, vec!["audio.mp3", "diagram.png"]);
+	}
+
+	#[test]
+	fn declared_media_sharing_a_file_name_isnt_exported() {
+		let repo = repository();
+		repo.write("a/x.png", "");
+		repo.write("b/x.png", "");
+		repo.write("index.flash", "=Basic=\nmedia [a/x.png, b/x.png]\nFront: a\nBack: b\n");
+		commit(&repo, "add a", 2_000_000_000);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert!(matches!(CrowdAnkiEntity::try_from(deck), Err(DeckError::MediaNameClash(..))));
+	}
+
+	#[test]
+	fn missing_media_is_refused() {
+		let repo = repository();
+		repo.write("index.flash", "=Basic=\nmedia [audio.mp3]\nFront: a\nBack: b\n");
+		commit(&repo, "add a", 2_000_000_000);
+
+		assert!(matches!(Deck::from(&repo.path), Err(DeckError::MediaNotFound(_))));
+	}
+
+	#[test]
+	fn an_uncommitted_index_is_reported_as_such() {
+		let repo = repository();
+		commit(&repo, "config", 1_000);
+		repo.write("index.flash", ONE);
+
+		assert!(
+			matches!(Deck::from(&repo.path), Err(DeckError::Uncommitted(file)) if file == "index.flash")
+		);
+	}
+
+	#[test]
+	fn an_uncommitted_deck_builds_from_the_working_tree() {
+		let repo = repository();
+		repo.write("index.flash", TWO);
+
+		let deck = Deck::from_working_tree(&repo.path).expect("the deck builds");
+		assert_eq!(deck.cards.len(), 2);
+	}
+
+	#[test]
+	fn media_the_fields_reference_is_checked_on_disk() {
+		let scratch = test_support::Scratch::new();
+		scratch.write("present.png", "");
+
+		let source =
+			"=Basic=\nFront: @html <img src=\"present.png\"><img src=\"missing.png\">\nBack: b\n";
+		let mut deck = test_support::deck(&[source]);
+		deck.path = scratch.path.clone();
+
+		assert_eq!(deck.verify_media().expect("the media is checked"), vec![
+			scratch.path.join("missing.png")
+		]);
+	}
+
+	/// The first note of `source`, parsed against models that outlive any deck
+	fn note(source: &'static str) -> Note<'static> {
+		let models = Box::leak(test_support::models().into_boxed_slice());
+		test_support::parse(models, source).remove(0)
+	}
+
+	#[test]
+	fn an_added_note_takes_the_id_committing_it_would_give() {
+		let mut deck = test_support::deck(&[ONE]);
+		let id = deck.add_note(note("=Basic=\nFront: c\nBack: d\n")).expect("the note is new");
+
+		assert_eq!(deck.cards.len(), 2);
+		assert_eq!(deck.cards[1].id, id);
+		assert_eq!(test_support::deck(&[ONE, TWO]).cards[1].id, id);
+	}
+
+	#[test]
+	fn adding_a_note_the_deck_holds_is_refused() {
+		let mut deck = test_support::deck(&[ONE]);
+		let existing = deck.cards[0].id;
+
+		assert!(
+			matches!(deck.add_note(note(ONE)), Err(DeckError::DuplicateNote(id)) if id == existing)
+		);
+		assert_eq!(deck.cards.len(), 1);
+	}
+
+	#[test]
+	fn progress_is_reported_for_each_commit_walked() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		let reported = std::cell::RefCell::new(Vec::new());
+		let progress = |current, total| reported.borrow_mut().push((current, total));
+		Deck::from_with_progress(&repo.path, Some(&progress)).expect("the deck builds");
+
+		assert_eq!(reported.into_inner(), vec![(1, 2), (2, 2)]);
 	}
 
-	Ok(elder_cards)
+	/// The ids of a deck holding ONE, committed at a fixed time beneath the
+	/// given namespace
+	fn ids_within(namespace: Option<&str>) -> Vec<Uuid> {
+		let repo = repository();
+		if let Some(namespace) = namespace {
+			let config =
+				format!("crowdanki_uuid = \"deck\"\nname = \"Test\"\nnamespace = \"{}\"\n", namespace);
+			repo.write("config.toml", config);
+		}
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		Deck::from(&repo.path).expect("the deck builds").cards.iter().map(|card| card.id).collect()
+	}
+
+	#[test]
+	fn a_namespace_keeps_identical_decks_apart() {
+		const NAMESPACE: &str = "4b1d2c3e-5f60-4718-8a9b-0c1d2e3f4a5b";
+
+		assert_eq!(ids_within(Some(NAMESPACE)), ids_within(Some(NAMESPACE)));
+		assert_ne!(ids_within(Some(NAMESPACE)), ids_within(None));
+	}
+
+	/// Whether the note has the given front
+	fn fronts(note: &Note, front: &str) -> bool {
+		note
+			.field("Front")
+			.is_some_and(|field| field.content == vec![TextElement::Text(front.to_string())])
+	}
+
+	#[test]
+	fn remapping_reassigns_just_the_notes_mapped() {
+		let mut deck = test_support::deck(&[TWO]);
+		let (a, migrated) = (deck.cards[0].id, Uuid::from_u128(7));
+
+		let count =
+			deck.remap_uuids(|note| fronts(note, "c").then_some(migrated)).expect("the ids stay unique");
+		assert_eq!(count, 1);
+		assert_eq!((deck.cards[0].id, deck.cards[1].id), (a, migrated));
+
+		// Mapping a note to the identity it already has changes nothing
+		assert_eq!(deck.remap_uuids(|note| fronts(note, "c").then_some(migrated)).unwrap(), 0);
+	}
+
+	#[test]
+	fn remapping_onto_another_note_changes_nothing() {
+		let mut deck = test_support::deck(&[TWO]);
+		let before: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+
+		let result = deck.remap_uuids(|note| fronts(note, "c").then_some(before[0]));
+		assert!(matches!(result, Err(DeckError::DuplicateNote(id)) if id == before[0]));
+		assert_eq!(deck.cards.iter().map(|card| card.id).collect::<Vec<_>>(), before);
+	}
+
+	#[test]
+	fn tolerant_parsing_keeps_the_notes_around_a_broken_one() {
+		let models = test_support::models();
+		let source = "=Basic=\nFront: a\nBack: b\n\nBack: no front\n\nFront: c\nBack: d\n";
+		let (notes, diagnostics) = Deck::parse_cards_tolerant(&models, source, NoteSeparator::Blank);
+
+		let fronts: Vec<bool> =
+			notes.iter().map(|note| fronts(note, "a") || fronts(note, "c")).collect();
+		assert_eq!(fronts, vec![true, true]);
+		assert_eq!(diagnostics.len(), 1);
+		assert!(diagnostics[0].message.contains("requirements"), "{:?}", diagnostics);
+		assert!(Deck::parse_cards(&models, source).is_err());
+	}
+
+	#[test]
+	fn diagnosing_reports_problems_in_the_working_tree() {
+		let repo = repository();
+		let source = "=Basic=\nFront: a\nBack: b\n\nBack: no front\n";
+		repo.write("index.flash", source);
+
+		let diagnostics = Deck::diagnose(&repo.path).expect("the deck reads");
+		assert_eq!(diagnostics.len(), 1);
+		let (file, diagnostic) = &diagnostics[0];
+		assert_eq!(file, "index.flash");
+		assert!(source[diagnostic.span.clone()].contains("no front"), "{:?}", diagnostics);
+	}
+
+	#[test]
+	fn diagnosing_reads_every_tracked_file() {
+		let repo = repository();
+		repo.write("config.toml", tracking("\"extra.flash\" = \"extra.flash\""));
+		repo.write("index.flash", TWO);
+		repo.write("extra.flash", "=Basic=\nBack: no front\n");
+
+		let diagnostics = Deck::diagnose(&repo.path).expect("the deck reads");
+		let files: Vec<&str> = diagnostics.iter().map(|(file, _)| file.as_str()).collect();
+		assert_eq!(files, vec!["extra.flash"]);
+	}
+
+	#[test]
+	fn diagnosing_a_sound_deck_reports_nothing() {
+		let repo = repository();
+		repo.write("index.flash", TWO);
+		assert_eq!(Deck::diagnose(&repo.path).expect("the deck reads"), vec![]);
+	}
+
+	#[test]
+	fn diagnosing_goes_by_the_decks_separator() {
+		let repo = repository();
+		let config = "crowdanki_uuid = \"deck\"\nname = \"Test\"\nnote_separator = \"rule\"\n";
+		repo.write("config.toml", config);
+		repo.write("index.flash", "=Basic=\nFront: one\n\ntwo\nBack: b\n---\nFront: c\nBack: d\n");
+		assert_eq!(Deck::diagnose(&repo.path).expect("the deck reads"), vec![]);
+	}
+
+	fn oid(id: &str) -> ObjectId { ObjectId::from_hex(id.as_bytes()).expect("a commit id") }
+
+	#[test]
+	fn notes_at_goes_by_ancestry_rather_than_the_clock() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+		// Made on a machine whose clock ran behind
+		repo.write("notes.txt", "unrelated");
+		let skewed = commit(&repo, "unrelated", 1_000_000_000);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert_eq!(deck.notes_at(oid(&skewed)).expect("the commit is known").len(), 1);
+	}
+
+	#[test]
+	fn a_revision_adding_one_note_adds_just_that_note() {
+		const THREE: &str = "=Basic=\nFront: a\nBack: b\n\nFront: c\nBack: d\n\nFront: e\nBack: f\n";
+
+		let deck = test_support::deck(&[TWO, THREE]);
+		assert_eq!(deck.cards.len(), 3);
+		assert_eq!(deck.cards[..2], test_support::deck(&[TWO]).cards[..]);
+	}
+
+	#[test]
+	fn notes_at_leaves_out_branches_merged_later() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		git(&repo.path, &["checkout", "--quiet", "-b", "side"]);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		git(&repo.path, &["checkout", "--quiet", "main"]);
+		repo.write("notes.txt", "unrelated");
+		let later = commit(&repo, "unrelated", 2_000_000_200);
+		git(&repo.path, &["merge", "--quiet", "--no-ff", "-m", "merge side", "side"]);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert_eq!(deck.cards.len(), 2);
+		assert_eq!(deck.notes_at(oid(&later)).expect("the commit is known").len(), 1);
+	}
+
+	#[test]
+	fn notes_at_a_revision_of_the_cards_matches_it() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		let first = commit(&repo, "add a", 2_000_000_000);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		let notes = deck.notes_at(oid(&first)).expect("the commit is known");
+		assert_eq!(notes.len(), 1);
+		assert_eq!(notes[0].id, deck.cards[0].id);
+	}
+
+	#[test]
+	fn notes_at_a_commit_before_the_cards_is_empty() {
+		let repo = repository();
+		let before = commit(&repo, "config", 1_000_000_000);
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert!(deck.notes_at(oid(&before)).expect("the commit is known").is_empty());
+	}
+
+	#[test]
+	fn notes_are_dated_by_the_commit_introducing_them() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		let created: Vec<Option<i64>> =
+			deck.cards.iter().map(|card| deck.created.get(&card.id).copied()).collect();
+		assert_eq!(created, vec![Some(2_000_000_000), Some(2_000_000_100)]);
+	}
+
+	#[test]
+	fn a_deck_builds_as_it_stood_at_a_reference() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+		git(&repo.path, &["tag", "v1"]);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		let then = Deck::from_ref(&repo.path, "v1").expect("the tag resolves");
+		let now = Deck::from(&repo.path).expect("the deck builds");
+		assert_eq!(then.cards.len(), 1);
+		assert_eq!(then.cards[0].id, now.cards[0].id);
+	}
+
+	#[test]
+	fn an_unknown_reference_fails() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		assert!(matches!(Deck::from_ref(&repo.path, "missing"), Err(DeckError::Git(_))));
+	}
+
+	#[test]
+	fn trimming_fields_leaves_edge_whitespace_out_of_an_added_note() {
+		let mut padded = note("=Basic=\nFront: c\nBack: d\n");
+		padded.fields[0].content.push(TextElement::Html("  ".to_string()));
+		let plain = note("=Basic=\nFront: c\nBack: d\n");
+
+		let mut deck = test_support::deck(&[ONE]);
+		deck.configuration.trim_fields = true;
+		let id = deck.add_note(padded).expect("the note is new");
+		assert_eq!(deck.cards[1].inner, plain);
+
+		let expected = test_support::deck(&[ONE]).add_note(plain).expect("the note is new");
+		assert_eq!(id, expected);
+	}
+
+	/// The ids of a deck holding ONE, first committed at `time` and configured
+	/// with the given host
+	fn ids_for_host(host: Option<&str>, time: i64) -> Vec<Uuid> {
+		let repo = repository();
+		if let Some(host) = host {
+			let config = format!("crowdanki_uuid = \"deck\"\nname = \"Test\"\nhost = \"{}\"\n", host);
+			repo.write("config.toml", config);
+		}
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", time);
+
+		Deck::from(&repo.path).expect("the deck builds").cards.iter().map(|card| card.id).collect()
+	}
+
+	#[test]
+	fn a_configured_host_holds_however_the_history_is_made() {
+		assert_eq!(
+			ids_for_host(Some("laptop"), 2_000_000_000),
+			ids_for_host(Some("laptop"), 2_100_000_000)
+		);
+		assert_ne!(ids_for_host(None, 2_000_000_000), ids_for_host(None, 2_100_000_000));
+		assert_ne!(
+			ids_for_host(Some("laptop"), 2_000_000_000),
+			ids_for_host(Some("desktop"), 2_000_000_000)
+		);
+	}
+
+	#[test]
+	fn an_added_note_is_identified_in_composed_form() {
+		let mut deck = test_support::deck(&[ONE]);
+		let decomposed = deck.add_note(note("=Basic=\nFront: cafe\u{301}\n")).expect("the note is new");
+
+		let mut deck = test_support::deck(&[ONE]);
+		let composed = deck.add_note(note("=Basic=\nFront: caf\u{e9}\n")).expect("the note is new");
+		assert_eq!(decomposed, composed);
+		assert!(fronts(&deck.cards[1].inner, "caf\u{e9}"));
+	}
+
+	#[test]
+	fn a_deck_identifies_notes_through_its_strategy() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		let history = GixHistory::discover(&repo.path).expect("the repository opens");
+		let deck =
+			Deck::from_history_with_strategy(&repo.path, history, uuid_generator::ContentAddressed, None)
+				.expect("the deck builds");
+		assert_eq!(deck.cards[0].id, note(ONE).stable_uuid(&uuid_generator::DEFAULT_NAMESPACE));
+	}
+
+	/// Identities handed out from a fixed map, keyed by each note as written
+	struct MapStrategy(HashMap<String, Uuid>);
+
+	impl UuidStrategy for MapStrategy {
+		fn note_id(&self, note: &Note, _ctx: &HostContext) -> Uuid {
+			self.0.get(&note.to_string()).copied().unwrap_or_else(|| panic!("no id for {}", note))
+		}
+	}
+
+	#[test]
+	fn every_note_takes_the_id_its_strategy_gives() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+		repo.write("index.flash", TWO);
+		commit(&repo, "add c", 2_000_000_100);
+
+		let (first, second) = (Uuid::from_u128(7), Uuid::from_u128(9));
+		let strategy = MapStrategy(HashMap::from([
+			("Front: a\nBack: b".to_string(), first),
+			("Front: c\nBack: d".to_string(), second),
+		]));
+		let history = GixHistory::discover(&repo.path).expect("the repository opens");
+		let deck = Deck::from_history_with_strategy(&repo.path, history, strategy, None)
+			.expect("the deck builds");
+
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		assert_eq!(ids, vec![first, second]);
+	}
+
+	#[test]
+	fn decks_in_memory_track_every_card_file() {
+		let files = vec![
+			("index.flash".to_string(), vec![ONE.to_string()]),
+			("extra.flash".to_string(), vec!["=Basic=\nFront: e\nBack: f\n".to_string()]),
+		];
+		let deck = Deck::from_parts(test_support::models(), files, test_support::host())
+			.expect("the deck builds");
+		assert_eq!(deck.cards.len(), 2);
+	}
+
+	#[test]
+	fn decks_in_memory_refuse_anything_but_card_files() {
+		let files = vec![
+			("index.flash".to_string(), vec![ONE.to_string()]),
+			("notes.txt".to_string(), vec!["unrelated".to_string()]),
+		];
+		let deck = Deck::from_parts(test_support::models(), files, test_support::host());
+		assert!(matches!(deck, Err(DeckError::DeckInit(_))));
+	}
+
+	#[test]
+	fn decks_in_memory_read_their_history_without_the_filesystem() {
+		let deck = test_support::deck(&[ONE, TWO]);
+		assert!(deck.in_memory());
+		assert_eq!(deck.revisions().expect("the revisions are read").len(), 2);
+		assert!(deck.orphan_media().expect("nothing is scanned").is_empty());
+	}
+
+	#[test]
+	fn decks_in_memory_resolve_identities_across_their_revisions() {
+		let deck = test_support::deck(&[TWO, ONE]);
+		assert_eq!(deck.cards.len(), 1);
+		assert_eq!(deck.cards[0].id, test_support::deck(&[TWO]).cards[0].id);
+	}
+
+	/// A deck holding `theirs` under the identity of `ours`' first note
+	fn conflicting(ours: &Deck) -> Deck<'static> {
+		let mut theirs = test_support::deck(&["=Basic=\nFront: x\nBack: y\n"]);
+		theirs.cards[0].id = ours.cards[0].id;
+		theirs
+	}
+
+	#[test]
+	fn merging_appends_notes_only_the_other_deck_holds() {
+		let mut ours = test_support::deck(&[ONE]);
+		let theirs = test_support::deck(&[TWO]);
+		let expected: Vec<Uuid> = theirs.cards.iter().map(|card| card.id).collect();
+
+		let report = ours.merge(theirs, MergePolicy::Error).expect("nothing conflicts");
+		assert_eq!(report, MergeReport { added: vec![expected[1]], conflicts: vec![] });
+		assert_eq!(ours.cards.iter().map(|card| card.id).collect::<Vec<_>>(), expected);
+	}
+
+	#[test]
+	fn conflicts_are_refused_by_default_and_resolved_by_the_policy() {
+		let mut ours = test_support::deck(&[ONE]);
+		let id = ours.cards[0].id;
+
+		let refused = ours.merge(conflicting(&ours), MergePolicy::default());
+		let Err(DeckError::MergeConflicts(conflicts)) = refused else {
+			panic!("the conflict is refused, got {:?}", refused.map(|_| ()));
+		};
+		assert_eq!(conflicts, vec![id]);
+		assert_eq!(ours.cards[0].inner.fields[0].content, vec![TextElement::Text("a".into())]);
+
+		let report = ours.merge(conflicting(&ours), MergePolicy::Ours).expect("ours is kept");
+		assert_eq!(report.conflicts, vec![id]);
+		assert_eq!(ours.cards[0].inner.fields[0].content, vec![TextElement::Text("a".into())]);
+
+		ours.merge(conflicting(&ours), MergePolicy::Theirs).expect("theirs is taken");
+		assert_eq!(ours.cards.len(), 1);
+		assert_eq!(ours.cards[0].inner.fields[0].content, vec![TextElement::Text("x".into())]);
+	}
+
+	#[test]
+	fn overrides_take_precedence_over_the_config_file() {
+		let repo = repository();
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		let plain = Deck::from(&repo.path).expect("the deck builds");
+		let overrides =
+			ConfigOverrides { name: Some("Renamed".to_string()), host: Some("ci".to_string()) };
+		let deck = Deck::from_with_overrides(&repo.path, overrides).expect("the deck builds");
+
+		assert_eq!(deck.configuration.name, "Renamed");
+		assert_eq!(deck.configuration.host.as_deref(), Some("ci"));
+		assert_ne!(deck.cards[0].id, plain.cards[0].id);
+	}
+
+	#[test]
+	fn macros_expand_on_request_without_changing_identities() {
+		let repo = repository();
+		repo.write("index.flash", "=Basic=\nFront: built at {{@commit}}\nBack: b\n");
+		let head = commit(&repo, "add a", 2_000_000_000);
+
+		let front = |deck: &Deck| -> String {
+			let field = deck.cards[0].inner.field("Front").expect("the note has a front");
+			field
+				.content
+				.iter()
+				.filter_map(|part| match part {
+					TextElement::Text(text) => Some(text.as_str()),
+					_ => None,
+				})
+				.collect()
+		};
+
+		let mut deck = Deck::from(&repo.path).expect("the deck builds");
+		let id = deck.cards[0].id;
+		assert_eq!(front(&deck), "built at {{@commit}}");
+
+		deck.expand_macros();
+		assert_eq!(front(&deck), format!("built at {}", &head[..7]));
+		assert_eq!(deck.cards[0].id, id);
+	}
+
+	#[test]
+	fn dates_are_given_in_the_civil_calendar() {
+		assert_eq!(civil_date(0), "1970-01-01");
+		assert_eq!(civil_date(951_782_400), "2000-02-29");
+		assert_eq!(civil_date(1_709_251_199), "2024-02-29");
+		assert_eq!(civil_date(-86_400), "1969-12-31");
+	}
+
+	/// Config for a repository, with `track` as its `[track]` table
+	fn tracking(track: &str) -> String {
+		format!("crowdanki_uuid = \"deck\"\nname = \"Test\"\n\n[track]\n{}\n", track)
+	}
+
+	#[test]
+	fn tracked_files_follow_the_index() {
+		let repo = repository();
+		repo.write("config.toml", tracking("\"extra.flash\" = \"extra.flash\""));
+		repo.write("index.flash", ONE);
+		repo.write("extra.flash", "=Basic=\nFront: e\nBack: f\n");
+		commit(&repo, "add a and e", 2_000_000_000);
+
+		let deck = Deck::from(&repo.path).expect("the deck builds");
+		assert_eq!(deck.cards.len(), 2);
+		assert!(fronts(&deck.cards[0].inner, "a") && fronts(&deck.cards[1].inner, "e"));
+	}
+
+	#[test]
+	fn a_tracked_path_without_history_is_reported() {
+		let repo = repository();
+		repo.write("config.toml", tracking("\"index.flash\" = \"gone.flash\""));
+		repo.write("index.flash", ONE);
+		commit(&repo, "add a", 2_000_000_000);
+
+		let result = Deck::from(&repo.path);
+		let Err(DeckError::TrackedPathNotInHistory(file, path)) = result else {
+			panic!("the missing history is reported, got {:?}", result.err());
+		};
+		assert_eq!((file.as_str(), path.as_str()), ("index.flash", "gone.flash"));
+	}
+
+	#[test]
+	fn sorting_orders_notes_by_their_sort_field_ignoring_case() {
+		let mut deck =
+			test_support::deck(&["=Basic=\nFront: b\nBack: 1\n\nFront: A\n\nFront: B\nBack: 2\n"]);
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+
+		deck.sort_by_sort_field();
+		let sorted: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		// The two notes fronted by b sort the same, so keep their order
+		assert_eq!(sorted, vec![ids[1], ids[0], ids[2]]);
+		assert!(fronts(&deck.cards[0].inner, "A"));
+	}
+
+	/// A deck of three notes, all under the identity of the first
+	fn duplicated() -> Deck<'static> {
+		let mut deck = test_support::deck(&["=Basic=\nFront: a\n\nFront: b\n\nFront: c\n"]);
+		let id = deck.cards[0].id;
+		for card in &mut deck.cards {
+			card.id = id;
+		}
+		deck
+	}
+
+	#[test]
+	fn repairing_gives_every_later_duplicate_an_identity_of_its_own() {
+		let mut deck = duplicated();
+		let shared = deck.cards[0].id;
+		let repairs = deck.repair_duplicates();
+
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+		assert_eq!(ids[0], shared);
+		assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 3);
+		assert_eq!(repairs, vec![Repair { old: shared, new: ids[1] }, Repair {
+			old: shared,
+			new: ids[2],
+		}]);
+		assert!(deck.repair_duplicates().is_empty());
+	}
+
+	#[test]
+	fn repairs_are_reproducible() {
+		assert_eq!(duplicated().repair_duplicates(), duplicated().repair_duplicates());
+	}
+
+	#[test]
+	fn history_out_of_step_with_its_revisions_is_refused() {
+		let models = test_support::models();
+		let content = vec![ONE.to_string(), TWO.to_string()];
+		let commit =
+			CommitMeta { id: "a".to_string(), author: UNKNOWN_AUTHOR.to_string(), time: 1 };
+		let host = HostContext { host_uuid: test_support::host() };
+
+		let result = process_card_history(
+			&models,
+			&content,
+			&[&commit],
+			&HostScoped,
+			&host,
+			&DeckConfig::default(),
+			None,
+		);
+		assert!(matches!(result, Err(DeckError::HistoryMismatch(2, 1))));
+	}
+
+	const ILLUSTRATED: &str =
+		"=Basic=\nFront: ![a](img/a.png)\n\nFront: ![b](b.png)\n\nFront: none\n";
+
+	#[test]
+	fn media_is_traced_to_the_notes_using_it() {
+		let deck = test_support::deck(&[ILLUSTRATED]);
+		let ids: Vec<Uuid> = deck.cards.iter().map(|card| card.id).collect();
+
+		assert_eq!(deck.notes_using_media("img/a.png"), vec![ids[0]]);
+		// Anki's media folder is flat, so a bare name finds it anywhere
+		assert_eq!(deck.notes_using_media("a.png"), vec![ids[0]]);
+		assert_eq!(deck.notes_using_media("b.png"), vec![ids[1]]);
+		assert!(deck.notes_using_media("img/b.png").is_empty());
+	}
+
+	#[test]
+	fn files_nothing_references_are_orphans() {
+		let scratch = test_support::Scratch::new();
+		for name in
+			["img/a.png", "b.png", "c.png", "index.flash", "config.toml", ".hidden", "Basic.model/x.png"]
+		{
+			scratch.write(name, "");
+		}
+
+		let mut deck = test_support::deck(&[ILLUSTRATED]);
+		deck.path = scratch.path.clone();
+		assert_eq!(deck.orphan_media().expect("the deck is readable"), vec![
+			scratch.path.join("c.png")
+		]);
+	}
 }
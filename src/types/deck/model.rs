@@ -1,10 +1,53 @@
-use gix::Repository;
+use std::{collections::HashMap, path::PathBuf};
 
-use crate::types::{crowd_anki_config::DeckConfig, note::{Identified, Note, NoteModel}};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{history::HistoryProvider, types::{crowd_anki_config::DeckConfig, note::{Identified, Note, NoteModel}}, uuid_generator::UuidStrategy};
 
 pub struct Deck<'a> {
+	// The .deck directory everything was loaded from
+	pub path:          PathBuf,
 	pub models:        Vec<NoteModel>,
-	pub backing_vcs:   Repository,
+	pub history:       Box<dyn HistoryProvider>,
 	pub cards:         Vec<Identified<Note<'a>>>,
 	pub configuration: DeckConfig,
+	// Resolved paths of every media file declared by the cards
+	pub media:         Vec<PathBuf>,
+	// The namespace every note's UUID is derived within
+	pub host_uuid:     Uuid,
+	// How notes are identified as they appear
+	pub strategy:      Box<dyn UuidStrategy>,
+	// When each card first appeared under its identity, in seconds since the
+	// epoch, taken from the commit that introduced it
+	pub created:       HashMap<Uuid, i64>,
+}
+
+/// What `Deck::merge` does with a note both decks hold under the same identity
+/// but with different content
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+	// Refuse the merge, leaving the deck untouched
+	#[default]
+	Error,
+	// Keep the deck's own copy
+	Ours,
+	// Take the other deck's copy
+	Theirs,
+}
+
+/// The outcome of a merge
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+	// Notes only the other deck held, now appended
+	pub added:     Vec<Uuid>,
+	// Notes both decks held with different content, resolved by the policy
+	pub conflicts: Vec<Uuid>,
+}
+
+/// A note given a new identity by `Deck::repair_duplicates`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Repair {
+	pub old: Uuid,
+	pub new: Uuid,
 }
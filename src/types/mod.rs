@@ -2,6 +2,7 @@ use gix::object::tree::Entry;
 
 use crate::error::DeckError;
 
+pub mod collection;
 pub mod config;
 pub mod crowd_anki_config;
 pub mod crowd_anki_models;
@@ -1,14 +1,14 @@
-use std::{borrow::Cow, path::PathBuf};
+use std::{borrow::Cow, collections::BTreeMap, path::PathBuf};
 
 use evalexpr::Node;
 use semver::Version;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::types::{config::{Defaults, Template}, note_methods::Identifiable};
+use crate::types::{config::{Defaults, ModelKind, Template}, note_methods::Identifiable};
 
 // Wrapper that adds an ID to any type
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Identified<T> {
 	pub id:    Uuid,
 	pub inner: T,
@@ -16,26 +16,87 @@ pub struct Identified<T> {
 
 #[derive(Debug, PartialOrd, Ord, Clone, Eq, PartialEq)]
 pub struct Note<'a> {
-	pub fields: Vec<NoteField>,
-	pub model:  Cow<'a, NoteModel>,
-	pub tags:   Vec<String>,
+	pub fields:   Vec<NoteField>,
+	pub model:    Cow<'a, NoteModel>,
+	pub tags:     Vec<String>,
+	// Media files explicitly declared for the note, relative to the deck directory
+	pub media:    Vec<PathBuf>,
+	// Comments written directly above the note in its source
+	pub comments: Vec<String>,
+	// Free-form data declared through `@data key=value`, exported as JSON in
+	// the note's data. Like tags it plays no part in the note's identity
+	pub data:     BTreeMap<String, String>,
+	// Classes declared through `@class`, wrapped around each filled field on
+	// export for the model's CSS to target. No part of the note's identity
+	pub classes:  Vec<String>,
+	// Which clozes each card reveals, set through `@cloze`. No part of the
+	// note's identity
+	pub reveal:   ClozeReveal,
+}
+
+/// Which clozes a cloze note's cards reveal. Either way there's a card per
+/// cloze number, they differ in what the card asks for.
+#[derive(
+	Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClozeReveal {
+	// Anki's usual, a card asks for its own cloze with every other revealed as
+	// context
+	#[default]
+	All,
+	// A card reveals only its own cloze, asking for every other. Exported as
+	// clozes carrying several numbers, `{{c2,3::answer}}`, which Anki reads
+	// from 23.10 on
+	One,
 }
 
 // All notes can be identified
 impl Identifiable for Note<'_> {}
 
-#[derive(Debug, PartialOrd, Ord, Default, Eq, Clone, PartialEq)]
+// Notes are persisted detached from their model, which is named instead
+impl Serialize for Note<'_> {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		DetachedNote::from(self).serialize(serializer)
+	}
+}
+
+/// A note as it's persisted, naming its model rather than holding it. It's
+/// linked back up against the deck's models to become a `Note` again.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DetachedNote {
+	pub fields:   Vec<NoteField>,
+	pub model:    String,
+	pub tags:     Vec<String>,
+	pub media:    Vec<PathBuf>,
+	pub comments: Vec<String>,
+	#[serde(default)]
+	pub data:     BTreeMap<String, String>,
+	#[serde(default)]
+	pub classes:  Vec<String>,
+	#[serde(default)]
+	pub reveal:   ClozeReveal,
+}
+
+#[derive(Debug, PartialOrd, Ord, Default, Eq, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NoteField {
 	pub name:    String,
 	pub content: Vec<TextElement>,
 }
 
+// Unknown keys are refused, a misspelt option would otherwise be dropped
+// silently
 #[derive(Debug, Eq, PartialOrd, Ord, Hash, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct NoteModel {
 	pub name: String,
 
 	pub id: Uuid,
 
+	// Declared as `type = "cloze"` for models whose notes contain clozes
+	#[serde(rename = "type", default)]
+	pub kind: ModelKind,
+
 	// The available templates
 	pub templates: Vec<Template>,
 
@@ -61,27 +122,48 @@ pub struct NoteModel {
 	pub sort_field: Option<String>,
 	pub tags:       Option<Vec<String>>,
 
+	// A field whose content becomes the hint of any cloze that doesn't give
+	// its own. Left empty on a note, those clozes simply have no hint
+	pub hint_field: Option<String>,
+
+	// A field the comments written above a note flow into on export, unless
+	// the note fills it itself. Comments stay out of the note's identity
+	pub comment_field: Option<String>,
+
 	// The required fields are determined at runtime, this String holds a boolean expression that
 	// affirms this.
-	pub required: Node,
+	pub required: Requirement,
 }
 
-#[derive(Debug, Ord, PartialOrd, Eq, Clone, PartialEq)]
+/// A model's `required` expression. evalexpr's trees can only be compared for
+/// equality, so beyond that they're compared and hashed as written.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Requirement(pub Node);
+
+#[derive(Debug, Ord, PartialOrd, Eq, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Cloze {
 	pub id:     u32,
 	pub answer: String,
-	pub hint:   Option<String>,
+	pub hint:   Vec<String>,
 }
 
-#[derive(Debug, PartialOrd, Ord, Eq, Clone, PartialEq)]
+#[derive(Debug, PartialOrd, Ord, Eq, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TextElement {
 	Text(String),
 	Cloze(Cloze),
+	// Markup taken verbatim from the source, passed through to the export as is
+	Html(String),
 }
 
 #[derive(Deserialize, Ord, PartialOrd, Eq, Hash, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Field {
 	pub name:             String,
 	pub sticky:           Option<bool>,
 	pub associated_media: Option<Vec<PathBuf>>,
+	// Overrides the model's default direction for just this field
+	pub rtl:              Option<bool>,
+	// BCP 47 language tag the field's content is written in
+	pub lang:             Option<String>,
 }
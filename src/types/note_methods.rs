@@ -1,9 +1,9 @@
-use std::{fs, path::Path};
+use std::{borrow::Cow, cmp::Ordering, collections::{BTreeMap, BTreeSet}, fs, hash::{Hash, Hasher}, ops::Deref, path::Path};
 
-use tracing::instrument;
+use tracing::{instrument, warn};
 use uuid::Uuid;
 
-use crate::{error::DeckError, types::{crowd_anki_models::{CrowdAnkiEntity, Deck as CrowdAnkiDeck, Field, Note, NoteModelType}, deck::Deck, note::{Cloze, Identified, TextElement}}};
+use crate::{error::DeckError, types::{collection::{Collection, crowd_anki_note}, config::ModelKind, crowd_anki_config::Normalization, crowd_anki_models::{CrowdAnkiEntity, Field, Note, NoteModelType}, deck::Deck, note::{Cloze, ClozeReveal, DetachedNote, Identified, Requirement, TextElement}}, uuid_generator};
 
 // Extension trait to add .identified() method
 pub trait Identifiable: Sized {
@@ -31,12 +31,35 @@ impl super::note::NoteModel {
 			self.latex_post = Some(fs::read_to_string(post_path)?);
 		}
 
+		// read_dir order is filesystem dependent, sort so exports are reproducible
+		let mut paths =
+			fs::read_dir(dir)?.map(|entry| entry.map(|e| e.path())).collect::<Result<Vec<_>, _>>()?;
+		paths.sort();
+
+		// Templates given inline in config.toml stand alone, the model's templates
+		// then coming from there or the .hbs files but never both
+		let inline =
+			self.templates.iter().any(|t| !t.question_format.is_empty() || !t.answer_format.is_empty());
+		if inline {
+			if paths.iter().any(|path| path.extension().is_some_and(|ext| ext == "hbs")) {
+				return Err(DeckError::MixedTemplates(dir.to_path_buf()));
+			}
+
+			for (idx, template) in self.templates.iter_mut().enumerate() {
+				if template.question_format.is_empty() || template.answer_format.is_empty() {
+					return Err(DeckError::IncompleteTemplate(dir.to_path_buf(), template.name.clone()));
+				}
+				template.order = idx as i32;
+			}
+			return Ok(());
+		}
+
+		// Templates declared in config.toml dictate the order, anything else follows
+		let declared: Vec<String> = self.templates.iter().map(|t| t.name.clone()).collect();
+
 		// Load templates from .hbs files
 		let mut templates = Vec::new();
-		for entry in fs::read_dir(dir)? {
-			let entry = entry?;
-			let path = entry.path();
-
+		for path in paths {
 			if let Some(ext) = path.extension().and_then(|e| e.to_str())
 				&& ext == "hbs"
 			{
@@ -89,64 +112,266 @@ impl super::note::NoteModel {
 			}
 		}
 
+		// Stable, so undeclared templates keep their filename order at the end
+		templates
+			.sort_by_key(|t| declared.iter().position(|name| *name == t.name).unwrap_or(declared.len()));
+		for (idx, template) in templates.iter_mut().enumerate() {
+			template.order = idx as i32;
+		}
+
+		if templates.is_empty() {
+			templates.push(self.default_template(dir)?);
+		}
+
 		self.templates = templates;
 		Ok(())
 	}
+
+	/// A template to fall back on when the model directory has none, as Anki
+	/// wouldn't generate a single card from the model otherwise
+	fn default_template(&self, dir: &Path) -> Result<super::config::Template, DeckError> {
+		let (question_format, answer_format) = match (self.kind, self.fields.as_slice()) {
+			(ModelKind::Cloze, [text, ..]) => {
+				(format!("{{{{cloze:{}}}}}", text.name), format!("{{{{cloze:{}}}}}", text.name))
+			}
+			(ModelKind::Standard, [front, back, ..]) => (
+				format!("{{{{{}}}}}", front.name),
+				format!("{{{{FrontSide}}}}\n\n<hr id=answer>\n\n{{{{{}}}}}", back.name),
+			),
+			_ => return Err(DeckError::NoTemplates(dir.to_path_buf())),
+		};
+
+		warn!("Model '{}' has no templates in {:?}, falling back to a default one", self.name, dir);
+		Ok(super::config::Template {
+			name: "Card 1".to_string(),
+			order: 0,
+			question_format,
+			answer_format,
+			browser_question_format: String::new(),
+			browser_answer_format: String::new(),
+		})
+	}
+}
+
+impl Deref for Requirement {
+	type Target = evalexpr::Node;
+
+	fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl Eq for Requirement {}
+
+impl PartialOrd for Requirement {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Requirement {
+	fn cmp(&self, other: &Self) -> Ordering { self.0.to_string().cmp(&other.0.to_string()) }
+}
+
+impl Hash for Requirement {
+	fn hash<H: Hasher>(&self, state: &mut H) { self.0.to_string().hash(state) }
+}
+
+impl<'a> TryFrom<Deck<'a>> for CrowdAnkiEntity {
+	type Error = DeckError;
+
+	fn try_from(deck: Deck<'a>) -> Result<Self, DeckError> {
+		Collection::try_from(deck)?.to_crowd_anki()
+	}
 }
 
-impl<'a> From<Deck<'a>> for CrowdAnkiEntity {
-	fn from(deck: Deck<'a>) -> Self {
-		// Convert note models from deck to CrowdAnki format
-		let note_models: Vec<crate::types::crowd_anki_models::NoteModel> =
-			deck.models.iter().map(|model| model.into()).collect();
-
-		// Convert notes to CrowdAnki format
-		let crowd_anki_notes: Vec<Note> = deck.cards.into_iter().map(|note| note.into()).collect();
-
-		// Use the deck's configuration
-		let deck_config = deck.configuration;
-		let deck_config_uuid = deck_config.crowdanki_uuid.clone();
-		let deck_uuid = deck_config.crowdanki_uuid.clone();
-		let deck_name = deck_config.name.clone();
-
-		CrowdAnkiEntity::Deck(CrowdAnkiDeck {
-			name: deck_name,
-			crowdanki_uuid: deck_uuid,
-			deck_config_uuid,
-			desc: String::new(), // Could be extended to read from deck metadata
-			is_dynamic: 0,
-			extend_new: 0,
-			extend_rev: 0,
-			note_models,
-			deck_configurations: vec![deck_config],
-			notes: crowd_anki_notes,
-			children: Vec::new(),
-			media_files: Vec::new(),
+impl<'a> From<&crate::types::note::Note<'a>> for DetachedNote {
+	fn from(note: &crate::types::note::Note<'a>) -> Self {
+		DetachedNote {
+			fields:   note.fields.clone(),
+			model:    note.model.name.clone(),
+			tags:     note.tags.clone(),
+			media:    note.media.clone(),
+			comments: note.comments.clone(),
+			data:     note.data.clone(),
+			classes:  note.classes.clone(),
+			reveal:   note.reveal,
+		}
+	}
+}
+
+impl DetachedNote {
+	/// Reattach the note to its model, which must be among `models`
+	pub fn link(
+		self,
+		models: &[super::note::NoteModel],
+	) -> Result<crate::types::note::Note<'_>, DeckError> {
+		let model = models
+			.iter()
+			.find(|model| model.name == self.model)
+			.ok_or_else(|| DeckError::ModelNotFound(self.model.clone()))?;
+
+		Ok(crate::types::note::Note {
+			fields:   self.fields,
+			model:    Cow::Borrowed(model),
+			tags:     self.tags,
+			media:    self.media,
+			comments: self.comments,
+			data:     self.data,
+			classes:  self.classes,
+			reveal:   self.reveal,
 		})
 	}
 }
 
+impl crate::types::note::NoteField {
+	/// Whether the field has no meaningful content, only whitespace counts as
+	/// empty since a cloze always carries an answer
+	pub fn is_empty(&self) -> bool {
+		self.content.iter().all(|part| match part {
+			TextElement::Text(text) | TextElement::Html(text) => text.trim().is_empty(),
+			TextElement::Cloze(_) => false,
+		})
+	}
+
+	/// Strip whitespace from the edges of the rendered content, along with any
+	/// trailing whitespace on its lines. Parts left empty are dropped.
+	pub fn trim(&mut self) {
+		for part in &mut self.content {
+			if let TextElement::Text(text) | TextElement::Html(text) = part {
+				// Only whitespace before a line break, a space leading into a
+				// cloze that follows the part is content
+				let mut lines: Vec<&str> = text.split('\n').collect();
+				let last = lines.pop().unwrap_or_default();
+				let trimmed: Vec<&str> =
+					lines.into_iter().map(str::trim_end).chain(std::iter::once(last)).collect();
+				*text = trimmed.join("\n");
+			}
+		}
+
+		while let Some(TextElement::Text(text) | TextElement::Html(text)) = self.content.last_mut() {
+			text.truncate(text.trim_end().len());
+			if !text.is_empty() {
+				break;
+			}
+			self.content.pop();
+		}
+
+		while let Some(TextElement::Text(text) | TextElement::Html(text)) = self.content.first_mut() {
+			*text = text.trim_start().to_string();
+			if !text.is_empty() {
+				break;
+			}
+			self.content.remove(0);
+		}
+	}
+}
+
 impl<'a> crate::types::note::Note<'a> {
+	/// Trim every field of the note, see `NoteField::trim`
+	pub fn trim_fields(&mut self) {
+		self.fields.iter_mut().for_each(crate::types::note::NoteField::trim);
+	}
+
+	/// Put all of the note's text in the given Unicode form, see
+	/// `Normalization`
+	pub fn normalize(&mut self, form: Normalization) {
+		for part in self.fields.iter_mut().flat_map(|field| &mut field.content) {
+			match part {
+				TextElement::Text(text) | TextElement::Html(text) => *text = form.apply(text),
+				TextElement::Cloze(cloze) => {
+					cloze.answer = form.apply(&cloze.answer);
+					cloze.hint.iter_mut().for_each(|hint| *hint = form.apply(hint));
+				}
+			}
+		}
+		self.tags.iter_mut().for_each(|tag| *tag = form.apply(tag));
+	}
+
+	/// Detach the note from the model it borrows, taking a copy of the model
+	pub fn into_owned(self) -> crate::types::note::Note<'static> {
+		crate::types::note::Note {
+			fields:   self.fields,
+			model:    Cow::Owned(self.model.into_owned()),
+			tags:     self.tags,
+			media:    self.media,
+			comments: self.comments,
+			data:     self.data,
+			classes:  self.classes,
+			reveal:   self.reveal,
+		}
+	}
+
+	/// Export just this note under the given identity, for sharing a single
+	/// card without the rest of its deck. It's exported as it would be within
+	/// `deck`, with the deck's default tags, schedule, and creation times.
+	pub fn to_crowd_anki_entity(&self, id: Uuid, deck: &Deck) -> CrowdAnkiEntity {
+		let created = deck.created.get(&id).copied();
+		CrowdAnkiEntity::Note(crowd_anki_note(
+			&deck.configuration,
+			self.clone().identified(id),
+			created,
+		))
+	}
+
+	/// Look up one of the note's fields by its (alias resolved) name
+	pub fn field(&self, name: &str) -> Option<&crate::types::note::NoteField> {
+		self.fields.iter().find(|field| field.name == name)
+	}
+
+	/// The cloze numbers a cloze numbered `id` is asked for on. With every
+	/// other cloze revealed that's its own, otherwise it's every other number
+	/// the note has, which leaves a note of a single number as it was.
+	pub fn cloze_numbers(&self, id: u32) -> Vec<u32> {
+		let others: Vec<u32> = match self.reveal {
+			ClozeReveal::All => Vec::new(),
+			ClozeReveal::One => self
+				.fields
+				.iter()
+				.flat_map(|field| &field.content)
+				.filter_map(|part| match part {
+					TextElement::Cloze(cloze) if cloze.id != id => Some(cloze.id),
+					_ => None,
+				})
+				.collect::<BTreeSet<u32>>()
+				.into_iter()
+				.collect(),
+		};
+
+		if others.is_empty() { vec![id] } else { others }
+	}
+
+	/// The identity the note is given when it first appears in a deck with the
+	/// given host, exactly as the exporter derives it
+	pub fn stable_uuid(&self, host_uuid: &Uuid) -> Uuid {
+		uuid_generator::generate_note_uuid(host_uuid, &self.to_content_string())
+	}
+
 	/// Generate a deterministic string representation of the note's content
-	/// for UUID generation
+	/// for UUID generation. Tags are left out entirely, so neither their order
+	/// nor their presence has any bearing on a note's identity. Clozes count by
+	/// their answer alone, renumbering one (`{c1::x}` to `{c2::x}`) or editing
+	/// its hints keeps the note the same note, along with its review history.
+	///
+	/// Every name and part is prefixed with its length, so no content can be
+	/// mistaken for a boundary: a field `AB` holding `c` and a field `A`
+	/// holding `Bc` encode differently, where plain concatenation made them
+	/// the same note.
 	#[instrument(skip(self))]
 	pub fn to_content_string(&self) -> String {
+		fn push_prefixed(content: &mut String, value: &str) {
+			content.push_str(&format!("{}:{}", value.len(), value));
+		}
+
 		let mut content = String::new();
 
 		for field in &self.fields {
-			content.push_str(&field.name);
+			push_prefixed(&mut content, &field.name);
+			content.push_str(&format!("{}:", field.content.len()));
 
-			let field_content = field
-				.content
-				.iter()
-				.map(|part| match part {
-					TextElement::Text(text) => text.as_str(),
+			for part in &field.content {
+				let text = match part {
+					TextElement::Text(text) | TextElement::Html(text) => text.as_str(),
 					TextElement::Cloze(cloze) => cloze.answer.as_str(),
-				})
-				.collect::<Vec<&str>>()
-				.join("\0");
-
-			content.push_str(&field_content);
+				};
+				push_prefixed(&mut content, text);
+			}
 		}
 
 		content
@@ -158,7 +383,10 @@ impl<'a> From<&'a crate::types::note::NoteModel> for super::crowd_anki_models::N
 		super::crowd_anki_models::NoteModel {
 			crowdanki_uuid: model.id.to_string(),
 			name:           model.name.clone(),
-			kind:           NoteModelType::Standard,
+			kind:           match model.kind {
+				ModelKind::Standard => NoteModelType::Standard,
+				ModelKind::Cloze => NoteModelType::Cloze,
+			},
 			flds:           model
 				.fields
 				.iter()
@@ -167,7 +395,7 @@ impl<'a> From<&'a crate::types::note::NoteModel> for super::crowd_anki_models::N
 					name:   field.name.clone(),
 					ord:    idx as i32,
 					sticky: field.sticky.unwrap_or(false),
-					rtl:    model.defaults.as_ref().map(|d| d.rtl).unwrap_or(false),
+					rtl:    field.rtl.or_else(|| model.defaults.as_ref().map(|d| d.rtl)).unwrap_or(false),
 					font:   model
 						.defaults
 						.as_ref()
@@ -207,41 +435,113 @@ impl<'a> From<&'a crate::types::note::NoteModel> for super::crowd_anki_models::N
 	}
 }
 
+/// Anki only supports a single hint per cloze, so multiple hints are joined
+/// into one using this separator
+pub const CLOZE_HINT_SEPARATOR: &str = "; ";
+
 /// This type represents Cloze's as anki expects them in note fields
 pub struct ClozeString(String);
 
 impl From<Cloze> for ClozeString {
 	fn from(cloze: Cloze) -> Self {
-		if let Some(hint) = cloze.hint {
-			ClozeString(format!("{{{{c{}::{}::{}}}}}", cloze.id, cloze.answer, hint))
+		let id = cloze.id;
+		ClozeString::asked_on(cloze, &[id])
+	}
+}
+
+impl ClozeString {
+	/// A cloze asked for on each of the cards `numbers`, comma separated as Anki
+	/// reads a cloze belonging to several
+	pub fn asked_on(cloze: Cloze, numbers: &[u32]) -> Self {
+		let numbers: Vec<String> = numbers.iter().map(u32::to_string).collect();
+		let numbers = numbers.join(",");
+		if !cloze.hint.is_empty() {
+			let hint = cloze.hint.join(CLOZE_HINT_SEPARATOR);
+			ClozeString(format!("{{{{c{}::{}::{}}}}}", numbers, cloze.answer, hint))
 		} else {
-			ClozeString(format!("{{{{c{}::{}}}}}", cloze.id, cloze.answer))
+			ClozeString(format!("{{{{c{}::{}}}}}", numbers, cloze.answer))
 		}
 	}
 }
 
+impl crate::types::note::Field {
+	/// Wrap rendered content so its language and direction are carried into the
+	/// card, leaving it untouched when the field declares neither
+	pub fn wrap_content(&self, content: String) -> String {
+		let mut attributes = String::new();
+		if let Some(lang) = &self.lang {
+			attributes.push_str(&format!(" lang=\"{}\"", lang));
+		}
+		if self.rtl == Some(true) {
+			attributes.push_str(" dir=\"rtl\"");
+		}
+
+		if attributes.is_empty() { content } else { format!("<span{}>{}</span>", attributes, content) }
+	}
+}
+
 impl<'a> From<Identified<crate::types::note::Note<'a>>> for Note {
 	fn from(note: Identified<crate::types::note::Note<'a>>) -> Self {
-		let inner_note = note.inner;
+		let mut inner_note = note.inner;
+
+		// The cards each cloze is asked for on, which `@cloze reveal-one` turns
+		// around
+		let numbers: BTreeMap<u32, Vec<u32>> = inner_note
+			.fields
+			.iter()
+			.flat_map(|field| &field.content)
+			.filter_map(|part| match part {
+				TextElement::Cloze(cloze) => Some((cloze.id, inner_note.cloze_numbers(cloze.id))),
+				_ => None,
+			})
+			.collect();
+		let model = inner_note.model;
+
+		// Empty fields stay empty, so the templates' sections still see them as such
+		let classes = inner_note.classes.join(" ");
+		let classed = |content: String| {
+			if classes.is_empty() || content.is_empty() {
+				content
+			} else {
+				format!("<div class=\"{}\">{}</div>", classes, content)
+			}
+		};
+
 		Note {
 			guid:            note.id.to_string(),
-			note_model_uuid: inner_note.model.id.to_string(),
-			fields:          inner_note
+			note_model_uuid: model.id.to_string(),
+			// Anki expects every field in model order, omitted fields are left empty
+			fields:          model
 				.fields
-				.into_iter()
-				.map(|field| {
-					field
+				.iter()
+				.map(|model_field| {
+					let Some(idx) = inner_note.fields.iter().position(|f| f.name == model_field.name) else {
+						// The comments above the note stand in for the field the model routes them to
+						if model.comment_field.as_ref() == Some(&model_field.name)
+							&& !inner_note.comments.is_empty()
+						{
+							return classed(model_field.wrap_content(inner_note.comments.join("<br>")));
+						}
+						return String::new();
+					};
+
+					let content = inner_note
+						.fields
+						.swap_remove(idx)
 						.content
 						.into_iter()
 						.map(|elem| match elem {
 							crate::types::note::TextElement::Text(s) => s,
+							crate::types::note::TextElement::Html(s) => s,
 							crate::types::note::TextElement::Cloze(c) => {
 								// Turn into cloze string
-								let clozed: ClozeString = c.into();
-								clozed.0
+								let asked_on = numbers.get(&c.id).cloned().unwrap_or_else(|| vec![c.id]);
+								ClozeString::asked_on(c, &asked_on).0
 							}
 						})
-						.collect::<String>()
+						.collect::<String>();
+
+					classed(model_field.wrap_content(content))
 				})
 				.collect(),
 			tags:            inner_note.tags,
@@ -251,3 +551,326 @@ impl<'a> From<Identified<crate::types::note::Note<'a>>> for Note {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	/// The first field of the note `source` holds, as it's exported
+	fn exported(source: &str) -> String {
+		let models = test_support::models();
+		let note = test_support::parse(&models, source).remove(0);
+		let note: Note = Identified { id: Uuid::nil(), inner: note }.into();
+		note.fields[0].clone()
+	}
+
+	#[test]
+	fn hints_are_joined_into_the_single_one_anki_takes() {
+		let text = exported("=Cloze=\nText: {c1::Paris|a city|in France}\n");
+		assert_eq!(text, format!("{{{{c1::Paris::a city{}in France}}}}", CLOZE_HINT_SEPARATOR));
+	}
+
+	/// A model declaring Second ahead of First, both read from .hbs files
+	const ORDERED_CONFIG: &str = r#"
+schema_version = "1.0.0"
+name           = "Ordered"
+id             = "7d2c4e6a-1b3f-5a8c-9e0d-2f4a6c8e0b12"
+required       = "Front"
+fields         = [{ name = "Front" }]
+templates      = [{ name = "Second" }, { name = "First" }]
+"#;
+
+	#[test]
+	fn templates_follow_the_order_config_declares() {
+		let scratch = test_support::Scratch::new();
+		for name in ["Extra", "First", "Second"] {
+			scratch.write(&format!("{}+front.hbs", name), "{{Front}}");
+			scratch.write(&format!("{}+back.hbs", name), "{{Front}}");
+		}
+
+		let mut model: crate::types::note::NoteModel =
+			toml::from_str(ORDERED_CONFIG).expect("the model is valid");
+		model.complete(&scratch.path).expect("the templates load");
+
+		let names: Vec<(&str, i32)> =
+			model.templates.iter().map(|t| (t.name.as_str(), t.order)).collect();
+		assert_eq!(names, vec![("Second", 0), ("First", 1), ("Extra", 2)]);
+	}
+
+	/// A model whose Back is written right to left in Arabic
+	const ARABIC_CONFIG: &str = r#"
+schema_version = "1.0.0"
+name           = "Arabic"
+id             = "3e5a7c9b-2d4f-5b6a-8c0e-1f3a5c7e9b24"
+required       = "Front"
+fields         = [{ name = "Front" }, { name = "Back", lang = "ar", rtl = true }]
+templates      = [{ name = "Card 1", qfmt = "{{Front}}", afmt = "{{Back}}" }]
+"#;
+
+	#[test]
+	fn fields_carry_their_language_and_direction_into_the_card() {
+		let model: crate::types::note::NoteModel =
+			toml::from_str(ARABIC_CONFIG).expect("the model is valid");
+		let models = [model];
+		let note = test_support::parse(&models, "=Arabic=\nFront: book\nBack: kitab\n").remove(0);
+		let note: Note = Identified { id: Uuid::nil(), inner: note }.into();
+
+		assert_eq!(note.fields, vec!["book", "<span lang=\"ar\" dir=\"rtl\">kitab</span>"]);
+	}
+
+	#[test]
+	fn a_field_direction_overrides_the_model_default() {
+		let model: crate::types::note::NoteModel =
+			toml::from_str(ARABIC_CONFIG).expect("the model is valid");
+		let model = crate::types::crowd_anki_models::NoteModel::from(&model);
+
+		let directions: Vec<bool> = model.flds.iter().map(|field| field.rtl).collect();
+		assert_eq!(directions, vec![false, true]);
+	}
+
+	/// Every field of the note `source` holds, as it's exported
+	fn exported_fields(source: &str) -> Vec<String> {
+		let models = test_support::models();
+		let note = test_support::parse(&models, source).remove(0);
+		Note::from(Identified { id: Uuid::nil(), inner: note }).fields
+	}
+
+	#[test]
+	fn fields_are_exported_in_model_order() {
+		assert_eq!(exported_fields("=Basic=\nBack: b\nFront: a\n"), vec!["a", "b"]);
+	}
+
+	#[test]
+	fn omitted_fields_are_exported_empty() {
+		assert_eq!(exported_fields("=Basic=\nFront: a\n"), vec!["a", ""]);
+	}
+
+	#[test]
+	fn the_model_kind_is_exported() {
+		let models = test_support::models();
+		let kinds: Vec<String> = models
+			.iter()
+			.map(|model| {
+				serde_json::to_value(crate::types::crowd_anki_models::NoteModel::from(model)).unwrap()
+			})
+			.map(|model| model["type"].to_string())
+			.collect();
+
+		// Basic, Image Occlusion and Cloze
+		assert_eq!(kinds, vec!["0", "1", "1"]);
+	}
+
+	#[test]
+	fn a_single_note_exports_under_its_identity() {
+		let deck = test_support::deck(&["=Basic=\nFront: a\nBack: b\n"]);
+		let note = &deck.cards[0];
+
+		let CrowdAnkiEntity::Note(exported) = note.inner.to_crowd_anki_entity(note.id, &deck) else {
+			panic!("a note exports as a note")
+		};
+		assert_eq!(exported.guid, note.id.to_string());
+		assert_eq!(exported.note_model_uuid, note.inner.model.id.to_string());
+		assert_eq!(exported.fields, vec!["a", "b"]);
+	}
+
+	#[test]
+	fn tags_play_no_part_in_a_note_identity() {
+		let models = test_support::models();
+		let notes = test_support::parse(
+			&models,
+			"=Basic=\n[b, a]\nFront: x\nBack: y\n\n[a, b]\nFront: x\nBack: y\n\nFront: x\nBack: y\n",
+		);
+
+		assert_eq!(notes[0].to_content_string(), notes[1].to_content_string());
+		assert_eq!(notes[0].to_content_string(), notes[2].to_content_string());
+	}
+
+	#[test]
+	fn cloze_numbers_and_hints_play_no_part_in_a_note_identity() {
+		let models = test_support::models();
+		let notes = test_support::parse(
+			&models,
+			"=Cloze=\nText: {c1::x}\n\nText: {c2::x}\n\nText: {x|a hint}\n",
+		);
+
+		assert_eq!(notes[0].to_content_string(), notes[1].to_content_string());
+		assert_eq!(notes[0].to_content_string(), notes[2].to_content_string());
+	}
+
+	#[test]
+	fn a_note_round_trips_through_json_by_model_name() {
+		let models = test_support::models();
+		let note =
+			test_support::parse(&models, "=Cloze=\n// why\n[t]\nText: {c1::a|hint} b\n").remove(0);
+
+		let json = serde_json::to_value(&note).expect("the note serializes");
+		assert_eq!(json["model"], "Cloze");
+
+		let detached: DetachedNote = serde_json::from_value(json).expect("the note deserializes");
+		assert_eq!(detached.link(&models).expect("the model is known"), note);
+	}
+
+	#[test]
+	fn linking_to_an_unknown_model_fails() {
+		let models = test_support::models();
+		let note = test_support::parse(&models, "=Basic=\nFront: a\n").remove(0);
+		let mut detached = DetachedNote::from(&note);
+		detached.model = "Missing".to_string();
+
+		assert!(
+			matches!(detached.link(&models), Err(DeckError::ModelNotFound(name)) if name == "Missing")
+		);
+	}
+
+	#[test]
+	fn the_stable_uuid_is_the_id_a_deck_gives_the_note() {
+		let source = "=Basic=\nFront: a\nBack: b\n";
+		let models = test_support::models();
+		let note = test_support::parse(&models, source).remove(0);
+
+		let host = test_support::host();
+		assert_eq!(note.stable_uuid(&host), test_support::deck(&[source]).cards[0].id);
+		assert_ne!(note.stable_uuid(&host), note.stable_uuid(&Uuid::nil()));
+	}
+
+	/// A model of the given kind and fields, with no templates of its own
+	fn untemplated(kind: &str, fields: &str) -> Result<crate::types::note::NoteModel, DeckError> {
+		let config = format!(
+			r#"
+schema_version = "1.0.0"
+name           = "Bare"
+id             = "{}"
+type           = "{}"
+required       = "true"
+fields         = {}
+templates      = []
+"#,
+			Uuid::nil(),
+			kind,
+			fields
+		);
+		let mut model: crate::types::note::NoteModel =
+			toml::from_str(&config).expect("the model is valid");
+
+		let scratch = test_support::Scratch::new();
+		model.complete(&scratch.path).map(|_| model)
+	}
+
+	#[test]
+	fn models_without_templates_fall_back_on_a_default() {
+		let model = untemplated("standard", "[{ name = \"Front\" }, { name = \"Back\" }]")
+			.expect("a default is found");
+		assert_eq!(model.templates.len(), 1);
+		assert_eq!(model.templates[0].question_format, "{{Front}}");
+		assert!(model.templates[0].answer_format.ends_with("{{Back}}"));
+
+		let model = untemplated("cloze", "[{ name = \"Text\" }]").expect("a default is found");
+		assert_eq!(model.templates[0].question_format, "{{cloze:Text}}");
+	}
+
+	#[test]
+	fn a_single_standard_field_has_no_default_template() {
+		let result = untemplated("standard", "[{ name = \"Front\" }]");
+		assert!(matches!(result, Err(DeckError::NoTemplates(_))));
+	}
+
+	#[test]
+	fn trimming_a_field_strips_its_edges_and_line_ends() {
+		let cloze = TextElement::Cloze(Cloze { id: 1, answer: "c".to_string(), hint: vec![] });
+		let mut field = crate::types::note::NoteField {
+			name:    "Front".to_string(),
+			content: vec![
+				TextElement::Text("  ".to_string()),
+				TextElement::Text(" a  \nb ".to_string()),
+				cloze.clone(),
+				TextElement::Html(" \n".to_string()),
+			],
+		};
+		field.trim();
+
+		// The space leading into the cloze is content, and stays
+		assert_eq!(field.content, vec![TextElement::Text("a\nb ".to_string()), cloze]);
+	}
+
+	#[test]
+	fn content_strings_prefix_every_name_and_part_with_its_length() {
+		let models = test_support::models();
+		let note = test_support::parse(&models, "=Basic=\nFront: a\nBack: bc\n").remove(0);
+		assert_eq!(note.to_content_string(), "5:Front1:1:a4:Back1:2:bc");
+	}
+
+	#[test]
+	fn content_cant_pass_for_a_field_boundary() {
+		let models = test_support::models();
+		let mut first = test_support::parse(&models, "=Basic=\nFront: c\n").remove(0);
+		first.fields[0].name = "AB".to_string();
+		let mut second = test_support::parse(&models, "=Basic=\nFront: Bc\n").remove(0);
+		second.fields[0].name = "A".to_string();
+
+		assert_ne!(first.to_content_string(), second.to_content_string());
+	}
+
+	#[test]
+	fn comments_flow_into_the_comment_field_unless_the_note_fills_it() {
+		let mut models = test_support::models();
+		models[0].comment_field = Some("Back".to_string());
+		let source = "=Basic=\n// one\n// two\nFront: a\n\n// left out\nFront: c\nBack: own\n";
+		let fields: Vec<Vec<String>> = test_support::parse(&models, source)
+			.into_iter()
+			.map(|note| Note::from(Identified { id: Uuid::nil(), inner: note }).fields)
+			.collect();
+
+		assert_eq!(fields, vec![vec!["a", "one<br>two"], vec!["c", "own"]]);
+	}
+
+	#[test]
+	fn clozes_sharing_a_number_are_exported_apart_for_a_single_card() {
+		let source = "=Cloze=\nText: {c1::first} and then {c1::second}\n";
+		assert_eq!(exported(source), "{{c1::first}} and then {{c1::second}}");
+
+		let models = test_support::models();
+		let note = test_support::parse(&models, source).remove(0);
+		assert_eq!(crate::render::render_note(&note).len(), 1);
+	}
+
+	#[test]
+	fn classes_wrap_each_filled_field_without_changing_identity() {
+		let source = "=Basic=\n@class loud wide\nFront: a\n";
+		assert_eq!(exported_fields(source), vec!["<div class=\"loud wide\">a</div>", ""]);
+
+		let models = test_support::models();
+		let classed = test_support::parse(&models, source).remove(0);
+		let plain = test_support::parse(&models, "=Basic=\nFront: a\n").remove(0);
+		assert_eq!(classed.stable_uuid(&Uuid::nil()), plain.stable_uuid(&Uuid::nil()));
+	}
+
+	#[test]
+	fn clozes_are_asked_for_on_their_own_card_by_default() {
+		let text = exported("=Cloze=\nText: {c1::a} and {c2::b} and {c3::c}\n");
+		assert_eq!(text, "{{c1::a}} and {{c2::b}} and {{c3::c}}");
+	}
+
+	#[test]
+	fn revealing_one_asks_for_every_other_cloze() {
+		let text = exported("=Cloze=\n@cloze reveal-one\nText: {c1::a} and {c2::b|hint} and {c3::c}\n");
+		assert_eq!(text, "{{c2,3::a}} and {{c1,3::b::hint}} and {{c1,2::c}}");
+	}
+
+	#[test]
+	fn revealing_one_of_a_single_number_changes_nothing() {
+		let text = exported("=Cloze=\n@cloze reveal-one\nText: {c1::a} and {c1::b}\n");
+		assert_eq!(text, "{{c1::a}} and {{c1::b}}");
+	}
+
+	#[test]
+	fn the_reveal_plays_no_part_in_identity() {
+		let models = test_support::models();
+		let plain = test_support::parse(&models, "=Cloze=\nText: {c1::a} {c2::b}\n").remove(0);
+		let turned =
+			test_support::parse(&models, "=Cloze=\n@cloze reveal-one\nText: {c1::a} {c2::b}\n").remove(0);
+
+		assert_eq!(turned.reveal, ClozeReveal::One);
+		assert_eq!(plain.fields, turned.fields);
+	}
+}
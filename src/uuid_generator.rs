@@ -1,16 +1,34 @@
 use tracing::{debug, instrument, warn};
 use uuid::Uuid;
 
+use crate::types::note::Note;
+
+/// The root namespace hosts are derived within, unless a deck configures its
+/// own
+pub const DEFAULT_NAMESPACE: Uuid = Uuid::NAMESPACE_DNS;
+
 /// Creates the main UUID based on the author of the initial commit and the
-/// time
+/// time, scoped to a root namespace so identical decks can be kept distinct
 #[instrument]
-pub fn create_host_uuid(author: String, time: i64) -> Uuid {
+pub fn create_host_uuid(root: &Uuid, author: String, time: i64) -> Uuid {
 	debug!("Creating host UUID for author: {}, time: {}", author, time);
 
 	// Note: This is fragile and will break under rebase conditions
-	// This is inherent to the design for deterministic generation
+	// This is inherent to the design for deterministic generation. Commits
+	// without an author come through as `history::UNKNOWN_AUTHOR`, and the
+	// time is written as is, negative or far off as it may be
 	let namespace = format!("{}{}", author, time);
-	Uuid::new_v5(&Uuid::NAMESPACE_DNS, namespace.as_bytes())
+	Uuid::new_v5(root, namespace.as_bytes())
+}
+
+/// Creates the main UUID from a host identity set in the deck's config, so it
+/// holds however the history is rewritten and whoever makes the commits
+#[instrument]
+pub fn create_configured_host_uuid(root: &Uuid, host: &str) -> Uuid {
+	debug!("Creating host UUID for configured host: {}", host);
+
+	// Prefixed so a configured host can never collide with an author and time
+	Uuid::new_v5(root, format!("host:{}", host).as_bytes())
 }
 
 /// Generate a UUID for a specific note based on its content
@@ -18,3 +36,77 @@ pub fn create_host_uuid(author: String, time: i64) -> Uuid {
 pub fn generate_note_uuid(host_uuid: &Uuid, content: &str) -> Uuid {
 	Uuid::new_v5(host_uuid, content.as_bytes())
 }
+
+/// What a deck knows about where its notes come from, for a `UuidStrategy` to
+/// draw on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostContext {
+	// The namespace the deck's notes are identified within, see
+	// `create_host_uuid`
+	pub host_uuid: Uuid,
+}
+
+/// Decides the identity a note is given when it first appears, or when it
+/// changes beyond recognition. Notes otherwise keep the identity they were
+/// given as the history is replayed, whatever the strategy.
+pub trait UuidStrategy {
+	fn note_id(&self, note: &Note, ctx: &HostContext) -> Uuid;
+}
+
+/// Identities derived from the note's content within the deck's host, so the
+/// same note in two decks is two notes in Anki. The default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HostScoped;
+
+impl UuidStrategy for HostScoped {
+	fn note_id(&self, note: &Note, ctx: &HostContext) -> Uuid { note.stable_uuid(&ctx.host_uuid) }
+}
+
+/// Identities derived from the note's content alone, so the same note shares
+/// one identity wherever it's written
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ContentAddressed;
+
+impl UuidStrategy for ContentAddressed {
+	fn note_id(&self, note: &Note, _ctx: &HostContext) -> Uuid {
+		note.stable_uuid(&DEFAULT_NAMESPACE)
+	}
+}
+
+/// Generate the UUID for a named scheduling preset. It depends on the name
+/// alone, so every deck naming the same preset shares it in Anki, and
+/// re-exports reuse it rather than minting another.
+#[instrument]
+pub fn generate_preset_uuid(name: &str) -> Uuid {
+	Uuid::new_v5(&DEFAULT_NAMESPACE, format!("preset:{}", name).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_support;
+
+	const HOSTS: [HostContext; 2] =
+		[HostContext { host_uuid: Uuid::from_u128(1) }, HostContext { host_uuid: Uuid::from_u128(2) }];
+
+	#[test]
+	fn host_scoped_ids_differ_between_hosts() {
+		let models = test_support::models();
+		let note = test_support::parse(&models, "=Basic=\nFront: a\n").remove(0);
+
+		assert_ne!(HostScoped.note_id(&note, &HOSTS[0]), HostScoped.note_id(&note, &HOSTS[1]));
+		assert_eq!(HostScoped.note_id(&note, &HOSTS[0]), note.stable_uuid(&HOSTS[0].host_uuid));
+	}
+
+	#[test]
+	fn content_addressed_ids_hold_across_hosts() {
+		let models = test_support::models();
+		let note = test_support::parse(&models, "=Basic=\nFront: a\n").remove(0);
+
+		assert_eq!(
+			ContentAddressed.note_id(&note, &HOSTS[0]),
+			ContentAddressed.note_id(&note, &HOSTS[1])
+		);
+		assert_ne!(ContentAddressed.note_id(&note, &HOSTS[0]), HostScoped.note_id(&note, &HOSTS[0]));
+	}
+}